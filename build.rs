@@ -0,0 +1,18 @@
+//! Records the git commit the daemon is built from, reported by `ctl status`.
+
+use std::process::Command;
+
+fn main() {
+    let commit = Command::new("git")
+        .args(["rev-parse", "--short", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_string());
+    // Builds from a source tarball have no commit
+    if let Some(commit) = commit {
+        println!("cargo:rustc-env=SLEEPWATCHER_GIT_COMMIT={}", commit);
+    }
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs/heads");
+}
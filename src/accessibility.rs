@@ -0,0 +1,57 @@
+//! Idle policy for screen reader users. Someone listening to a screen reader can go a long
+//! time without touching the keyboard or pointer, so idle timeouts are extended or idle
+//! actions held back while one runs.
+
+use log::info;
+use std::sync::{Arc, Mutex};
+
+use super::settings::{AccessibilitySettings, ScreenReaderPolicy};
+
+#[derive(Debug)]
+pub struct Accessibility {
+    settings: AccessibilitySettings,
+    screen_reader: bool,
+}
+
+pub type AccessibilityHandle = Arc<Mutex<Accessibility>>;
+
+impl Accessibility {
+    pub fn new(settings: AccessibilitySettings) -> AccessibilityHandle {
+        Arc::new(Mutex::new(Self {
+            settings,
+            screen_reader: false,
+        }))
+    }
+
+    pub fn screen_reader(&self) -> bool {
+        self.screen_reader
+    }
+
+    /// Updates the screen reader state. Returns whether the timeouts changed, then the idle
+    /// notifications need to be recreated.
+    pub fn set_screen_reader(&mut self, active: bool) -> bool {
+        if self.screen_reader == active {
+            return false;
+        }
+        info!(
+            "Screen reader {}",
+            if active { "started" } else { "stopped" }
+        );
+        let previous = self.multiplier();
+        self.screen_reader = active;
+        self.multiplier() != previous
+    }
+
+    /// Factor the idle timeouts are scaled with.
+    pub fn multiplier(&self) -> f64 {
+        match (self.screen_reader, self.settings.screen_reader) {
+            (true, ScreenReaderPolicy::Extend) => self.settings.extend_multiplier,
+            _ => 1.0,
+        }
+    }
+
+    /// Whether idle actions are held back altogether.
+    pub fn inhibits_idle(&self) -> bool {
+        self.screen_reader && self.settings.screen_reader == ScreenReaderPolicy::Disable
+    }
+}
@@ -0,0 +1,178 @@
+//! Activity sources of the Lua config, for input the compositor doesn't count, like a MIDI
+//! controller or a drawing tablet daemon. Reported activity resets a timer layered above the
+//! idle notifications: an idle timeout only passes once both the compositor and the sources
+//! were quiet for that long.
+
+use log::{debug, info};
+use mlua::{UserData, UserDataMethods};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, Notify};
+use uuid::Uuid;
+
+use super::types::Request;
+use super::utils;
+
+#[derive(Debug, Default)]
+struct Source {
+    last: Option<Instant>,
+    /// Declared by the daemon, kept across config reloads
+    builtin: bool,
+}
+
+#[derive(Debug, Default)]
+pub struct Activity {
+    sources: BTreeMap<String, Source>,
+    /// Last report of any source
+    last: Option<Instant>,
+    /// Idle notifications that idled on the compositor side while a source was active, with
+    /// their timeout. They idle once the sources are quiet for that long.
+    held: HashMap<Uuid, Duration>,
+    changed: Arc<Notify>,
+}
+
+pub type ActivityHandle = Arc<Mutex<Activity>>;
+
+impl Activity {
+    pub fn new() -> ActivityHandle {
+        Arc::new(Mutex::new(Self::default()))
+    }
+
+    pub fn clear(&mut self) {
+        self.sources.retain(|_, source| source.builtin);
+        self.held.clear();
+    }
+
+    pub fn declare_builtin(&mut self, name: &str) {
+        info!("Activity source {} declared by the daemon", name);
+        self.sources.entry(name.to_string()).or_default().builtin = true;
+    }
+
+    pub fn has_source(&self, name: &str) -> bool {
+        self.sources.contains_key(name)
+    }
+
+    /// Records activity of a declared source.
+    pub fn report(&mut self, name: &str) -> anyhow::Result<()> {
+        let Some(source) = self.sources.get_mut(name) else {
+            anyhow::bail!("Unknown activity source {}", name);
+        };
+        let now = Instant::now();
+        source.last = Some(now);
+        self.last = Some(now);
+        debug!("Activity reported by {}", name);
+        self.changed.notify_one();
+        Ok(())
+    }
+
+    /// Whether a source reported activity within `timeout`.
+    pub fn active_within(&self, timeout: Duration) -> bool {
+        self.last.is_some_and(|last| last.elapsed() < timeout)
+    }
+
+    /// Time since the last report of `name`, or of any source.
+    pub fn since(&self, name: Option<&str>) -> Option<Duration> {
+        let last = match name {
+            Some(name) => self.sources.get(name)?.last,
+            None => self.last,
+        };
+        last.map(|last| last.elapsed())
+    }
+
+    pub fn is_held(&self, uuid: &Uuid) -> bool {
+        self.held.contains_key(uuid)
+    }
+
+    pub fn hold(&mut self, uuid: Uuid, timeout: Duration) {
+        self.held.insert(uuid, timeout);
+        self.changed.notify_one();
+    }
+
+    /// Stops holding an idle notification, returns whether it was held.
+    pub fn release(&mut self, uuid: &Uuid) -> bool {
+        self.held.remove(uuid).is_some()
+    }
+
+    /// Removes and returns the held notifications whose timeout passed since the last report.
+    fn due(&mut self) -> Vec<Uuid> {
+        let Some(last) = self.last else {
+            return self.held.drain().map(|(uuid, _)| uuid).collect();
+        };
+        let due: Vec<Uuid> = self
+            .held
+            .iter()
+            .filter(|(_, timeout)| last.elapsed() >= **timeout)
+            .map(|(uuid, _)| *uuid)
+            .collect();
+        for uuid in &due {
+            self.held.remove(uuid);
+        }
+        due
+    }
+
+    fn next_due(&self) -> Option<Instant> {
+        let last = self.last?;
+        self.held.values().map(|timeout| last + *timeout).min()
+    }
+}
+
+/// Lets held idle notifications idle once the activity sources were quiet for their timeout.
+pub async fn activity_run(activity: ActivityHandle, tx: mpsc::Sender<Request>) {
+    let changed = activity.lock().unwrap().changed.clone();
+    loop {
+        let (due, next) = {
+            let mut activity = activity.lock().unwrap();
+            (activity.due(), activity.next_due())
+        };
+        for uuid in due {
+            let _ = tx.send(Request::ActivityIdle(uuid)).await;
+        }
+        match next {
+            Some(next) => tokio::select! {
+                _ = tokio::time::sleep_until(next.into()) => {},
+                _ = changed.notified() => {},
+            },
+            None => changed.notified().await,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ActivityHelpers {
+    pub activity: ActivityHandle,
+    pub tx: mpsc::Sender<Request>,
+}
+
+impl UserData for ActivityHelpers {
+    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_method("source", |_lua, this, name: String| {
+            info!("Activity source {} declared", name);
+            this.activity
+                .lock()
+                .unwrap()
+                .sources
+                .entry(name)
+                .or_default();
+            Ok(())
+        });
+        methods.add_method("report", |_lua, this, name: String| {
+            if !this.activity.lock().unwrap().has_source(&name) {
+                return Err(mlua::Error::RuntimeError(format!(
+                    "Unknown activity source {}",
+                    name
+                )));
+            }
+            utils::send_request(&this.tx, Request::Activity(name));
+            Ok(())
+        });
+        methods.add_method("since", |_lua, this, name: Option<String>| {
+            Ok(this
+                .activity
+                .lock()
+                .unwrap()
+                .since(name.as_deref())
+                .map(|since| since.as_secs_f64()))
+        });
+    }
+}
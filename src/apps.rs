@@ -0,0 +1,132 @@
+use glob::Pattern;
+use log::{debug, info};
+use mlua::{UserData, UserDataMethods, Value};
+use std::sync::{Arc, Mutex};
+
+#[derive(Debug)]
+enum Inhibit {
+    None,
+    All,
+    Callbacks(Vec<String>),
+}
+
+#[derive(Debug)]
+struct AppRule {
+    pattern: Pattern,
+    multiplier: f64,
+    inhibit: Inhibit,
+}
+
+/// Timeout rules for the focused application, matched against its Wayland app_id.
+/// The first matching rule wins.
+#[derive(Debug, Default)]
+pub struct AppRules {
+    rules: Vec<AppRule>,
+    focused: Option<String>,
+}
+
+pub type AppRulesHandle = Arc<Mutex<AppRules>>;
+
+impl AppRules {
+    pub fn new() -> AppRulesHandle {
+        Arc::new(Mutex::new(Self::default()))
+    }
+
+    pub fn clear(&mut self) {
+        self.rules.clear();
+    }
+
+    fn active_rule(&self) -> Option<&AppRule> {
+        let app_id = self.focused.as_ref()?;
+        self.rules.iter().find(|rule| rule.pattern.matches(app_id))
+    }
+
+    /// Factor all idle timeouts are scaled with while the focused application matches a rule.
+    pub fn multiplier(&self) -> f64 {
+        self.active_rule().map_or(1.0, |rule| rule.multiplier)
+    }
+
+    /// Whether idle events for the callback `fn_name` are suppressed for the focused application.
+    pub fn inhibits(&self, fn_name: &str) -> bool {
+        match self.active_rule().map(|rule| &rule.inhibit) {
+            Some(Inhibit::All) => true,
+            Some(Inhibit::Callbacks(callbacks)) => callbacks.iter().any(|name| name == fn_name),
+            Some(Inhibit::None) | None => false,
+        }
+    }
+
+    /// What the rule of the focused application inhibits, for the inhibitor list.
+    pub fn inhibited(&self) -> Option<String> {
+        match &self.active_rule()?.inhibit {
+            Inhibit::None => None,
+            Inhibit::All => Some("all idle callbacks".to_string()),
+            Inhibit::Callbacks(callbacks) => Some(callbacks.join(", ")),
+        }
+    }
+
+    pub fn focused(&self) -> Option<String> {
+        self.focused.clone()
+    }
+
+    /// Updates the focused application and returns true if the timeout multiplier changed.
+    pub fn set_focused(&mut self, app_id: Option<String>) -> bool {
+        if self.focused == app_id {
+            return false;
+        }
+        let previous = self.multiplier();
+        debug!("Focused application: {:?}", app_id);
+        self.focused = app_id;
+        let multiplier = self.multiplier();
+        if previous != multiplier {
+            info!(
+                "Idle timeout multiplier changed to {} for {:?}",
+                multiplier, self.focused
+            );
+        }
+        previous != multiplier
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct AppHelpers {
+    pub rules: AppRulesHandle,
+}
+
+impl UserData for AppHelpers {
+    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_method(
+            "rule",
+            |_lua, this, (pattern, options): (String, Option<mlua::Table>)| {
+                let pattern = Pattern::new(&pattern).map_err(|e| {
+                    mlua::Error::RuntimeError(format!("invalid app_id pattern {}: {}", pattern, e))
+                })?;
+                let mut multiplier = 1.0;
+                let mut inhibit = Inhibit::None;
+                if let Some(options) = options {
+                    multiplier = options.get::<_, Option<f64>>("multiplier")?.unwrap_or(1.0);
+                    if multiplier <= 0.0 {
+                        return Err(mlua::Error::RuntimeError(
+                            "multiplier has to be positive".to_string(),
+                        ));
+                    }
+                    inhibit = match options.get::<_, Value>("inhibit")? {
+                        Value::Boolean(true) => Inhibit::All,
+                        Value::Table(callbacks) => Inhibit::Callbacks(
+                            callbacks.sequence_values().collect::<mlua::Result<_>>()?,
+                        ),
+                        _ => Inhibit::None,
+                    };
+                }
+                this.rules.lock().unwrap().rules.push(AppRule {
+                    pattern,
+                    multiplier,
+                    inhibit,
+                });
+                Ok(())
+            },
+        );
+        methods.add_method("focused", |_lua, this, (): ()| {
+            Ok(this.rules.lock().unwrap().focused())
+        });
+    }
+}
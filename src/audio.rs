@@ -0,0 +1,196 @@
+//! Audio playback as a reason to stay awake. Music players and conference calls often don't
+//! inhibit through `org.freedesktop.ScreenSaver`, so with `enabled` in the `[audio]` section
+//! the playback streams are followed with `pactl`, which talks to PulseAudio and to PipeWire's
+//! pulse server alike. The config asks `Audio:is_playing()` or turns on `Audio:inhibit(true)`,
+//! which holds back idle callbacks while a stream plays.
+
+use anyhow::Context;
+use log::{debug, error, info};
+use mlua::{UserData, UserDataMethods};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::process::Stdio;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::process::Command;
+
+use super::settings::AudioSettings;
+
+/// Waited before `pactl subscribe` is started again, e.g. after the sound server restarted
+const RESTART_DELAY: Duration = Duration::from_secs(10);
+
+/// A sink input of `pactl --format=json list sink-inputs`.
+#[derive(Deserialize, Debug)]
+struct SinkInput {
+    corked: bool,
+    #[serde(default)]
+    properties: HashMap<String, String>,
+}
+
+/// A playback stream that isn't paused.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Stream {
+    pub application: String,
+    /// What is played, e.g. the title of a song, if the application tells
+    pub media: Option<String>,
+}
+
+#[derive(Debug, Default)]
+pub struct Audio {
+    settings: AudioSettings,
+    playing: Vec<Stream>,
+    /// Set by the config, holds back idle callbacks while a stream plays
+    inhibit: bool,
+}
+
+pub type AudioHandle = Arc<Mutex<Audio>>;
+
+impl Audio {
+    pub fn new(settings: AudioSettings) -> AudioHandle {
+        Arc::new(Mutex::new(Self {
+            settings,
+            ..Default::default()
+        }))
+    }
+
+    pub fn clear(&mut self) {
+        self.inhibit = false;
+    }
+
+    pub fn playing(&self) -> &[Stream] {
+        &self.playing
+    }
+
+    pub fn is_playing(&self) -> bool {
+        !self.playing.is_empty()
+    }
+
+    /// Whether idle callbacks are held back for the playback.
+    pub fn inhibits_idle(&self) -> bool {
+        self.inhibit && self.is_playing()
+    }
+
+    fn set_playing(&mut self, playing: Vec<Stream>) {
+        if self.playing == playing {
+            return;
+        }
+        match playing.is_empty() {
+            true => info!("Audio playback stopped"),
+            false => info!(
+                "Audio playing: {}",
+                playing
+                    .iter()
+                    .map(|stream| stream.application.as_str())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+        }
+        self.playing = playing;
+    }
+}
+
+/// The playback streams that aren't paused, without the ignored applications.
+async fn playing(ignore: &[String]) -> anyhow::Result<Vec<Stream>> {
+    let output = Command::new("pactl")
+        .args(["--format=json", "list", "sink-inputs"])
+        .output()
+        .await?;
+    if !output.status.success() {
+        anyhow::bail!(
+            "pactl failed with {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    let inputs: Vec<SinkInput> =
+        serde_json::from_slice(&output.stdout).context("pactl is too old for --format=json")?;
+    Ok(inputs
+        .into_iter()
+        .filter(|input| !input.corked)
+        .map(|mut input| Stream {
+            application: input
+                .properties
+                .remove("application.name")
+                .unwrap_or_else(|| "unknown".to_string()),
+            media: input.properties.remove("media.name"),
+        })
+        .filter(|stream| !ignore.contains(&stream.application))
+        .collect())
+}
+
+/// Follows the playback until `pactl subscribe` exits.
+async fn watch(audio: &AudioHandle, ignore: &[String]) -> anyhow::Result<()> {
+    let mut child = Command::new("pactl")
+        .arg("subscribe")
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .kill_on_drop(true)
+        .spawn()?;
+    let stdout = child.stdout.take().context("no stdout of pactl")?;
+    let mut lines = BufReader::new(stdout).lines();
+    let streams = playing(ignore).await?;
+    audio.lock().unwrap().set_playing(streams);
+    // e.g. `Event 'change' on sink-input #42`
+    while let Some(line) = lines.next_line().await? {
+        if line.contains("sink-input") {
+            debug!("pactl: {}", line);
+            let streams = playing(ignore).await?;
+            audio.lock().unwrap().set_playing(streams);
+        }
+    }
+    anyhow::bail!("pactl subscribe exited with {}", child.wait().await?)
+}
+
+/// Follows the playback streams if enabled in the settings.
+pub async fn audio_run(audio: AudioHandle) {
+    let settings = audio.lock().unwrap().settings.clone();
+    if !settings.enabled {
+        return;
+    }
+    info!("Following audio playback");
+    loop {
+        if let Err(e) = watch(&audio, &settings.ignore).await {
+            error!("Lost track of audio playback: {}", e);
+        }
+        audio.lock().unwrap().set_playing(Vec::new());
+        tokio::time::sleep(RESTART_DELAY).await;
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct AudioHelpers {
+    pub audio: AudioHandle,
+}
+
+impl UserData for AudioHelpers {
+    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_method("is_playing", |_lua, this, (): ()| {
+            Ok(this.audio.lock().unwrap().is_playing())
+        });
+        methods.add_method("streams", |lua, this, (): ()| {
+            let streams = lua.create_table()?;
+            for stream in this.audio.lock().unwrap().playing() {
+                let entry = lua.create_table()?;
+                entry.set("application", stream.application.as_str())?;
+                entry.set("media", stream.media.as_deref())?;
+                streams.push(entry)?;
+            }
+            Ok(streams)
+        });
+        methods.add_method("inhibit", |_lua, this, inhibit: bool| {
+            let mut audio = this.audio.lock().unwrap();
+            if !audio.settings.enabled && inhibit {
+                return Err(mlua::Error::RuntimeError(
+                    "audio playback isn't followed, set enabled in [audio]".to_string(),
+                ));
+            }
+            info!(
+                "Idle callbacks {} while audio plays",
+                if inhibit { "held back" } else { "run" }
+            );
+            audio.inhibit = inhibit;
+            Ok(())
+        });
+    }
+}
@@ -0,0 +1,613 @@
+//! Idle sources. The engine asks a backend for a watch per idle notification of the config and
+//! gets idled and resumed events back, so another platform only needs an implementation of
+//! `Backend`. The backend is picked at startup: the first available one of `[backend] order`
+//! in the settings.
+
+use glob::glob;
+use log::{debug, error, info, warn};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::fs::File;
+use std::io::Read;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{mpsc, Notify};
+use tokio::time::Instant;
+use uuid::Uuid;
+use wayland_client::protocol::wl_seat;
+use wayland_client::{Connection, Dispatch, Proxy, QueueHandle};
+use wayland_protocols::ext::idle_notify::v1::client::{
+    ext_idle_notification_v1, ext_idle_notifier_v1,
+};
+use wayland_protocols_plasma::idle::client::{org_kde_kwin_idle, org_kde_kwin_idle_timeout};
+
+use super::types::Request;
+use super::x11;
+
+/// ext-idle-notify-v1, supported by most Wayland compositors
+pub const WAYLAND: &str = "wayland-ext-idle";
+/// org_kde_kwin_idle of older KWin and sway before 1.8
+pub const KDE_IDLE: &str = "kde-idle";
+/// Reads the input devices directly, needs access to `/dev/input`
+pub const EVDEV: &str = "evdev";
+/// Idle time of the X server, for X11 sessions
+pub const X11: &str = "x11";
+/// Names of backends that other platforms would need, accepted in the settings
+const PLANNED: &[&str] = &["macos"];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum IdleEvent {
+    Idled,
+    Resumed,
+}
+
+/// Reports idle and resumed events of one notification until destroyed.
+pub trait IdleWatch: Send + Sync + fmt::Debug {
+    fn destroy(&self);
+}
+
+pub trait Backend: Send + Sync + fmt::Debug {
+    fn name(&self) -> &'static str;
+    /// Reports `uuid` idle after `timeout` milliseconds without input on `seat`, the default
+    /// seat if `None`, and resumed on the next input.
+    fn watch(&self, uuid: Uuid, timeout: u32, seat: Option<&str>) -> Box<dyn IdleWatch>;
+    /// Sends pending requests, for watches changed outside the backend's event loop.
+    fn flush(&self) {}
+}
+
+pub type BackendHandle = Arc<dyn Backend>;
+
+/// Picks the first available backend of `order`. The Wayland backends are set up with the
+/// rest of the Wayland globals, so they're passed in, the ones the compositor supports.
+pub fn select(
+    order: &[String],
+    wayland: Vec<BackendHandle>,
+    tx: &mpsc::Sender<Request>,
+) -> Option<BackendHandle> {
+    for name in order {
+        let backend = match name.as_str() {
+            WAYLAND | KDE_IDLE => wayland
+                .iter()
+                .find(|backend| backend.name() == name)
+                .cloned(),
+            EVDEV => Evdev::open(tx.clone()).map(|evdev| Arc::new(evdev) as BackendHandle),
+            X11 => X11Idle::open(tx.clone()).map(|x11| Arc::new(x11) as BackendHandle),
+            name if PLANNED.contains(&name) => {
+                info!("The {} idle backend isn't implemented yet", name);
+                None
+            }
+            name => {
+                warn!("Unknown idle backend {}", name);
+                None
+            }
+        };
+        match backend {
+            Some(backend) => {
+                info!("Using the {} idle backend", name);
+                return Some(backend);
+            }
+            None => debug!("Idle backend {} is not available", name),
+        }
+    }
+    None
+}
+
+/// Passed with the events of an ext-idle-notify notification.
+#[derive(Clone, Debug)]
+pub struct NotificationContext {
+    pub uuid: Uuid,
+}
+
+/// Stands in for the notification of a seat that isn't there (yet). It's recreated when the
+/// seat appears.
+#[derive(Debug)]
+struct Detached;
+
+impl IdleWatch for Detached {
+    fn destroy(&self) {}
+}
+
+#[derive(Debug)]
+struct Seat {
+    wl_seat: wl_seat::WlSeat,
+    /// Sent by the compositor after the bind, from `wl_seat` version 2
+    name: Option<String>,
+}
+
+/// The seats of the compositor by their registry name. Seats come and go at runtime, e.g.
+/// with a second keyboard and mouse set up as another seat.
+#[derive(Debug, Default)]
+pub struct Seats {
+    seats: BTreeMap<u32, Seat>,
+}
+
+pub type SeatsHandle = Arc<Mutex<Seats>>;
+
+impl Seats {
+    pub fn new() -> SeatsHandle {
+        Arc::new(Mutex::new(Self::default()))
+    }
+
+    pub fn add(&mut self, global: u32, wl_seat: wl_seat::WlSeat) {
+        self.seats.insert(
+            global,
+            Seat {
+                wl_seat,
+                name: None,
+            },
+        );
+    }
+
+    /// Removes the seat of the registry name `global`, returns it with its name.
+    pub fn remove(&mut self, global: u32) -> Option<(wl_seat::WlSeat, Option<String>)> {
+        self.seats
+            .remove(&global)
+            .map(|seat| (seat.wl_seat, seat.name))
+    }
+
+    pub fn set_name(&mut self, wl_seat: &wl_seat::WlSeat, name: String) {
+        if let Some(seat) = self
+            .seats
+            .values_mut()
+            .find(|seat| &seat.wl_seat == wl_seat)
+        {
+            seat.name = Some(name);
+        }
+    }
+
+    /// The seat that was announced first, used when the config doesn't ask for one.
+    pub fn default_seat(&self) -> Option<&wl_seat::WlSeat> {
+        self.seats.values().next().map(|seat| &seat.wl_seat)
+    }
+
+    pub fn default_name(&self) -> Option<String> {
+        self.seats
+            .values()
+            .next()
+            .and_then(|seat| seat.name.clone())
+    }
+
+    pub fn is_default_global(&self, global: u32) -> bool {
+        self.seats.keys().next() == Some(&global)
+    }
+
+    pub fn is_default(&self, wl_seat: &wl_seat::WlSeat) -> bool {
+        self.default_seat() == Some(wl_seat)
+    }
+
+    fn get(&self, name: Option<&str>) -> Option<&wl_seat::WlSeat> {
+        match name {
+            Some(name) => self
+                .seats
+                .values()
+                .find(|seat| seat.name.as_deref() == Some(name))
+                .map(|seat| &seat.wl_seat),
+            None => self.default_seat(),
+        }
+    }
+
+    /// Names of the seats, in the order they were announced.
+    pub fn names(&self) -> Vec<String> {
+        self.seats
+            .values()
+            .filter_map(|seat| seat.name.clone())
+            .collect()
+    }
+}
+
+/// The seat `name` of `seats`, the default one if `None`, and `None` if it's gone.
+fn find_seat(seats: &SeatsHandle, name: Option<&str>) -> Option<wl_seat::WlSeat> {
+    let seat = seats
+        .lock()
+        .unwrap()
+        .get(name)
+        .filter(|wl_seat| wl_seat.is_alive())
+        .cloned();
+    if seat.is_none() {
+        info!(
+            "Seat {} is not there, its idle notification waits for it",
+            name.unwrap_or("(default)")
+        );
+    }
+    seat
+}
+
+impl IdleWatch for ext_idle_notification_v1::ExtIdleNotificationV1 {
+    fn destroy(&self) {
+        ext_idle_notification_v1::ExtIdleNotificationV1::destroy(self);
+    }
+}
+
+/// Notifications of ext-idle-notify-v1. The events are dispatched by the Wayland event loop.
+pub struct WaylandIdle<D> {
+    idle_notifier: ext_idle_notifier_v1::ExtIdleNotifierV1,
+    seats: SeatsHandle,
+    qh: QueueHandle<D>,
+    conn: Connection,
+}
+
+impl<D> WaylandIdle<D> {
+    pub fn new(
+        idle_notifier: ext_idle_notifier_v1::ExtIdleNotifierV1,
+        seats: SeatsHandle,
+        qh: QueueHandle<D>,
+        conn: Connection,
+    ) -> Self {
+        Self {
+            idle_notifier,
+            seats,
+            qh,
+            conn,
+        }
+    }
+}
+
+impl<D> fmt::Debug for WaylandIdle<D> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WaylandIdle")
+            .field("idle_notifier", &self.idle_notifier)
+            .field("seats", &self.seats)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<D> Backend for WaylandIdle<D>
+where
+    D: Dispatch<ext_idle_notification_v1::ExtIdleNotificationV1, NotificationContext> + 'static,
+{
+    fn name(&self) -> &'static str {
+        WAYLAND
+    }
+
+    fn watch(&self, uuid: Uuid, timeout: u32, seat: Option<&str>) -> Box<dyn IdleWatch> {
+        let Some(wl_seat) = find_seat(&self.seats, seat) else {
+            return Box::new(Detached);
+        };
+        Box::new(self.idle_notifier.get_idle_notification(
+            timeout,
+            &wl_seat,
+            &self.qh,
+            NotificationContext { uuid },
+        ))
+    }
+
+    fn flush(&self) {
+        if let Err(e) = self.conn.flush() {
+            error!("Failed to flush the idle notifications: {}", e);
+        }
+    }
+}
+
+impl IdleWatch for org_kde_kwin_idle_timeout::OrgKdeKwinIdleTimeout {
+    fn destroy(&self) {
+        self.release();
+    }
+}
+
+/// Timeouts of org_kde_kwin_idle, for compositors that predate ext-idle-notify-v1. The events
+/// are dispatched by the Wayland event loop.
+pub struct KdeIdle<D> {
+    idle: org_kde_kwin_idle::OrgKdeKwinIdle,
+    seats: SeatsHandle,
+    qh: QueueHandle<D>,
+    conn: Connection,
+}
+
+impl<D> KdeIdle<D> {
+    pub fn new(
+        idle: org_kde_kwin_idle::OrgKdeKwinIdle,
+        seats: SeatsHandle,
+        qh: QueueHandle<D>,
+        conn: Connection,
+    ) -> Self {
+        Self {
+            idle,
+            seats,
+            qh,
+            conn,
+        }
+    }
+}
+
+impl<D> fmt::Debug for KdeIdle<D> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("KdeIdle")
+            .field("idle", &self.idle)
+            .field("seats", &self.seats)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<D> Backend for KdeIdle<D>
+where
+    D: Dispatch<org_kde_kwin_idle_timeout::OrgKdeKwinIdleTimeout, NotificationContext> + 'static,
+{
+    fn name(&self) -> &'static str {
+        KDE_IDLE
+    }
+
+    fn watch(&self, uuid: Uuid, timeout: u32, seat: Option<&str>) -> Box<dyn IdleWatch> {
+        let Some(wl_seat) = find_seat(&self.seats, seat) else {
+            return Box::new(Detached);
+        };
+        Box::new(self.idle.get_idle_timeout(
+            &wl_seat,
+            timeout,
+            &self.qh,
+            NotificationContext { uuid },
+        ))
+    }
+
+    fn flush(&self) {
+        if let Err(e) = self.conn.flush() {
+            error!("Failed to flush the idle timeouts: {}", e);
+        }
+    }
+}
+
+#[derive(Debug)]
+struct InputWatch {
+    timeout: Duration,
+    /// Idle time counts from here at the earliest, like for a new Wayland notification
+    created: Instant,
+    idled: bool,
+}
+
+/// Watches of the backends that only learn when the last input was, evdev and X11.
+#[derive(Debug)]
+struct InputState {
+    watches: HashMap<Uuid, InputWatch>,
+    last_input: Instant,
+}
+
+impl InputState {
+    fn new(last_input: Instant) -> Arc<Mutex<Self>> {
+        Arc::new(Mutex::new(Self {
+            watches: HashMap::new(),
+            last_input,
+        }))
+    }
+
+    /// Marks the watches that reached their timeout as idled and returns them, with when the
+    /// next one does.
+    fn poll(&mut self, now: Instant) -> (Vec<Uuid>, Option<Instant>) {
+        let mut idled = Vec::new();
+        let mut next = None;
+        for (uuid, watch) in &mut self.watches {
+            if watch.idled {
+                continue;
+            }
+            let due = self.last_input.max(watch.created) + watch.timeout;
+            if due <= now {
+                watch.idled = true;
+                idled.push(*uuid);
+            } else {
+                next = Some(next.map_or(due, |next: Instant| next.min(due)));
+            }
+        }
+        (idled, next)
+    }
+
+    /// Records input at `at` and returns the watches that resumed.
+    fn input(&mut self, at: Instant) -> Vec<Uuid> {
+        self.last_input = at;
+        self.watches
+            .iter_mut()
+            .filter(|(_, watch)| watch.idled)
+            .map(|(uuid, watch)| {
+                watch.idled = false;
+                *uuid
+            })
+            .collect()
+    }
+}
+
+/// Idle time from the input devices, for sessions without an idle protocol. Devices plugged
+/// in after the start are not read, and the input of all devices counts for every seat.
+#[derive(Debug)]
+pub struct Evdev {
+    state: Arc<Mutex<InputState>>,
+    changed: Arc<Notify>,
+}
+
+impl Evdev {
+    /// `None` if no input device can be read, usually because the user isn't in the `input`
+    /// group.
+    fn open(tx: mpsc::Sender<Request>) -> Option<Self> {
+        let devices: Vec<File> = glob("/dev/input/event*")
+            .ok()?
+            .flatten()
+            .filter_map(|path| match File::open(&path) {
+                Ok(file) => Some(file),
+                Err(e) => {
+                    debug!("Can't read {}: {}", path.display(), e);
+                    None
+                }
+            })
+            .collect();
+        if devices.is_empty() {
+            return None;
+        }
+        info!("Watching {} input devices", devices.len());
+        let evdev = Self {
+            state: InputState::new(Instant::now()),
+            changed: Arc::new(Notify::new()),
+        };
+        for device in devices {
+            let (state, changed, tx) = (evdev.state.clone(), evdev.changed.clone(), tx.clone());
+            std::thread::spawn(move || read_device(device, state, changed, tx));
+        }
+        tokio::spawn(input_run(evdev.state.clone(), evdev.changed.clone(), tx));
+        Some(evdev)
+    }
+}
+
+/// Reads input events until the device goes away. Only the fact that something arrived
+/// matters, not the events themselves.
+fn read_device(
+    mut device: File,
+    state: Arc<Mutex<InputState>>,
+    changed: Arc<Notify>,
+    tx: mpsc::Sender<Request>,
+) {
+    let mut buf = [0u8; 1024];
+    while let Ok(1..) = device.read(&mut buf) {
+        let resumed = state.lock().unwrap().input(Instant::now());
+        for uuid in resumed {
+            let _ = tx.blocking_send(Request::Idle(uuid, IdleEvent::Resumed));
+        }
+        changed.notify_one();
+    }
+}
+
+async fn input_run(state: Arc<Mutex<InputState>>, changed: Arc<Notify>, tx: mpsc::Sender<Request>) {
+    loop {
+        let (idled, next) = state.lock().unwrap().poll(Instant::now());
+        for uuid in idled {
+            let _ = tx.send(Request::Idle(uuid, IdleEvent::Idled)).await;
+        }
+        match next {
+            Some(next) => tokio::select! {
+                _ = tokio::time::sleep_until(next) => {},
+                _ = changed.notified() => {},
+            },
+            None => changed.notified().await,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct InputWatchHandle {
+    uuid: Uuid,
+    state: Arc<Mutex<InputState>>,
+    changed: Arc<Notify>,
+}
+
+impl IdleWatch for InputWatchHandle {
+    fn destroy(&self) {
+        self.state.lock().unwrap().watches.remove(&self.uuid);
+        self.changed.notify_one();
+    }
+}
+
+fn watch_input(
+    state: &Arc<Mutex<InputState>>,
+    changed: &Arc<Notify>,
+    uuid: Uuid,
+    timeout: u32,
+) -> Box<dyn IdleWatch> {
+    state.lock().unwrap().watches.insert(
+        uuid,
+        InputWatch {
+            timeout: Duration::from_millis(timeout as u64),
+            created: Instant::now(),
+            idled: false,
+        },
+    );
+    changed.notify_one();
+    Box::new(InputWatchHandle {
+        uuid,
+        state: state.clone(),
+        changed: changed.clone(),
+    })
+}
+
+impl Backend for Evdev {
+    fn name(&self) -> &'static str {
+        EVDEV
+    }
+
+    fn watch(&self, uuid: Uuid, timeout: u32, seat: Option<&str>) -> Box<dyn IdleWatch> {
+        if let Some(seat) = seat {
+            debug!(
+                "The evdev backend doesn't tell seats apart, watching all input for {}",
+                seat
+            );
+        }
+        watch_input(&self.state, &self.changed, uuid, timeout)
+    }
+}
+
+/// Idle time of the X server from the MIT-SCREEN-SAVER extension, for X11 sessions. The
+/// server only tells how long ago the last input was, so it's asked every `X11_POLL_INTERVAL`.
+#[derive(Debug)]
+pub struct X11Idle {
+    state: Arc<Mutex<InputState>>,
+    changed: Arc<Notify>,
+}
+
+impl X11Idle {
+    /// `None` without an X server, and under Wayland, where XWayland only sees the input of
+    /// X11 windows.
+    fn open(tx: mpsc::Sender<Request>) -> Option<Self> {
+        if std::env::var_os("WAYLAND_DISPLAY").is_some() {
+            debug!("Not using the X11 idle time of XWayland");
+            return None;
+        }
+        let query = x11::IdleQuery::connect()
+            .inspect_err(|e| debug!("Can't get the X11 idle time: {}", e))
+            .ok()?;
+        let idle = query.idle().ok()?;
+        let now = Instant::now();
+        let x11 = Self {
+            state: InputState::new(now.checked_sub(idle).unwrap_or(now)),
+            changed: Arc::new(Notify::new()),
+        };
+        let (state, changed, poll_tx) = (x11.state.clone(), x11.changed.clone(), tx.clone());
+        std::thread::spawn(move || poll_x11(query, state, changed, poll_tx));
+        tokio::spawn(input_run(x11.state.clone(), x11.changed.clone(), tx));
+        Some(x11)
+    }
+}
+
+/// Input is only noticed this late, and idle watches may idle this early
+const X11_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Asks the X server for the idle time until the connection is lost.
+fn poll_x11(
+    query: x11::IdleQuery,
+    state: Arc<Mutex<InputState>>,
+    changed: Arc<Notify>,
+    tx: mpsc::Sender<Request>,
+) {
+    loop {
+        let idle = match query.idle() {
+            Ok(idle) => idle,
+            Err(e) => {
+                error!("Lost the X11 idle time: {}", e);
+                return;
+            }
+        };
+        let now = Instant::now();
+        let last_input = now.checked_sub(idle).unwrap_or(now);
+        let resumed = {
+            let mut state = state.lock().unwrap();
+            // The idle time is in milliseconds and read a bit later each time
+            if last_input.saturating_duration_since(state.last_input) < Duration::from_millis(50) {
+                None
+            } else {
+                Some(state.input(last_input))
+            }
+        };
+        if let Some(resumed) = resumed {
+            for uuid in resumed {
+                let _ = tx.blocking_send(Request::Idle(uuid, IdleEvent::Resumed));
+            }
+            changed.notify_one();
+        }
+        std::thread::sleep(X11_POLL_INTERVAL);
+    }
+}
+
+impl Backend for X11Idle {
+    fn name(&self) -> &'static str {
+        X11
+    }
+
+    fn watch(&self, uuid: Uuid, timeout: u32, seat: Option<&str>) -> Box<dyn IdleWatch> {
+        if let Some(seat) = seat {
+            debug!("X11 has no seats, watching all input for {}", seat);
+        }
+        watch_input(&self.state, &self.changed, uuid, timeout)
+    }
+}
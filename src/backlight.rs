@@ -0,0 +1,214 @@
+//! The `Backlight` global: brightness in percent of the devices in `/sys/class/backlight`,
+//! without brightnessctl. Writes go through logind's `SetBrightness`, which any user of an
+//! active session may call, and fall back to the privileged helper. Like `Power:set_backlight`,
+//! changes inside an idle stage are put back when the user returns, see `restore.rs`.
+
+use log::{debug, error, info, warn};
+use mlua::{UserData, UserDataMethods};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+use super::dbus::logind_session;
+use super::privileged::{self, Action};
+use super::restore::RestoreHandle;
+use super::types::Request;
+use super::utils;
+
+const BACKLIGHT_DIR: &str = "/sys/class/backlight";
+/// Time between two steps of a fade
+const FADE_STEP: Duration = Duration::from_millis(40);
+
+pub fn read(device: &str, file: &str) -> anyhow::Result<u32> {
+    let path = PathBuf::from(BACKLIGHT_DIR).join(device).join(file);
+    Ok(fs::read_to_string(path)?.trim().parse()?)
+}
+
+/// The backlights sorted by name.
+fn devices() -> anyhow::Result<Vec<String>> {
+    let mut devices: Vec<String> = fs::read_dir(BACKLIGHT_DIR)?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.file_name().to_string_lossy().into_owned())
+        .collect();
+    devices.sort();
+    Ok(devices)
+}
+
+/// The first backlight by name, which is the only one on most laptops.
+fn default_device() -> anyhow::Result<String> {
+    devices()?
+        .into_iter()
+        .next()
+        .ok_or_else(|| anyhow::anyhow!("no backlight in {}", BACKLIGHT_DIR))
+}
+
+/// Brightness of every backlight in percent, for `ctl status`.
+pub fn levels() -> BTreeMap<String, f64> {
+    devices()
+        .unwrap_or_default()
+        .into_iter()
+        .filter_map(|device| {
+            let percent = percent(&device).ok()?;
+            Some((device, (percent * 10.0).round() / 10.0))
+        })
+        .collect()
+}
+
+/// Brightness of `device` in percent of its maximum.
+fn percent(device: &str) -> anyhow::Result<f64> {
+    let max = read(device, "max_brightness")?.max(1);
+    Ok(f64::from(read(device, "brightness")?) * 100.0 / f64::from(max))
+}
+
+/// The raw value of `device` for `percent`.
+fn raw(device: &str, percent: f64) -> anyhow::Result<u32> {
+    let max = read(device, "max_brightness")?;
+    Ok((f64::from(max) * percent.clamp(0.0, 100.0) / 100.0).round() as u32)
+}
+
+#[derive(Debug, Default)]
+pub struct Backlight {
+    /// Counts the fades, a running fade stops once it's no longer the latest
+    fade: u64,
+}
+
+pub type BacklightHandle = Arc<Mutex<Backlight>>;
+
+impl Backlight {
+    pub fn new() -> BacklightHandle {
+        Arc::new(Mutex::new(Self::default()))
+    }
+
+    /// Stops a running fade, e.g. before the brightness from before a stage is put back.
+    pub fn cancel_fade(&mut self) {
+        self.fade += 1;
+    }
+}
+
+/// Steps the brightness of `device` from `from` to `to` over `duration`, unless another fade or
+/// change comes first.
+async fn fade(
+    backlight: BacklightHandle,
+    id: u64,
+    device: String,
+    from: u32,
+    to: u32,
+    duration: Duration,
+) {
+    let steps = (duration.as_millis() / FADE_STEP.as_millis()).max(1) as u32;
+    let session = match zbus::Connection::system().await {
+        Ok(conn) => logind_session(&conn).await.ok(),
+        Err(_) => None,
+    };
+    let mut interval = tokio::time::interval((duration / steps).max(Duration::from_millis(1)));
+    for step in 1..=steps {
+        interval.tick().await;
+        if backlight.lock().unwrap().fade != id {
+            debug!("Fade of {} stopped", device);
+            return;
+        }
+        let brightness = (i64::from(from)
+            + (i64::from(to) - i64::from(from)) * i64::from(step) / i64::from(steps))
+            as u32;
+        let Some(session) = &session else {
+            break;
+        };
+        if let Err(e) = session
+            .set_brightness("backlight", &device, brightness)
+            .await
+        {
+            info!("logind cannot set the brightness, skipping the fade: {}", e);
+            break;
+        }
+        if step == steps {
+            return;
+        }
+    }
+    // Without logind a step per helper run would be too slow, so only the end is set
+    if backlight.lock().unwrap().fade == id {
+        let action = Action::Backlight {
+            device: device.clone(),
+            brightness: to,
+        };
+        if let Err(e) = privileged::run(action).await {
+            error!("Failed to set backlight {}: {}", device, e);
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct BacklightHelpers {
+    pub backlight: BacklightHandle,
+    pub restore: RestoreHandle,
+    pub tx: mpsc::Sender<Request>,
+}
+
+impl BacklightHelpers {
+    fn device(device: Option<String>) -> mlua::Result<String> {
+        match device {
+            Some(device) => Ok(device),
+            None => default_device().map_err(|e| mlua::Error::RuntimeError(e.to_string())),
+        }
+    }
+
+    /// Saves the brightness for the stage that changes it and stops a running fade.
+    fn before_change(&self, device: &str) -> u64 {
+        if let Err(e) = self.restore.lock().unwrap().save_backlight(device) {
+            warn!("Can't save backlight {}: {}", device, e);
+        }
+        let mut backlight = self.backlight.lock().unwrap();
+        backlight.cancel_fade();
+        backlight.fade
+    }
+}
+
+impl UserData for BacklightHelpers {
+    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_method("get", |_lua, _this, device: Option<String>| {
+            let device = Self::device(device)?;
+            percent(&device).map_err(|e| {
+                mlua::Error::RuntimeError(format!("Can't read backlight {}: {}", device, e))
+            })
+        });
+        methods.add_method(
+            "set",
+            |_lua, this, (percent, device): (f64, Option<String>)| {
+                let device = Self::device(device)?;
+                let brightness = raw(&device, percent).map_err(|e| {
+                    mlua::Error::RuntimeError(format!("Can't read backlight {}: {}", device, e))
+                })?;
+                this.before_change(&device);
+                utils::send_request(
+                    &this.tx,
+                    Request::Privileged(Action::Backlight { device, brightness }),
+                );
+                Ok(())
+            },
+        );
+        methods.add_method(
+            "fade_to",
+            |_lua, this, (percent, secs, device): (f64, f64, Option<String>)| {
+                let device = Self::device(device)?;
+                let (from, to) = read(&device, "brightness")
+                    .and_then(|from| Ok((from, raw(&device, percent)?)))
+                    .map_err(|e| {
+                        mlua::Error::RuntimeError(format!("Can't read backlight {}: {}", device, e))
+                    })?;
+                let id = this.before_change(&device);
+                debug!("Fading {} from {} to {} in {}s", device, from, to, secs);
+                tokio::spawn(fade(
+                    this.backlight.clone(),
+                    id,
+                    device,
+                    from,
+                    to,
+                    Duration::from_secs_f64(secs.max(0.0)),
+                ));
+                Ok(())
+            },
+        );
+    }
+}
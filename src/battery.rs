@@ -0,0 +1,248 @@
+use log::{debug, info};
+use mlua::{Lua, UserData, UserDataMethods};
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc;
+
+use super::callbacks;
+use super::hooks::HooksHandle;
+use super::journal;
+use super::privileged::Action;
+use super::scripts;
+use super::types::Request;
+use super::utils;
+
+const POWER_SUPPLY_DIR: &str = "/sys/class/power_supply";
+
+#[derive(Debug)]
+struct Threshold {
+    percent: f64,
+    fn_name: String,
+    /// Cleared when the action ran, set again once the battery is charged above the threshold
+    armed: bool,
+}
+
+/// Charge thresholds of a battery in percent, as the vendor driver exposes them in sysfs.
+#[derive(Clone, Debug)]
+struct ChargeLimit {
+    battery: String,
+    end: u32,
+    /// Only some drivers have a start threshold
+    start: Option<u32>,
+}
+
+impl ChargeLimit {
+    /// The thresholds of the first battery that has them.
+    fn read() -> Option<Self> {
+        let mut batteries: Vec<PathBuf> = fs::read_dir(POWER_SUPPLY_DIR)
+            .ok()?
+            .flatten()
+            .map(|entry| entry.path())
+            .filter(|path| {
+                path.file_name()
+                    .is_some_and(|name| name.to_string_lossy().starts_with("BAT"))
+            })
+            .collect();
+        batteries.sort();
+        batteries.into_iter().find_map(|dir| {
+            let read = |name: &str| fs::read_to_string(dir.join(name)).ok()?.trim().parse().ok();
+            Some(ChargeLimit {
+                battery: dir.file_name()?.to_string_lossy().into_owned(),
+                end: read("charge_control_end_threshold")?,
+                start: read("charge_control_start_threshold"),
+            })
+        })
+    }
+
+    fn action(&self) -> Action {
+        Action::ChargeThreshold {
+            battery: self.battery.clone(),
+            end: self.end,
+            start: self.start,
+        }
+    }
+}
+
+/// Actions for battery levels, run at most once per discharge cycle.
+#[derive(Debug, Default)]
+pub struct Battery {
+    thresholds: Vec<Threshold>,
+    on_battery: bool,
+    level: Option<f64>,
+    /// Called when the machine switches between AC and battery
+    on_power_changed: Vec<String>,
+    /// The charge thresholds before the config changed them, kept across config reloads
+    saved_limit: Option<ChargeLimit>,
+}
+
+pub type BatteryHandle = Arc<Mutex<Battery>>;
+
+impl Battery {
+    pub fn new() -> BatteryHandle {
+        Arc::new(Mutex::new(Self::default()))
+    }
+
+    pub fn clear(&mut self) {
+        self.thresholds.clear();
+        self.on_power_changed.clear();
+    }
+
+    /// Drops the callbacks of the script `script`, before it's loaded again.
+    pub fn forget_script(&mut self, script: &str) {
+        self.thresholds
+            .retain(|threshold| !scripts::owns(script, &threshold.fn_name));
+        self.on_power_changed
+            .retain(|fn_name| !scripts::owns(script, fn_name));
+    }
+
+    pub fn on_battery(&self) -> bool {
+        self.on_battery
+    }
+
+    pub fn watch_power(&mut self, fn_name: String) {
+        self.on_power_changed.push(fn_name);
+    }
+
+    pub fn power_changed_callbacks(&self) -> Vec<String> {
+        self.on_power_changed.clone()
+    }
+
+    pub fn set_on_battery(&mut self, on_battery: bool) -> Vec<String> {
+        self.on_battery = on_battery;
+        self.check()
+    }
+
+    pub fn set_level(&mut self, level: f64) -> Vec<String> {
+        self.level = Some(level);
+        self.check()
+    }
+
+    pub fn level(&self) -> Option<f64> {
+        self.level
+    }
+
+    /// Returns the actions whose threshold was crossed while discharging. A threshold is only
+    /// re-armed on AC above its level, so a level that flaps around it doesn't repeat the
+    /// action.
+    fn check(&mut self) -> Vec<String> {
+        let Some(level) = self.level else {
+            return Vec::new();
+        };
+        let mut due = Vec::new();
+        for threshold in self.thresholds.iter_mut() {
+            if self.on_battery && threshold.armed && level <= threshold.percent {
+                threshold.armed = false;
+                due.push(threshold.fn_name.clone());
+            } else if !self.on_battery && level > threshold.percent {
+                threshold.armed = true;
+            }
+        }
+        due
+    }
+}
+
+/// Calls the battery actions with the current level.
+pub fn run_actions(lua: &Lua, hooks: &HooksHandle, due: Vec<String>, level: f64) {
+    for fn_name in due {
+        journal::event(
+            "battery",
+            &format!("Battery at {}%, running {}", level, fn_name),
+            &[("LEVEL", &level.to_string()), ("CALLBACK", &fn_name)],
+        );
+        callbacks::call::<_, ()>(lua, hooks, &fn_name, level);
+    }
+}
+
+/// Calls the `Power:on_power_changed` callbacks as `fn(on_battery, level)`.
+pub fn run_power_changed(
+    lua: &Lua,
+    hooks: &HooksHandle,
+    callbacks: Vec<String>,
+    on_battery: bool,
+    level: Option<f64>,
+) {
+    for fn_name in callbacks {
+        callbacks::call::<_, ()>(lua, hooks, &fn_name, (on_battery, level));
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct BatteryHelpers {
+    pub battery: BatteryHandle,
+    pub tx: mpsc::Sender<Request>,
+}
+
+impl UserData for BatteryHelpers {
+    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_method("at", |_lua, this, (percent, fn_name): (f64, String)| {
+            if !(0.0..=100.0).contains(&percent) {
+                return Err(mlua::Error::RuntimeError(format!(
+                    "invalid battery level {}",
+                    percent
+                )));
+            }
+            debug!("Battery action {} at {}%", fn_name, percent);
+            let mut battery = this.battery.lock().unwrap();
+            // After a config reload, a threshold that was already crossed counts as handled
+            let crossed = battery.on_battery && battery.level.is_some_and(|level| level <= percent);
+            battery.thresholds.push(Threshold {
+                percent,
+                fn_name,
+                armed: !crossed,
+            });
+            Ok(())
+        });
+        methods.add_method("level", |_lua, this, (): ()| {
+            Ok(this.battery.lock().unwrap().level())
+        });
+        methods.add_method("charge_limit", |_lua, _this, (): ()| {
+            let limit = ChargeLimit::read();
+            Ok((
+                limit.as_ref().map(|limit| limit.end),
+                limit.and_then(|limit| limit.start),
+            ))
+        });
+        methods.add_method(
+            "set_charge_limit",
+            |_lua, this, (end, start): (u32, Option<u32>)| {
+                if end > 100 || start.is_some_and(|start| start >= end) {
+                    return Err(mlua::Error::RuntimeError(format!(
+                        "invalid charge limit {} (start {:?})",
+                        end, start
+                    )));
+                }
+                let Some(current) = ChargeLimit::read() else {
+                    return Err(mlua::Error::RuntimeError(
+                        "no battery with charge thresholds".to_string(),
+                    ));
+                };
+                let limit = ChargeLimit {
+                    start: start.or(current.start),
+                    end,
+                    battery: current.battery.clone(),
+                };
+                info!("Setting the charge limit of {} to {}%", limit.battery, end);
+                // Only the first change is saved, so restoring goes back to the user's setting
+                this.battery
+                    .lock()
+                    .unwrap()
+                    .saved_limit
+                    .get_or_insert(current);
+                utils::send_request(&this.tx, Request::Privileged(limit.action()));
+                Ok(())
+            },
+        );
+        methods.add_method("restore_charge_limit", |_lua, this, (): ()| {
+            let Some(saved) = this.battery.lock().unwrap().saved_limit.take() else {
+                return Ok(false);
+            };
+            info!(
+                "Restoring the charge limit of {} to {}%",
+                saved.battery, saved.end
+            );
+            utils::send_request(&this.tx, Request::Privileged(saved.action()));
+            Ok(true)
+        });
+    }
+}
@@ -0,0 +1,113 @@
+//! Privileged helper started through pkexec for the few actions that need root. It only
+//! accepts a fixed set of verbs with validated arguments and writes to sysfs directly.
+//!
+//! sleepwatcher-rs-helper hibernate
+//! sleepwatcher-rs-helper rtcwake <unix timestamp>
+//! sleepwatcher-rs-helper backlight <device> <brightness>
+//! sleepwatcher-rs-helper charge-threshold <battery> <end> [start]
+
+use std::fs;
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const BACKLIGHT_DIR: &str = "/sys/class/backlight";
+const POWER_SUPPLY_DIR: &str = "/sys/class/power_supply";
+const RTC_WAKEALARM: &str = "/sys/class/rtc/rtc0/wakealarm";
+const POWER_STATE: &str = "/sys/power/state";
+const USAGE: &str = "usage: sleepwatcher-rs-helper hibernate | rtcwake <timestamp> | \
+                     backlight <device> <brightness> | charge-threshold <battery> <end> [start]";
+
+fn hibernate() -> Result<(), String> {
+    fs::write(POWER_STATE, "disk").map_err(|e| format!("{}: {}", POWER_STATE, e))
+}
+
+fn rtcwake(timestamp: &str) -> Result<(), String> {
+    let timestamp: u64 = timestamp
+        .parse()
+        .map_err(|_| format!("invalid timestamp {}", timestamp))?;
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_err(|e| e.to_string())?
+        .as_secs();
+    if timestamp <= now {
+        return Err(format!("timestamp {} is in the past", timestamp));
+    }
+    // The alarm has to be cleared before a new one can be set
+    fs::write(RTC_WAKEALARM, "0").map_err(|e| format!("{}: {}", RTC_WAKEALARM, e))?;
+    fs::write(RTC_WAKEALARM, timestamp.to_string()).map_err(|e| format!("{}: {}", RTC_WAKEALARM, e))
+}
+
+fn backlight(device: &str, brightness: &str) -> Result<(), String> {
+    if device.is_empty() || device.contains('/') || device.starts_with('.') {
+        return Err(format!("invalid backlight device {}", device));
+    }
+    let brightness: u32 = brightness
+        .parse()
+        .map_err(|_| format!("invalid brightness {}", brightness))?;
+    let dir = PathBuf::from(BACKLIGHT_DIR).join(device);
+    let max: u32 = fs::read_to_string(dir.join("max_brightness"))
+        .map_err(|e| format!("{}: {}", device, e))?
+        .trim()
+        .parse()
+        .map_err(|_| format!("{}: unreadable max_brightness", device))?;
+    fs::write(dir.join("brightness"), brightness.min(max).to_string())
+        .map_err(|e| format!("{}: {}", device, e))
+}
+
+fn percent(value: &str) -> Result<u32, String> {
+    match value.parse() {
+        Ok(percent) if percent <= 100 => Ok(percent),
+        _ => Err(format!("invalid percentage {}", value)),
+    }
+}
+
+fn charge_threshold(battery: &str, end: &str, start: Option<&str>) -> Result<(), String> {
+    if !battery.starts_with("BAT") || battery.contains('/') {
+        return Err(format!("invalid battery {}", battery));
+    }
+    let end = percent(end)?;
+    let start = start.map(percent).transpose()?;
+    if start.is_some_and(|start| start >= end) {
+        return Err("the start threshold has to be below the end threshold".to_string());
+    }
+    let dir = PathBuf::from(POWER_SUPPLY_DIR).join(battery);
+    let write = |name: &str, value: u32| {
+        fs::write(dir.join(name), value.to_string()).map_err(|e| format!("{}: {}", name, e))
+    };
+    let Some(start) = start else {
+        return write("charge_control_end_threshold", end);
+    };
+    // Drivers reject a start above the current end, and an end below the current start
+    let current_start: u32 = fs::read_to_string(dir.join("charge_control_start_threshold"))
+        .ok()
+        .and_then(|start| start.trim().parse().ok())
+        .unwrap_or(0);
+    if end >= current_start {
+        write("charge_control_end_threshold", end)?;
+        write("charge_control_start_threshold", start)
+    } else {
+        write("charge_control_start_threshold", start)?;
+        write("charge_control_end_threshold", end)
+    }
+}
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    let result = match args.as_slice() {
+        ["hibernate"] => hibernate(),
+        ["rtcwake", timestamp] => rtcwake(timestamp),
+        ["backlight", device, brightness] => backlight(device, brightness),
+        ["charge-threshold", battery, end] => charge_threshold(battery, end, None),
+        ["charge-threshold", battery, end, start] => charge_threshold(battery, end, Some(start)),
+        _ => Err(USAGE.to_string()),
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("sleepwatcher-rs-helper: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
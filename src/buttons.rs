@@ -0,0 +1,113 @@
+//! The lid switch and the power button as events of the config. The lid comes from UPower's
+//! `LidIsClosed` property, the power button from the socket of acpid, since logind handles the
+//! key without telling anyone. logind still suspends on the lid and powers off on the key as
+//! set in logind.conf, unless `handle_lid_switch` or `handle_power_key` in the `[buttons]`
+//! section take that over with a block inhibitor, leaving it to the event handlers.
+
+use futures::stream::StreamExt;
+use log::{debug, error, info};
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::net::UnixStream;
+use tokio::sync::mpsc;
+use zbus::zvariant::OwnedFd;
+
+use super::config;
+use super::dbus::{LogindManagerInterfaceProxy, UPowerInterfaceProxy};
+use super::settings::ButtonSettings;
+use super::types::Request;
+
+/// Takes over what logind does on `what`, e.g. `handle-lid-switch`, for as long as the fd is
+/// held.
+async fn take_over(manager: &LogindManagerInterfaceProxy<'_>, what: &str) -> Option<OwnedFd> {
+    match manager
+        .inhibit(what, config::APP_NAME, "Handled by the config", "block")
+        .await
+    {
+        Ok(fd) => {
+            info!("Took over {} from logind", what);
+            Some(fd)
+        }
+        Err(e) => {
+            error!("Failed to take over {} from logind: {}", what, e);
+            None
+        }
+    }
+}
+
+/// Follows the lid through UPower, with the inhibitors of the settings.
+async fn lid_run(settings: ButtonSettings, tx: mpsc::Sender<Request>) -> anyhow::Result<()> {
+    let conn = zbus::Connection::system().await?;
+    let manager = LogindManagerInterfaceProxy::new(&conn).await?;
+    let upower = UPowerInterfaceProxy::new(&conn).await?;
+    let present = upower.lid_is_present().await?;
+    let mut closed = upower.lid_is_closed().await?;
+    let mut changes = upower.receive_lid_is_closed_changed().await;
+    if !present {
+        info!("No lid found, lid events are disabled");
+    }
+
+    tokio::spawn(async move {
+        let _lid_switch = match settings.handle_lid_switch {
+            true => take_over(&manager, "handle-lid-switch").await,
+            false => None,
+        };
+        let _power_key = match settings.handle_power_key {
+            true => take_over(&manager, "handle-power-key").await,
+            false => None,
+        };
+        if !present {
+            // Keeps holding the inhibitors
+            return std::future::pending().await;
+        }
+        while let Some(change) = changes.next().await {
+            let Ok(now_closed) = change.get().await else {
+                continue;
+            };
+            if now_closed == closed {
+                continue;
+            }
+            closed = now_closed;
+            let docked = manager.docked().await.unwrap_or(false);
+            debug!("Lid closed: {}, docked: {}", closed, docked);
+            let _ = tx.send(Request::Lid { closed, docked }).await;
+        }
+        error!("Lost the connection to UPower, lid events stopped");
+    });
+    Ok(())
+}
+
+/// Reads acpid's events until the socket is closed, e.g. `button/power PBTN 00000080 00000000`.
+async fn acpid_run(settings: ButtonSettings, tx: mpsc::Sender<Request>) -> anyhow::Result<()> {
+    let stream = UnixStream::connect(&settings.acpid_socket).await?;
+    info!("Reading ACPI events from {:?}", settings.acpid_socket);
+    tokio::spawn(async move {
+        let mut lines = BufReader::new(stream).lines();
+        loop {
+            match lines.next_line().await {
+                Ok(Some(line)) => {
+                    debug!("acpid: {}", line);
+                    if line.split_whitespace().next() == Some("button/power") {
+                        let _ = tx.send(Request::PowerButton).await;
+                    }
+                }
+                Ok(None) => break,
+                Err(e) => {
+                    error!("Failed to read from acpid: {}", e);
+                    break;
+                }
+            }
+        }
+        error!("acpid closed its socket, power button events stopped");
+    });
+    Ok(())
+}
+
+/// Starts following the lid and the power button.
+pub async fn buttons_run(settings: ButtonSettings, tx: mpsc::Sender<Request>) {
+    if let Err(e) = lid_run(settings.clone(), tx.clone()).await {
+        error!("Failed to follow the lid through UPower: {}", e);
+    }
+    if let Err(e) = acpid_run(settings, tx).await {
+        info!("No power button events without acpid: {}", e);
+    }
+}
@@ -0,0 +1,89 @@
+//! Keep-awake mode. While caffeinated, idle callbacks are skipped and a virtual pointer
+//! nudges the compositor regularly, so its own idle handling (DPMS, locking) doesn't kick in.
+
+use chrono::{DateTime, Duration as ChronoDuration, Local};
+use log::{debug, error, info, warn};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use wayland_client::Connection;
+use wayland_protocols_wlr::virtual_pointer::v1::client::zwlr_virtual_pointer_v1;
+
+/// Shorter than any sensible compositor idle timeout
+const NUDGE_INTERVAL: Duration = Duration::from_secs(20);
+
+#[derive(Debug, Default)]
+pub struct Caffeine {
+    until: Option<DateTime<Local>>,
+    pointer: Option<(zwlr_virtual_pointer_v1::ZwlrVirtualPointerV1, Connection)>,
+}
+
+pub type CaffeineHandle = Arc<Mutex<Caffeine>>;
+
+impl Caffeine {
+    pub fn new() -> CaffeineHandle {
+        Arc::new(Mutex::new(Self::default()))
+    }
+
+    pub fn set_pointer(
+        &mut self,
+        pointer: zwlr_virtual_pointer_v1::ZwlrVirtualPointerV1,
+        conn: Connection,
+    ) {
+        self.pointer = Some((pointer, conn));
+    }
+
+    /// Stays awake for `duration`. A zero duration ends caffeinate mode.
+    pub fn set(&mut self, duration: Duration) -> Option<DateTime<Local>> {
+        self.until = ChronoDuration::from_std(duration)
+            .ok()
+            .filter(|duration| !duration.is_zero())
+            .and_then(|duration| Local::now().checked_add_signed(duration));
+        match self.until {
+            Some(until) => {
+                info!("Caffeinated until {}", until);
+                if self.pointer.is_none() {
+                    warn!(
+                        "No wlr-virtual-pointer, only idle callbacks of this daemon are held back"
+                    );
+                }
+            }
+            None => info!("Caffeinate mode off"),
+        }
+        self.until
+    }
+
+    pub fn until(&self) -> Option<DateTime<Local>> {
+        self.until.filter(|until| *until > Local::now())
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.until().is_some()
+    }
+
+    /// Moves the virtual pointer back and forth, which counts as user activity.
+    fn nudge(&self, start: Instant) {
+        let Some((pointer, conn)) = &self.pointer else {
+            return;
+        };
+        let time = start.elapsed().as_millis() as u32;
+        pointer.motion(time, 1.0, 0.0);
+        pointer.motion(time, -1.0, 0.0);
+        pointer.frame();
+        if let Err(e) = conn.flush() {
+            error!("Failed to flush the virtual pointer: {}", e);
+        }
+    }
+}
+
+pub async fn caffeinate_run(caffeine: CaffeineHandle) {
+    let start = Instant::now();
+    let mut ticker = tokio::time::interval(NUDGE_INTERVAL);
+    loop {
+        ticker.tick().await;
+        let caffeine = caffeine.lock().unwrap();
+        if caffeine.is_active() {
+            debug!("Nudging the compositor");
+            caffeine.nudge(start);
+        }
+    }
+}
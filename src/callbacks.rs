@@ -0,0 +1,171 @@
+//! Lua callbacks run on a thread of their own, in the order they were queued, so a slow
+//! callback doesn't hold up the request loop. Every call is limited to the callback timeout:
+//! Lua code still running then is aborted. A command the callback waits for, e.g. with
+//! `os.execute`, can't be aborted, but it only holds up the callbacks queued after it.
+
+use log::error;
+use mlua::{FromLuaMulti, IntoLuaMulti, Lua, VmState};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use super::hooks::{self, HooksHandle};
+use super::scripts;
+
+type Job = Box<dyn FnOnce(&Lua) + Send>;
+
+/// `callback_timeout_secs` of the settings, kept with the Lua state
+struct DefaultTimeout(Duration);
+
+/// Queue of the thread that runs Lua callbacks.
+#[derive(Clone, Debug)]
+pub struct Callbacks {
+    jobs: mpsc::Sender<Job>,
+}
+
+impl Callbacks {
+    /// Starts the callback thread, which runs every job with `lua` locked. Has to be called
+    /// on the runtime, which helpers of the config spawn their tasks on.
+    pub fn start(lua: Arc<Mutex<Lua>>) -> Self {
+        let (jobs, queue) = mpsc::channel::<Job>();
+        let runtime = tokio::runtime::Handle::current();
+        std::thread::Builder::new()
+            .name("lua-callbacks".to_string())
+            .spawn(move || {
+                let _runtime = runtime.enter();
+                for job in queue {
+                    job(&lua.lock().unwrap());
+                }
+            })
+            .expect("Failed to start the callback thread");
+        Self { jobs }
+    }
+
+    /// Queues `job` behind the callbacks already waiting.
+    pub fn run(&self, job: impl FnOnce(&Lua) + Send + 'static) {
+        if self.jobs.send(Box::new(job)).is_err() {
+            error!("The callback thread is gone, dropping a callback");
+        }
+    }
+
+    /// Queues `job` and waits until it ran, for the exit hooks, which have to run before the
+    /// daemon exits.
+    pub fn run_and_wait(&self, job: impl FnOnce(&Lua) + Send + 'static) {
+        let (done_tx, done) = mpsc::channel();
+        self.run(move |lua| {
+            job(lua);
+            let _ = done_tx.send(());
+        });
+        let _ = done.recv();
+    }
+}
+
+/// Sets the time callbacks of `lua` may run when they don't ask for a timeout of their own.
+pub fn set_default_timeout(lua: &Lua, limit: Duration) {
+    lua.set_app_data(DefaultTimeout(limit));
+}
+
+/// The timeout set with `set_default_timeout`.
+pub fn default_timeout(lua: &Lua) -> Duration {
+    lua.app_data_ref::<DefaultTimeout>()
+        .map_or(Duration::from_secs(10), |limit| limit.0)
+}
+
+/// Calls the callback `fn_name` with `args`, aborting Lua code that still runs after `limit`.
+/// A callback that took too long is an error even if it couldn't be aborted.
+pub fn call_timed<'lua, A: IntoLuaMulti<'lua>, R: FromLuaMulti<'lua>>(
+    lua: &'lua Lua,
+    fn_name: &str,
+    args: A,
+    limit: Duration,
+) -> mlua::Result<R> {
+    let handler = scripts::callback(lua, fn_name)?;
+    let started = Instant::now();
+    let deadline = started + limit;
+    lua.set_interrupt(move |_| {
+        if Instant::now() < deadline {
+            Ok(VmState::Continue)
+        } else {
+            Err(mlua::Error::RuntimeError(format!(
+                "aborted after {}s",
+                limit.as_secs_f64()
+            )))
+        }
+    });
+    let result = handler.call::<_, R>(args);
+    lua.remove_interrupt();
+    let result = result?;
+    if started.elapsed() > limit {
+        return Err(mlua::Error::RuntimeError(format!(
+            "took {:.1}s, more than its timeout of {}s",
+            started.elapsed().as_secs_f64(),
+            limit.as_secs_f64()
+        )));
+    }
+    Ok(result)
+}
+
+/// Calls the callback `fn_name` with `args` within the default callback timeout. Errors,
+/// including a missing function, are logged and passed to the `on_error` hook.
+pub fn call<'lua, A: IntoLuaMulti<'lua>, R: FromLuaMulti<'lua>>(
+    lua: &'lua Lua,
+    hooks: &HooksHandle,
+    fn_name: &str,
+    args: A,
+) -> Option<R> {
+    call_with_limit(lua, hooks, fn_name, args, default_timeout(lua))
+}
+
+/// Like `call`, with a timeout of its own.
+pub fn call_with_limit<'lua, A: IntoLuaMulti<'lua>, R: FromLuaMulti<'lua>>(
+    lua: &'lua Lua,
+    hooks: &HooksHandle,
+    fn_name: &str,
+    args: A,
+    limit: Duration,
+) -> Option<R> {
+    match call_timed(lua, fn_name, args, limit) {
+        Ok(result) => Some(result),
+        Err(e) => {
+            error!("Error calling {}: {}", fn_name, e);
+            hooks::report_error(
+                lua,
+                hooks,
+                &e.to_string(),
+                &[("source", "callback"), ("callback", fn_name)],
+            );
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn aborts_callbacks_past_their_timeout() {
+        let lua = Lua::new();
+        lua.load("function spin() while true do end end function quick() return 1 end")
+            .exec()
+            .unwrap();
+        let err = call_timed::<_, ()>(&lua, "spin", (), Duration::from_millis(50)).unwrap_err();
+        assert!(err.to_string().contains("aborted"), "{}", err);
+        let quick: i32 = call_timed(&lua, "quick", (), Duration::from_secs(1)).unwrap();
+        assert_eq!(quick, 1);
+    }
+
+    #[test]
+    fn runs_jobs_in_order() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let _runtime = runtime.enter();
+        let callbacks = Callbacks::start(Arc::new(Mutex::new(Lua::new())));
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        for n in 0..3 {
+            let seen = seen.clone();
+            callbacks.run(move |_| seen.lock().unwrap().push(n));
+        }
+        callbacks.run_and_wait(|_| {});
+        assert_eq!(*seen.lock().unwrap(), [0, 1, 2]);
+    }
+}
@@ -0,0 +1,228 @@
+//! What the compositor and the system offer, so configs can check before relying on a
+//! protocol or service and warn with something better than a silent no-op.
+
+use mlua::{UserData, UserDataMethods};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+#[derive(Clone, Copy, Debug)]
+enum Needs {
+    /// A Wayland global at a minimum version
+    Protocol(&'static str, u32),
+    Service(&'static str),
+}
+
+/// Feature names for `Caps:has`, what they need and what doesn't work without them.
+const FEATURES: &[(&str, Needs, &str)] = &[
+    (
+        "idle_notify",
+        Needs::Protocol("ext_idle_notifier_v1", 1),
+        "idle timeouts need the evdev backend",
+    ),
+    (
+        "output_names",
+        Needs::Protocol("wl_output", 4),
+        "outputs are only known by their description, so rules by output name don't match",
+    ),
+    (
+        "seat_names",
+        Needs::Protocol("wl_seat", 2),
+        "the seat is reported as seat0",
+    ),
+    (
+        "session_lock",
+        Needs::Protocol("ext_session_lock_manager_v1", 1),
+        "lockers like swaylock and Lock:engage can't lock the session securely",
+    ),
+    (
+        "gamma_control",
+        Needs::Protocol("zwlr_gamma_control_manager_v1", 1),
+        "the night light won't change the screens",
+    ),
+    (
+        "output_power",
+        Needs::Protocol("zwlr_output_power_manager_v1", 1),
+        "Outputs:set_power can't turn the screens off",
+    ),
+    (
+        "foreign_toplevel",
+        Needs::Protocol("zwlr_foreign_toplevel_manager_v1", 1),
+        "per-application rules don't see the focused window",
+    ),
+    (
+        "virtual_pointer",
+        Needs::Protocol("zwlr_virtual_pointer_manager_v1", 1),
+        "caffeinate only holds back the callbacks of this daemon",
+    ),
+    (
+        "session_bus",
+        Needs::Service("session_bus"),
+        "the D-Bus interface and desktop notifications are missing",
+    ),
+    (
+        "logind",
+        Needs::Service("logind"),
+        "no lock, unlock and sleep signals, suspends are detected from the clock",
+    ),
+    (
+        "upower",
+        Needs::Service("upower"),
+        "battery state and battery actions aren't updated",
+    ),
+    (
+        "timedated",
+        Needs::Service("timedated"),
+        "schedules don't follow time zone changes",
+    ),
+    (
+        "screensaver",
+        Needs::Service("screensaver"),
+        "inhibitors of applications like Firefox and mpv don't hold back idle callbacks",
+    ),
+    (
+        "at-spi",
+        Needs::Service("at-spi"),
+        "screen readers aren't detected",
+    ),
+];
+
+#[derive(Serialize, Debug)]
+pub struct Missing {
+    pub feature: String,
+    pub consequence: String,
+}
+
+#[derive(Debug, Default)]
+pub struct Caps {
+    /// Wayland globals with the version in use, the lower of what the compositor offers and
+    /// what sleepwatcher-rs supports
+    protocols: BTreeMap<String, u32>,
+    /// `None` until the services were probed, which happens after the config first ran
+    services: Option<BTreeMap<String, bool>>,
+}
+
+pub type CapsHandle = Arc<Mutex<Caps>>;
+
+impl Caps {
+    pub fn new() -> CapsHandle {
+        Arc::new(Mutex::new(Self::default()))
+    }
+
+    pub fn set_protocols(&mut self, protocols: BTreeMap<String, u32>) {
+        self.protocols = protocols;
+    }
+
+    pub fn set_services(&mut self, services: BTreeMap<String, bool>) {
+        self.services = Some(services);
+    }
+
+    pub fn version(&self, interface: &str) -> Option<u32> {
+        self.protocols.get(interface).copied()
+    }
+
+    fn service(&self, name: &str) -> Option<bool> {
+        Some(self.services.as_ref()?.get(name).copied().unwrap_or(false))
+    }
+
+    fn needs(&self, needs: Needs) -> Option<bool> {
+        match needs {
+            Needs::Protocol(interface, min) => Some(
+                self.version(interface)
+                    .is_some_and(|version| version >= min),
+            ),
+            Needs::Service(name) => self.service(name),
+        }
+    }
+
+    /// Whether a feature, Wayland global or service is available. `None` for services before
+    /// they were probed.
+    pub fn has(&self, name: &str) -> Option<bool> {
+        if let Some((_, needs, _)) = FEATURES.iter().find(|(feature, _, _)| *feature == name) {
+            return self.needs(*needs);
+        }
+        if self.protocols.contains_key(name) {
+            return Some(true);
+        }
+        match &self.services {
+            Some(services) if services.contains_key(name) => self.service(name),
+            // Anything else is taken as a Wayland global the compositor doesn't have
+            _ => Some(false),
+        }
+    }
+
+    pub fn features(&self) -> BTreeMap<String, Option<bool>> {
+        FEATURES
+            .iter()
+            .map(|(feature, needs, _)| (feature.to_string(), self.needs(*needs)))
+            .collect()
+    }
+
+    /// Features known to be missing, with what doesn't work because of it.
+    pub fn missing(&self) -> Vec<Missing> {
+        FEATURES
+            .iter()
+            .filter(|(_, needs, _)| self.needs(*needs) == Some(false))
+            .map(|(feature, needs, consequence)| {
+                // Present at a lower version
+                let version = match needs {
+                    Needs::Protocol(interface, min) => self
+                        .version(interface)
+                        .map(|version| (interface, min, version)),
+                    Needs::Service(_) => None,
+                };
+                let consequence = match version {
+                    Some((interface, min, version)) => format!(
+                        "{} (needs {} version {}, the compositor has {})",
+                        consequence, interface, min, version
+                    ),
+                    None => consequence.to_string(),
+                };
+                Missing {
+                    feature: feature.to_string(),
+                    consequence,
+                }
+            })
+            .collect()
+    }
+
+    pub fn json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "protocols": self.protocols,
+            "services": self.services,
+            "features": self.features(),
+            "missing": self.missing(),
+        })
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct CapsHelpers {
+    pub caps: CapsHandle,
+}
+
+impl UserData for CapsHelpers {
+    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_method("has", |_lua, this, name: String| {
+            Ok(this.caps.lock().unwrap().has(&name))
+        });
+        methods.add_method("version", |_lua, this, interface: String| {
+            Ok(this.caps.lock().unwrap().version(&interface))
+        });
+        methods.add_method("list", |lua, this, (): ()| {
+            let caps = this.caps.lock().unwrap();
+            let table = lua.create_table()?;
+            table.set("protocols", lua.create_table_from(caps.protocols.clone())?)?;
+            if let Some(services) = &caps.services {
+                table.set("services", lua.create_table_from(services.clone())?)?;
+            }
+            table.set("features", lua.create_table_from(caps.features())?)?;
+            let missing = lua.create_table()?;
+            for entry in caps.missing() {
+                missing.set(entry.feature, entry.consequence)?;
+            }
+            table.set("missing", missing)?;
+            Ok(table)
+        });
+    }
+}
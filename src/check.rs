@@ -0,0 +1,304 @@
+//! `sleepwatcher-rs check`: loads the config and the scripts with stand-ins for the globals of
+//! the daemon, which record the calls instead of acting on them. Reports what would only show
+//! up at runtime: syntax and load errors, callbacks named after functions that don't exist and
+//! timeouts that can't work.
+
+use mlua::{Lua, MultiValue, Table, Value};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use super::scripts;
+use super::settings::Settings;
+
+/// Globals set up by `lua_globals`
+const GLOBALS: &[&str] = &[
+    "IdleNotifier",
+    "Helpers",
+    "DbusHandler",
+    "Jobs",
+    "Power",
+    "NightLight",
+    "Outputs",
+    "Lock",
+    "Events",
+    "Profiles",
+    "Restore",
+    "Options",
+    "Timer",
+    "Schedule",
+    "Solar",
+    "Dnd",
+    "Notify",
+    "Caps",
+    "Hooks",
+    "Status",
+    "Exec",
+    "Thermal",
+    "Peers",
+    "Inhibitors",
+    "Activity",
+    "Audio",
+    "Backlight",
+    "Escalation",
+    "Battery",
+    "Kiosk",
+    "Screensaver",
+    "Apps",
+    "Secrets",
+    "System",
+    "log",
+];
+
+/// Methods that take the name of a callback, with the position of the name
+const CALLBACKS: &[(&str, &str, usize)] = &[
+    ("IdleNotifier", "get_notification", 1),
+    ("DbusHandler", "PrepareSleep", 0),
+    ("DbusHandler", "LockHandler", 0),
+    ("DbusHandler", "UnlockHandler", 0),
+    ("DbusHandler", "on_sleep", 0),
+    ("DbusHandler", "on_resume", 0),
+    ("DbusHandler", "on_shutdown", 0),
+    ("Power", "on_power_changed", 0),
+    ("Events", "on", 1),
+    ("Profiles", "on_enter", 1),
+    ("Profiles", "on_exit", 1),
+    ("Timer", "after", 1),
+    ("Timer", "every", 1),
+    ("Schedule", "at", 1),
+    ("Solar", "on_sunrise", 0),
+    ("Solar", "on_sunset", 0),
+    ("Hooks", "on_error", 0),
+    ("Hooks", "on_start", 0),
+    ("Hooks", "on_exit", 0),
+    ("Hooks", "on_after_wake", 0),
+    ("Hooks", "on_return", 0),
+    ("Exec", "run_stream", 1),
+    ("Thermal", "at", 2),
+    ("Peers", "on_event", 0),
+    ("Inhibitors", "register", 1),
+    ("Escalation", "after_lock", 1),
+    ("Battery", "at", 1),
+];
+
+/// Methods that take a timeout in seconds, with its position and whether it has to be whole
+const TIMEOUTS: &[(&str, &str, usize, bool)] = &[
+    ("IdleNotifier", "get_notification", 0, true),
+    ("Timer", "after", 0, false),
+    ("Timer", "every", 0, false),
+];
+
+#[derive(Clone, Debug)]
+enum Arg {
+    String(String),
+    Number(f64),
+    Other(&'static str),
+}
+
+/// A recorded method call on a global.
+#[derive(Debug)]
+struct Call {
+    global: String,
+    method: String,
+    args: Vec<Arg>,
+    /// Where it was called, e.g. `idle_config.lua:12`
+    location: String,
+}
+
+type Calls = Arc<Mutex<Vec<Call>>>;
+
+fn location(lua: &Lua) -> String {
+    lua.inspect_stack(1)
+        .map(|debug| {
+            let source = debug.source();
+            let name = source.short_src.as_deref().unwrap_or("?").to_string();
+            format!("{}:{}", name, debug.curr_line())
+        })
+        .unwrap_or_else(|| "?".to_string())
+}
+
+/// A table that accepts every method call, records it and returns another stand-in, so that
+/// handles like the one of `get_notification` work as well.
+fn stand_in<'lua>(lua: &'lua Lua, calls: &Calls, name: &str) -> mlua::Result<Table<'lua>> {
+    let object = lua.create_table()?;
+    let meta = lua.create_table()?;
+    let (index_calls, index_name) = (calls.clone(), name.to_string());
+    meta.set(
+        "__index",
+        lua.create_function(move |lua, (object, method): (Table, String)| {
+            let (calls, global) = (index_calls.clone(), index_name.clone());
+            let object = object.to_pointer() as usize;
+            lua.create_function(move |lua, args: MultiValue| {
+                let mut args = args.into_iter().peekable();
+                // Called with `:` the stand-in itself comes first
+                if let Some(Value::Table(first)) = args.peek() {
+                    if first.to_pointer() as usize == object {
+                        args.next();
+                    }
+                }
+                let args = args
+                    .map(|arg| match arg {
+                        Value::String(s) => Arg::String(s.to_string_lossy().into_owned()),
+                        Value::Integer(n) => Arg::Number(n as f64),
+                        Value::Number(n) => Arg::Number(n),
+                        other => Arg::Other(other.type_name()),
+                    })
+                    .collect();
+                calls.lock().unwrap().push(Call {
+                    global: global.clone(),
+                    method: method.clone(),
+                    args,
+                    location: location(lua),
+                });
+                stand_in(lua, &calls, &format!("{}:{}()", global, method))
+            })
+        })?,
+    )?;
+    // Results used in expressions, e.g. `os.time() - Status:last_activity()`
+    for arithmetic in [
+        "__add", "__sub", "__mul", "__div", "__mod", "__unm", "__len",
+    ] {
+        meta.set(
+            arithmetic,
+            lua.create_function(|_lua, _: MultiValue| Ok(0))?,
+        )?;
+    }
+    for comparison in ["__lt", "__le"] {
+        meta.set(
+            comparison,
+            lua.create_function(|_lua, _: MultiValue| Ok(false))?,
+        )?;
+    }
+    let text = name.to_string();
+    meta.set(
+        "__tostring",
+        lua.create_function(move |_lua, _: MultiValue| Ok(text.clone()))?,
+    )?;
+    let text = name.to_string();
+    meta.set(
+        "__concat",
+        lua.create_function(move |_lua, _: MultiValue| Ok(text.clone()))?,
+    )?;
+    object.set_metatable(Some(meta));
+    Ok(object)
+}
+
+/// What is wrong with the recorded calls, given the globals after loading.
+fn find_problems(lua: &Lua, calls: &[Call]) -> Vec<String> {
+    let mut problems = Vec::new();
+    for call in calls {
+        let name = format!("{}:{}", call.global, call.method);
+        for (global, method, position) in CALLBACKS {
+            if call.global != *global || call.method != *method {
+                continue;
+            }
+            match call.args.get(*position) {
+                Some(Arg::String(fn_name)) => {
+                    if scripts::callback(lua, fn_name).is_err() {
+                        problems.push(format!(
+                            "{}: {} names {}, which isn't a function of the config or a script",
+                            call.location, name, fn_name
+                        ));
+                    }
+                }
+                _ => problems.push(format!(
+                    "{}: {} expects a function name as argument {}",
+                    call.location,
+                    name,
+                    position + 1
+                )),
+            }
+        }
+        for (global, method, position, whole) in TIMEOUTS {
+            if call.global != *global || call.method != *method {
+                continue;
+            }
+            let valid = match call.args.get(*position) {
+                Some(Arg::Number(secs)) => *secs > 0.0 && (!whole || secs.fract() == 0.0),
+                _ => false,
+            };
+            if !valid {
+                let expected = match whole {
+                    true => "a whole number of seconds above 0",
+                    false => "a number of seconds above 0",
+                };
+                problems.push(format!(
+                    "{}: {} expects {} as argument {}, got {}",
+                    call.location,
+                    name,
+                    expected,
+                    position + 1,
+                    call.args
+                        .get(*position)
+                        .map(|arg| match arg {
+                            Arg::String(s) => format!("{:?}", s),
+                            Arg::Number(n) => n.to_string(),
+                            Arg::Other(kind) => kind.to_string(),
+                        })
+                        .unwrap_or_else(|| "nothing".to_string())
+                ));
+            }
+        }
+    }
+    problems
+}
+
+/// Checks the config at `path` and the scripts of the settings, prints the problems and
+/// returns whether there were none.
+pub fn run(path: &Path, settings: &Settings, config_dir: &Path) -> anyhow::Result<bool> {
+    let lua = Lua::new();
+    super::sandbox::apply(&lua, &settings.sandbox)?;
+    let globals = lua.globals();
+    if settings.sandbox.os_execute {
+        // The config isn't run for real
+        let os: Table = globals.get("os")?;
+        os.set(
+            "execute",
+            lua.create_function(|_lua, _: MultiValue| Ok((true, "exit", 0)))?,
+        )?;
+    }
+    let calls = Calls::default();
+    for name in GLOBALS {
+        globals.set(*name, stand_in(&lua, &calls, name)?)?;
+    }
+
+    let mut problems = Vec::new();
+    let source = std::fs::read_to_string(path)?;
+    // `@` marks a file name, so messages read `idle_config.lua:12: ...`
+    let chunk_name = path
+        .file_name()
+        .map(|name| format!("@{}", name.to_string_lossy()));
+    if let Err(e) = lua
+        .load(&source)
+        .set_name(chunk_name.unwrap_or_default())
+        .exec()
+    {
+        problems.push(e.to_string());
+    }
+    for script in &settings.scripts.files {
+        let path = config_dir.join(script);
+        if let Err(e) = scripts::load(&lua, &scripts::name(script), &path) {
+            problems.push(format!("{}: {}", path.display(), e));
+        }
+    }
+    let calls = calls.lock().unwrap();
+    problems.extend(find_problems(&lua, &calls));
+
+    for problem in &problems {
+        println!("{}", problem);
+    }
+    let notifications = calls
+        .iter()
+        .filter(|call| call.global == "IdleNotifier" && call.method == "get_notification")
+        .count();
+    match problems.len() {
+        0 => println!(
+            "{} is fine: {} idle notifications, {} scripts",
+            path.display(),
+            notifications,
+            settings.scripts.files.len()
+        ),
+        1 => println!("1 problem in {}", path.display()),
+        count => println!("{} problems in {}", count, path.display()),
+    }
+    Ok(problems.is_empty())
+}
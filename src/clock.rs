@@ -0,0 +1,71 @@
+//! Time of the scheduler and the night light. It's the wall clock, unless `ctl clock set` or
+//! `ctl clock advance` moved it, e.g. to preview tomorrow's night light transition or the
+//! weekend profile switch in seconds. The moved clock keeps ticking from where it was put.
+
+use chrono::{DateTime, Duration as ChronoDuration, Local, NaiveDateTime, NaiveTime, TimeZone};
+use log::info;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+#[derive(Clone, Debug, Default)]
+pub struct Clock {
+    /// Offset of the simulated clock from the wall clock, `None` for the wall clock
+    offset: Arc<Mutex<Option<ChronoDuration>>>,
+}
+
+impl Clock {
+    pub fn now(&self) -> DateTime<Local> {
+        let now = Local::now();
+        match *self.offset.lock().unwrap() {
+            Some(offset) => now.checked_add_signed(offset).unwrap_or(now),
+            None => now,
+        }
+    }
+
+    pub fn is_simulated(&self) -> bool {
+        self.offset.lock().unwrap().is_some()
+    }
+
+    pub fn set(&self, time: DateTime<Local>) {
+        info!("Simulated clock set to {}", time);
+        *self.offset.lock().unwrap() = Some(time - Local::now());
+    }
+
+    pub fn advance(&self, duration: Duration) -> Result<DateTime<Local>, String> {
+        let duration = ChronoDuration::from_std(duration).map_err(|e| e.to_string())?;
+        let mut offset = self.offset.lock().unwrap();
+        let advanced = offset.unwrap_or_else(ChronoDuration::zero) + duration;
+        *offset = Some(advanced);
+        let now = Local::now() + advanced;
+        info!("Simulated clock advanced to {}", now);
+        Ok(now)
+    }
+
+    pub fn reset(&self) {
+        info!("Back to the wall clock");
+        *self.offset.lock().unwrap() = None;
+    }
+}
+
+/// Parses `YYYY-MM-DD HH:MM[:SS]`, with a `T` as separator as well, or `HH:MM[:SS]` for today.
+pub fn parse_time(time: &str) -> Result<DateTime<Local>, String> {
+    let naive = [
+        "%Y-%m-%d %H:%M:%S",
+        "%Y-%m-%d %H:%M",
+        "%Y-%m-%dT%H:%M:%S",
+        "%Y-%m-%dT%H:%M",
+    ]
+    .iter()
+    .find_map(|format| NaiveDateTime::parse_from_str(time, format).ok())
+    .or_else(|| {
+        ["%H:%M:%S", "%H:%M"]
+            .iter()
+            .find_map(|format| NaiveTime::parse_from_str(time, format).ok())
+            .map(|clock| Local::now().date_naive().and_time(clock))
+    })
+    .ok_or_else(|| format!("invalid time {}, expected YYYY-MM-DD HH:MM", time))?;
+    Local
+        .from_local_datetime(&naive)
+        .earliest()
+        .ok_or_else(|| format!("{} doesn't exist in the local timezone", time))
+}
@@ -0,0 +1,115 @@
+//! Command log: the last commands the daemon ran, when, why and how they ended, in a small
+//! file of the XDG state directory. Entries are written as commands start and end, so after a
+//! crash or an OOM kill the file tells what the daemon did last, also when it died before the
+//! journal or the Lua config were set up.
+
+use chrono::Local;
+use log::error;
+use once_cell::sync::Lazy;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use xdg::BaseDirectories;
+
+use super::config;
+
+/// Entries kept when the file is compacted, it grows to twice as many before
+const MAX_ENTRIES: usize = 200;
+
+struct CommandLog {
+    path: PathBuf,
+    file: File,
+    lines: usize,
+}
+
+impl CommandLog {
+    fn open() -> io::Result<Self> {
+        let path = BaseDirectories::with_prefix(config::APP_NAME)?
+            .place_state_file(config::COMMAND_LOG_FILE_NAME)?;
+        let lines = match File::open(&path) {
+            Ok(file) => BufReader::new(file).lines().count(),
+            Err(_) => 0,
+        };
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        Ok(Self { path, file, lines })
+    }
+
+    /// Drops all but the last `MAX_ENTRIES` lines. The file is replaced atomically, so a
+    /// crash while compacting doesn't lose the log.
+    fn compact(&mut self) -> io::Result<()> {
+        let content = fs::read_to_string(&self.path)?;
+        let lines: Vec<&str> = content.lines().collect();
+        let kept = &lines[lines.len().saturating_sub(MAX_ENTRIES)..];
+        let tmp = self.path.with_extension("tmp");
+        fs::write(
+            &tmp,
+            kept.iter()
+                .map(|line| format!("{}\n", line))
+                .collect::<String>(),
+        )?;
+        fs::rename(&tmp, &self.path)?;
+        self.file = OpenOptions::new().append(true).open(&self.path)?;
+        self.lines = kept.len();
+        Ok(())
+    }
+
+    /// Appends an entry with a single write, so a killed daemon leaves complete lines.
+    fn append(&mut self, mut entry: serde_json::Value) -> io::Result<()> {
+        entry["time"] = Local::now().to_rfc3339().into();
+        entry["pid"] = std::process::id().into();
+        self.file.write_all(format!("{}\n", entry).as_bytes())?;
+        self.lines += 1;
+        if self.lines >= 2 * MAX_ENTRIES {
+            self.compact()?;
+        }
+        Ok(())
+    }
+}
+
+static LOG: Lazy<Mutex<Option<CommandLog>>> = Lazy::new(|| {
+    let log = CommandLog::open();
+    if let Err(e) = &log {
+        error!("Failed to open the command log: {}", e);
+    }
+    Mutex::new(log.ok())
+});
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+fn append(entry: serde_json::Value) {
+    if let Some(log) = LOG.lock().unwrap().as_mut() {
+        if let Err(e) = log.append(entry) {
+            error!("Failed to write the command log: {}", e);
+        }
+    }
+}
+
+/// Marks the start of a daemon, so the entries of earlier runs can be told apart.
+pub fn daemon_started() {
+    append(serde_json::json!({ "event": "daemon_start" }));
+}
+
+/// Records that `command` is about to start, `why` being the event or API that started it.
+/// Returns the id that `finished` takes.
+pub fn started(command: &str, why: &str) -> u64 {
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    append(serde_json::json!({
+        "event": "start",
+        "id": id,
+        "command": command,
+        "why": why,
+    }));
+    id
+}
+
+/// Records how the command with `id` ended, its exit status or why it couldn't run.
+pub fn finished(id: u64, command: &str, result: &str) {
+    append(serde_json::json!({
+        "event": "end",
+        "id": id,
+        "command": command,
+        "result": result,
+    }));
+}
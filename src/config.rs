@@ -1,2 +1,37 @@
 pub const APP_NAME: &str = "sleepwatcher-rs";
 pub const CONFIG_FILE_NAME: &str = "idle_config.lua";
+pub const CONTROL_SOCKET_NAME: &str = "ctl.sock";
+pub const TRUST_FILE_NAME: &str = "trusted_modules";
+pub const SETTINGS_FILE_NAME: &str = "sleepwatcher.toml";
+pub const SECRETS_DIR_NAME: &str = "secrets";
+pub const NIGHTLIGHT_STATE_FILE_NAME: &str = "nightlight.json";
+pub const COMMAND_LOG_FILE_NAME: &str = "commands.log";
+/// The verified fleet bundle with its signature
+pub const FLEET_CACHE_FILE_NAME: &str = "fleet_bundle.json";
+pub const HELPER_PATH: &str = "/usr/libexec/sleepwatcher-rs-helper";
+/// PAM service of the built-in locker, `/etc/pam.d/sleepwatcher-rs`
+pub const PAM_SERVICE: &str = "sleepwatcher-rs";
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+/// Set by build.rs when built from a git checkout
+pub const GIT_COMMIT: Option<&str> = option_env!("SLEEPWATCHER_GIT_COMMIT");
+
+/// Optional cargo features the daemon was built with.
+pub fn features() -> Vec<&'static str> {
+    let mut features = Vec::new();
+    if cfg!(feature = "otlp") {
+        features.push("otlp");
+    }
+    if cfg!(feature = "remote") {
+        features.push("remote");
+    }
+    if cfg!(feature = "fleet") {
+        features.push("fleet");
+    }
+    if cfg!(feature = "lock") {
+        features.push("lock");
+    }
+    if cfg!(feature = "x11") {
+        features.push("x11");
+    }
+    features
+}
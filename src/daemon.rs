@@ -0,0 +1,607 @@
+use chrono::{DateTime, Local};
+use log::{debug, error, info};
+use mlua::{UserData, UserDataMethods};
+use once_cell::sync::OnceCell;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, Notify};
+use zbus::{dbus_interface, fdo, SignalContext};
+
+use super::dbus;
+use super::types::Request;
+use super::utils;
+
+pub const BUS_NAME: &str = "org.sleepwatcher.Daemon";
+pub const OBJECT_PATH: &str = "/org/sleepwatcher/Daemon";
+
+/// The connection that owns `BUS_NAME`, for signals outside of the interface
+pub static CONNECTION: OnceCell<zbus::Connection> = OnceCell::new();
+/// Color temperature with no night light applied
+pub const NEUTRAL_TEMPERATURE: u32 = 6500;
+/// How often `Inhibited` and `NextStage` are checked for changes, they depend on more than
+/// the status
+const OVERVIEW_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Runtime state exposed on the `org.sleepwatcher.Daemon` D-Bus object.
+#[derive(Debug)]
+pub struct Status {
+    paused: bool,
+    /// End of a pause from `ctl inhibit --for`
+    paused_until: Option<DateTime<Local>>,
+    profile: String,
+    temperature: u32,
+    idle_since: Option<Instant>,
+    /// Last idle or session event, for command templates
+    event: String,
+    /// Callback that handled the last event
+    stage: String,
+    /// Written with the event, stage and idle time on every event
+    state_file: Option<PathBuf>,
+    /// Name of the default seat, whose idle state is the session's
+    seat: String,
+    /// Last input per seat, kept across config reloads
+    last_activity: HashMap<String, DateTime<Local>>,
+    /// Other seats with an idle notification that idled
+    idle_seats: HashSet<String>,
+    output: Option<String>,
+    heartbeat: Option<DateTime<Local>>,
+    /// Presentation mode holds back idle actions and the night light
+    presenting: bool,
+    /// Start of the current absence and when the session was locked, for `on_return`
+    away_since: Option<DateTime<Local>>,
+    locked_at: Option<DateTime<Local>>,
+    /// Start of the daemon, for the uptime in bug reports
+    started: DateTime<Local>,
+    /// Name of the idle backend in use
+    backend: Option<&'static str>,
+    /// `WAYLAND_DISPLAY` of the session this one is nested in
+    parent_session: Option<String>,
+    changed: Arc<Notify>,
+}
+
+pub type StatusHandle = Arc<Mutex<Status>>;
+
+/// An absence that just ended, passed to the `on_return` hooks.
+#[derive(Clone, Debug)]
+pub struct Away {
+    pub since: DateTime<Local>,
+    pub away: Duration,
+    /// Zero if the session wasn't locked
+    pub locked: Duration,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct Snapshot {
+    paused: bool,
+    profile: String,
+    temperature: u32,
+    idle: bool,
+    overview: Overview,
+}
+
+/// What status bars show besides the status, computed from the rest of the daemon.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Overview {
+    /// Idle callbacks are held back by a pause, an inhibitor or another policy
+    pub inhibited: bool,
+    /// Callback of the next idle stage and the seconds until it fires
+    pub next_stage: Option<(String, u64)>,
+}
+
+pub type OverviewFn = Arc<dyn Fn() -> Overview + Send + Sync>;
+
+impl Status {
+    pub fn new() -> StatusHandle {
+        Arc::new(Mutex::new(Self {
+            paused: false,
+            paused_until: None,
+            profile: "default".to_string(),
+            temperature: NEUTRAL_TEMPERATURE,
+            idle_since: None,
+            event: String::new(),
+            stage: String::new(),
+            state_file: None,
+            seat: "seat0".to_string(),
+            last_activity: HashMap::new(),
+            idle_seats: HashSet::new(),
+            output: None,
+            heartbeat: None,
+            presenting: false,
+            away_since: None,
+            locked_at: None,
+            started: Local::now(),
+            backend: None,
+            parent_session: None,
+            changed: Arc::new(Notify::new()),
+        }))
+    }
+
+    fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            paused: self.paused,
+            profile: self.profile.clone(),
+            temperature: self.temperature(),
+            idle: self.idle_since.is_some(),
+            overview: Overview::default(),
+        }
+    }
+
+    /// While paused, idle callbacks are not run.
+    pub fn paused(&self) -> bool {
+        self.paused
+    }
+
+    pub fn set_paused(&mut self, paused: bool) {
+        self.paused = paused;
+        self.paused_until = None;
+        self.changed.notify_one();
+    }
+
+    /// Pauses, for `duration` if given. Returns when the pause ends.
+    pub fn pause_for(&mut self, duration: Option<Duration>) -> Option<DateTime<Local>> {
+        self.set_paused(true);
+        self.paused_until = duration
+            .and_then(|duration| chrono::Duration::from_std(duration).ok())
+            .and_then(|duration| Local::now().checked_add_signed(duration));
+        self.paused_until
+    }
+
+    /// Ends the pause that was set to end at `until`, unless it was changed since.
+    pub fn pause_expired(&mut self, until: DateTime<Local>) {
+        if self.paused && self.paused_until == Some(until) {
+            info!("Pause ended");
+            self.set_paused(false);
+        }
+    }
+
+    pub fn paused_until(&self) -> Option<DateTime<Local>> {
+        self.paused_until
+    }
+
+    pub fn started(&self) -> DateTime<Local> {
+        self.started
+    }
+
+    pub fn set_backend(&mut self, backend: &'static str) {
+        self.backend = Some(backend);
+    }
+
+    pub fn backend(&self) -> Option<&'static str> {
+        self.backend
+    }
+
+    pub fn set_parent_session(&mut self, parent: String) {
+        self.parent_session = Some(parent);
+    }
+
+    pub fn parent_session(&self) -> Option<String> {
+        self.parent_session.clone()
+    }
+
+    pub fn profile(&self) -> String {
+        self.profile.clone()
+    }
+
+    pub fn set_profile(&mut self, profile: String) {
+        self.profile = profile;
+        self.changed.notify_one();
+    }
+
+    /// The night light temperature, neutral during presentation mode.
+    pub fn temperature(&self) -> u32 {
+        if self.presenting {
+            NEUTRAL_TEMPERATURE
+        } else {
+            self.temperature
+        }
+    }
+
+    pub fn set_temperature(&mut self, temperature: u32) {
+        if self.temperature != temperature {
+            self.temperature = temperature;
+            self.changed.notify_one();
+        }
+    }
+
+    pub fn presenting(&self) -> bool {
+        self.presenting
+    }
+
+    pub fn set_presenting(&mut self, presenting: bool) {
+        self.presenting = presenting;
+        self.changed.notify_one();
+    }
+
+    /// Records that the user went idle `timeout` ago. The earliest notification wins.
+    pub fn idled(&mut self, timeout: Duration) {
+        let since = Instant::now() - timeout;
+        if self.idle_since.is_none_or(|idle_since| since < idle_since) {
+            self.idle_since = Some(since);
+            if let Ok(timeout) = chrono::Duration::from_std(timeout) {
+                let last = Local::now() - timeout;
+                self.last_activity.insert(self.seat.clone(), last);
+                if self.away_since.is_none_or(|away_since| last < away_since) {
+                    self.away_since = Some(last);
+                }
+            }
+            self.changed.notify_one();
+        }
+    }
+
+    /// Returns the absence that ended, unless the session is locked: then the user is only
+    /// back once it is unlocked.
+    pub fn resumed(&mut self) -> Option<Away> {
+        self.last_activity.insert(self.seat.clone(), Local::now());
+        if self.idle_since.take().is_some() {
+            self.changed.notify_one();
+        }
+        if self.locked_at.is_some() {
+            return None;
+        }
+        self.take_away()
+    }
+
+    /// Records that `seat`, not the default seat, went idle `timeout` ago. Only its last
+    /// activity is kept, the session stays active.
+    pub fn seat_idled(&mut self, seat: &str, timeout: Duration) {
+        if !self.idle_seats.insert(seat.to_string()) {
+            return;
+        }
+        if let Ok(timeout) = chrono::Duration::from_std(timeout) {
+            self.last_activity
+                .insert(seat.to_string(), Local::now() - timeout);
+        }
+    }
+
+    pub fn seat_resumed(&mut self, seat: &str) {
+        self.idle_seats.remove(seat);
+        self.last_activity.insert(seat.to_string(), Local::now());
+    }
+
+    pub fn locked(&mut self) {
+        let now = Local::now();
+        self.locked_at.get_or_insert(now);
+        self.away_since.get_or_insert(now);
+    }
+
+    pub fn unlocked(&mut self) -> Option<Away> {
+        self.take_away()
+    }
+
+    /// Since when the session is locked and since when the user is away, if locked.
+    pub fn lock_times(&self) -> Option<(DateTime<Local>, DateTime<Local>)> {
+        self.locked_at.zip(self.away_since)
+    }
+
+    /// Takes over the lock of a replaced instance.
+    pub fn restore_lock(&mut self, locked_at: DateTime<Local>, away_since: DateTime<Local>) {
+        self.locked_at = Some(locked_at);
+        self.away_since = Some(away_since);
+    }
+
+    fn take_away(&mut self) -> Option<Away> {
+        let since = self.away_since.take()?;
+        let now = Local::now();
+        let locked = self.locked_at.take().map(|locked_at| now - locked_at);
+        Some(Away {
+            since,
+            away: (now - since).to_std().unwrap_or_default(),
+            locked: locked
+                .and_then(|locked| locked.to_std().ok())
+                .unwrap_or_default(),
+        })
+    }
+
+    /// When the user of `seat` last gave input. While the seat is active this is now, the
+    /// precision is the shortest idle timeout of the config.
+    pub fn last_activity(&self, seat: &str) -> Option<DateTime<Local>> {
+        let active = match seat == self.seat {
+            true => self.idle_since.is_none(),
+            false => self.last_activity.contains_key(seat) && !self.idle_seats.contains(seat),
+        };
+        if active {
+            return Some(Local::now());
+        }
+        self.last_activity.get(seat).copied()
+    }
+
+    /// Last activity of every seat seen so far.
+    pub fn last_activity_by_seat(&self) -> HashMap<String, DateTime<Local>> {
+        self.last_activity
+            .keys()
+            .chain(std::iter::once(&self.seat))
+            .filter_map(|seat| Some((seat.clone(), self.last_activity(seat)?)))
+            .collect()
+    }
+
+    /// Seconds since the user went idle, 0 while active.
+    pub fn idle_elapsed(&self) -> u64 {
+        self.idle_since.map_or(0, |since| since.elapsed().as_secs())
+    }
+
+    pub fn set_event(&mut self, event: &str, stage: &str) {
+        self.event = event.to_string();
+        self.stage = stage.to_string();
+        if let Some(path) = &self.state_file {
+            if let Err(e) = self.write_state_file(path) {
+                error!("Failed to write the state file {:?}: {}", path, e);
+            }
+        }
+    }
+
+    pub fn set_state_file(&mut self, path: Option<PathBuf>) {
+        self.state_file = path;
+    }
+
+    /// Environment of the commands the config runs, so shell hooks know why they were called.
+    pub fn env_vars(&self) -> Vec<(&'static str, String)> {
+        let idle_ms = self
+            .idle_since
+            .map_or(0, |since| since.elapsed().as_millis());
+        vec![
+            ("SLEEPWATCHER_EVENT", self.event.clone()),
+            ("SLEEPWATCHER_STAGE", self.stage.clone()),
+            ("SLEEPWATCHER_IDLE_MS", idle_ms.to_string()),
+        ]
+    }
+
+    /// Writes the environment variables as a file that shell scripts can source. The file
+    /// is replaced atomically, so readers never see a partial write.
+    fn write_state_file(&self, path: &Path) -> std::io::Result<()> {
+        let content: String = self
+            .env_vars()
+            .into_iter()
+            .map(|(key, value)| format!("{}={}\n", key, value))
+            .collect();
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, content)?;
+        fs::rename(&tmp, path)
+    }
+
+    pub fn seat(&self) -> String {
+        self.seat.clone()
+    }
+
+    pub fn set_seat(&mut self, seat: String) {
+        self.seat = seat;
+    }
+
+    /// Output the focused window is on.
+    pub fn set_output(&mut self, output: Option<String>) {
+        self.output = output;
+    }
+
+    pub fn heartbeat(&self) -> Option<DateTime<Local>> {
+        self.heartbeat
+    }
+
+    pub fn beat(&mut self) {
+        self.heartbeat = Some(Local::now());
+    }
+
+    /// Values for the `${...}` placeholders in commands.
+    pub fn template_vars(&self) -> HashMap<&'static str, String> {
+        HashMap::from([
+            ("output", self.output.clone().unwrap_or_default()),
+            ("seat", self.seat.clone()),
+            ("idle_secs", self.idle_elapsed().to_string()),
+            ("event", self.event.clone()),
+            ("profile", self.profile.clone()),
+        ])
+    }
+}
+
+struct DaemonInterface {
+    status: StatusHandle,
+    overview: OverviewFn,
+    tx: mpsc::Sender<Request>,
+}
+
+impl DaemonInterface {
+    async fn send(&self, request: Request) -> fdo::Result<()> {
+        self.tx
+            .send(request)
+            .await
+            .map_err(|e| fdo::Error::Failed(e.to_string()))
+    }
+}
+
+#[dbus_interface(name = "org.sleepwatcher.Daemon")]
+impl DaemonInterface {
+    /// Asks logind to lock the session, which runs the configured lock handler.
+    async fn lock(&self) -> fdo::Result<()> {
+        dbus::lock_session().await?;
+        Ok(())
+    }
+
+    /// Reloads the Lua config.
+    async fn reload(&self) -> fdo::Result<()> {
+        self.send(Request::Reset).await
+    }
+
+    /// Calls the global Lua function `name`.
+    async fn trigger(&self, name: String) -> fdo::Result<()> {
+        self.send(Request::LuaCallback(name)).await
+    }
+
+    #[dbus_interface(property)]
+    fn paused(&self) -> bool {
+        self.status.lock().unwrap().paused()
+    }
+
+    #[dbus_interface(property)]
+    fn set_paused(&mut self, paused: bool) {
+        info!("Paused set to {} over D-Bus", paused);
+        self.status.lock().unwrap().set_paused(paused);
+    }
+
+    #[dbus_interface(property)]
+    fn profile(&self) -> String {
+        self.status.lock().unwrap().profile()
+    }
+
+    #[dbus_interface(property)]
+    fn set_profile(&mut self, profile: String) {
+        info!("Profile set to {} over D-Bus", profile);
+        // Through the command loop, which runs the profile hooks
+        utils::send_request(&self.tx, Request::Profile(profile));
+    }
+
+    #[dbus_interface(property)]
+    fn temperature(&self) -> u32 {
+        self.status.lock().unwrap().temperature()
+    }
+
+    #[dbus_interface(property)]
+    fn idle_elapsed(&self) -> u64 {
+        self.status.lock().unwrap().idle_elapsed()
+    }
+
+    #[dbus_interface(property)]
+    fn inhibited(&self) -> bool {
+        (self.overview)().inhibited
+    }
+
+    /// Empty without an idle stage to come.
+    #[dbus_interface(property)]
+    fn next_stage(&self) -> String {
+        (self.overview)()
+            .next_stage
+            .map(|(stage, _)| stage)
+            .unwrap_or_default()
+    }
+
+    #[dbus_interface(property)]
+    fn next_stage_in(&self) -> u64 {
+        (self.overview)().next_stage.map_or(0, |(_, secs)| secs)
+    }
+}
+
+async fn emit_changes(
+    iface: &DaemonInterface,
+    ctxt: &SignalContext<'_>,
+    old: &Snapshot,
+    new: &Snapshot,
+) -> zbus::Result<()> {
+    if old.paused != new.paused {
+        iface.paused_changed(ctxt).await?;
+    }
+    if old.profile != new.profile {
+        iface.profile_changed(ctxt).await?;
+    }
+    if old.temperature != new.temperature {
+        iface.temperature_changed(ctxt).await?;
+    }
+    // IdleElapsed grows continuously, so changes are only announced when going idle or active
+    if old.idle != new.idle {
+        iface.idle_elapsed_changed(ctxt).await?;
+    }
+    if old.overview.inhibited != new.overview.inhibited {
+        iface.inhibited_changed(ctxt).await?;
+    }
+    // Like IdleElapsed, NextStageIn counts down, so only a new stage is announced
+    let stage = |snapshot: &Snapshot| snapshot.overview.next_stage.clone().map(|(stage, _)| stage);
+    if stage(old) != stage(new) || old.idle != new.idle {
+        iface.next_stage_changed(ctxt).await?;
+        iface.next_stage_in_changed(ctxt).await?;
+    }
+    Ok(())
+}
+
+/// Serves the daemon object on the session bus and emits PropertiesChanged whenever the
+/// status changes.
+pub async fn daemon_run(
+    status: StatusHandle,
+    overview: OverviewFn,
+    tx: mpsc::Sender<Request>,
+) -> anyhow::Result<()> {
+    let snapshot = {
+        let status = status.clone();
+        let overview = overview.clone();
+        move || Snapshot {
+            overview: overview(),
+            ..status.lock().unwrap().snapshot()
+        }
+    };
+    let changed = status.lock().unwrap().changed.clone();
+    let mut last = snapshot();
+    let iface = DaemonInterface {
+        status: status.clone(),
+        overview,
+        tx,
+    };
+    let conn = zbus::ConnectionBuilder::session()?
+        .name(BUS_NAME)?
+        .serve_at(OBJECT_PATH, iface)?
+        .build()
+        .await?;
+    info!("Serving {} on the session bus", BUS_NAME);
+    let _ = CONNECTION.set(conn.clone());
+
+    tokio::spawn(async move {
+        let iface_ref = match conn
+            .object_server()
+            .interface::<_, DaemonInterface>(OBJECT_PATH)
+            .await
+        {
+            Ok(iface_ref) => iface_ref,
+            Err(e) => {
+                error!("Failed to look up the daemon interface: {}", e);
+                return;
+            }
+        };
+        let mut ticker = tokio::time::interval(OVERVIEW_INTERVAL);
+        loop {
+            tokio::select! {
+                _ = changed.notified() => {}
+                _ = ticker.tick() => {}
+            }
+            let current = snapshot();
+            if current == last {
+                continue;
+            }
+            debug!("Daemon status changed: {:?}", current);
+            let iface = iface_ref.get().await;
+            if let Err(e) = emit_changes(&iface, iface_ref.signal_context(), &last, &current).await
+            {
+                error!("Failed to emit PropertiesChanged: {}", e);
+            }
+            last = current;
+        }
+    });
+    Ok(())
+}
+
+#[derive(Clone, Debug)]
+pub struct StatusHelpers {
+    pub status: StatusHandle,
+}
+
+impl UserData for StatusHelpers {
+    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_method("profile", |_lua, this, (): ()| {
+            Ok(this.status.lock().unwrap().profile())
+        });
+        methods.add_method("set_profile", |_lua, this, profile: String| {
+            this.status.lock().unwrap().set_profile(profile);
+            Ok(())
+        });
+        methods.add_method("paused", |_lua, this, (): ()| {
+            Ok(this.status.lock().unwrap().paused())
+        });
+        methods.add_method("presenting", |_lua, this, (): ()| {
+            Ok(this.status.lock().unwrap().presenting())
+        });
+        methods.add_method("idle_elapsed", |_lua, this, (): ()| {
+            Ok(this.status.lock().unwrap().idle_elapsed())
+        });
+        methods.add_method("last_activity", |_lua, this, seat: Option<String>| {
+            let status = this.status.lock().unwrap();
+            let seat = seat.unwrap_or_else(|| status.seat());
+            Ok(status.last_activity(&seat).map(|time| time.timestamp()))
+        });
+    }
+}
@@ -1,8 +1,28 @@
+use super::config;
+use super::settings::{LogindIdlePolicy, SleepSettings};
 use super::types::Request;
+use super::utils;
 use futures::stream::StreamExt;
-use log::{debug, error};
+use log::{debug, error, info, warn};
+use std::collections::HashMap;
+use std::time::Duration;
 use tokio::sync::mpsc;
 use zbus::dbus_proxy;
+use zbus::zvariant::{self, ObjectPath, OwnedObjectPath, OwnedValue, Value};
+
+/// logind doesn't wait longer for delay locks by default (`InhibitDelayMaxSec`)
+pub const BEFORE_SLEEP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Reports a signal stream that ended, which happens when the bus connection is lost.
+async fn report_disconnect(tx: &mpsc::Sender<Request>, service: &str) {
+    error!("Lost the D-Bus connection to {}", service);
+    let context = vec![
+        ("source".to_string(), "dbus".to_string()),
+        ("service".to_string(), service.to_string()),
+    ];
+    let error = format!("D-Bus connection to {} lost", service);
+    let _ = tx.send(Request::Error(error, context)).await;
+}
 
 pub async fn upower_watcher(tx: mpsc::Sender<Request>) -> anyhow::Result<()> {
     let conn = zbus::Connection::system().await?;
@@ -12,7 +32,9 @@ pub async fn upower_watcher(tx: mpsc::Sender<Request>) -> anyhow::Result<()> {
     let mut power_stream = proxy.receive_on_battery_changed().await;
     tx.send(Request::OnBattery(state)).await.unwrap();
 
+    let power_tx = tx.clone();
     tokio::spawn(async move {
+        let tx = power_tx;
         while let Some(on_battery_changed) = power_stream.next().await {
             match on_battery_changed.get().await {
                 Ok(on_battery) => {
@@ -23,6 +45,26 @@ pub async fn upower_watcher(tx: mpsc::Sender<Request>) -> anyhow::Result<()> {
                 }
             }
         }
+        report_disconnect(&tx, "org.freedesktop.UPower").await;
+    });
+
+    let device = UPowerDeviceInterfaceProxy::new(&conn).await?;
+    if !device.is_present().await? {
+        info!("No battery found, battery actions are disabled");
+        return Ok(());
+    }
+    let mut level_stream = device.receive_percentage_changed().await;
+    tx.send(Request::BatteryLevel(device.percentage().await?))
+        .await?;
+    tokio::spawn(async move {
+        while let Some(percentage_changed) = level_stream.next().await {
+            match percentage_changed.get().await {
+                Ok(level) => {
+                    let _ = tx.send(Request::BatteryLevel(level)).await;
+                }
+                Err(e) => error!("Error getting the battery percentage: {}", e),
+            }
+        }
     });
     Ok(())
 }
@@ -35,39 +77,431 @@ pub async fn upower_watcher(tx: mpsc::Sender<Request>) -> anyhow::Result<()> {
 trait UPowerInterface {
     #[dbus_proxy(property)]
     fn on_battery(&self) -> zbus::Result<bool>;
+    #[dbus_proxy(property)]
+    fn lid_is_present(&self) -> zbus::Result<bool>;
+    #[dbus_proxy(property)]
+    fn lid_is_closed(&self) -> zbus::Result<bool>;
 }
 
+/// The composite battery UPower shows in the panel.
+#[dbus_proxy(
+    interface = "org.freedesktop.UPower.Device",
+    default_service = "org.freedesktop.UPower",
+    default_path = "/org/freedesktop/UPower/devices/DisplayDevice"
+)]
+trait UPowerDeviceInterface {
+    #[dbus_proxy(property)]
+    fn is_present(&self) -> zbus::Result<bool>;
+    #[dbus_proxy(property)]
+    fn percentage(&self) -> zbus::Result<f64>;
+}
+
+/// Reports whether a screen reader like Orca runs, set by the screen reader itself.
+pub async fn screen_reader_watcher(tx: mpsc::Sender<Request>) -> anyhow::Result<()> {
+    let conn = zbus::Connection::session().await?;
+    let proxy = A11yStatusInterfaceProxy::new(&conn).await?;
+
+    let mut enabled_stream = proxy.receive_screen_reader_enabled_changed().await;
+    tx.send(Request::ScreenReader(proxy.screen_reader_enabled().await?))
+        .await?;
+    tokio::spawn(async move {
+        while let Some(enabled_changed) = enabled_stream.next().await {
+            match enabled_changed.get().await {
+                Ok(enabled) => {
+                    let _ = tx.send(Request::ScreenReader(enabled)).await;
+                }
+                Err(e) => error!("Error getting the screen reader state: {}", e),
+            }
+        }
+    });
+    Ok(())
+}
+
+#[dbus_proxy(
+    interface = "org.a11y.Status",
+    default_service = "org.a11y.Bus",
+    default_path = "/org/a11y/bus"
+)]
+trait A11yStatusInterface {
+    #[dbus_proxy(property)]
+    fn screen_reader_enabled(&self) -> zbus::Result<bool>;
+}
+
+#[dbus_proxy(
+    interface = "org.freedesktop.PackageKit",
+    default_service = "org.freedesktop.PackageKit",
+    default_path = "/org/freedesktop/PackageKit"
+)]
+trait PackageKitInterface {
+    /// Set while a transaction holds the package backend, e.g. during an update
+    #[dbus_proxy(property)]
+    fn locked(&self) -> zbus::Result<bool>;
+}
+
+/// (what, who, why, mode, uid, pid) as returned by logind's ListInhibitors
+pub type LogindInhibitor = (String, String, String, String, u32, u32);
+
 #[dbus_proxy(
     interface = "org.freedesktop.login1.Manager",
     default_service = "org.freedesktop.login1",
     default_path = "/org/freedesktop/login1"
 )]
 trait LogindManagerInterface {
+    fn suspend(&self, interactive: bool) -> zbus::Result<()>;
+    fn hibernate(&self, interactive: bool) -> zbus::Result<()>;
+    fn list_inhibitors(&self) -> zbus::Result<Vec<LogindInhibitor>>;
+    fn get_session(&self, session_id: &str) -> zbus::Result<OwnedObjectPath>;
+    #[dbus_proxy(name = "GetSessionByPID")]
+    fn get_session_by_pid(&self, pid: u32) -> zbus::Result<OwnedObjectPath>;
+    #[dbus_proxy(property)]
+    fn idle_action(&self) -> zbus::Result<String>;
+    #[dbus_proxy(property, name = "IdleActionUSec")]
+    fn idle_action_usec(&self) -> zbus::Result<u64>;
+    /// Set with a docking station or more than one display connected
+    #[dbus_proxy(property)]
+    fn docked(&self) -> zbus::Result<bool>;
+    fn inhibit(
+        &self,
+        what: &str,
+        who: &str,
+        why: &str,
+        mode: &str,
+    ) -> zbus::Result<zvariant::OwnedFd>;
     #[dbus_proxy(signal)]
     fn prepare_for_sleep(&self, start: bool) -> fdo::Result<()>;
+    #[dbus_proxy(signal)]
+    fn prepare_for_shutdown(&self, start: bool) -> fdo::Result<()>;
 }
 
 #[dbus_proxy(
-    interface = "org.freedesktop.login1.Session",
+    interface = "org.freedesktop.NetworkManager",
+    default_service = "org.freedesktop.NetworkManager",
+    default_path = "/org/freedesktop/NetworkManager"
+)]
+trait NetworkManagerInterface {
+    #[dbus_proxy(property)]
+    fn active_connections(&self) -> zbus::Result<Vec<OwnedObjectPath>>;
+}
+
+#[dbus_proxy(
+    interface = "org.freedesktop.NetworkManager.Connection.Active",
+    default_service = "org.freedesktop.NetworkManager"
+)]
+trait ActiveConnectionInterface {
+    #[dbus_proxy(property, name = "Type")]
+    fn connection_type(&self) -> zbus::Result<String>;
+    /// The access point of a wireless connection
+    #[dbus_proxy(property)]
+    fn specific_object(&self) -> zbus::Result<OwnedObjectPath>;
+}
+
+#[dbus_proxy(
+    interface = "org.freedesktop.NetworkManager.AccessPoint",
+    default_service = "org.freedesktop.NetworkManager"
+)]
+trait AccessPointInterface {
+    #[dbus_proxy(property)]
+    fn ssid(&self) -> zbus::Result<Vec<u8>>;
+}
+
+/// SSIDs of the active Wi-Fi connections, from NetworkManager.
+pub async fn wifi_ssids(conn: &zbus::Connection) -> zbus::Result<Vec<String>> {
+    let manager = NetworkManagerInterfaceProxy::new(conn).await?;
+    let mut ssids = Vec::new();
+    for path in manager.active_connections().await? {
+        let active = ActiveConnectionInterfaceProxy::builder(conn)
+            .path(path)?
+            .build()
+            .await?;
+        if active.connection_type().await? != "802-11-wireless" {
+            continue;
+        }
+        let access_point = AccessPointInterfaceProxy::builder(conn)
+            .path(active.specific_object().await?)?
+            .build()
+            .await?;
+        ssids.push(String::from_utf8_lossy(&access_point.ssid().await?).into_owned());
+    }
+    Ok(ssids)
+}
+
+/// logind's view of whether the machine is docked.
+pub async fn docked(conn: &zbus::Connection) -> zbus::Result<bool> {
+    LogindManagerInterfaceProxy::new(conn).await?.docked().await
+}
+
+#[dbus_proxy(
+    interface = "org.freedesktop.GeoClue2.Manager",
+    default_service = "org.freedesktop.GeoClue2",
+    default_path = "/org/freedesktop/GeoClue2/Manager"
+)]
+trait GeoclueManager {
+    fn get_client(&self) -> zbus::Result<OwnedObjectPath>;
+}
+
+/// A client of geoclue, at the path `GetClient` returned.
+#[dbus_proxy(
+    interface = "org.freedesktop.GeoClue2.Client",
+    default_service = "org.freedesktop.GeoClue2"
+)]
+trait GeoclueClient {
+    fn start(&self) -> zbus::Result<()>;
+    #[dbus_proxy(property)]
+    fn set_desktop_id(&self, id: &str) -> zbus::Result<()>;
+    #[dbus_proxy(property)]
+    fn set_requested_accuracy_level(&self, level: u32) -> zbus::Result<()>;
+    #[dbus_proxy(property)]
+    fn set_distance_threshold(&self, meters: u32) -> zbus::Result<()>;
+    #[dbus_proxy(signal)]
+    fn location_updated(&self, old: ObjectPath<'_>, new: ObjectPath<'_>) -> zbus::Result<()>;
+}
+
+#[dbus_proxy(
+    interface = "org.freedesktop.GeoClue2.Location",
+    default_service = "org.freedesktop.GeoClue2"
+)]
+trait GeoclueLocation {
+    #[dbus_proxy(property)]
+    fn latitude(&self) -> zbus::Result<f64>;
+    #[dbus_proxy(property)]
+    fn longitude(&self) -> zbus::Result<f64>;
+}
+
+/// (session, parameters, value, content_type) as defined by the Secret Service API
+pub type Secret = (OwnedObjectPath, Vec<u8>, Vec<u8>, String);
+
+#[dbus_proxy(
+    interface = "org.freedesktop.Secret.Service",
+    default_service = "org.freedesktop.secrets",
+    default_path = "/org/freedesktop/secrets"
+)]
+trait SecretService {
+    fn open_session(
+        &self,
+        algorithm: &str,
+        input: &Value<'_>,
+    ) -> zbus::Result<(OwnedValue, OwnedObjectPath)>;
+    fn search_items(
+        &self,
+        attributes: HashMap<&str, &str>,
+    ) -> zbus::Result<(Vec<OwnedObjectPath>, Vec<OwnedObjectPath>)>;
+    fn get_secrets(
+        &self,
+        items: &[ObjectPath<'_>],
+        session: &ObjectPath<'_>,
+    ) -> zbus::Result<HashMap<OwnedObjectPath, Secret>>;
+}
+
+#[dbus_proxy(
+    interface = "org.freedesktop.Notifications",
+    default_service = "org.freedesktop.Notifications",
+    default_path = "/org/freedesktop/Notifications"
+)]
+trait Notifications {
+    #[allow(clippy::too_many_arguments)]
+    fn notify(
+        &self,
+        app_name: &str,
+        replaces_id: u32,
+        app_icon: &str,
+        summary: &str,
+        body: &str,
+        actions: &[&str],
+        hints: HashMap<&str, &Value<'_>>,
+        expire_timeout: i32,
+    ) -> zbus::Result<u32>;
+
+    fn close_notification(&self, id: u32) -> zbus::Result<()>;
+}
+
+#[dbus_proxy(
+    interface = "org.freedesktop.login1.User",
     default_service = "org.freedesktop.login1",
-    default_path = "/org/freedesktop/login1"
+    default_path = "/org/freedesktop/login1/user/self"
+)]
+trait LogindUserInterface {
+    /// The graphical session of the user
+    #[dbus_proxy(property)]
+    fn display(&self) -> zbus::Result<(String, OwnedObjectPath)>;
+}
+
+/// The session is resolved with `logind_session`, signals are only emitted on its real path.
+#[dbus_proxy(
+    interface = "org.freedesktop.login1.Session",
+    default_service = "org.freedesktop.login1"
 )]
 trait LogindSessionInterface {
+    #[dbus_proxy(property)]
+    fn locked_hint(&self) -> zbus::Result<bool>;
+    #[dbus_proxy(name = "Lock")]
+    fn lock_session(&self) -> zbus::Result<()>;
+    #[dbus_proxy(name = "Unlock")]
+    fn unlock_session(&self) -> zbus::Result<()>;
+    #[dbus_proxy(name = "Terminate")]
+    fn terminate_session(&self) -> zbus::Result<()>;
+    fn set_brightness(&self, subsystem: &str, name: &str, brightness: u32) -> zbus::Result<()>;
     #[dbus_proxy(signal)]
     fn lock(&self) -> fdo::Result<()>;
     #[dbus_proxy(signal)]
     fn unlock(&self) -> fdo::Result<()>;
 }
 
-pub async fn logind_watcher(tx: mpsc::Sender<Request>) -> anyhow::Result<()> {
+/// The path of our session: the one of `XDG_SESSION_ID`, else the one of the process. Started
+/// as a user service, the daemon belongs to no session, then it's the graphical session of
+/// the user.
+async fn session_path(conn: &zbus::Connection) -> zbus::Result<OwnedObjectPath> {
+    let manager = LogindManagerInterfaceProxy::new(conn).await?;
+    if let Ok(id) = std::env::var("XDG_SESSION_ID") {
+        match manager.get_session(&id).await {
+            Ok(path) => return Ok(path),
+            Err(e) => debug!("No logind session {}: {}", id, e),
+        }
+    }
+    match manager.get_session_by_pid(std::process::id()).await {
+        Ok(path) => return Ok(path),
+        Err(e) => debug!("Not running in a logind session: {}", e),
+    }
+    let (id, path) = LogindUserInterfaceProxy::new(conn).await?.display().await?;
+    if id.is_empty() {
+        return Err(zbus::Error::Failure(
+            "the user has no graphical session".to_string(),
+        ));
+    }
+    Ok(path)
+}
+
+/// A proxy for our logind session.
+pub async fn logind_session(
+    conn: &zbus::Connection,
+) -> zbus::Result<LogindSessionInterfaceProxy<'static>> {
+    let path = session_path(conn).await?;
+    debug!("Using the logind session at {}", path.as_str());
+    LogindSessionInterfaceProxy::builder(conn)
+        .path(path)?
+        .build()
+        .await
+}
+
+/// Asks logind to lock the session, which runs the configured lock handler.
+pub async fn lock_session() -> zbus::Result<()> {
     let conn = zbus::Connection::system().await?;
-    let session_proxy = LogindSessionInterfaceProxy::new(&conn).await?;
+    let session = logind_session(&conn).await?;
+    session.lock_session().await
+}
+
+/// Asks logind to unlock the session, which runs the configured unlock handler.
+pub async fn unlock_session() -> zbus::Result<()> {
+    let conn = zbus::Connection::system().await?;
+    let session = logind_session(&conn).await?;
+    session.unlock_session().await
+}
+
+/// Takes a delay lock, so that logind waits for `before_sleep_cmd` and the `PrepareSleep`
+/// handler, and the locker it starts, before suspending. The lock is released by dropping the
+/// fd.
+async fn delay_sleep(manager: &LogindManagerInterfaceProxy<'_>) -> Option<zvariant::OwnedFd> {
+    match manager
+        .inhibit(
+            "sleep",
+            config::APP_NAME,
+            "Locking the screen before suspending",
+            "delay",
+        )
+        .await
+    {
+        Ok(fd) => Some(fd),
+        Err(e) => {
+            error!("Failed to take a sleep delay lock: {}", e);
+            None
+        }
+    }
+}
+
+/// logind's own `IdleAction` with its timeout, unless it is `ignore` or `lock`, which don't
+/// compete with the idle actions of the config.
+pub async fn logind_idle_action(
+    manager: &LogindManagerInterfaceProxy<'_>,
+) -> zbus::Result<Option<(String, Duration)>> {
+    let action = manager.idle_action().await?;
+    if matches!(action.as_str(), "ignore" | "lock") {
+        return Ok(None);
+    }
+    let after = Duration::from_micros(manager.idle_action_usec().await?);
+    Ok(Some((action, after)))
+}
+
+/// Checks logind's `IdleAction` at startup. With the `inhibit` policy the returned idle
+/// inhibitor keeps logind from acting as long as it is held.
+async fn check_idle_action(
+    manager: &LogindManagerInterfaceProxy<'_>,
+    policy: LogindIdlePolicy,
+) -> Option<zvariant::OwnedFd> {
+    if policy == LogindIdlePolicy::Ignore {
+        return None;
+    }
+    let (action, after) = match logind_idle_action(manager).await {
+        Ok(Some(idle_action)) => idle_action,
+        Ok(None) => return None,
+        Err(e) => {
+            error!("Failed to read logind's IdleAction: {}", e);
+            return None;
+        }
+    };
+    let after = humantime::format_duration(after);
+    match policy {
+        LogindIdlePolicy::Warn => warn!(
+            "logind runs IdleAction={} after {} idle, next to the idle actions of the config. \
+             Set IdleAction=ignore in logind.conf, or logind_idle_action in the [sleep] settings",
+            action, after
+        ),
+        LogindIdlePolicy::Inhibit => {
+            info!("Inhibiting logind's IdleAction={} after {}", action, after);
+            match manager
+                .inhibit(
+                    "idle",
+                    config::APP_NAME,
+                    "The idle actions of the config replace logind's IdleAction",
+                    "block",
+                )
+                .await
+            {
+                Ok(fd) => return Some(fd),
+                Err(e) => error!("Failed to inhibit logind's IdleAction: {}", e),
+            }
+        }
+        LogindIdlePolicy::Defer => info!(
+            "Power:idle_suspend() is left to logind's IdleAction={} after {}",
+            action, after
+        ),
+        LogindIdlePolicy::Ignore => {}
+    }
+    None
+}
+
+async fn run_sleep_cmd(cmd: String, event: &str) {
+    let env = vec![("SLEEPWATCHER_EVENT", event.to_string())];
+    match utils::run(cmd.clone(), env).await {
+        Ok(finished) if !finished.status.success() => {
+            error!("{} failed with {}", cmd, finished.status)
+        }
+        Ok(_) => {}
+        Err(e) => error!("Failed to run {}: {}", cmd, e),
+    }
+}
+
+pub async fn logind_watcher(tx: mpsc::Sender<Request>, sleep: SleepSettings) -> anyhow::Result<()> {
+    let conn = zbus::Connection::system().await?;
+    let session_proxy = logind_session(&conn).await?;
     let manager_proxy = LogindManagerInterfaceProxy::new(&conn).await?;
 
     tokio::spawn(async move {
+        let _idle_inhibitor = check_idle_action(&manager_proxy, sleep.logind_idle_action).await;
+        let mut delay = delay_sleep(&manager_proxy).await;
         let mut lock_stream = session_proxy.receive_lock().await.unwrap();
         let mut unlock_stream = session_proxy.receive_unlock().await.unwrap();
         let mut prepare_sleep_stream = manager_proxy.receive_prepare_for_sleep().await.unwrap();
+        let mut prepare_shutdown_stream =
+            manager_proxy.receive_prepare_for_shutdown().await.unwrap();
 
         loop {
             tokio::select! {
@@ -85,8 +519,22 @@ pub async fn logind_watcher(tx: mpsc::Sender<Request>) -> anyhow::Result<()> {
                         Ok(args) => {
                             if *args.start() {
                                 let _ = tx.send(Request::LuaMethod("PrepareSleep".to_string())).await;
+                                if let Some(cmd) = &sleep.before_sleep_cmd {
+                                    let run = run_sleep_cmd(cmd.clone(), "prepare_sleep");
+                                    if tokio::time::timeout(BEFORE_SLEEP_TIMEOUT, run).await.is_err() {
+                                        error!("{} didn't finish before the suspend", cmd);
+                                    }
+                                }
+                                // Released by the event loop once the Lua handler is done
+                                if let Some(delay) = delay.take() {
+                                    let _ = tx.send(Request::SleepDelay(delay)).await;
+                                }
                             } else {
                                 let _ = tx.send(Request::LuaMethod("Wakeup".to_string())).await;
+                                delay = delay_sleep(&manager_proxy).await;
+                                if let Some(cmd) = &sleep.after_resume_cmd {
+                                    tokio::spawn(run_sleep_cmd(cmd.clone(), "wakeup"));
+                                }
                             }
                         }
                         Err(e) => {
@@ -94,8 +542,50 @@ pub async fn logind_watcher(tx: mpsc::Sender<Request>) -> anyhow::Result<()> {
                         }
                     }
                 },
+                Some(signal) = prepare_shutdown_stream.next() => {
+                    debug!("Prepare for Shutdown signal received");
+                    match signal.args() {
+                        Ok(args) if *args.start() => {
+                            let _ = tx.send(Request::LuaMethod("PrepareShutdown".to_string())).await;
+                        }
+                        Ok(_) => info!("Shutdown cancelled"),
+                        Err(e) => {
+                            error!("Error getting prepare_for_shutdown args: {}", e);
+                        }
+                    }
+                },
+                else => break,
+            }
+        }
+        report_disconnect(&tx, "org.freedesktop.login1").await;
+    });
+    Ok(())
+}
+
+/// Watches timedated for timezone changes. The signal is subscribed without activating
+/// timedated, which is only started when something changes the time settings.
+pub async fn timedate_watcher(tx: mpsc::Sender<Request>) -> anyhow::Result<()> {
+    let conn = zbus::Connection::system().await?;
+    let proxy = zbus::fdo::PropertiesProxy::builder(&conn)
+        .destination("org.freedesktop.timedate1")?
+        .path("/org/freedesktop/timedate1")?
+        .build()
+        .await?;
+    let mut changes = proxy.receive_properties_changed().await?;
+
+    tokio::spawn(async move {
+        while let Some(signal) = changes.next().await {
+            let Ok(args) = signal.args() else {
+                continue;
+            };
+            if args.changed_properties().contains_key("Timezone")
+                || args.invalidated_properties().contains(&"Timezone")
+            {
+                debug!("Timezone changed");
+                let _ = tx.send(Request::TimezoneChanged).await;
             }
         }
+        report_disconnect(&tx, "org.freedesktop.timedate1").await;
     });
     Ok(())
 }
@@ -0,0 +1,126 @@
+use chrono::{Datelike, Local, NaiveDateTime, NaiveTime};
+use clap::ValueEnum;
+use mlua::{UserData, UserDataMethods};
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, Mutex};
+
+use super::schedule;
+
+/// Manual override of the configured do-not-disturb windows.
+#[derive(ValueEnum, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum DndMode {
+    /// Follow the configured windows
+    Auto,
+    /// Do not disturb until switched back
+    On,
+    /// Ignore the configured windows
+    Off,
+}
+
+#[derive(Debug)]
+struct DndWindow {
+    start: NaiveTime,
+    end: NaiveTime,
+    /// Days the window starts on
+    days: schedule::Days,
+    allow_suspend: bool,
+}
+
+impl DndWindow {
+    fn contains(&self, now: NaiveDateTime) -> bool {
+        let (time, today) = (now.time(), now.weekday());
+        if self.start <= self.end {
+            self.days.contains(today) && self.start <= time && time < self.end
+        } else {
+            // The window wraps around midnight, the part after midnight belongs to the day before
+            (time >= self.start && self.days.contains(today))
+                || (time < self.end && self.days.contains(today.pred()))
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Dnd {
+    windows: Vec<DndWindow>,
+    mode: DndMode,
+}
+
+pub type DndHandle = Arc<Mutex<Dnd>>;
+
+impl Dnd {
+    pub fn new() -> DndHandle {
+        Arc::new(Mutex::new(Self {
+            windows: Vec::new(),
+            mode: DndMode::Auto,
+        }))
+    }
+
+    /// Removes the configured windows. The manual mode survives config reloads.
+    pub fn clear(&mut self) {
+        self.windows.clear();
+    }
+
+    pub fn mode(&self) -> DndMode {
+        self.mode
+    }
+
+    pub fn set_mode(&mut self, mode: DndMode) {
+        self.mode = mode;
+    }
+
+    fn active_window(&self) -> Option<&DndWindow> {
+        let now = Local::now().naive_local();
+        self.windows.iter().find(|window| window.contains(now))
+    }
+
+    /// Whether desktop notifications from the daemon should be held back.
+    pub fn is_active(&self) -> bool {
+        match self.mode {
+            DndMode::On => true,
+            DndMode::Off => false,
+            DndMode::Auto => self.active_window().is_some(),
+        }
+    }
+
+    /// Whether idle actions have been downgraded so that the machine must not be suspended.
+    pub fn blocks_suspend(&self) -> bool {
+        match self.mode {
+            DndMode::On => true,
+            DndMode::Off => false,
+            DndMode::Auto => self
+                .active_window()
+                .is_some_and(|window| !window.allow_suspend),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct DndHelpers {
+    pub dnd: DndHandle,
+}
+
+impl UserData for DndHelpers {
+    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_method(
+            "window",
+            |_lua, this, (start, end, options): (String, String, Option<mlua::Table>)| {
+                let allow_suspend = match &options {
+                    Some(options) => options.get::<_, Option<bool>>("allow_suspend")?,
+                    None => None,
+                };
+                let days = schedule::Days::parse(options.as_ref())?;
+                this.dnd.lock().unwrap().windows.push(DndWindow {
+                    start: schedule::parse_time(&start)?,
+                    end: schedule::parse_time(&end)?,
+                    days,
+                    allow_suspend: allow_suspend.unwrap_or(true),
+                });
+                Ok(())
+            },
+        );
+        methods.add_method("active", |_lua, this, (): ()| {
+            Ok(this.dnd.lock().unwrap().is_active())
+        });
+    }
+}
@@ -0,0 +1,201 @@
+//! Escalation after locking: callbacks that run once the session has been locked for a
+//! while, e.g. to turn off the screens, then suspend, then hibernate. The time counts from
+//! the moment the lock engaged, not from the last input.
+//!
+//! Besides logind's `Lock` signal, a running locker process counts as a lock, so a `swaylock`
+//! started by hand is adopted, and its exit counts as unlocking.
+
+use log::{debug, info};
+use mlua::{UserData, UserDataMethods};
+use nix::unistd::Uid;
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+
+use super::daemon::StatusHandle;
+use super::types::Request;
+
+/// How often a due stage checks again whether the user stopped typing at the lock screen
+const ACTIVE_RETRY: Duration = Duration::from_secs(10);
+const LOCKER_POLL_INTERVAL: Duration = Duration::from_secs(2);
+const DEFAULT_LOCKERS: &[&str] = &["swaylock", "gtklock", "hyprlock", "waylock"];
+
+#[derive(Clone, Debug)]
+struct Stage {
+    after: Duration,
+    fn_name: String,
+}
+
+#[derive(Debug, Default)]
+pub struct Escalation {
+    stages: Vec<Stage>,
+    /// Counts lock sessions, so the task of an earlier lock stops after unlocking
+    session: u64,
+    locked: bool,
+    /// Process names of lockers, `None` for the defaults
+    lockers: Option<Vec<String>>,
+}
+
+pub type EscalationHandle = Arc<Mutex<Escalation>>;
+
+impl Escalation {
+    pub fn new() -> EscalationHandle {
+        Arc::new(Mutex::new(Self::default()))
+    }
+
+    pub fn clear(&mut self) {
+        self.stages.clear();
+        self.lockers = None;
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.locked
+    }
+
+    /// Starts a lock session and returns its id, or None if there are no stages.
+    pub fn locked(&mut self) -> Option<u64> {
+        if self.locked {
+            return None;
+        }
+        self.locked = true;
+        self.session += 1;
+        (!self.stages.is_empty()).then_some(self.session)
+    }
+
+    pub fn unlocked(&mut self) {
+        self.locked = false;
+        self.session += 1;
+    }
+
+    fn is_current(&self, session: u64) -> bool {
+        self.locked && self.session == session
+    }
+
+    fn lockers(&self) -> Vec<String> {
+        match &self.lockers {
+            Some(lockers) => lockers.clone(),
+            None => DEFAULT_LOCKERS
+                .iter()
+                .map(|name| name.to_string())
+                .collect(),
+        }
+    }
+}
+
+/// A locker process of the current user, with its pid.
+fn running_locker(names: &[String]) -> Option<(u32, String)> {
+    let uid = Uid::current().as_raw();
+    fs::read_dir("/proc").ok()?.flatten().find_map(|entry| {
+        let pid = entry.file_name().to_str()?.parse().ok()?;
+        if entry.metadata().ok()?.uid() != uid {
+            return None;
+        }
+        let comm = fs::read_to_string(entry.path().join("comm")).ok()?;
+        let comm = comm.trim_end();
+        names
+            .iter()
+            .find(|name| name.as_str() == comm)
+            .map(|name| (pid, name.clone()))
+    })
+}
+
+/// Watches for locker processes, including ones the daemon didn't start, and reports when
+/// the first one starts and when the last one exits.
+pub async fn locker_run(escalation: EscalationHandle, tx: mpsc::Sender<Request>) {
+    let mut ticker = tokio::time::interval(LOCKER_POLL_INTERVAL);
+    let mut running: Option<(u32, String)> = None;
+    loop {
+        ticker.tick().await;
+        let names = escalation.lock().unwrap().lockers();
+        let found = running_locker(&names);
+        let request = match (&running, &found) {
+            (None, Some((pid, name))) => {
+                debug!("Locker {} running as pid {}", name, pid);
+                Request::Locker(Some(name.clone()))
+            }
+            (Some((_, name)), None) => {
+                debug!("Locker {} exited", name);
+                Request::Locker(None)
+            }
+            _ => {
+                running = found;
+                continue;
+            }
+        };
+        running = found;
+        if tx.send(request).await.is_err() {
+            return;
+        }
+    }
+}
+
+/// Runs the stages of a lock session in order. A stage that is due while the user is active
+/// at the lock screen waits until they are idle again. For a lock taken over with
+/// `--replace`, `locked_for` is the time it has been locked, and the stages that were due
+/// already ran in the replaced instance.
+pub async fn run(
+    session: u64,
+    locked_for: Duration,
+    escalation: EscalationHandle,
+    status: StatusHandle,
+    tx: mpsc::Sender<Request>,
+) {
+    let now = Instant::now();
+    let locked_at = now.checked_sub(locked_for).unwrap_or(now);
+    let mut stages = escalation.lock().unwrap().stages.clone();
+    stages.sort_by_key(|stage| stage.after);
+    for stage in stages {
+        if stage.after < locked_for {
+            continue;
+        }
+        tokio::time::sleep_until(locked_at + stage.after).await;
+        loop {
+            if !escalation.lock().unwrap().is_current(session) {
+                return;
+            }
+            if status.lock().unwrap().idle_elapsed() > 0 {
+                break;
+            }
+            debug!("Active at the lock screen, holding back {}", stage.fn_name);
+            tokio::time::sleep(ACTIVE_RETRY).await;
+        }
+        let locked_secs = locked_at.elapsed().as_secs();
+        info!(
+            "Locked for {}s, running escalation stage {}",
+            locked_secs, stage.fn_name
+        );
+        let request = Request::Escalation(stage.fn_name, locked_secs);
+        if tx.send(request).await.is_err() {
+            return;
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct EscalationHelpers {
+    pub escalation: EscalationHandle,
+}
+
+impl UserData for EscalationHelpers {
+    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_method(
+            "after_lock",
+            |_lua, this, (secs, fn_name): (u64, String)| {
+                debug!("Escalation stage {} after {}s locked", fn_name, secs);
+                this.escalation.lock().unwrap().stages.push(Stage {
+                    after: Duration::from_secs(secs),
+                    fn_name,
+                });
+                Ok(())
+            },
+        );
+        methods.add_method("lockers", |_lua, this, names: Vec<String>| {
+            debug!("Lockers: {:?}", names);
+            this.escalation.lock().unwrap().lockers = Some(names);
+            Ok(())
+        });
+    }
+}
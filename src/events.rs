@@ -0,0 +1,256 @@
+//! Named events of the session, like swayidle's `before-sleep`, `lock` and `unlock`. The event
+//! sources (logind signals, locker processes, idle notifications) publish into one place, and
+//! any number of Lua functions can listen with `Events:on(name, fn_name)`.
+//!
+//! Every event is streamed as a line of JSON as well, to `ctl subscribe` and the FIFO of
+//! `--events-fifo`, so shell scripts and status bars can react without the control protocol.
+
+use chrono::Local;
+use log::{debug, error, info, warn};
+use mlua::{IntoLuaMulti, UserData, UserDataMethods};
+use nix::sys::stat::Mode;
+use nix::unistd::mkfifo;
+use std::collections::HashMap;
+use std::fmt;
+use std::os::unix::fs::FileTypeExt;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::broadcast;
+
+use super::callbacks::{self, Callbacks};
+use super::hooks::HooksHandle;
+use super::scripts;
+
+/// Lines a slow subscriber may fall behind before it misses some
+const STREAM_CAPACITY: usize = 256;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Event {
+    /// logind is about to suspend
+    BeforeSleep,
+    /// Woken up, with the seconds spent suspended
+    AfterResume,
+    /// logind is about to power off or reboot
+    BeforeShutdown,
+    Lock,
+    Unlock,
+    /// An idle stage ran, with its callback and timeout
+    Idle,
+    /// The user came back to an idle stage, with its callback and timeout
+    Resume,
+    /// The lid was closed, with whether the machine is docked
+    LidClosed,
+    /// The lid was opened, with whether the machine is docked
+    LidOpened,
+    PowerButton,
+    /// A monitor was plugged in, with its name and description
+    OutputAdded,
+    /// A monitor was unplugged, with its name
+    OutputRemoved,
+}
+
+impl Event {
+    const ALL: [Event; 12] = [
+        Event::BeforeSleep,
+        Event::AfterResume,
+        Event::BeforeShutdown,
+        Event::Lock,
+        Event::Unlock,
+        Event::Idle,
+        Event::Resume,
+        Event::LidClosed,
+        Event::LidOpened,
+        Event::PowerButton,
+        Event::OutputAdded,
+        Event::OutputRemoved,
+    ];
+
+    fn name(&self) -> &'static str {
+        match self {
+            Event::BeforeSleep => "before-sleep",
+            Event::AfterResume => "after-resume",
+            Event::BeforeShutdown => "before-shutdown",
+            Event::Lock => "lock",
+            Event::Unlock => "unlock",
+            Event::Idle => "idle",
+            Event::Resume => "resume",
+            Event::LidClosed => "lid-closed",
+            Event::LidOpened => "lid-opened",
+            Event::PowerButton => "power-button",
+            Event::OutputAdded => "output-added",
+            Event::OutputRemoved => "output-removed",
+        }
+    }
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Event {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Event::ALL
+            .into_iter()
+            .find(|event| event.name() == s)
+            .ok_or_else(|| {
+                let known: Vec<&str> = Event::ALL.iter().map(Event::name).collect();
+                format!("unknown event {}, expected one of {}", s, known.join(", "))
+            })
+    }
+}
+
+#[derive(Debug)]
+pub struct Events {
+    handlers: HashMap<Event, Vec<String>>,
+    /// JSON lines for the subscribers, kept across config reloads
+    stream: broadcast::Sender<String>,
+}
+
+pub type EventsHandle = Arc<Mutex<Events>>;
+
+impl Events {
+    pub fn new() -> EventsHandle {
+        let (stream, _) = broadcast::channel(STREAM_CAPACITY);
+        Arc::new(Mutex::new(Self {
+            handlers: HashMap::new(),
+            stream,
+        }))
+    }
+
+    pub fn clear(&mut self) {
+        self.handlers.clear();
+    }
+
+    /// Drops the callbacks of the script `script`, before it's loaded again.
+    pub fn forget_script(&mut self, script: &str) {
+        for handlers in self.handlers.values_mut() {
+            handlers.retain(|fn_name| !scripts::owns(script, fn_name));
+        }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<String> {
+        self.stream.subscribe()
+    }
+
+    /// Streams `{"event": name, "time": ...}` with the fields of `detail` to the subscribers.
+    /// Also used for events that have no Lua handlers, like inhibitor changes.
+    pub fn emit(&self, name: &str, detail: serde_json::Value) {
+        let mut line = serde_json::json!({
+            "event": name,
+            "time": Local::now().to_rfc3339(),
+        });
+        if let (Some(line), serde_json::Value::Object(detail)) = (line.as_object_mut(), detail) {
+            line.extend(detail);
+        }
+        // Fails only without subscribers
+        let _ = self.stream.send(line.to_string());
+    }
+}
+
+/// Streams `event` with `detail` right away and queues the calls of its handlers with `args`,
+/// in the order they were registered.
+pub fn publish<A>(
+    callbacks: &Callbacks,
+    hooks: &HooksHandle,
+    events: &EventsHandle,
+    event: Event,
+    args: A,
+    detail: serde_json::Value,
+) where
+    A: for<'lua> IntoLuaMulti<'lua> + Clone + Send + 'static,
+{
+    let handlers = {
+        let events = events.lock().unwrap();
+        events.emit(event.name(), detail);
+        events.handlers.get(&event).cloned().unwrap_or_default()
+    };
+    if handlers.is_empty() {
+        return;
+    }
+    let hooks = hooks.clone();
+    callbacks.run(move |lua| {
+        for fn_name in handlers {
+            debug!("Event {} calling {}", event, fn_name);
+            callbacks::call::<_, ()>(lua, &hooks, &fn_name, args.clone());
+        }
+    });
+}
+
+#[derive(Clone, Debug)]
+pub struct EventsHelpers {
+    pub events: EventsHandle,
+}
+
+impl UserData for EventsHelpers {
+    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_method("on", |_lua, this, (event, fn_name): (String, String)| {
+            let event: Event = event.parse().map_err(mlua::Error::RuntimeError)?;
+            debug!("Calling {} on {}", fn_name, event);
+            this.events
+                .lock()
+                .unwrap()
+                .handlers
+                .entry(event)
+                .or_default()
+                .push(fn_name);
+            Ok(())
+        });
+        methods.add_method("off", |_lua, this, (event, fn_name): (String, String)| {
+            let event: Event = event.parse().map_err(mlua::Error::RuntimeError)?;
+            let mut events = this.events.lock().unwrap();
+            let Some(handlers) = events.handlers.get_mut(&event) else {
+                return Ok(false);
+            };
+            let before = handlers.len();
+            handlers.retain(|handler| *handler != fn_name);
+            Ok(handlers.len() != before)
+        });
+    }
+}
+
+/// Writes the event stream to a named pipe, created if it doesn't exist. Events are dropped
+/// while no reader has the pipe open, and a reader that goes away is waited for again.
+pub async fn fifo_run(path: PathBuf, events: EventsHandle) -> anyhow::Result<()> {
+    match std::fs::metadata(&path) {
+        Ok(metadata) if metadata.file_type().is_fifo() => {}
+        Ok(_) => anyhow::bail!("{:?} exists and is not a FIFO", path),
+        Err(_) => mkfifo(&path, Mode::from_bits_truncate(0o600))?,
+    }
+    info!("Streaming events to {:?}", path);
+    tokio::spawn(async move {
+        loop {
+            // Blocks until a reader opens the pipe
+            let mut fifo = match tokio::fs::OpenOptions::new().write(true).open(&path).await {
+                Ok(fifo) => fifo,
+                Err(e) => {
+                    error!("Failed to open {:?}: {}", path, e);
+                    return;
+                }
+            };
+            debug!("Reader connected to {:?}", path);
+            let mut lines = events.lock().unwrap().subscribe();
+            loop {
+                let line = match lines.recv().await {
+                    Ok(line) => line,
+                    Err(broadcast::error::RecvError::Lagged(missed)) => {
+                        warn!("Event FIFO reader missed {} events", missed);
+                        continue;
+                    }
+                    Err(broadcast::error::RecvError::Closed) => return,
+                };
+                let written = fifo.write_all(format!("{}\n", line).as_bytes()).await;
+                if written.is_err() || fifo.flush().await.is_err() {
+                    debug!("Reader of {:?} went away", path);
+                    break;
+                }
+            }
+        }
+    });
+    Ok(())
+}
@@ -0,0 +1,259 @@
+use anyhow::{anyhow, Context};
+use log::{debug, error, info, warn};
+use mlua::{IntoLua, Lua, UserData, UserDataMethods};
+use std::collections::HashMap;
+use std::os::unix::process::ExitStatusExt;
+use std::process::{Output, Stdio};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::process::Command;
+use tokio::sync::{mpsc, oneshot};
+
+use super::cmdlog;
+use super::scripts;
+use super::types::Request;
+use super::utils;
+
+/// Commands started with `Exec:run_stream`. Dropping the sender of a stream stops it.
+#[derive(Debug, Default)]
+pub struct Streams {
+    next_id: u64,
+    /// The callback of each stream and the sender that stops it
+    running: HashMap<u64, (String, oneshot::Sender<()>)>,
+}
+
+pub type StreamsHandle = Arc<Mutex<Streams>>;
+
+impl Streams {
+    pub fn new() -> StreamsHandle {
+        Arc::new(Mutex::new(Self::default()))
+    }
+
+    /// Stops all running streams.
+    pub fn clear(&mut self) {
+        self.running.clear();
+    }
+
+    /// Stops the streams of the script `script`, before it's loaded again.
+    pub fn forget_script(&mut self, script: &str) {
+        self.running
+            .retain(|_, (fn_name, _)| !scripts::owns(script, fn_name));
+    }
+
+    fn cancel(&mut self, id: u64) -> bool {
+        self.running.remove(&id).is_some()
+    }
+}
+
+/// Runs `cmd` and hands every stdout line to the Lua function `fn_name`. Lines are passed on
+/// through the stream channel `line_tx`, so a slow callback stops the reader and the child
+/// blocks on a full pipe instead of lines piling up.
+pub async fn stream(
+    id: u64,
+    cmd: String,
+    fn_name: String,
+    mut cancel: oneshot::Receiver<()>,
+    streams: StreamsHandle,
+    line_tx: mpsc::Sender<Request>,
+) {
+    let child = utils::get_args(&cmd).and_then(|(program, args)| {
+        Ok(Command::new(&program)
+            .args(args)
+            .stdout(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()?)
+    });
+    let mut child = match child {
+        Ok(child) => child,
+        Err(e) => {
+            error!("Failed to spawn {}: {}", cmd, e);
+            streams.lock().unwrap().cancel(id);
+            return;
+        }
+    };
+    let mut lines = BufReader::new(child.stdout.take().unwrap()).lines();
+
+    loop {
+        tokio::select! {
+            _ = &mut cancel => {
+                info!("Stream {} ({}) cancelled", id, cmd);
+                let _ = child.kill().await;
+                return;
+            }
+            line = lines.next_line() => match line {
+                Ok(Some(line)) => {
+                    let request = Request::StreamLine(fn_name.clone(), id, line);
+                    if line_tx.send(request).await.is_err() {
+                        break;
+                    }
+                }
+                Ok(None) => break,
+                Err(e) => {
+                    error!("Error reading output of {}: {}", cmd, e);
+                    break;
+                }
+            },
+        }
+    }
+
+    match child.wait().await {
+        Ok(status) => debug!("Stream {} ({}) ended with {}", id, cmd, status),
+        Err(e) => error!("Stream {} ({}) failed: {}", id, cmd, e),
+    }
+    streams.lock().unwrap().cancel(id);
+}
+
+/// Runs a command started with `Helpers:spawn` to the end, killing it after `timeout`.
+async fn spawn_waited(
+    program: &str,
+    args: &[String],
+    capture: bool,
+    timeout: Option<Duration>,
+) -> anyhow::Result<Output> {
+    let (stdout, stderr) = match capture {
+        true => (Stdio::piped(), Stdio::piped()),
+        false => (Stdio::inherit(), Stdio::inherit()),
+    };
+    let child = Command::new(program)
+        .args(args)
+        .stdout(stdout)
+        .stderr(stderr)
+        .kill_on_drop(true)
+        .spawn()
+        .with_context(|| format!("Failed to spawn {}", program))?;
+    let output = child.wait_with_output();
+    match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, output)
+            .await
+            .map_err(|_| anyhow!("{} timed out after {:?}", program, timeout))?,
+        None => output.await,
+    }
+    .with_context(|| format!("{} failed to run", program))
+}
+
+/// Starts `program` for `Helpers:spawn` and `Helpers:spawn_shell`. Without `wait` it returns
+/// the pid and the exit status is only logged. With `wait` the daemon blocks until the command
+/// exits and a table with its status, and its output with `capture`, is returned.
+pub fn spawn<'lua>(
+    lua: &'lua Lua,
+    program: String,
+    args: Vec<String>,
+    options: Option<mlua::Table>,
+) -> mlua::Result<mlua::Value<'lua>> {
+    let (wait, capture, timeout) = match &options {
+        Some(options) => (
+            options.get::<_, Option<bool>>("wait")?,
+            options.get::<_, Option<bool>>("capture")?,
+            options.get::<_, Option<f64>>("timeout")?,
+        ),
+        None => (None, None, None),
+    };
+    debug!("Spawning {} {:?}", program, args);
+    let command = std::iter::once(&program)
+        .chain(&args)
+        .map(String::as_str)
+        .collect::<Vec<_>>()
+        .join(" ");
+    let id = cmdlog::started(&command, "Helpers:spawn");
+    if !wait.unwrap_or(false) {
+        let mut child = Command::new(&program).args(&args).spawn().map_err(|e| {
+            cmdlog::finished(id, &command, &e.to_string());
+            mlua::Error::RuntimeError(format!("Failed to spawn {}: {}", program, e))
+        })?;
+        let pid = child.id();
+        tokio::spawn(async move {
+            let outcome = match child.wait().await {
+                Ok(status) if status.success() => {
+                    debug!("{} exited", program);
+                    status.to_string()
+                }
+                Ok(status) => {
+                    warn!("{} exited with {}", program, status);
+                    status.to_string()
+                }
+                Err(e) => {
+                    error!("{} failed: {}", program, e);
+                    e.to_string()
+                }
+            };
+            cmdlog::finished(id, &command, &outcome);
+        });
+        return pid.into_lua(lua);
+    }
+
+    // Lua runs inside the tokio runtime, so the command is awaited on a runtime of its own
+    let capture = capture.unwrap_or(false);
+    let timeout = timeout.map(Duration::from_secs_f64);
+    let output = std::thread::spawn(move || {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?
+            .block_on(spawn_waited(&program, &args, capture, timeout))
+    })
+    .join()
+    .map_err(|_| mlua::Error::RuntimeError("spawned command panicked".to_string()))?;
+    let outcome = match &output {
+        Ok(output) => output.status.to_string(),
+        Err(e) => format!("{:#}", e),
+    };
+    cmdlog::finished(id, &command, &outcome);
+    let output = output.map_err(|e| mlua::Error::RuntimeError(format!("{:#}", e)))?;
+
+    let result = lua.create_table()?;
+    result.set("success", output.status.success())?;
+    result.set("code", output.status.code())?;
+    result.set("signal", output.status.signal())?;
+    if capture {
+        result.set("stdout", String::from_utf8_lossy(&output.stdout))?;
+        result.set("stderr", String::from_utf8_lossy(&output.stderr))?;
+    }
+    Ok(mlua::Value::Table(result))
+}
+
+#[derive(Clone, Debug)]
+pub struct ExecHelpers {
+    pub streams: StreamsHandle,
+    pub allow_exec: bool,
+    pub tx: mpsc::Sender<Request>,
+}
+
+impl UserData for ExecHelpers {
+    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_method(
+            "run_stream",
+            |_lua, this, (cmd, fn_name): (String, String)| {
+                if !this.allow_exec {
+                    return Err(mlua::Error::RuntimeError(
+                        "running commands is disabled by the sandbox policy".to_string(),
+                    ));
+                }
+                if cmd.trim().is_empty() {
+                    return Err(mlua::Error::RuntimeError("empty command".to_string()));
+                }
+                let (cancel_tx, cancel) = oneshot::channel();
+                let id = {
+                    let mut streams = this.streams.lock().unwrap();
+                    streams.next_id += 1;
+                    let id = streams.next_id;
+                    streams.running.insert(id, (fn_name.clone(), cancel_tx));
+                    id
+                };
+                debug!("Starting stream {}: {} -> {}", id, cmd, fn_name);
+                utils::send_request(
+                    &this.tx,
+                    Request::RunStream {
+                        id,
+                        cmd,
+                        fn_name,
+                        cancel,
+                    },
+                );
+                Ok(id)
+            },
+        );
+        methods.add_method("cancel", |_lua, this, id: u64| {
+            Ok(this.streams.lock().unwrap().cancel(id))
+        });
+    }
+}
@@ -0,0 +1,280 @@
+use log::{error, info, warn};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use super::cmdlog;
+use super::dnd::DndHandle;
+use super::journal;
+use super::notify;
+use super::settings::FailurePolicy;
+use super::telemetry;
+use super::utils;
+
+#[derive(Debug, Default)]
+struct Streak {
+    failures: u32,
+    /// How often the command reached the threshold without succeeding in between
+    trips: u32,
+    cooldown_until: Option<Instant>,
+}
+
+/// Tracks consecutive failures per command line and suppresses commands that keep failing.
+/// Also coalesces duplicate requests of the same command.
+#[derive(Debug)]
+pub struct FailureTracker {
+    policy: FailurePolicy,
+    streaks: HashMap<String, Streak>,
+    /// Last launch per command line
+    launches: HashMap<String, Instant>,
+}
+
+pub type FailureTrackerHandle = Arc<Mutex<FailureTracker>>;
+
+impl FailureTracker {
+    pub fn new(policy: FailurePolicy) -> FailureTrackerHandle {
+        Arc::new(Mutex::new(Self {
+            policy,
+            streaks: HashMap::new(),
+            launches: HashMap::new(),
+        }))
+    }
+
+    /// Returns how long ago the command was launched if that was within the coalescing
+    /// window, e.g. when both the idle timeout and PrepareForSleep ask for the locker.
+    /// Otherwise the launch is recorded.
+    pub fn coalesce(&mut self, cmd: &str) -> Option<Duration> {
+        let window = Duration::from_millis(self.policy.coalesce_ms);
+        let now = Instant::now();
+        self.launches.retain(|_, launched| now - *launched < window);
+        if let Some(launched) = self.launches.get(cmd) {
+            return Some(now - *launched);
+        }
+        if !window.is_zero() {
+            self.launches.insert(cmd.to_string(), now);
+        }
+        None
+    }
+
+    /// Returns the remaining suppression period if the command is cooling down.
+    pub fn cooldown(&self, cmd: &str) -> Option<Duration> {
+        let until = self.streaks.get(cmd)?.cooldown_until?;
+        until.checked_duration_since(Instant::now())
+    }
+
+    pub fn record_success(&mut self, cmd: &str) {
+        self.streaks.remove(cmd);
+    }
+
+    /// Records a failure. Returns the streak length and the suppression period if the
+    /// command just reached the threshold.
+    pub fn record_failure(&mut self, cmd: &str) -> Option<(u32, Duration)> {
+        let streak = self.streaks.entry(cmd.to_string()).or_default();
+        streak.failures += 1;
+        if streak.failures < self.policy.threshold.max(1) {
+            return None;
+        }
+
+        let cooldown = self
+            .policy
+            .cooldown_secs
+            .saturating_mul(1 << streak.trips.min(16))
+            .min(self.policy.max_cooldown_secs);
+        let cooldown = Duration::from_secs(cooldown);
+        let failures = streak.failures;
+        streak.failures = 0;
+        streak.trips += 1;
+        streak.cooldown_until = Some(Instant::now() + cooldown);
+        Some((failures, cooldown))
+    }
+}
+
+/// Runs a command, keeping track of its failure streak. Commands that are cooling down after
+/// repeated failures are skipped. Returns the error if the command failed.
+pub async fn run_tracked(
+    cmd: String,
+    env: Vec<(&'static str, String)>,
+    once: bool,
+    failures: &FailureTrackerHandle,
+    dnd: &DndHandle,
+) -> Option<String> {
+    let span = telemetry::span("command");
+    span.set_attribute("command", &cmd);
+    if let Some(remaining) = failures.lock().unwrap().cooldown(&cmd) {
+        span.set_attribute("suppressed", true);
+        info!(
+            "Skipping {}, suppressed after repeated failures for another {}s",
+            cmd,
+            remaining.as_secs()
+        );
+        return None;
+    }
+    if let Some(ago) = failures.lock().unwrap().coalesce(&cmd) {
+        span.set_attribute("coalesced", true);
+        let ago_ms = ago.as_millis().to_string();
+        info!(
+            "Skipping {}, the same command was launched {}ms ago",
+            cmd, ago_ms
+        );
+        journal::event(
+            "coalesced",
+            &format!("{} coalesced with the launch {}ms ago", cmd, ago_ms),
+            &[("COMMAND", &cmd), ("AGO_MS", &ago_ms)],
+        );
+        return None;
+    }
+
+    // The event and the callback that handled it
+    let why: Vec<&str> = env
+        .iter()
+        .filter(|(key, _)| matches!(*key, "SLEEPWATCHER_EVENT" | "SLEEPWATCHER_STAGE"))
+        .map(|(_, value)| value.as_str())
+        .filter(|value| !value.is_empty())
+        .collect();
+    let id = cmdlog::started(&cmd, &why.join(" "));
+    let result = if once {
+        utils::run_once(cmd.clone(), env).await
+    } else {
+        utils::run(cmd.clone(), env).await.map(Some)
+    };
+    let outcome = match &result {
+        Ok(Some(finished)) => finished.status.to_string(),
+        Ok(None) => "already running".to_string(),
+        Err(e) => e.to_string(),
+    };
+    cmdlog::finished(id, &cmd, &outcome);
+    if let Ok(Some(finished)) = &result {
+        let status = finished.status;
+        let code = status
+            .code()
+            .map_or_else(|| "none".to_string(), |code| code.to_string());
+        span.set_attribute("exit_code", &code);
+        journal::event(
+            "command",
+            &format!("{} exited with {}", cmd, status),
+            &[("COMMAND", &cmd), ("EXIT_CODE", &code)],
+        );
+    }
+    let (error, stderr_tail) = match result {
+        Ok(None) => return None,
+        Ok(Some(finished)) if finished.status.success() => {
+            failures.lock().unwrap().record_success(&cmd);
+            return None;
+        }
+        Ok(Some(finished)) => (
+            format!("exited with {}", finished.status),
+            finished.stderr_tail.join("\n"),
+        ),
+        Err(e) => (e.to_string(), String::new()),
+    };
+
+    warn!("Command {} failed: {}", cmd, error);
+    if !stderr_tail.is_empty() {
+        warn!("stderr of {}:\n{}", cmd, stderr_tail);
+    }
+    span.error(&error);
+    let (tripped, policy) = {
+        let mut failures = failures.lock().unwrap();
+        (failures.record_failure(&cmd), failures.policy.clone())
+    };
+    let notification = match tripped {
+        Some((count, cooldown)) => {
+            error!(
+                "{} failed {} times in a row, suppressing it for {}s",
+                cmd,
+                count,
+                cooldown.as_secs()
+            );
+            policy.notify.then(|| {
+                (
+                    "Command keeps failing".to_string(),
+                    format!(
+                        "{} failed {} times in a row ({}). Retries are suppressed for {}.",
+                        cmd,
+                        count,
+                        error,
+                        humantime::format_duration(cooldown)
+                    ),
+                )
+            })
+        }
+        None => policy.notify_each.then(|| {
+            let program = cmd.split_whitespace().next().unwrap_or_default();
+            (format!("{} failed", program), format!("{} {}", cmd, error))
+        }),
+    };
+    if let Some((summary, mut body)) = notification {
+        if !stderr_tail.is_empty() {
+            body = format!("{}\n\n{}", body, stderr_tail);
+        }
+        if let Err(e) = notify::send(dnd, &summary, &body).await {
+            error!("Failed to send notification: {}", e);
+        }
+    }
+    Some(error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tracker(policy: FailurePolicy) -> FailureTracker {
+        FailureTracker {
+            policy,
+            streaks: HashMap::new(),
+            launches: HashMap::new(),
+        }
+    }
+
+    #[test]
+    fn trips_at_threshold() {
+        let mut tracker = tracker(FailurePolicy::default());
+        assert_eq!(tracker.record_failure("lock"), None);
+        assert_eq!(tracker.record_failure("lock"), None);
+        assert_eq!(
+            tracker.record_failure("lock"),
+            Some((3, Duration::from_secs(60)))
+        );
+        assert!(tracker.cooldown("lock").is_some());
+        assert!(tracker.cooldown("other").is_none());
+    }
+
+    #[test]
+    fn doubles_cooldown_up_to_max() {
+        let mut tracker = tracker(FailurePolicy {
+            threshold: 1,
+            cooldown_secs: 60,
+            max_cooldown_secs: 200,
+            ..FailurePolicy::default()
+        });
+        let cooldowns: Vec<u64> = (0..4)
+            .map(|_| tracker.record_failure("lock").unwrap().1.as_secs())
+            .collect();
+        assert_eq!(cooldowns, [60, 120, 200, 200]);
+    }
+
+    #[test]
+    fn success_resets_streak() {
+        let mut tracker = tracker(FailurePolicy::default());
+        tracker.record_failure("lock");
+        tracker.record_failure("lock");
+        tracker.record_success("lock");
+        assert_eq!(tracker.record_failure("lock"), None);
+        assert!(tracker.cooldown("lock").is_none());
+    }
+
+    #[test]
+    fn coalesces_within_window() {
+        let mut coalescing = tracker(FailurePolicy::default());
+        assert_eq!(coalescing.coalesce("lock"), None);
+        assert!(coalescing.coalesce("lock").is_some());
+        assert_eq!(coalescing.coalesce("dim"), None);
+
+        let mut launching = tracker(FailurePolicy {
+            coalesce_ms: 0,
+            ..FailurePolicy::default()
+        });
+        assert_eq!(launching.coalesce("lock"), None);
+        assert_eq!(launching.coalesce("lock"), None);
+    }
+}
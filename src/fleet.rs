@@ -0,0 +1,249 @@
+//! Fleet mode: the Lua config is fetched from a URL at startup, for admins managing the idle
+//! and lock policy of many kiosks or workstations. The bundle is a manifest with the config,
+//! a serial and an optional expiry, and has to carry an Ed25519 signature, which is checked
+//! with the `fleet` cargo feature. A verified bundle is cached, so a machine that is offline at
+//! boot keeps the last policy. Without one the local config runs. Bundles with a lower serial
+//! than the cached one are rejected, so an old signed bundle can't be played back.
+
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::process::Command;
+use xdg::BaseDirectories;
+
+use super::config;
+use super::settings::FleetSettings;
+
+#[cfg(feature = "fleet")]
+mod imp {
+    use base64::Engine;
+    use ring::signature::{UnparsedPublicKey, ED25519};
+
+    /// Checks the signature of the bundle, given raw or base64 encoded.
+    pub fn verify(bundle: &[u8], signature: &[u8], public_key: &str) -> anyhow::Result<()> {
+        let engine = base64::engine::general_purpose::STANDARD;
+        let key = engine.decode(public_key.trim())?;
+        let signature = match signature.len() {
+            64 => signature.to_vec(),
+            _ => engine.decode(String::from_utf8_lossy(signature).trim())?,
+        };
+        UnparsedPublicKey::new(&ED25519, key)
+            .verify(bundle, &signature)
+            .map_err(|_| anyhow::anyhow!("the signature doesn't match the public key"))
+    }
+}
+
+#[cfg(not(feature = "fleet"))]
+mod imp {
+    pub fn verify(_bundle: &[u8], _signature: &[u8], _public_key: &str) -> anyhow::Result<()> {
+        anyhow::bail!("sleepwatcher-rs was built without the fleet feature")
+    }
+}
+
+/// What the signature covers.
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct Manifest {
+    /// Raised with every new bundle
+    serial: u64,
+    /// RFC 3339, the bundle isn't used anymore after it
+    expires: Option<String>,
+    /// The Lua config
+    config: String,
+}
+
+impl Manifest {
+    /// Parses the signed bundle, which has to be verified already.
+    fn parse(bundle: &[u8]) -> anyhow::Result<Self> {
+        let manifest: Manifest =
+            serde_json::from_slice(bundle).context("the bundle isn't a valid manifest")?;
+        if let Some(expires) = &manifest.expires {
+            let expires = DateTime::parse_from_rfc3339(expires)
+                .with_context(|| format!("invalid expiry {}", expires))?;
+            if expires < Utc::now() {
+                anyhow::bail!("bundle {} expired at {}", manifest.serial, expires);
+            }
+        }
+        Ok(manifest)
+    }
+
+    /// Whether the bundle may replace `cached`, a lower serial would roll the policy back.
+    fn may_replace(&self, cached: &Manifest) -> bool {
+        self.serial >= cached.serial
+    }
+}
+
+/// The bundle and its signature, cached in one file so they are replaced together.
+#[derive(Serialize, Deserialize)]
+struct Cached {
+    bundle: String,
+    signature: Vec<u8>,
+}
+
+fn cache_path() -> std::io::Result<PathBuf> {
+    BaseDirectories::with_prefix(config::APP_NAME)?.place_cache_file(config::FLEET_CACHE_FILE_NAME)
+}
+
+/// The verified manifest of the cache.
+fn read_cache(public_key: &str) -> anyhow::Result<Manifest> {
+    let cached: Cached = serde_json::from_slice(&fs::read(cache_path()?)?)?;
+    imp::verify(cached.bundle.as_bytes(), &cached.signature, public_key)?;
+    Manifest::parse(cached.bundle.as_bytes())
+}
+
+/// Replaces the file atomically, so the cache is never read half written.
+fn write_atomic(path: &Path, content: &[u8]) -> std::io::Result<()> {
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, content)?;
+    fs::rename(&tmp, path)
+}
+
+async fn download(url: &str, timeout: Duration) -> anyhow::Result<Vec<u8>> {
+    let output = Command::new("curl")
+        .args(["--fail", "--silent", "--show-error", "--location"])
+        .arg("--max-time")
+        .arg(timeout.as_secs().to_string())
+        .arg(url)
+        .output()
+        .await
+        .context("Failed to run curl")?;
+    if !output.status.success() {
+        anyhow::bail!(
+            "{}: {}",
+            url,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(output.stdout)
+}
+
+/// Fetches and verifies the bundle and replaces the cached one. On failure the cache is
+/// left alone.
+pub async fn fetch(settings: &FleetSettings) -> anyhow::Result<()> {
+    let Some(url) = &settings.url else {
+        return Ok(());
+    };
+    let public_key = settings
+        .public_key
+        .as_deref()
+        .context("fleet mode needs public_key")?;
+    let signature_url = match &settings.signature_url {
+        Some(signature_url) => signature_url.clone(),
+        None => format!("{}.sig", url),
+    };
+    let timeout = Duration::from_secs(settings.timeout_secs);
+    let bundle = download(url, timeout).await?;
+    let signature = download(&signature_url, timeout).await?;
+    imp::verify(&bundle, &signature, public_key)?;
+    let manifest = Manifest::parse(&bundle)?;
+    if let Ok(cached) = read_cache(public_key) {
+        if !manifest.may_replace(&cached) {
+            anyhow::bail!(
+                "{} serves bundle {}, older than the cached bundle {}",
+                url,
+                manifest.serial,
+                cached.serial
+            );
+        }
+    }
+    let cached = Cached {
+        bundle: String::from_utf8(bundle)?,
+        signature,
+    };
+    write_atomic(&cache_path()?, &serde_json::to_vec(&cached)?)?;
+    info!(
+        "Fleet config {} from {} verified and cached",
+        manifest.serial, url
+    );
+    Ok(())
+}
+
+/// The cached bundle if fleet mode is on, verified again in case the cache was changed.
+pub fn cached_config(settings: &FleetSettings) -> Option<String> {
+    settings.url.as_ref()?;
+    let public_key = settings.public_key.as_deref()?;
+    match read_cache(public_key) {
+        Ok(manifest) => Some(manifest.config),
+        Err(e) => {
+            warn!("No usable fleet config, running the local config: {}", e);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bundle(serial: u64, expires: Option<&str>) -> Vec<u8> {
+        let manifest = serde_json::json!({
+            "serial": serial,
+            "expires": expires,
+            "config": "IdleNotifier:get_notification(300, \"lock\")",
+        });
+        serde_json::to_vec(&manifest).unwrap()
+    }
+
+    #[test]
+    fn parses_current_bundles() {
+        let manifest = Manifest::parse(&bundle(7, None)).unwrap();
+        assert_eq!(manifest.serial, 7);
+        assert!(manifest.config.contains("get_notification"));
+        assert!(Manifest::parse(&bundle(7, Some("2999-01-01T00:00:00Z"))).is_ok());
+    }
+
+    #[test]
+    fn rejects_expired_and_malformed_bundles() {
+        assert!(Manifest::parse(&bundle(7, Some("2020-01-01T00:00:00Z"))).is_err());
+        assert!(Manifest::parse(&bundle(7, Some("next week"))).is_err());
+        assert!(Manifest::parse(br#"{"serial": 7, "config": "", "owner": "me"}"#).is_err());
+        assert!(Manifest::parse(br#"{"config": ""}"#).is_err());
+        assert!(Manifest::parse(b"IdleNotifier:get_notification(300, 'lock')").is_err());
+    }
+
+    #[test]
+    fn refuses_to_roll_back() {
+        let cached = Manifest::parse(&bundle(7, None)).unwrap();
+        assert!(Manifest::parse(&bundle(8, None))
+            .unwrap()
+            .may_replace(&cached));
+        assert!(Manifest::parse(&bundle(7, None))
+            .unwrap()
+            .may_replace(&cached));
+        assert!(!Manifest::parse(&bundle(6, None))
+            .unwrap()
+            .may_replace(&cached));
+    }
+
+    #[cfg(feature = "fleet")]
+    #[test]
+    fn verifies_signatures() {
+        use base64::Engine;
+        use ring::rand::SystemRandom;
+        use ring::signature::{Ed25519KeyPair, KeyPair};
+
+        let engine = base64::engine::general_purpose::STANDARD;
+        let keys = |seed| {
+            let pkcs8 = Ed25519KeyPair::generate_pkcs8(seed).unwrap();
+            Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap()
+        };
+        let random = SystemRandom::new();
+        let (key, other) = (keys(&random), keys(&random));
+        let public_key = engine.encode(key.public_key().as_ref());
+        let bundle = bundle(7, None);
+        let signature = key.sign(&bundle);
+        assert!(imp::verify(&bundle, signature.as_ref(), &public_key).is_ok());
+        let encoded = engine.encode(signature.as_ref());
+        assert!(imp::verify(&bundle, encoded.as_bytes(), &public_key).is_ok());
+        let mut tampered = bundle.clone();
+        let middle = tampered.len() / 2;
+        tampered[middle] ^= 1;
+        assert!(imp::verify(&tampered, signature.as_ref(), &public_key).is_err());
+        let forged = other.sign(&bundle);
+        assert!(imp::verify(&bundle, forged.as_ref(), &public_key).is_err());
+    }
+}
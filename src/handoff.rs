@@ -0,0 +1,109 @@
+//! `--replace`: a new instance takes over from the running one, e.g. after an upgrade. It asks
+//! the old instance for its runtime state over the control socket, waits until it exited and
+//! then restores the state: manual overrides, the profile, the ScreenSaver cookies of
+//! applications, presentation mode and the lock with its escalation stages. The night light
+//! override and excluded outputs are in the state file of the night light already.
+
+use anyhow::Context;
+use chrono::{DateTime, Local, TimeZone};
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::UnixStream;
+use tokio::time::Instant;
+
+use super::inhibitors::Cookie;
+use super::ipc::{self, CtlCommand, CtlRequest};
+use super::presentation::Handoff as PresentationHandoff;
+use super::settings::Settings;
+
+/// Reason passed to the `on_exit` hooks of the replaced instance
+pub const REASON: &str = "replaced";
+const EXIT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// Waited for the old instance beyond the timeout of its exit hooks
+const EXIT_GRACE: Duration = Duration::from_secs(5);
+
+/// Runtime state of the replaced instance.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct State {
+    pub pid: u32,
+    pub profile: String,
+    /// Control commands that recreate the manual overrides, with the remaining durations
+    pub commands: Vec<CtlCommand>,
+    pub cookies: BTreeMap<u32, Cookie>,
+    pub presentation: Option<PresentationHandoff>,
+    /// Since when the session is locked and since when the user is away, as Unix timestamps
+    pub locked: Option<(i64, i64)>,
+}
+
+impl State {
+    pub fn lock_times(&self) -> Option<(DateTime<Local>, DateTime<Local>)> {
+        let (locked_at, away_since) = self.locked?;
+        Local
+            .timestamp_opt(locked_at, 0)
+            .single()
+            .zip(Local.timestamp_opt(away_since, 0).single())
+    }
+}
+
+/// Time left until `until`, `None` once it passed.
+pub fn remaining(until: DateTime<Local>, now: DateTime<Local>) -> Option<Duration> {
+    (until - now)
+        .to_std()
+        .ok()
+        .filter(|remaining| !remaining.is_zero())
+}
+
+/// A zombie already released the socket and the bus names.
+fn running(pid: u32) -> bool {
+    fs::read_to_string(format!("/proc/{}/stat", pid)).is_ok_and(|stat| {
+        stat.rsplit_once(") ")
+            .is_some_and(|(_, fields)| !fields.starts_with('Z'))
+    })
+}
+
+/// Takes the state of the running instance and waits until it exited. `None` if no instance
+/// is running.
+pub async fn take_over(settings: &Settings) -> anyhow::Result<Option<State>> {
+    let path = ipc::socket_path(&settings.ipc)?;
+    let Ok(stream) = UnixStream::connect(&path).await else {
+        info!("No running instance to replace at {:?}", path);
+        return Ok(None);
+    };
+    let (reader, mut writer) = stream.into_split();
+    let request = CtlRequest {
+        cmd: CtlCommand::Handoff,
+        token: None,
+    };
+    writer
+        .write_all(format!("{}\n", serde_json::to_string(&request)?).as_bytes())
+        .await?;
+    let reply = BufReader::new(reader)
+        .lines()
+        .next_line()
+        .await?
+        .context("the running instance closed the connection")?;
+    let mut reply: serde_json::Value = serde_json::from_str(&reply)?;
+    if reply["ok"] != true {
+        anyhow::bail!(
+            "the running instance refused: {}",
+            reply["error"].as_str().unwrap_or("unknown error")
+        );
+    }
+    let state: State = serde_json::from_value(reply["state"].take())?;
+
+    info!("Waiting for the instance with pid {} to exit", state.pid);
+    let deadline =
+        Instant::now() + Duration::from_secs(settings.hooks.exit_timeout_secs) + EXIT_GRACE;
+    while running(state.pid) {
+        if Instant::now() > deadline {
+            anyhow::bail!("the instance with pid {} didn't exit", state.pid);
+        }
+        tokio::time::sleep(EXIT_POLL_INTERVAL).await;
+    }
+    info!("Took over from the instance with pid {}", state.pid);
+    Ok(Some(state))
+}
@@ -0,0 +1,27 @@
+use chrono::Local;
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+use super::types::Request;
+
+/// Asks the request loop for a heartbeat at a fixed interval. The heartbeat is recorded by
+/// the loop itself, so it stops when the loop is wedged, not only when the process died.
+pub async fn heartbeat_run(interval: Duration, tx: mpsc::Sender<Request>) {
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        if tx.send(Request::Heartbeat).await.is_err() {
+            break;
+        }
+    }
+}
+
+/// Writes the current unix timestamp to the heartbeat file. The file is replaced atomically
+/// so watchdogs never read a partial write.
+pub fn write(path: &Path) -> std::io::Result<()> {
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, format!("{}\n", Local::now().timestamp()))?;
+    fs::rename(&tmp, path)
+}
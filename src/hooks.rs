@@ -0,0 +1,184 @@
+use log::{debug, error, info};
+use mlua::{Lua, UserData, UserDataMethods};
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use super::callbacks;
+use super::daemon::Away;
+use super::scripts;
+
+/// Lua functions registered for daemon lifecycle events.
+#[derive(Debug, Default)]
+pub struct Hooks {
+    on_error: Option<String>,
+    on_exit: Vec<String>,
+    on_start: Vec<String>,
+    on_after_wake: Vec<String>,
+    on_return: Vec<String>,
+}
+
+/// What was found on startup, passed to the `on_start` hooks.
+#[derive(Debug, Default)]
+pub struct StartContext {
+    /// Wayland globals announced by the compositor with their versions
+    pub protocols: BTreeMap<String, u32>,
+    pub seats: Vec<String>,
+    pub outputs: Vec<String>,
+    /// D-Bus services and whether they could be reached
+    pub services: BTreeMap<String, bool>,
+}
+
+pub type HooksHandle = Arc<Mutex<Hooks>>;
+
+impl Hooks {
+    pub fn new() -> HooksHandle {
+        Arc::new(Mutex::new(Self::default()))
+    }
+
+    pub fn clear(&mut self) {
+        *self = Self::default();
+    }
+
+    /// Drops the callbacks of the script `script`, before it's loaded again.
+    pub fn forget_script(&mut self, script: &str) {
+        if self
+            .on_error
+            .as_ref()
+            .is_some_and(|fn_name| scripts::owns(script, fn_name))
+        {
+            self.on_error = None;
+        }
+        for hooks in [
+            &mut self.on_exit,
+            &mut self.on_start,
+            &mut self.on_after_wake,
+            &mut self.on_return,
+        ] {
+            hooks.retain(|fn_name| !scripts::owns(script, fn_name));
+        }
+    }
+}
+
+/// Passes an internal failure to the `on_error` hook as `(err, context)`. Errors raised by the
+/// hook itself are only logged.
+pub fn report_error(lua: &Lua, hooks: &HooksHandle, err: &str, context: &[(&str, &str)]) {
+    let Some(fn_name) = hooks.lock().unwrap().on_error.clone() else {
+        return;
+    };
+    let result = (|| {
+        let table = lua.create_table()?;
+        for (key, value) in context {
+            table.set(*key, *value)?;
+        }
+        let limit = callbacks::default_timeout(lua);
+        callbacks::call_timed::<_, ()>(lua, &fn_name, (err, table), limit)
+    })();
+    match result {
+        Ok(()) => debug!("Reported error to {}: {}", fn_name, err),
+        Err(e) => error!("Error hook {} failed: {}", fn_name, e),
+    }
+}
+
+/// Calls the `on_start` hooks with a table describing the detected protocols, seats, outputs
+/// and services.
+pub fn run_start_hooks(lua: &Lua, hooks: &HooksHandle, ctx: &StartContext) {
+    let handlers = hooks.lock().unwrap().on_start.clone();
+    if handlers.is_empty() {
+        return;
+    }
+    let table = (|| {
+        let table = lua.create_table()?;
+        table.set("protocols", lua.create_table_from(ctx.protocols.clone())?)?;
+        table.set("seats", lua.create_sequence_from(ctx.seats.clone())?)?;
+        table.set("outputs", lua.create_sequence_from(ctx.outputs.clone())?)?;
+        table.set("services", lua.create_table_from(ctx.services.clone())?)?;
+        Ok::<_, mlua::Error>(table)
+    })();
+    let table = match table {
+        Ok(table) => table,
+        Err(e) => {
+            error!("Failed to build the on_start context: {}", e);
+            return;
+        }
+    };
+    for fn_name in handlers {
+        debug!("Running start hook {}", fn_name);
+        callbacks::call::<_, ()>(lua, hooks, &fn_name, table.clone());
+    }
+}
+
+/// Calls the `on_exit` hooks with the shutdown reason. Lua code still running at the deadline
+/// is aborted so a stuck hook cannot keep the daemon from exiting.
+pub fn run_exit_hooks(lua: &Lua, hooks: &HooksHandle, reason: &str, deadline: Instant) {
+    let handlers = hooks.lock().unwrap().on_exit.clone();
+    for fn_name in handlers {
+        info!("Running exit hook {}", fn_name);
+        let limit = deadline.saturating_duration_since(Instant::now());
+        if let Err(e) = callbacks::call_timed::<_, ()>(lua, &fn_name, reason, limit) {
+            error!("Exit hook {} failed: {}", fn_name, e);
+        }
+    }
+}
+
+/// Calls the `on_after_wake` hooks with the seconds spent suspended and where the wakeup was
+/// detected, `"logind"` or `"clock"`.
+pub fn run_wake_hooks(lua: &Lua, hooks: &HooksHandle, slept: Duration, source: &str) {
+    let handlers = hooks.lock().unwrap().on_after_wake.clone();
+    for fn_name in handlers {
+        debug!("Running wake hook {}", fn_name);
+        callbacks::call::<_, ()>(lua, hooks, &fn_name, (slept.as_secs(), source));
+    }
+}
+
+/// Calls the `on_return` hooks with a table of `away_secs`, `locked_secs` and `since`, the
+/// unix time the absence started.
+pub fn run_return_hooks(lua: &Lua, hooks: &HooksHandle, away: &Away) {
+    let handlers = hooks.lock().unwrap().on_return.clone();
+    for fn_name in handlers {
+        debug!("Running return hook {}", fn_name);
+        let table = (|| {
+            let table = lua.create_table()?;
+            table.set("away_secs", away.away.as_secs())?;
+            table.set("locked_secs", away.locked.as_secs())?;
+            table.set("since", away.since.timestamp())?;
+            Ok::<_, mlua::Error>(table)
+        })();
+        match table {
+            Ok(table) => {
+                callbacks::call::<_, ()>(lua, hooks, &fn_name, table);
+            }
+            Err(e) => error!("Failed to build the on_return context: {}", e),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct HookHelpers {
+    pub hooks: HooksHandle,
+}
+
+impl UserData for HookHelpers {
+    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_method("on_error", |_lua, this, fn_name: String| {
+            this.hooks.lock().unwrap().on_error = Some(fn_name);
+            Ok(())
+        });
+        methods.add_method("on_start", |_lua, this, fn_name: String| {
+            this.hooks.lock().unwrap().on_start.push(fn_name);
+            Ok(())
+        });
+        methods.add_method("on_exit", |_lua, this, fn_name: String| {
+            this.hooks.lock().unwrap().on_exit.push(fn_name);
+            Ok(())
+        });
+        methods.add_method("on_after_wake", |_lua, this, fn_name: String| {
+            this.hooks.lock().unwrap().on_after_wake.push(fn_name);
+            Ok(())
+        });
+        methods.add_method("on_return", |_lua, this, fn_name: String| {
+            this.hooks.lock().unwrap().on_return.push(fn_name);
+            Ok(())
+        });
+    }
+}
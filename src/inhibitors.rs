@@ -0,0 +1,557 @@
+//! Everything that currently keeps the machine awake, in one list. Lua configs can add their
+//! own inhibitors, which hold back idle callbacks like presentation mode does, and register
+//! providers: functions that are polled and decide whether to inhibit.
+//!
+//! Applications like Firefox, mpv and Steam inhibit through `org.freedesktop.ScreenSaver`,
+//! which is served here unless the desktop already does. Their cookies hold back idle
+//! callbacks as well. Idle inhibitors of other Wayland clients and portal sessions are handled
+//! by the compositor and desktop services and can't be listed from here.
+
+use chrono::{DateTime, Local};
+use futures::stream::StreamExt;
+use log::{debug, error, info, warn};
+use mlua::{UserData, UserDataMethods};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+use zbus::{dbus_interface, MessageHeader};
+
+use super::dbus::LogindManagerInterfaceProxy;
+use super::events::EventsHandle;
+use super::scripts;
+use super::types::Request;
+use super::utils;
+
+/// How often the logind inhibitors are refreshed for `Inhibitors:list()`
+const LOGIND_POLL_INTERVAL: Duration = Duration::from_secs(30);
+const DEFAULT_PROVIDER_INTERVAL: u64 = 30;
+/// Granularity of the provider intervals
+const PROVIDER_TICK: Duration = Duration::from_secs(5);
+/// How often the list is compared for the `inhibitors` event of the event stream. Timed
+/// inhibitors expire by themselves, so changes can't all be caught where they are made
+const STREAM_INTERVAL: Duration = Duration::from_secs(1);
+const SCREENSAVER_BUS_NAME: &str = "org.freedesktop.ScreenSaver";
+/// Applications use either path
+const SCREENSAVER_PATHS: [&str; 2] = ["/org/freedesktop/ScreenSaver", "/ScreenSaver"];
+
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct Inhibitor {
+    /// Where the inhibitor comes from: `logind`, `lua`, `provider` or `daemon`
+    pub source: String,
+    pub who: String,
+    /// What is inhibited, e.g. `idle`, `sleep` or `idle:sleep` for logind
+    pub what: String,
+    pub why: String,
+}
+
+impl Inhibitor {
+    pub fn new(source: &str, who: &str, what: &str, why: &str) -> Self {
+        Self {
+            source: source.to_string(),
+            who: who.to_string(),
+            what: what.to_string(),
+            why: why.to_string(),
+        }
+    }
+}
+
+/// A Lua function that is polled and returns a reason to inhibit, or `nil`/`false`.
+#[derive(Debug)]
+struct Provider {
+    fn_name: String,
+    /// `None` for providers that are only polled on `Inhibitors:poll(name)`
+    interval: Option<Duration>,
+    last_poll: Option<Instant>,
+    /// The reason of the last poll, if it inhibits
+    active: Option<String>,
+}
+
+/// An inhibitor of the Lua config that expires by itself.
+#[derive(Debug)]
+struct Timed {
+    why: String,
+    until: DateTime<Local>,
+    /// Tells a handle apart from a later inhibitor with the same name
+    id: u64,
+}
+
+#[derive(Debug, Default)]
+pub struct Inhibitors {
+    /// Inhibitors of the Lua config, name to reason
+    lua: BTreeMap<String, String>,
+    /// Inhibitors of `Inhibitors:for_duration`, expired ones are only dropped on the next one
+    timed: BTreeMap<String, Timed>,
+    next_timed: u64,
+    providers: BTreeMap<String, Provider>,
+    /// Last list from logind's ListInhibitors
+    logind: Vec<Inhibitor>,
+    /// Cookies handed out by `org.freedesktop.ScreenSaver.Inhibit`
+    screensaver: BTreeMap<u32, Cookie>,
+    next_cookie: u32,
+}
+
+/// An inhibitor of an application over `org.freedesktop.ScreenSaver`.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Cookie {
+    application: String,
+    reason: String,
+    /// Unique bus name of the caller, its cookies are dropped when it disconnects
+    sender: String,
+}
+
+pub type InhibitorsHandle = Arc<Mutex<Inhibitors>>;
+
+impl Inhibitors {
+    pub fn new() -> InhibitorsHandle {
+        Arc::new(Mutex::new(Self::default()))
+    }
+
+    pub fn clear(&mut self) {
+        self.lua.clear();
+        self.timed.clear();
+        self.providers.clear();
+    }
+
+    /// Drops the providers of the script `script`, before it's loaded again.
+    pub fn forget_script(&mut self, script: &str) {
+        self.providers
+            .retain(|_, provider| !scripts::owns(script, &provider.fn_name));
+    }
+
+    fn timed_active(&self) -> impl Iterator<Item = (&String, &Timed)> {
+        let now = Local::now();
+        self.timed
+            .iter()
+            .filter(move |(_, timed)| timed.until > now)
+    }
+
+    /// Whether the Lua config, one of its providers or an application holds back idle
+    /// callbacks.
+    pub fn inhibits_idle(&self) -> bool {
+        !self.lua.is_empty()
+            || self.timed_active().next().is_some()
+            || !self.screensaver.is_empty()
+            || self
+                .providers
+                .values()
+                .any(|provider| provider.active.is_some())
+    }
+
+    pub fn lua(&self) -> Vec<Inhibitor> {
+        let providers = self.providers.iter().filter_map(|(name, provider)| {
+            let why = provider.active.as_ref()?;
+            Some(Inhibitor::new("provider", name, "idle", why))
+        });
+        let timed = self.timed_active().map(|(name, timed)| {
+            let why = format!("{} (until {})", timed.why, timed.until.to_rfc3339());
+            Inhibitor::new("lua", name, "idle", why.trim_start())
+        });
+        self.lua
+            .iter()
+            .map(|(name, why)| Inhibitor::new("lua", name, "idle", why))
+            .chain(timed)
+            .chain(providers)
+            .collect()
+    }
+
+    /// The function of the provider, to be polled by `Request::InhibitorPoll`.
+    pub fn provider(&mut self, name: &str) -> Option<String> {
+        let provider = self.providers.get_mut(name)?;
+        provider.last_poll = Some(Instant::now());
+        Some(provider.fn_name.clone())
+    }
+
+    /// Records the result of a poll.
+    pub fn set_provided(&mut self, name: &str, active: Option<String>) {
+        let Some(provider) = self.providers.get_mut(name) else {
+            return;
+        };
+        match (&provider.active, &active) {
+            (None, Some(why)) => info!("Provider {} inhibits idle: {}", name, why),
+            (Some(_), None) => info!("Provider {} stopped inhibiting", name),
+            _ => {}
+        }
+        provider.active = active;
+    }
+
+    /// Names of the providers whose interval passed.
+    fn due(&self) -> Vec<String> {
+        self.providers
+            .iter()
+            .filter(|(_, provider)| {
+                provider.interval.is_some_and(|interval| {
+                    provider
+                        .last_poll
+                        .is_none_or(|last| last.elapsed() >= interval)
+                })
+            })
+            .map(|(name, _)| name.clone())
+            .collect()
+    }
+
+    /// The logind inhibitors as of the last refresh.
+    pub fn logind(&self) -> Vec<Inhibitor> {
+        self.logind.clone()
+    }
+
+    /// Inhibitors of applications over `org.freedesktop.ScreenSaver`.
+    pub fn screensaver(&self) -> Vec<Inhibitor> {
+        self.screensaver
+            .values()
+            .map(|cookie| {
+                Inhibitor::new("screensaver", &cookie.application, "idle", &cookie.reason)
+            })
+            .collect()
+    }
+
+    fn add_cookie(&mut self, application: String, reason: String, sender: String) -> u32 {
+        // Cookies are never 0, some applications take that for a failed call
+        self.next_cookie = self.next_cookie.wrapping_add(1).max(1);
+        let cookie = self.next_cookie;
+        info!("{} inhibits idle: {}", application, reason);
+        self.screensaver.insert(
+            cookie,
+            Cookie {
+                application,
+                reason,
+                sender,
+            },
+        );
+        cookie
+    }
+
+    /// Releases `cookie` if `sender` holds it, other clients can't release it.
+    fn remove_cookie(&mut self, cookie: u32, sender: &str) {
+        match self.screensaver.get(&cookie) {
+            Some(held) if held.sender == sender => {
+                info!("{} stopped inhibiting idle", held.application);
+                self.screensaver.remove(&cookie);
+            }
+            Some(held) => warn!(
+                "{} tried to release ScreenSaver cookie {} of {}",
+                sender, cookie, held.application
+            ),
+            None => debug!("Unknown ScreenSaver cookie {}", cookie),
+        }
+    }
+
+    /// The cookies handed out so far, for `--replace`.
+    pub fn cookies(&self) -> BTreeMap<u32, Cookie> {
+        self.screensaver.clone()
+    }
+
+    /// Keeps the cookies of the replaced instance valid, so applications can still release
+    /// them.
+    pub fn adopt_cookies(&mut self, cookies: BTreeMap<u32, Cookie>) {
+        if let Some(last) = cookies.keys().max() {
+            self.next_cookie = self.next_cookie.max(*last);
+        }
+        self.screensaver.extend(cookies);
+    }
+
+    /// Drops the cookies of a caller that left the bus without releasing them.
+    fn remove_sender(&mut self, sender: &str) {
+        self.screensaver.retain(|_, cookie| {
+            if cookie.sender == sender {
+                info!(
+                    "{} left without releasing its idle inhibitor",
+                    cookie.application
+                );
+            }
+            cookie.sender != sender
+        });
+    }
+}
+
+/// Asks logind for its current inhibitors.
+async fn logind_inhibitors() -> anyhow::Result<Vec<Inhibitor>> {
+    let conn = zbus::Connection::system().await?;
+    let manager = LogindManagerInterfaceProxy::new(&conn).await?;
+    Ok(manager
+        .list_inhibitors()
+        .await?
+        .into_iter()
+        .map(|(what, who, why, mode, _, _)| Inhibitor {
+            source: "logind".to_string(),
+            who,
+            what: format!("{} ({})", what, mode),
+            why,
+        })
+        .collect())
+}
+
+/// Keeps the logind inhibitors for `Inhibitors:list()` up to date.
+pub async fn inhibitors_run(inhibitors: InhibitorsHandle) {
+    let mut ticker = tokio::time::interval(LOGIND_POLL_INTERVAL);
+    loop {
+        ticker.tick().await;
+        match logind_inhibitors().await {
+            Ok(logind) => inhibitors.lock().unwrap().logind = logind,
+            Err(e) => {
+                error!("Failed to list the logind inhibitors: {}", e);
+                return;
+            }
+        }
+    }
+}
+
+/// Polls the providers whose interval passed.
+pub async fn providers_run(inhibitors: InhibitorsHandle, tx: mpsc::Sender<Request>) {
+    let mut ticker = tokio::time::interval(PROVIDER_TICK);
+    loop {
+        ticker.tick().await;
+        let due = inhibitors.lock().unwrap().due();
+        for name in due {
+            let _ = tx.send(Request::InhibitorPoll(name)).await;
+        }
+    }
+}
+
+/// Streams the whole list as an `inhibitors` event whenever it changes.
+pub async fn stream_run(inhibitors: InhibitorsHandle, events: EventsHandle) {
+    let mut ticker = tokio::time::interval(STREAM_INTERVAL);
+    let mut last = Vec::new();
+    loop {
+        ticker.tick().await;
+        let (list, inhibits_idle) = {
+            let inhibitors = inhibitors.lock().unwrap();
+            let mut list = inhibitors.lua();
+            list.extend(inhibitors.screensaver());
+            list.extend(inhibitors.logind());
+            (list, inhibitors.inhibits_idle())
+        };
+        if list == last {
+            continue;
+        }
+        events.lock().unwrap().emit(
+            "inhibitors",
+            serde_json::json!({ "inhibits_idle": inhibits_idle, "inhibitors": list }),
+        );
+        last = list;
+    }
+}
+
+/// The unique bus name of the client that sent the call.
+fn caller(header: &MessageHeader<'_>) -> String {
+    header
+        .sender()
+        .ok()
+        .flatten()
+        .map(|sender| sender.to_string())
+        .unwrap_or_default()
+}
+
+struct ScreenSaverInterface {
+    inhibitors: InhibitorsHandle,
+}
+
+#[dbus_interface(name = "org.freedesktop.ScreenSaver")]
+impl ScreenSaverInterface {
+    fn inhibit(
+        &self,
+        #[zbus(header)] header: MessageHeader<'_>,
+        application_name: String,
+        reason_for_inhibit: String,
+    ) -> u32 {
+        self.inhibitors.lock().unwrap().add_cookie(
+            application_name,
+            reason_for_inhibit,
+            caller(&header),
+        )
+    }
+
+    fn un_inhibit(&self, #[zbus(header)] header: MessageHeader<'_>, cookie: u32) {
+        self.inhibitors
+            .lock()
+            .unwrap()
+            .remove_cookie(cookie, &caller(&header));
+    }
+}
+
+/// Serves `org.freedesktop.ScreenSaver` on the session bus, unless the desktop already does.
+pub async fn screensaver_run(inhibitors: InhibitorsHandle) -> anyhow::Result<()> {
+    let mut builder = zbus::ConnectionBuilder::session()?.name(SCREENSAVER_BUS_NAME)?;
+    for path in SCREENSAVER_PATHS {
+        builder = builder.serve_at(
+            path,
+            ScreenSaverInterface {
+                inhibitors: inhibitors.clone(),
+            },
+        )?;
+    }
+    let conn = builder.build().await.map_err(|e| match e {
+        zbus::Error::NameTaken => {
+            anyhow::anyhow!("{} is served by the desktop", SCREENSAVER_BUS_NAME)
+        }
+        e => e.into(),
+    })?;
+    info!("Serving {} on the session bus", SCREENSAVER_BUS_NAME);
+
+    let dbus = zbus::fdo::DBusProxy::new(&conn).await?;
+    let mut owner_changes = dbus.receive_name_owner_changed().await?;
+    tokio::spawn(async move {
+        // Keeps the connection and the object server alive
+        let _conn = conn;
+        while let Some(change) = owner_changes.next().await {
+            let Ok(args) = change.args() else {
+                continue;
+            };
+            if args.new_owner().is_none() {
+                inhibitors.lock().unwrap().remove_sender(args.name());
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Handle of an inhibitor from `Inhibitors:for_duration`.
+#[derive(Clone, Debug)]
+pub struct TimedInhibitor {
+    inhibitors: InhibitorsHandle,
+    name: String,
+    id: u64,
+}
+
+impl UserData for TimedInhibitor {
+    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_method("release", |_lua, this, (): ()| {
+            let mut inhibitors = this.inhibitors.lock().unwrap();
+            let Some(timed) = inhibitors
+                .timed
+                .get(&this.name)
+                .filter(|timed| timed.id == this.id)
+            else {
+                return Ok(false);
+            };
+            let active = timed.until > Local::now();
+            inhibitors.timed.remove(&this.name);
+            if active {
+                info!("Lua inhibitor {} released", this.name);
+            }
+            Ok(active)
+        });
+        methods.add_method("remaining", |_lua, this, (): ()| {
+            let inhibitors = this.inhibitors.lock().unwrap();
+            Ok(inhibitors
+                .timed
+                .get(&this.name)
+                .filter(|timed| timed.id == this.id)
+                .map(|timed| (timed.until - Local::now()).num_milliseconds() as f64 / 1000.0)
+                .filter(|remaining| *remaining > 0.0))
+        });
+    }
+}
+
+/// Collects the inhibitors of all sources, see `all_inhibitors`.
+pub type ListInhibitors = Arc<dyn Fn() -> Vec<Inhibitor> + Send + Sync>;
+
+#[derive(Clone)]
+pub struct InhibitorHelpers {
+    pub inhibitors: InhibitorsHandle,
+    pub list: ListInhibitors,
+    pub tx: mpsc::Sender<Request>,
+}
+
+impl UserData for InhibitorHelpers {
+    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_method(
+            "add",
+            |_lua, this, (name, why): (String, Option<String>)| {
+                info!("Lua inhibitor {} added", name);
+                this.inhibitors
+                    .lock()
+                    .unwrap()
+                    .lua
+                    .insert(name, why.unwrap_or_default());
+                Ok(())
+            },
+        );
+        methods.add_method(
+            "for_duration",
+            |_lua, this, (name, seconds, why): (String, f64, Option<String>)| {
+                let until = Duration::try_from_secs_f64(seconds)
+                    .ok()
+                    .and_then(|duration| chrono::Duration::from_std(duration).ok())
+                    .and_then(|duration| Local::now().checked_add_signed(duration))
+                    .ok_or_else(|| {
+                        mlua::Error::RuntimeError(format!("invalid duration {}", seconds))
+                    })?;
+                let mut inhibitors = this.inhibitors.lock().unwrap();
+                let now = Local::now();
+                inhibitors.timed.retain(|_, timed| timed.until > now);
+                inhibitors.next_timed += 1;
+                let id = inhibitors.next_timed;
+                info!("Lua inhibitor {} added until {}", name, until);
+                inhibitors.timed.insert(
+                    name.clone(),
+                    Timed {
+                        why: why.unwrap_or_default(),
+                        until,
+                        id,
+                    },
+                );
+                Ok(TimedInhibitor {
+                    inhibitors: this.inhibitors.clone(),
+                    name,
+                    id,
+                })
+            },
+        );
+        methods.add_method("remove", |_lua, this, name: String| {
+            let mut inhibitors = this.inhibitors.lock().unwrap();
+            let timed = inhibitors.timed.remove(&name).is_some();
+            if inhibitors.lua.remove(&name).is_some() || timed {
+                info!("Lua inhibitor {} removed", name);
+            } else {
+                debug!("No Lua inhibitor {}", name);
+            }
+            Ok(())
+        });
+        methods.add_method(
+            "register",
+            |_lua, this, (name, fn_name, options): (String, String, Option<mlua::Table>)| {
+                let interval = match &options {
+                    Some(options) => options.get::<_, Option<u64>>("interval")?,
+                    None => None,
+                }
+                .unwrap_or(DEFAULT_PROVIDER_INTERVAL);
+                debug!("Inhibitor provider {} registered: {}", name, fn_name);
+                let provider = Provider {
+                    fn_name,
+                    interval: (interval > 0).then(|| Duration::from_secs(interval)),
+                    last_poll: None,
+                    active: None,
+                };
+                this.inhibitors
+                    .lock()
+                    .unwrap()
+                    .providers
+                    .insert(name.clone(), provider);
+                // Polled right away, so a reload doesn't drop an active inhibitor for long
+                utils::send_request(&this.tx, Request::InhibitorPoll(name));
+                Ok(())
+            },
+        );
+        methods.add_method("poll", |_lua, this, name: String| {
+            utils::send_request(&this.tx, Request::InhibitorPoll(name));
+            Ok(())
+        });
+        methods.add_method("inhibited", |_lua, this, (): ()| {
+            Ok(this.inhibitors.lock().unwrap().inhibits_idle())
+        });
+        methods.add_method("list", |lua, this, (): ()| {
+            let list = lua.create_table()?;
+            for inhibitor in (this.list)() {
+                let entry = lua.create_table()?;
+                entry.set("source", inhibitor.source)?;
+                entry.set("who", inhibitor.who)?;
+                entry.set("what", inhibitor.what)?;
+                entry.set("why", inhibitor.why)?;
+                list.push(entry)?;
+            }
+            Ok(list)
+        });
+    }
+}
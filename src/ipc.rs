@@ -0,0 +1,505 @@
+use clap::Subcommand;
+use log::{debug, error, info, warn};
+use nix::unistd::Uid;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::{broadcast, mpsc, oneshot};
+use xdg::BaseDirectories;
+
+use super::config;
+use super::dnd::DndMode;
+use super::nightlight::NightLightMode;
+use super::presentation::PresentationMode;
+use super::settings::{self, IpcSettings};
+use super::types::Request;
+
+/// Commands accepted on the control socket. The same enum is used for the `ctl` subcommand
+/// and, serialized as JSON, as the wire format.
+#[derive(Subcommand, Serialize, Deserialize, Debug, Clone)]
+pub enum CtlCommand {
+    /// Defer scheduled actions, e.g. `snooze 30m`. `snooze 0s` cancels an active snooze
+    Snooze {
+        #[arg(value_parser = humantime::parse_duration)]
+        duration: Duration,
+    },
+    /// Override the do-not-disturb windows
+    Dnd {
+        #[arg(value_enum)]
+        mode: DndMode,
+    },
+    /// Inhibit idle actions, disable the night light, raise the brightness and hold back
+    /// notifications, optionally for a duration like `1h`
+    Presentation {
+        #[arg(value_enum)]
+        mode: PresentationMode,
+        #[arg(value_parser = humantime::parse_duration)]
+        duration: Option<Duration>,
+    },
+    /// Override the night light schedule, e.g. `nightlight off --for 2h`. Without a duration
+    /// the override lasts until the next schedule point, `auto` ends it
+    Nightlight {
+        #[arg(value_enum)]
+        mode: NightLightMode,
+        /// Color temperature in Kelvin for `on`
+        #[arg(long)]
+        temperature: Option<u32>,
+        #[arg(long = "for", value_parser = humantime::parse_duration)]
+        duration: Option<Duration>,
+    },
+    /// Leave outputs whose name or description matches a glob pattern out of the night light,
+    /// e.g. `exclude-output DP-2`
+    ExcludeOutput { pattern: String },
+    /// Undo `exclude-output`, or an exclusion of the config until it is reloaded
+    IncludeOutput { pattern: String },
+    /// Keep the session awake, e.g. `caffeinate 2h`. `caffeinate 0s` ends it
+    Caffeinate {
+        #[arg(value_parser = humantime::parse_duration)]
+        duration: Duration,
+    },
+    /// Report activity for an activity source of the Lua config, e.g. from a script watching
+    /// a MIDI controller
+    Activity { source: String },
+    /// Check that the daemon is responsive and show the last heartbeat
+    Ping,
+    /// Show the profile, pause, presentation, caffeinate, night light and do-not-disturb state
+    Status,
+    /// List what keeps the machine awake
+    Inhibitors,
+    /// List the Wayland protocols and services that were found, and what is missing
+    Caps,
+    /// Lock the session through logind
+    Lock,
+    /// Reload the Lua config
+    Reload,
+    /// Hold back idle callbacks, like the `Paused` D-Bus property, e.g. `inhibit --for 30m`.
+    /// Without a duration until `uninhibit`
+    Inhibit {
+        #[arg(long = "for", value_parser = humantime::parse_duration)]
+        duration: Option<Duration>,
+    },
+    /// End `inhibit`
+    Uninhibit,
+    /// Call a global Lua function, e.g. one that turns the screens back on
+    Trigger { name: String },
+    /// Print the session events as JSON lines until interrupted, e.g. for a status bar
+    Subscribe,
+    /// Run the scheduler and the night light on a simulated clock, to preview them
+    Clock {
+        #[command(subcommand)]
+        action: ClockAction,
+    },
+    /// Fire idle, lock and sleep events without waiting for them, to try out the config, e.g.
+    /// `simulate idle 300`
+    Simulate {
+        #[command(subcommand)]
+        event: SimulatedEvent,
+    },
+    /// Hand the runtime state to a new instance started with `--replace` and exit
+    #[command(hide = true)]
+    Handoff,
+}
+
+#[derive(Subcommand, Serialize, Deserialize, Debug, Clone)]
+pub enum ClockAction {
+    /// Show the time the scheduler and the night light see
+    Show,
+    /// Put the clock at a local time, e.g. `set "2024-06-01 05:59"` or `set 21:59`
+    Set { time: String },
+    /// Move the clock forward, e.g. `advance 8h`. Scheduled actions in between run once
+    Advance {
+        #[arg(value_parser = humantime::parse_duration)]
+        duration: Duration,
+    },
+    /// Go back to the wall clock
+    Reset,
+}
+
+#[derive(Subcommand, Serialize, Deserialize, Debug, Clone)]
+pub enum SimulatedEvent {
+    /// Idle for a number of seconds: the stages with a timeout up to it idle, shortest first
+    Idle { secs: u32 },
+    /// The user is back: the stages that idled resume
+    Resume,
+    /// logind's `Lock` signal
+    Lock,
+    /// logind's `Unlock` signal
+    Unlock,
+    /// logind's `PrepareForSleep` before a suspend
+    Sleep,
+    /// logind's `PrepareForSleep` after the resume
+    Wake,
+    /// logind's `PrepareForShutdown`
+    Shutdown,
+    /// The lid is closed, undocked
+    LidClose,
+    /// The lid is opened, undocked
+    LidOpen,
+    /// The power button is pressed
+    PowerButton,
+    /// A monitor with this name is plugged in
+    OutputAdd { name: String },
+    /// The monitor with this name is unplugged
+    OutputRemove { name: String },
+}
+
+/// A command on the wire. Connections from other users need the token for commands that
+/// change something.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CtlRequest {
+    pub cmd: CtlCommand,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+}
+
+impl CtlCommand {
+    /// Commands that only report state, allowed for the `readonly_uids` of the settings.
+    fn is_read_only(&self) -> bool {
+        matches!(
+            self,
+            CtlCommand::Ping
+                | CtlCommand::Status
+                | CtlCommand::Inhibitors
+                | CtlCommand::Caps
+                | CtlCommand::Subscribe
+                | CtlCommand::Clock {
+                    action: ClockAction::Show
+                }
+        )
+    }
+
+    /// Commands accepted by the remote listener. `trigger` only calls the functions listed
+    /// in `actions`.
+    pub fn is_remote(&self, actions: &[String]) -> bool {
+        match self {
+            CtlCommand::Ping | CtlCommand::Status | CtlCommand::Lock => true,
+            CtlCommand::Trigger { name } => actions.contains(name),
+            _ => false,
+        }
+    }
+}
+
+/// What a connected peer may do, decided by its UID.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Access {
+    Full,
+    ReadOnly,
+}
+
+pub fn socket_path(settings: &IpcSettings) -> std::io::Result<PathBuf> {
+    if let Some(path) = &settings.socket {
+        return Ok(path.clone());
+    }
+    let xdg_dirs = BaseDirectories::with_prefix(config::APP_NAME)?;
+    xdg_dirs.place_runtime_file(config::CONTROL_SOCKET_NAME)
+}
+
+pub fn read_token(path: &Path) -> anyhow::Result<String> {
+    let token = fs::read_to_string(path)?.trim().to_string();
+    if token.is_empty() {
+        anyhow::bail!("{:?} is empty", path);
+    }
+    Ok(token)
+}
+
+/// Compares in constant time, so the token can't be guessed byte by byte.
+pub fn token_matches(expected: &str, given: &str) -> bool {
+    expected.len() == given.len()
+        && expected
+            .bytes()
+            .zip(given.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+fn peer_access(stream: &UnixStream, settings: &IpcSettings) -> Option<Access> {
+    uid_access(stream.peer_cred().ok()?.uid(), settings)
+}
+
+fn uid_access(uid: u32, settings: &IpcSettings) -> Option<Access> {
+    if uid == Uid::current().as_raw() || settings.allowed_uids.contains(&uid) {
+        Some(Access::Full)
+    } else if settings.readonly_uids.contains(&uid) {
+        Some(Access::ReadOnly)
+    } else {
+        info!("Rejected control connection from uid {}", uid);
+        None
+    }
+}
+
+/// Answers the JSON commands of a connection, one per line. Commands for which `authorize`
+/// returns false are refused. After `subscribe` the connection only carries the event stream.
+pub async fn serve<S: AsyncRead + AsyncWrite>(
+    stream: S,
+    authorize: impl Fn(&CtlRequest) -> bool,
+    tx: mpsc::Sender<Request>,
+) -> anyhow::Result<()> {
+    let (reader, mut writer) = tokio::io::split(stream);
+    let mut lines = BufReader::new(reader).lines();
+
+    while let Some(line) = lines.next_line().await? {
+        let request = serde_json::from_str::<CtlRequest>(&line).or_else(|_| {
+            serde_json::from_str::<CtlCommand>(&line).map(|cmd| CtlRequest { cmd, token: None })
+        });
+        let reply = match request {
+            Ok(request) if matches!(request.cmd, CtlCommand::Subscribe) && authorize(&request) => {
+                debug!("Control connection subscribed to the events");
+                let (reply_tx, reply_rx) = oneshot::channel();
+                tx.send(Request::Subscribe(reply_tx)).await?;
+                let events = reply_rx.await?;
+                let reply = serde_json::json!({ "ok": true });
+                writer.write_all(format!("{}\n", reply).as_bytes()).await?;
+                writer.flush().await?;
+                return forward_events(events, writer).await;
+            }
+            Ok(request) if authorize(&request) => {
+                debug!("Control command: {:?}", request.cmd);
+                let (reply_tx, reply_rx) = oneshot::channel();
+                tx.send(Request::Ctl(request.cmd, reply_tx)).await?;
+                reply_rx.await?
+            }
+            Ok(request) => {
+                info!("Refused control command {:?}", request.cmd);
+                serde_json::json!({ "ok": false, "error": "permission denied" })
+            }
+            Err(e) => serde_json::json!({ "ok": false, "error": e.to_string() }),
+        };
+        writer.write_all(format!("{}\n", reply).as_bytes()).await?;
+        writer.flush().await?;
+    }
+    Ok(())
+}
+
+/// Writes the event stream to a subscribed connection until it goes away.
+async fn forward_events<W: AsyncWrite + Unpin>(
+    mut events: broadcast::Receiver<String>,
+    mut writer: W,
+) -> anyhow::Result<()> {
+    loop {
+        let line = match events.recv().await {
+            Ok(line) => line,
+            Err(broadcast::error::RecvError::Lagged(missed)) => {
+                warn!("Event subscriber missed {} events", missed);
+                continue;
+            }
+            Err(broadcast::error::RecvError::Closed) => return Ok(()),
+        };
+        writer.write_all(format!("{}\n", line).as_bytes()).await?;
+        writer.flush().await?;
+    }
+}
+
+/// Binds the socket at a temporary name and moves it to `path` once it has its permissions,
+/// so it is never reachable with others.
+fn bind_socket(path: &Path, mode: u32) -> anyhow::Result<UnixListener> {
+    let mut staging = path.as_os_str().to_owned();
+    staging.push(format!(".{}", std::process::id()));
+    let staging = PathBuf::from(staging);
+    let _ = fs::remove_file(&staging);
+    let listener = UnixListener::bind(&staging)?;
+    let placed = fs::set_permissions(&staging, fs::Permissions::from_mode(mode))
+        .and_then(|()| fs::rename(&staging, path));
+    if let Err(e) = placed {
+        let _ = fs::remove_file(&staging);
+        return Err(e.into());
+    }
+    Ok(listener)
+}
+
+pub async fn ipc_run(tx: mpsc::Sender<Request>, settings: IpcSettings) -> anyhow::Result<()> {
+    let shared = !settings.readonly_uids.is_empty() || !settings.allowed_uids.is_empty();
+    if shared && settings.socket.is_none() {
+        anyhow::bail!(
+            "readonly_uids and allowed_uids need a socket path other users can reach, \
+             $XDG_RUNTIME_DIR is private"
+        );
+    }
+    let path = socket_path(&settings)?;
+    let token = match &settings.token_file {
+        Some(token_file) => Some(Arc::new(read_token(token_file)?)),
+        None => None,
+    };
+    if UnixStream::connect(&path).await.is_ok() {
+        anyhow::bail!("another instance is listening on {:?}", path);
+    }
+    // Other users have to be able to connect, the peer UID decides what they may do
+    let listener = bind_socket(&path, if shared { 0o666 } else { 0o600 })?;
+    info!("Control socket listening on {:?}", path);
+
+    tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    let Some(access) = peer_access(&stream, &settings) else {
+                        continue;
+                    };
+                    let tx = tx.clone();
+                    let token = token.clone();
+                    let authorize = move |request: &CtlRequest| {
+                        access == Access::Full
+                            || request.cmd.is_read_only()
+                            || token
+                                .as_deref()
+                                .zip(request.token.as_deref())
+                                .is_some_and(|(expected, given)| token_matches(expected, given))
+                    };
+                    tokio::spawn(async move {
+                        if let Err(e) = serve(stream, authorize, tx).await {
+                            error!("Control client error: {}", e);
+                        }
+                    });
+                }
+                Err(e) => {
+                    error!("Error accepting control connection: {}", e);
+                }
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Client side of `sleepwatcher-rs ctl`: sends a single command and prints the JSON reply,
+/// followed by the events for `subscribe`.
+pub async fn ctl(
+    cmd: CtlCommand,
+    socket: Option<PathBuf>,
+    token_file: Option<PathBuf>,
+) -> anyhow::Result<()> {
+    let path = match socket {
+        Some(socket) => socket,
+        None => socket_path(&settings::load()?.ipc)?,
+    };
+    let token = match token_file {
+        Some(token_file) => Some(read_token(&token_file)?),
+        None => None,
+    };
+    let stream = UnixStream::connect(path).await?;
+    let (reader, mut writer) = stream.into_split();
+
+    let subscribe = matches!(cmd, CtlCommand::Subscribe);
+    let request = CtlRequest { cmd, token };
+    writer
+        .write_all(format!("{}\n", serde_json::to_string(&request)?).as_bytes())
+        .await?;
+
+    let mut lines = BufReader::new(reader).lines();
+    if let Some(reply) = lines.next_line().await? {
+        println!("{}", reply);
+        let reply: serde_json::Value = serde_json::from_str(&reply)?;
+        if reply["ok"] != true {
+            std::process::exit(1);
+        }
+    }
+    if subscribe {
+        while let Some(event) = lines.next_line().await? {
+            println!("{}", event);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn token_matches_only_equal_tokens() {
+        assert!(token_matches("3f9a0c", "3f9a0c"));
+        assert!(!token_matches("3f9a0c", "3f9a0d"));
+        assert!(!token_matches("3f9a0c", "3f9a0"));
+        assert!(!token_matches("3f9a0c", "3f9a0c0"));
+        assert!(!token_matches("3f9a0c", ""));
+    }
+
+    #[tokio::test]
+    async fn socket_gets_its_mode_before_it_is_reachable() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("ctl.sock");
+        let _listener = bind_socket(&path, 0o600).unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[tokio::test]
+    async fn refuses_a_second_instance_and_shared_private_sockets() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("ctl.sock");
+        let settings = IpcSettings {
+            socket: Some(path.clone()),
+            ..Default::default()
+        };
+        let (tx, _rx) = mpsc::channel(1);
+        ipc_run(tx.clone(), settings.clone()).await.unwrap();
+        assert!(ipc_run(tx.clone(), settings).await.is_err());
+        let private = IpcSettings {
+            readonly_uids: vec![1001],
+            ..Default::default()
+        };
+        assert!(ipc_run(tx, private).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn access_follows_the_uid_lists() {
+        let me = Uid::current().as_raw();
+        let other = me.wrapping_add(1);
+        let stranger = me.wrapping_add(2);
+        let settings = IpcSettings {
+            readonly_uids: vec![other],
+            ..Default::default()
+        };
+        let (stream, _peer) = UnixStream::pair().unwrap();
+        assert_eq!(peer_access(&stream, &settings), Some(Access::Full));
+        assert_eq!(uid_access(other, &settings), Some(Access::ReadOnly));
+        assert_eq!(uid_access(stranger, &settings), None);
+        let settings = IpcSettings {
+            readonly_uids: vec![other],
+            allowed_uids: vec![other],
+            ..Default::default()
+        };
+        assert_eq!(uid_access(other, &settings), Some(Access::Full));
+        assert_eq!(uid_access(stranger, &IpcSettings::default()), None);
+    }
+
+    #[test]
+    fn read_only_commands_change_nothing() {
+        assert!(CtlCommand::Status.is_read_only());
+        assert!(CtlCommand::Subscribe.is_read_only());
+        assert!(CtlCommand::Clock {
+            action: ClockAction::Show
+        }
+        .is_read_only());
+        assert!(!CtlCommand::Clock {
+            action: ClockAction::Reset
+        }
+        .is_read_only());
+        assert!(!CtlCommand::Lock.is_read_only());
+        assert!(!CtlCommand::Handoff.is_read_only());
+        assert!(!CtlCommand::Trigger {
+            name: "wake_screens".to_string()
+        }
+        .is_read_only());
+    }
+
+    #[test]
+    fn remote_commands_are_limited() {
+        let actions = vec!["wake_screens".to_string()];
+        let trigger = |name: &str| CtlCommand::Trigger {
+            name: name.to_string(),
+        };
+        assert!(CtlCommand::Ping.is_remote(&actions));
+        assert!(CtlCommand::Lock.is_remote(&actions));
+        assert!(trigger("wake_screens").is_remote(&actions));
+        assert!(!trigger("wipe_disk").is_remote(&actions));
+        assert!(!trigger("wake_screens").is_remote(&[]));
+        assert!(!CtlCommand::Reload.is_remote(&actions));
+        assert!(!CtlCommand::Subscribe.is_remote(&actions));
+        assert!(!CtlCommand::Handoff.is_remote(&actions));
+        assert!(!CtlCommand::Uninhibit.is_remote(&actions));
+    }
+}
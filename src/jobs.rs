@@ -0,0 +1,272 @@
+//! Maintenance jobs that run while the user is away. A job starts after its idle timeout
+//! while on AC, and is paused or killed as soon as the user returns or the AC is unplugged.
+
+use log::{debug, error, info};
+use mlua::{UserData, UserDataMethods};
+use nix::sys::signal::{killpg, Signal};
+use nix::unistd::Pid;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::process::Command;
+use tokio::sync::mpsc;
+
+use super::cmdlog;
+use super::journal;
+use super::types::Request;
+use super::utils;
+
+const DEFAULT_IDLE_SECS: i32 = 600;
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// What happens to a running job when the user comes back.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum OnResume {
+    /// Stop it with SIGSTOP and continue it on the next idle period
+    Pause,
+    /// Terminate it, it starts over on the next idle period
+    Kill,
+}
+
+#[derive(Debug)]
+struct Job {
+    cmd: String,
+    on_ac: bool,
+    on_resume: OnResume,
+    /// Minimum time after a successful run before the job runs again
+    interval: Duration,
+    /// Process group of the running job
+    running: Option<Pid>,
+    paused: bool,
+}
+
+impl Job {
+    fn signal(&self, signal: Signal) {
+        if let Some(pgid) = self.running {
+            if let Err(e) = killpg(pgid, signal) {
+                debug!("Failed to send {} to {}: {}", signal, self.cmd, e);
+            }
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Jobs {
+    jobs: HashMap<String, Job>,
+    /// Kept across config reloads, so a reload doesn't repeat jobs
+    last_success: HashMap<String, Instant>,
+    on_battery: bool,
+}
+
+pub type JobsHandle = Arc<Mutex<Jobs>>;
+
+impl Jobs {
+    pub fn new() -> JobsHandle {
+        Arc::new(Mutex::new(Self::default()))
+    }
+
+    /// Commands of the jobs by name.
+    pub fn commands(&self) -> BTreeMap<String, String> {
+        self.jobs
+            .iter()
+            .map(|(name, job)| (name.clone(), job.cmd.clone()))
+            .collect()
+    }
+
+    /// Terminates running jobs and forgets all of them.
+    pub fn clear(&mut self) {
+        for (name, job) in self.jobs.drain() {
+            if job.running.is_some() {
+                info!("Terminating job {}", name);
+                job.signal(Signal::SIGTERM);
+                job.signal(Signal::SIGCONT);
+            }
+        }
+    }
+
+    pub fn set_on_battery(&mut self, on_battery: bool) {
+        self.on_battery = on_battery;
+        if on_battery {
+            let names: Vec<String> = self.jobs.keys().cloned().collect();
+            for name in names {
+                if self.jobs[&name].on_ac {
+                    self.interrupt(&name);
+                }
+            }
+        }
+    }
+
+    /// Starts the job or continues it when it was paused. Returns the command if a new run
+    /// has to be spawned.
+    pub fn idled(&mut self, name: &str) -> Option<String> {
+        let on_battery = self.on_battery;
+        let last_success = self.last_success.get(name).copied();
+        let job = self.jobs.get_mut(name)?;
+        if job.on_ac && on_battery {
+            debug!("Not starting job {} on battery", name);
+            return None;
+        }
+        if job.paused {
+            info!("Continuing job {}", name);
+            job.paused = false;
+            job.signal(Signal::SIGCONT);
+            return None;
+        }
+        let due = last_success.is_none_or(|last| last.elapsed() >= job.interval);
+        if job.running.is_some() || !due {
+            return None;
+        }
+        Some(job.cmd.clone())
+    }
+
+    /// Pauses or terminates the job, depending on its `on_resume` option.
+    pub fn interrupt(&mut self, name: &str) {
+        let Some(job) = self.jobs.get_mut(name) else {
+            return;
+        };
+        if job.running.is_none() || job.paused {
+            return;
+        }
+        match job.on_resume {
+            OnResume::Pause => {
+                info!("Pausing job {}", name);
+                job.signal(Signal::SIGSTOP);
+                job.paused = true;
+            }
+            OnResume::Kill => {
+                info!("Terminating job {}", name);
+                job.signal(Signal::SIGTERM);
+            }
+        }
+    }
+
+    fn started(&mut self, name: &str, pgid: Pid) {
+        if let Some(job) = self.jobs.get_mut(name) {
+            job.running = Some(pgid);
+        }
+    }
+
+    pub fn finished(&mut self, name: &str, success: bool) {
+        if let Some(job) = self.jobs.get_mut(name) {
+            job.running = None;
+            job.paused = false;
+        }
+        if success {
+            self.last_success.insert(name.to_string(), Instant::now());
+        }
+    }
+}
+
+/// Runs a job in its own process group, so that pausing and terminating it reaches all of
+/// its processes, and reports the result with `Request::JobDone`.
+pub async fn run(name: String, cmd: String, jobs: JobsHandle, tx: mpsc::Sender<Request>) {
+    let id = cmdlog::started(&cmd, &format!("job {}", name));
+    let child = utils::get_args(&cmd).and_then(|(program, args)| {
+        Ok(Command::new(&program)
+            .args(args)
+            .process_group(0)
+            .kill_on_drop(true)
+            .spawn()?)
+    });
+    let mut child = match child {
+        Ok(child) => child,
+        Err(e) => {
+            error!("Failed to start job {}: {}", name, e);
+            cmdlog::finished(id, &cmd, &e.to_string());
+            let _ = tx.send(Request::JobDone(name, false)).await;
+            return;
+        }
+    };
+    if let Some(pid) = child.id() {
+        jobs.lock()
+            .unwrap()
+            .started(&name, Pid::from_raw(pid as i32));
+    }
+    journal::event(
+        "job",
+        &format!("Job {} started: {}", name, cmd),
+        &[("JOB", &name)],
+    );
+    let status = child.wait().await;
+    let outcome = match &status {
+        Ok(status) => status.to_string(),
+        Err(e) => e.to_string(),
+    };
+    cmdlog::finished(id, &cmd, &outcome);
+    let success = match status {
+        Ok(status) => {
+            journal::event(
+                "job",
+                &format!("Job {} ended with {}", name, status),
+                &[
+                    ("JOB", &name),
+                    ("EXIT_CODE", &status.code().unwrap_or(-1).to_string()),
+                ],
+            );
+            status.success()
+        }
+        Err(e) => {
+            error!("Job {} failed: {}", name, e);
+            false
+        }
+    };
+    let _ = tx.send(Request::JobDone(name, success)).await;
+}
+
+/// Creates the idle notification of a job, see `MyLuaFunctions::watch_idle`.
+pub type WatchIdle = Arc<dyn Fn(String, i32) -> bool + Send + Sync>;
+
+#[derive(Clone)]
+pub struct JobHelpers {
+    pub jobs: JobsHandle,
+    pub allow_exec: bool,
+    pub watch_idle: WatchIdle,
+}
+
+impl UserData for JobHelpers {
+    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_method(
+            "add",
+            |_lua, this, (name, cmd, options): (String, String, Option<mlua::Table>)| {
+                if !this.allow_exec {
+                    return Err(mlua::Error::RuntimeError(
+                        "running commands is disabled by the sandbox policy".to_string(),
+                    ));
+                }
+                if cmd.trim().is_empty() {
+                    return Err(mlua::Error::RuntimeError("empty command".to_string()));
+                }
+                let (idle, on_ac, on_resume, interval) = match &options {
+                    Some(options) => (
+                        options.get::<_, Option<i32>>("idle")?,
+                        options.get::<_, Option<bool>>("on_ac")?,
+                        options.get::<_, Option<String>>("on_resume")?,
+                        options.get::<_, Option<u64>>("interval")?,
+                    ),
+                    None => (None, None, None, None),
+                };
+                let on_resume = match on_resume.as_deref() {
+                    None | Some("pause") => OnResume::Pause,
+                    Some("kill") => OnResume::Kill,
+                    Some(other) => {
+                        return Err(mlua::Error::RuntimeError(format!(
+                            "invalid on_resume {}",
+                            other
+                        )))
+                    }
+                };
+                let job = Job {
+                    cmd,
+                    on_ac: on_ac.unwrap_or(true),
+                    on_resume,
+                    interval: interval.map_or(DEFAULT_INTERVAL, Duration::from_secs),
+                    running: None,
+                    paused: false,
+                };
+                debug!("Adding job {}: {:?}", name, job);
+                this.jobs.lock().unwrap().jobs.insert(name.clone(), job);
+                Ok((this.watch_idle)(name, idle.unwrap_or(DEFAULT_IDLE_SECS)))
+            },
+        );
+    }
+}
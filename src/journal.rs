@@ -0,0 +1,68 @@
+use log::{debug, info, log_enabled, Level};
+use once_cell::sync::Lazy;
+use std::io;
+use std::os::unix::net::UnixDatagram;
+
+use super::config;
+
+const JOURNAL_SOCKET: &str = "/run/systemd/journal/socket";
+/// syslog priority "info"
+const PRIORITY_INFO: &str = "6";
+
+/// systemd sets `JOURNAL_STREAM` when stderr of a service is connected to the journal.
+static JOURNAL: Lazy<Option<UnixDatagram>> = Lazy::new(|| {
+    std::env::var_os("JOURNAL_STREAM")?;
+    let socket = UnixDatagram::unbound().ok()?;
+    socket.connect(JOURNAL_SOCKET).ok()?;
+    Some(socket)
+});
+
+/// Serializes fields in the native journal protocol. Values containing newlines are sent
+/// with an explicit length.
+fn serialize(fields: &[(&str, &str)]) -> Vec<u8> {
+    let mut buf = Vec::new();
+    for (name, value) in fields {
+        buf.extend_from_slice(name.as_bytes());
+        if value.contains('\n') {
+            buf.push(b'\n');
+            buf.extend_from_slice(&(value.len() as u64).to_le_bytes());
+        } else {
+            buf.push(b'=');
+        }
+        buf.extend_from_slice(value.as_bytes());
+        buf.push(b'\n');
+    }
+    buf
+}
+
+fn send(
+    socket: &UnixDatagram,
+    event: &str,
+    message: &str,
+    fields: &[(&str, &str)],
+) -> io::Result<()> {
+    let mut entry = vec![
+        ("MESSAGE", message),
+        ("PRIORITY", PRIORITY_INFO),
+        ("SYSLOG_IDENTIFIER", config::APP_NAME),
+        ("EVENT", event),
+    ];
+    entry.extend_from_slice(fields);
+    socket.send(&serialize(&entry))?;
+    Ok(())
+}
+
+/// Logs a lifecycle event. When running under systemd, the entry goes to the journal directly
+/// with `EVENT` and the given fields attached, e.g. `journalctl -t sleepwatcher-rs EVENT=idle`.
+pub fn event(event: &str, message: &str, fields: &[(&str, &str)]) {
+    if !log_enabled!(Level::Info) {
+        return;
+    }
+    if let Some(socket) = JOURNAL.as_ref() {
+        match send(socket, event, message, fields) {
+            Ok(()) => return,
+            Err(e) => debug!("Failed to write to the journal: {}", e),
+        }
+    }
+    info!("{}", message);
+}
@@ -0,0 +1,236 @@
+//! Kiosk mode for signage and public terminals. One application is kept running and relaunched
+//! when it exits, and `Kiosk:reset()`, usually called from an idle callback, restarts it or
+//! runs a reset command instead of locking the session.
+
+use log::{debug, error, info};
+use mlua::{UserData, UserDataMethods};
+use nix::sys::signal::{killpg, Signal};
+use nix::unistd::Pid;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::process::{Child, Command};
+use tokio::sync::mpsc;
+
+use super::journal;
+use super::types::Request;
+use super::utils;
+
+/// Time the application gets to exit after SIGTERM before it is killed
+const TERM_TIMEOUT: Duration = Duration::from_secs(5);
+const MIN_RESTART_DELAY: Duration = Duration::from_secs(1);
+/// Upper bound for the restart delay of an application that keeps crashing
+const MAX_RESTART_DELAY: Duration = Duration::from_secs(60);
+/// A run this long resets the restart delay
+const STABLE_RUN: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Default)]
+pub struct Kiosk {
+    /// `(cmd, reset)` of the supervised application
+    config: Option<(String, Option<String>)>,
+    /// Requests a reset, dropping it stops the application
+    control: Option<mpsc::Sender<()>>,
+    /// Set on config reload, an application the new config doesn't start again is stopped
+    stale: bool,
+}
+
+pub type KioskHandle = Arc<Mutex<Kiosk>>;
+
+impl Kiosk {
+    pub fn new() -> KioskHandle {
+        Arc::new(Mutex::new(Self::default()))
+    }
+
+    /// Marks the application for `sweep`, so a reload of an unchanged config keeps it running.
+    pub fn clear(&mut self) {
+        self.stale = true;
+    }
+
+    /// Stops the application if the reloaded config didn't start it.
+    pub fn sweep(&mut self) {
+        if self.stale {
+            self.stop();
+        }
+    }
+
+    pub fn stop(&mut self) {
+        self.config = None;
+        self.control = None;
+        self.stale = false;
+    }
+
+    fn is_running(&self) -> bool {
+        self.control
+            .as_ref()
+            .is_some_and(|control| !control.is_closed())
+    }
+}
+
+fn spawn(cmd: &str) -> anyhow::Result<Child> {
+    let (program, args) = utils::get_args(cmd)?;
+    Ok(Command::new(&program)
+        .args(args)
+        .process_group(0)
+        .kill_on_drop(true)
+        .spawn()?)
+}
+
+/// Terminates the process group of the application, and kills it after `TERM_TIMEOUT`.
+async fn terminate(mut child: Child, cmd: &str) {
+    if let Some(pid) = child.id() {
+        let pgid = Pid::from_raw(pid as i32);
+        let _ = killpg(pgid, Signal::SIGTERM);
+        if tokio::time::timeout(TERM_TIMEOUT, child.wait())
+            .await
+            .is_ok()
+        {
+            debug!("Kiosk application {} exited", cmd);
+            return;
+        }
+        info!("Killing kiosk application {}", cmd);
+        let _ = killpg(pgid, Signal::SIGKILL);
+    }
+    let _ = child.kill().await;
+}
+
+/// Runs the reset command to completion.
+async fn run_reset(reset: &str) {
+    journal::event(
+        "kiosk",
+        &format!("Running kiosk reset {}", reset),
+        &[("COMMAND", reset)],
+    );
+    match spawn(reset) {
+        Ok(mut child) => {
+            if let Err(e) = child.wait().await {
+                error!("Kiosk reset {} failed: {}", reset, e);
+            }
+        }
+        Err(e) => error!("Failed to run kiosk reset {}: {}", reset, e),
+    }
+}
+
+/// Supervises the application until `control` is closed. Every message on `control` resets
+/// it: the reset command runs if there is one, otherwise the application is restarted.
+pub async fn run(cmd: String, reset: Option<String>, mut control: mpsc::Receiver<()>) {
+    let mut delay = MIN_RESTART_DELAY;
+    loop {
+        let started = Instant::now();
+        let mut child = match spawn(&cmd) {
+            Ok(child) => {
+                journal::event(
+                    "kiosk",
+                    &format!("Kiosk application started: {}", cmd),
+                    &[("COMMAND", &cmd)],
+                );
+                child
+            }
+            Err(e) => {
+                error!("Failed to start kiosk application {}: {}", cmd, e);
+                tokio::select! {
+                    _ = tokio::time::sleep(delay) => {},
+                    None = control.recv() => return,
+                }
+                delay = (delay * 2).min(MAX_RESTART_DELAY);
+                continue;
+            }
+        };
+        loop {
+            tokio::select! {
+                status = child.wait() => {
+                    journal::event(
+                        "kiosk",
+                        &format!("Kiosk application {} ended: {:?}", cmd, status),
+                        &[("COMMAND", &cmd)],
+                    );
+                    delay = if started.elapsed() >= STABLE_RUN {
+                        MIN_RESTART_DELAY
+                    } else {
+                        (delay * 2).min(MAX_RESTART_DELAY)
+                    };
+                    tokio::select! {
+                        _ = tokio::time::sleep(delay) => {},
+                        None = control.recv() => return,
+                    }
+                    break;
+                }
+                message = control.recv() => match (message, &reset) {
+                    (None, _) => {
+                        terminate(child, &cmd).await;
+                        return;
+                    }
+                    (Some(()), Some(reset)) => run_reset(reset).await,
+                    (Some(()), None) => {
+                        info!("Restarting kiosk application {}", cmd);
+                        terminate(child, &cmd).await;
+                        delay = MIN_RESTART_DELAY;
+                        break;
+                    }
+                },
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct KioskHelpers {
+    pub kiosk: KioskHandle,
+    pub allow_exec: bool,
+    pub tx: mpsc::Sender<Request>,
+}
+
+impl UserData for KioskHelpers {
+    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_method(
+            "start",
+            |_lua, this, (cmd, options): (String, Option<mlua::Table>)| {
+                if !this.allow_exec {
+                    return Err(mlua::Error::RuntimeError(
+                        "running commands is disabled by the sandbox policy".to_string(),
+                    ));
+                }
+                let reset = match options {
+                    Some(options) => options.get::<_, Option<String>>("reset")?,
+                    None => None,
+                };
+                if cmd.trim().is_empty() || reset.as_ref().is_some_and(|r| r.trim().is_empty()) {
+                    return Err(mlua::Error::RuntimeError("empty command".to_string()));
+                }
+                let mut kiosk = this.kiosk.lock().unwrap();
+                kiosk.stale = false;
+                let config = (cmd, reset);
+                if kiosk.is_running() && kiosk.config.as_ref() == Some(&config) {
+                    debug!("Kiosk application {} already running", config.0);
+                    return Ok(());
+                }
+                let (control_tx, control) = mpsc::channel(1);
+                // Replacing the sender stops an application that is already running
+                kiosk.control = Some(control_tx);
+                kiosk.config = Some(config.clone());
+                let (cmd, reset) = config;
+                utils::send_request(
+                    &this.tx,
+                    Request::Kiosk {
+                        cmd,
+                        reset,
+                        control,
+                    },
+                );
+                Ok(())
+            },
+        );
+        methods.add_method("reset", |_lua, this, (): ()| {
+            if let Some(control) = &this.kiosk.lock().unwrap().control {
+                // A reset that is still pending covers this one
+                let _ = control.try_send(());
+            }
+            Ok(())
+        });
+        methods.add_method("stop", |_lua, this, (): ()| {
+            this.kiosk.lock().unwrap().stop();
+            Ok(())
+        });
+        methods.add_method("running", |_lua, this, (): ()| {
+            Ok(this.kiosk.lock().unwrap().is_running())
+        });
+    }
+}
@@ -0,0 +1,713 @@
+//! Built-in locker. `Lock:engage()` locks the session with ext-session-lock-v1, covers every
+//! output with a solid color and unlocks once PAM accepts the password typed on the keyboard,
+//! so no external locker has to be started and watched for. Checking the password and reading
+//! the keymap need the `lock` cargo feature, which links libpam and libxkbcommon. If the daemon
+//! dies while locked, the compositor keeps the session locked.
+
+use log::{debug, error, info, warn};
+use mlua::{Table, UserData, UserDataMethods};
+use nix::sys::memfd::{memfd_create, MemFdCreateFlag};
+use nix::unistd::{Uid, User};
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::File;
+use std::io::Write;
+use std::os::fd::{AsFd, OwnedFd};
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc;
+use wayland_client::protocol::{
+    wl_buffer, wl_compositor, wl_output, wl_shm, wl_shm_pool, wl_surface,
+};
+use wayland_client::{Connection, Dispatch, QueueHandle};
+use wayland_protocols::ext::session_lock::v1::client::{
+    ext_session_lock_manager_v1, ext_session_lock_surface_v1, ext_session_lock_v1,
+};
+
+use super::config;
+use super::types::Request;
+use super::utils;
+
+const DEFAULT_COLOR: u32 = 0x202020;
+/// Shown after a wrong password until the next key press
+const FAILED_COLOR: u32 = 0x7a1f1f;
+/// Key symbols of xkbcommon
+const KEY_RETURN: u32 = 0xff0d;
+const KEY_KP_ENTER: u32 = 0xff8d;
+const KEY_BACKSPACE: u32 = 0xff08;
+const KEY_ESCAPE: u32 = 0xff1b;
+
+#[cfg(feature = "lock")]
+mod imp {
+    use nix::libc::{self, c_char, c_int, c_void};
+    use std::ffi::{CStr, CString};
+    use std::fs::File;
+    use std::io::Read;
+    use std::os::fd::OwnedFd;
+
+    const PAM_SUCCESS: c_int = 0;
+    const PAM_BUF_ERR: c_int = 5;
+    const PAM_CONV_ERR: c_int = 19;
+    const PAM_PROMPT_ECHO_OFF: c_int = 1;
+    const PAM_PROMPT_ECHO_ON: c_int = 2;
+    const XKB_KEYMAP_FORMAT_TEXT_V1: c_int = 1;
+
+    #[repr(C)]
+    struct PamMessage {
+        msg_style: c_int,
+        msg: *const c_char,
+    }
+
+    #[repr(C)]
+    struct PamResponse {
+        resp: *mut c_char,
+        resp_retcode: c_int,
+    }
+
+    #[repr(C)]
+    struct PamConv {
+        conv: unsafe extern "C" fn(
+            c_int,
+            *mut *const PamMessage,
+            *mut *mut PamResponse,
+            *mut c_void,
+        ) -> c_int,
+        appdata_ptr: *mut c_void,
+    }
+
+    #[link(name = "pam")]
+    extern "C" {
+        fn pam_start(
+            service: *const c_char,
+            user: *const c_char,
+            conv: *const PamConv,
+            pamh: *mut *mut c_void,
+        ) -> c_int;
+        fn pam_authenticate(pamh: *mut c_void, flags: c_int) -> c_int;
+        fn pam_end(pamh: *mut c_void, status: c_int) -> c_int;
+        fn pam_strerror(pamh: *mut c_void, errnum: c_int) -> *const c_char;
+    }
+
+    #[link(name = "xkbcommon")]
+    extern "C" {
+        fn xkb_context_new(flags: c_int) -> *mut c_void;
+        fn xkb_context_unref(context: *mut c_void);
+        fn xkb_keymap_new_from_string(
+            context: *mut c_void,
+            string: *const c_char,
+            format: c_int,
+            flags: c_int,
+        ) -> *mut c_void;
+        fn xkb_keymap_unref(keymap: *mut c_void);
+        fn xkb_state_new(keymap: *mut c_void) -> *mut c_void;
+        fn xkb_state_unref(state: *mut c_void);
+        fn xkb_state_update_mask(
+            state: *mut c_void,
+            depressed_mods: u32,
+            latched_mods: u32,
+            locked_mods: u32,
+            depressed_layout: u32,
+            latched_layout: u32,
+            locked_layout: u32,
+        ) -> c_int;
+        fn xkb_state_key_get_one_sym(state: *mut c_void, key: u32) -> u32;
+        fn xkb_state_key_get_utf8(
+            state: *mut c_void,
+            key: u32,
+            buffer: *mut c_char,
+            size: usize,
+        ) -> c_int;
+    }
+
+    /// Answers the prompts of PAM with the password. PAM frees the responses.
+    unsafe extern "C" fn converse(
+        count: c_int,
+        messages: *mut *const PamMessage,
+        responses: *mut *mut PamResponse,
+        password: *mut c_void,
+    ) -> c_int {
+        let Ok(count) = usize::try_from(count) else {
+            return PAM_CONV_ERR;
+        };
+        let replies = libc::calloc(count, std::mem::size_of::<PamResponse>()) as *mut PamResponse;
+        if replies.is_null() {
+            return PAM_BUF_ERR;
+        }
+        for i in 0..count {
+            let message = &**messages.add(i);
+            if matches!(message.msg_style, PAM_PROMPT_ECHO_OFF | PAM_PROMPT_ECHO_ON) {
+                (*replies.add(i)).resp = libc::strdup(password as *const c_char);
+            }
+        }
+        *responses = replies;
+        PAM_SUCCESS
+    }
+
+    pub fn authenticate(service: &str, user: &str, password: &str) -> Result<(), String> {
+        let service = CString::new(service).map_err(|e| e.to_string())?;
+        let user = CString::new(user).map_err(|e| e.to_string())?;
+        let password =
+            CString::new(password).map_err(|_| "the password contains a NUL byte".to_string())?;
+        let conv = PamConv {
+            conv: converse,
+            appdata_ptr: password.as_ptr() as *mut c_void,
+        };
+        let mut pamh = std::ptr::null_mut();
+        let result = unsafe {
+            let status = pam_start(service.as_ptr(), user.as_ptr(), &conv, &mut pamh);
+            if status != PAM_SUCCESS {
+                return Err(format!("pam_start failed with {}", status));
+            }
+            let status = pam_authenticate(pamh, 0);
+            let result = match status {
+                PAM_SUCCESS => Ok(()),
+                _ => Err(CStr::from_ptr(pam_strerror(pamh, status))
+                    .to_string_lossy()
+                    .into_owned()),
+            };
+            pam_end(pamh, status);
+            result
+        };
+        super::wipe(password.into_bytes());
+        result
+    }
+
+    /// Turns key codes into key symbols and text, with the keymap of the compositor.
+    pub struct Keyboard {
+        context: *mut c_void,
+        keymap: *mut c_void,
+        state: *mut c_void,
+    }
+
+    // Only used behind the mutex of the locker
+    unsafe impl Send for Keyboard {}
+
+    impl Keyboard {
+        pub fn new(fd: OwnedFd, size: u32) -> Result<Self, String> {
+            let mut keymap = Vec::with_capacity(size as usize);
+            File::from(fd)
+                .take(size.into())
+                .read_to_end(&mut keymap)
+                .map_err(|e| format!("failed to read the keymap: {}", e))?;
+            // The keymap ends with a NUL byte
+            let end = keymap.iter().position(|byte| *byte == 0);
+            keymap.truncate(end.unwrap_or(keymap.len()));
+            let keymap = CString::new(keymap).map_err(|e| e.to_string())?;
+            unsafe {
+                let context = xkb_context_new(0);
+                if context.is_null() {
+                    return Err("failed to create an xkb context".to_string());
+                }
+                let keymap = xkb_keymap_new_from_string(
+                    context,
+                    keymap.as_ptr(),
+                    XKB_KEYMAP_FORMAT_TEXT_V1,
+                    0,
+                );
+                if keymap.is_null() {
+                    xkb_context_unref(context);
+                    return Err("failed to compile the keymap".to_string());
+                }
+                Ok(Self {
+                    context,
+                    keymap,
+                    state: xkb_state_new(keymap),
+                })
+            }
+        }
+
+        pub fn update_modifiers(&mut self, depressed: u32, latched: u32, locked: u32, group: u32) {
+            unsafe {
+                xkb_state_update_mask(self.state, depressed, latched, locked, 0, 0, group);
+            }
+        }
+
+        /// The key symbol and the text of the key with the evdev code `key`.
+        pub fn key(&self, key: u32) -> (u32, String) {
+            // xkb key codes are evdev codes shifted by 8
+            let code = key + 8;
+            let mut buffer = [0 as c_char; 64];
+            unsafe {
+                let sym = xkb_state_key_get_one_sym(self.state, code);
+                let len =
+                    xkb_state_key_get_utf8(self.state, code, buffer.as_mut_ptr(), buffer.len());
+                let text = match len {
+                    1.. => CStr::from_ptr(buffer.as_ptr())
+                        .to_string_lossy()
+                        .into_owned(),
+                    _ => String::new(),
+                };
+                (sym, text)
+            }
+        }
+    }
+
+    impl Drop for Keyboard {
+        fn drop(&mut self) {
+            unsafe {
+                xkb_state_unref(self.state);
+                xkb_keymap_unref(self.keymap);
+                xkb_context_unref(self.context);
+            }
+        }
+    }
+}
+
+#[cfg(not(feature = "lock"))]
+mod imp {
+    use std::os::fd::OwnedFd;
+
+    const MISSING: &str = "sleepwatcher-rs was built without the lock feature";
+
+    pub struct Keyboard;
+
+    impl Keyboard {
+        pub fn new(_fd: OwnedFd, _size: u32) -> Result<Self, String> {
+            Err(MISSING.to_string())
+        }
+
+        pub fn update_modifiers(&mut self, _: u32, _: u32, _: u32, _: u32) {}
+
+        pub fn key(&self, _key: u32) -> (u32, String) {
+            (0, String::new())
+        }
+    }
+
+    pub fn authenticate(_service: &str, _user: &str, _password: &str) -> Result<(), String> {
+        Err(MISSING.to_string())
+    }
+}
+
+/// Overwrites a password before the memory is freed.
+fn wipe(mut bytes: Vec<u8>) {
+    bytes.fill(0);
+    std::hint::black_box(&bytes);
+}
+
+/// What the Wayland state has to dispatch for the locker.
+pub trait LockDispatch:
+    Dispatch<ext_session_lock_v1::ExtSessionLockV1, ()>
+    + Dispatch<ext_session_lock_surface_v1::ExtSessionLockSurfaceV1, u32>
+    + Dispatch<wl_surface::WlSurface, ()>
+    + Dispatch<wl_shm_pool::WlShmPool, ()>
+    + Dispatch<wl_buffer::WlBuffer, ()>
+    + 'static
+{
+}
+
+impl<D> LockDispatch for D where
+    D: Dispatch<ext_session_lock_v1::ExtSessionLockV1, ()>
+        + Dispatch<ext_session_lock_surface_v1::ExtSessionLockSurfaceV1, u32>
+        + Dispatch<wl_surface::WlSurface, ()>
+        + Dispatch<wl_shm_pool::WlShmPool, ()>
+        + Dispatch<wl_buffer::WlBuffer, ()>
+        + 'static
+{
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum LockState {
+    Unlocked,
+    /// Waiting for the compositor to confirm the lock
+    Locking,
+    Locked,
+}
+
+#[derive(Debug)]
+struct LockSurface {
+    /// Registry name of the output
+    output: u32,
+    surface: wl_surface::WlSurface,
+    lock_surface: ext_session_lock_surface_v1::ExtSessionLockSurfaceV1,
+    /// Known once the compositor configured the surface
+    size: Option<(u32, u32)>,
+}
+
+impl LockSurface {
+    fn destroy(self) {
+        self.lock_surface.destroy();
+        self.surface.destroy();
+    }
+}
+
+pub struct Locker {
+    manager: Option<ext_session_lock_manager_v1::ExtSessionLockManagerV1>,
+    compositor: Option<wl_compositor::WlCompositor>,
+    shm: Option<wl_shm::WlShm>,
+    /// Outputs by their registry name
+    outputs: HashMap<u32, wl_output::WlOutput>,
+    lock: Option<ext_session_lock_v1::ExtSessionLockV1>,
+    surfaces: Vec<LockSurface>,
+    state: LockState,
+    color: u32,
+    failed: bool,
+    keyboard: Option<imp::Keyboard>,
+    password: String,
+    /// PAM is checking a password, keys are ignored meanwhile
+    checking: bool,
+    conn: Option<Connection>,
+    tx: mpsc::Sender<Request>,
+}
+
+pub type LockerHandle = Arc<Mutex<Locker>>;
+
+impl fmt::Debug for Locker {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Leaves out the password
+        f.debug_struct("Locker")
+            .field("state", &self.state)
+            .field("surfaces", &self.surfaces)
+            .field("color", &self.color)
+            .field("failed", &self.failed)
+            .field("checking", &self.checking)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Covers a lock surface with `color` in a new shared memory buffer.
+fn draw<D: LockDispatch>(
+    shm: &wl_shm::WlShm,
+    qh: &QueueHandle<D>,
+    surface: &LockSurface,
+    color: u32,
+) -> anyhow::Result<()> {
+    let Some((width, height)) = surface.size else {
+        return Ok(());
+    };
+    let pixels = vec![color; width as usize * height as usize];
+    let mut file = File::from(memfd_create(
+        c"sleepwatcher-lock",
+        MemFdCreateFlag::MFD_CLOEXEC,
+    )?);
+    file.write_all(bytemuck::cast_slice(&pixels))?;
+    let size = i32::try_from(pixels.len() * 4)?;
+    let (width, height) = (i32::try_from(width)?, i32::try_from(height)?);
+    let pool = shm.create_pool(file.as_fd(), size, qh, ());
+    let buffer = pool.create_buffer(
+        0,
+        width,
+        height,
+        width * 4,
+        wl_shm::Format::Xrgb8888,
+        qh,
+        (),
+    );
+    pool.destroy();
+    surface.surface.attach(Some(&buffer), 0, 0);
+    surface.surface.damage_buffer(0, 0, width, height);
+    surface.surface.commit();
+    Ok(())
+}
+
+impl Locker {
+    pub fn new(tx: mpsc::Sender<Request>) -> LockerHandle {
+        Arc::new(Mutex::new(Self {
+            manager: None,
+            compositor: None,
+            shm: None,
+            outputs: HashMap::new(),
+            lock: None,
+            surfaces: Vec::new(),
+            state: LockState::Unlocked,
+            color: DEFAULT_COLOR,
+            failed: false,
+            keyboard: None,
+            password: String::new(),
+            checking: false,
+            conn: None,
+            tx,
+        }))
+    }
+
+    /// Requests are also sent from the Lua and PAM threads, which have to flush them.
+    pub fn set_conn(&mut self, conn: Connection) {
+        self.conn = Some(conn);
+    }
+
+    pub fn set_manager(&mut self, manager: ext_session_lock_manager_v1::ExtSessionLockManagerV1) {
+        self.manager = Some(manager);
+    }
+
+    pub fn set_compositor(&mut self, compositor: wl_compositor::WlCompositor) {
+        self.compositor = Some(compositor);
+    }
+
+    pub fn set_shm(&mut self, shm: wl_shm::WlShm) {
+        self.shm = Some(shm);
+    }
+
+    fn flush(&self) {
+        if let Some(conn) = &self.conn {
+            if let Err(e) = conn.flush() {
+                error!("Failed to flush the lock surfaces: {}", e);
+            }
+        }
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.state != LockState::Unlocked
+    }
+
+    /// Locks the session, with the background `color` as 0xRRGGBB.
+    pub fn engage<D: LockDispatch>(
+        &mut self,
+        qh: &QueueHandle<D>,
+        color: Option<u32>,
+    ) -> Result<(), String> {
+        if !cfg!(feature = "lock") {
+            return Err("sleepwatcher-rs was built without the lock feature".to_string());
+        }
+        if self.is_locked() {
+            return Ok(());
+        }
+        let (Some(manager), Some(_), Some(_)) = (&self.manager, &self.compositor, &self.shm) else {
+            return Err("the compositor doesn't support ext-session-lock-v1".to_string());
+        };
+        if self.keyboard.is_none() {
+            return Err("no keyboard to type the password with".to_string());
+        }
+        info!("Locking the session");
+        self.lock = Some(manager.lock(qh, ()));
+        self.state = LockState::Locking;
+        self.color = color.unwrap_or(DEFAULT_COLOR);
+        self.failed = false;
+        let outputs: Vec<(u32, wl_output::WlOutput)> = self
+            .outputs
+            .iter()
+            .map(|(reg_name, wl_output)| (*reg_name, wl_output.clone()))
+            .collect();
+        for (reg_name, wl_output) in outputs {
+            self.add_surface(qh, reg_name, &wl_output);
+        }
+        self.flush();
+        Ok(())
+    }
+
+    fn add_surface<D: LockDispatch>(
+        &mut self,
+        qh: &QueueHandle<D>,
+        reg_name: u32,
+        wl_output: &wl_output::WlOutput,
+    ) {
+        let (Some(lock), Some(compositor)) = (&self.lock, &self.compositor) else {
+            return;
+        };
+        let surface = compositor.create_surface(qh, ());
+        let lock_surface = lock.get_lock_surface(&surface, wl_output, qh, reg_name);
+        self.surfaces.push(LockSurface {
+            output: reg_name,
+            surface,
+            lock_surface,
+            size: None,
+        });
+    }
+
+    /// Outputs plugged in while locked are covered as well.
+    pub fn add_output<D: LockDispatch>(
+        &mut self,
+        qh: &QueueHandle<D>,
+        reg_name: u32,
+        wl_output: wl_output::WlOutput,
+    ) {
+        if self.is_locked() {
+            self.add_surface(qh, reg_name, &wl_output);
+        }
+        self.outputs.insert(reg_name, wl_output);
+    }
+
+    pub fn remove_output(&mut self, reg_name: u32) {
+        self.outputs.remove(&reg_name);
+        let (removed, kept) = std::mem::take(&mut self.surfaces)
+            .into_iter()
+            .partition(|surface| surface.output == reg_name);
+        self.surfaces = kept;
+        removed.into_iter().for_each(LockSurface::destroy);
+    }
+
+    /// The compositor confirmed the lock, all outputs are covered.
+    pub fn locked(&mut self) {
+        info!("Session locked");
+        self.state = LockState::Locked;
+        utils::send_request(
+            &self.tx,
+            Request::Locker(Some(config::APP_NAME.to_string())),
+        );
+    }
+
+    /// The compositor refused the lock, e.g. because another locker holds it, or ended it.
+    pub fn finished(&mut self) {
+        warn!("The compositor ended the session lock");
+        let was_locked = self.state == LockState::Locked;
+        if let Some(lock) = self.lock.take() {
+            lock.destroy();
+        }
+        self.reset();
+        if was_locked {
+            utils::send_request(&self.tx, Request::Locker(None));
+        }
+    }
+
+    fn reset(&mut self) {
+        self.surfaces.drain(..).for_each(LockSurface::destroy);
+        self.state = LockState::Unlocked;
+        self.failed = false;
+        wipe(std::mem::take(&mut self.password).into_bytes());
+        self.flush();
+    }
+
+    fn unlock(&mut self) {
+        info!("Session unlocked");
+        if let Some(lock) = self.lock.take() {
+            lock.unlock_and_destroy();
+        }
+        self.reset();
+        utils::send_request(&self.tx, Request::Locker(None));
+    }
+
+    pub fn configure<D: LockDispatch>(
+        &mut self,
+        qh: &QueueHandle<D>,
+        lock_surface: &ext_session_lock_surface_v1::ExtSessionLockSurfaceV1,
+        serial: u32,
+        width: u32,
+        height: u32,
+    ) {
+        lock_surface.ack_configure(serial);
+        let color = self.current_color();
+        let Some(surface) = self
+            .surfaces
+            .iter_mut()
+            .find(|surface| &surface.lock_surface == lock_surface)
+        else {
+            return;
+        };
+        debug!(
+            "Lock surface of output {} is {}x{}",
+            surface.output, width, height
+        );
+        surface.size = Some((width, height));
+        if let Some(shm) = &self.shm {
+            if let Err(e) = draw(shm, qh, surface, color) {
+                error!("Failed to draw the lock surface: {}", e);
+            }
+        }
+    }
+
+    fn current_color(&self) -> u32 {
+        if self.failed {
+            FAILED_COLOR
+        } else {
+            self.color
+        }
+    }
+
+    fn redraw<D: LockDispatch>(&self, qh: &QueueHandle<D>) {
+        let Some(shm) = &self.shm else {
+            return;
+        };
+        for surface in &self.surfaces {
+            if let Err(e) = draw(shm, qh, surface, self.current_color()) {
+                error!("Failed to draw the lock surface: {}", e);
+            }
+        }
+        self.flush();
+    }
+
+    pub fn set_keymap(&mut self, fd: OwnedFd, size: u32) {
+        match imp::Keyboard::new(fd, size) {
+            Ok(keyboard) => self.keyboard = Some(keyboard),
+            Err(e) => debug!("No keymap for the locker: {}", e),
+        }
+    }
+
+    pub fn set_modifiers(&mut self, depressed: u32, latched: u32, locked: u32, group: u32) {
+        if let Some(keyboard) = &mut self.keyboard {
+            keyboard.update_modifiers(depressed, latched, locked, group);
+        }
+    }
+
+    /// Adds a key press to the password, or checks the password on Enter. PAM runs on a
+    /// thread of its own, since it may wait for seconds after a wrong password.
+    pub fn key_pressed<D: LockDispatch>(handle: &LockerHandle, qh: &QueueHandle<D>, key: u32) {
+        let mut locker = handle.lock().unwrap();
+        if locker.state != LockState::Locked || locker.checking {
+            return;
+        }
+        let Some(keyboard) = &locker.keyboard else {
+            return;
+        };
+        let (sym, text) = keyboard.key(key);
+        if locker.failed {
+            locker.failed = false;
+            locker.redraw(qh);
+        }
+        match sym {
+            KEY_RETURN | KEY_KP_ENTER if !locker.password.is_empty() => {
+                let password = std::mem::take(&mut locker.password);
+                locker.checking = true;
+                let (handle, qh) = (handle.clone(), qh.clone());
+                std::thread::spawn(move || {
+                    let user = User::from_uid(Uid::current())
+                        .ok()
+                        .flatten()
+                        .map(|user| user.name)
+                        .unwrap_or_default();
+                    let result = imp::authenticate(config::PAM_SERVICE, &user, &password);
+                    wipe(password.into_bytes());
+                    let mut locker = handle.lock().unwrap();
+                    locker.checking = false;
+                    match result {
+                        Ok(()) => locker.unlock(),
+                        Err(e) => {
+                            info!("Unlocking failed: {}", e);
+                            locker.failed = true;
+                            locker.redraw(&qh);
+                        }
+                    }
+                });
+            }
+            KEY_BACKSPACE => {
+                locker.password.pop();
+            }
+            KEY_ESCAPE => wipe(std::mem::take(&mut locker.password).into_bytes()),
+            _ => locker.password.push_str(&text),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct LockHelpers<D: 'static> {
+    pub locker: LockerHandle,
+    /// `None` without a Wayland compositor
+    pub qh: Option<QueueHandle<D>>,
+}
+
+impl<D: LockDispatch> UserData for LockHelpers<D> {
+    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_method("engage", |_lua, this, options: Option<Table>| {
+            let color = match options {
+                Some(options) => options
+                    .get::<_, Option<String>>("color")?
+                    .map(|color| {
+                        u32::from_str_radix(color.trim_start_matches('#'), 16)
+                            .map_err(|_| format!("invalid color {}, expected #RRGGBB", color))
+                    })
+                    .transpose()
+                    .map_err(mlua::Error::RuntimeError)?,
+                None => None,
+            };
+            let Some(qh) = &this.qh else {
+                return Err(mlua::Error::RuntimeError(
+                    "the built-in locker needs a Wayland compositor".to_string(),
+                ));
+            };
+            this.locker
+                .lock()
+                .unwrap()
+                .engage(qh, color)
+                .map_err(mlua::Error::RuntimeError)
+        });
+        methods.add_method("locked", |_lua, this, (): ()| {
+            Ok(this.locker.lock().unwrap().is_locked())
+        });
+    }
+}
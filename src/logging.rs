@@ -0,0 +1,87 @@
+//! Setup of the logger from `-v`, `-q` and `--log-file`, and the `log` table of the config.
+//! Under systemd every line carries its syslog priority, so journald files messages of the
+//! daemon and of the config with the right level, e.g. `journalctl --user -p warning`.
+//! zbus logs through `tracing`, whose `log` feature forwards its events here, so
+//! `RUST_LOG=zbus=debug` shows the D-Bus traffic next to the daemon's messages.
+
+use env_logger::{Builder, Env, Target};
+use log::{Level, LevelFilter};
+use mlua::{Lua, Table};
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::Path;
+
+/// Target of the messages logged by the config
+const LUA_TARGET: &str = "lua";
+
+/// Info by default, every `-v` is one level more and every `-q` one level less.
+fn level_filter(verbose: u8, quiet: u8) -> LevelFilter {
+    match 3 + i16::from(verbose) - i16::from(quiet) {
+        i16::MIN..=0 => LevelFilter::Off,
+        1 => LevelFilter::Error,
+        2 => LevelFilter::Warn,
+        3 => LevelFilter::Info,
+        4 => LevelFilter::Debug,
+        _ => LevelFilter::Trace,
+    }
+}
+
+/// syslog priority prefix that journald strips from the line
+fn priority(level: Level) -> u8 {
+    match level {
+        Level::Error => 3,
+        Level::Warn => 4,
+        Level::Info => 6,
+        Level::Debug | Level::Trace => 7,
+    }
+}
+
+/// `RUST_LOG` still selects levels per module, `-v` and `-q` override the overall level.
+pub fn init(verbose: u8, quiet: u8, log_file: Option<&Path>) -> anyhow::Result<()> {
+    let mut builder = Builder::from_env(Env::default().default_filter_or("info"));
+    if verbose > 0 || quiet > 0 {
+        builder.filter_level(level_filter(verbose, quiet));
+    }
+    match log_file {
+        Some(path) => {
+            let file = OpenOptions::new().create(true).append(true).open(path)?;
+            builder.target(Target::Pipe(Box::new(file)));
+        }
+        None if std::env::var_os("JOURNAL_STREAM").is_some() => {
+            // journald adds the time itself
+            builder.format(|buf, record| {
+                writeln!(
+                    buf,
+                    "<{}>{}: {}",
+                    priority(record.level()),
+                    record.target(),
+                    record.args()
+                )
+            });
+        }
+        None => {}
+    }
+    builder.init();
+    Ok(())
+}
+
+/// `log.error`, `log.warn`, `log.info` and `log.debug` for the config, logged with the
+/// `lua` target, so `RUST_LOG=lua=debug` shows the debug messages of the config only.
+pub fn lua_table(lua: &Lua) -> mlua::Result<Table<'_>> {
+    let table = lua.create_table()?;
+    for (name, level) in [
+        ("error", Level::Error),
+        ("warn", Level::Warn),
+        ("info", Level::Info),
+        ("debug", Level::Debug),
+    ] {
+        table.set(
+            name,
+            lua.create_function(move |_lua, message: String| {
+                log::log!(target: LUA_TARGET, level, "{}", message);
+                Ok(())
+            })?,
+        )?;
+    }
+    Ok(table)
+}
@@ -1,36 +1,116 @@
-use clap::Parser;
-use color::{colorramp_fill, Color};
-use env_logger::{Builder, Env};
+use clap::{Parser, Subcommand};
 use inotify::{EventMask, Inotify, WatchMask};
-use log::{debug, error, info};
-use mlua::{AnyUserDataExt, Function, Lua, UserData, UserDataMethods};
-use std::collections::HashMap;
+use log::{debug, error, info, warn};
+use mlua::{AnyUserDataExt, Lua, UserData, UserDataMethods};
+use std::collections::{BTreeMap, HashMap};
 use std::fs::{self, File};
 use std::io::Write;
 use std::{
-    path::Path,
+    path::{Path, PathBuf},
     sync::{Arc, Mutex},
+    time::{Duration, Instant},
 };
+use tokio::signal::unix::{signal, SignalKind};
 use tokio::sync::{mpsc, Mutex as TokioMutex};
 use uuid::Uuid;
-use wayland_client::backend::ReadEventsGuard;
-use wayland_client::protocol::{wl_output, wl_registry, wl_seat};
-use wayland_client::{Connection, Dispatch, EventQueue, QueueHandle};
+use wayland_client::backend::{ObjectId, ReadEventsGuard};
+use wayland_client::protocol::{
+    wl_buffer, wl_compositor, wl_keyboard, wl_output, wl_registry, wl_seat, wl_shm, wl_shm_pool,
+    wl_surface,
+};
+use wayland_client::{
+    event_created_child, Connection, Dispatch, EventQueue, Proxy, QueueHandle, WEnum,
+};
+use wayland_protocols::ext::session_lock::v1::client::{
+    ext_session_lock_manager_v1, ext_session_lock_surface_v1, ext_session_lock_v1,
+};
 use wayland_protocols::wp::idle_inhibit::zv1::client::zwp_idle_inhibitor_v1;
 use wayland_protocols::{
     ext::idle_notify::v1::client::{ext_idle_notification_v1, ext_idle_notifier_v1},
     xdg::activation::v1::client::{xdg_activation_token_v1, xdg_activation_v1},
+    xdg::xdg_output::zv1::client::{zxdg_output_manager_v1, zxdg_output_v1},
+};
+use wayland_protocols_plasma::idle::client::{org_kde_kwin_idle, org_kde_kwin_idle_timeout};
+use wayland_protocols_wlr::foreign_toplevel::v1::client::{
+    zwlr_foreign_toplevel_handle_v1, zwlr_foreign_toplevel_manager_v1,
 };
 use wayland_protocols_wlr::gamma_control::v1::client::{
     zwlr_gamma_control_manager_v1, zwlr_gamma_control_v1,
 };
+use wayland_protocols_wlr::output_power_management::v1::client::{
+    zwlr_output_power_manager_v1, zwlr_output_power_v1,
+};
+use wayland_protocols_wlr::virtual_pointer::v1::client::{
+    zwlr_virtual_pointer_manager_v1, zwlr_virtual_pointer_v1,
+};
 
+mod accessibility;
+mod activity;
+mod apps;
+mod audio;
+mod backend;
+mod backlight;
+mod battery;
+mod buttons;
+mod caffeinate;
+mod callbacks;
+mod caps;
+mod check;
+mod clock;
+mod cmdlog;
 mod color;
 mod config;
+mod daemon;
 mod dbus;
+mod dnd;
+mod escalation;
+mod events;
+mod exec;
+mod failures;
+mod fleet;
+mod handoff;
+mod heartbeat;
+mod hooks;
+mod inhibitors;
+mod ipc;
+mod jobs;
+mod journal;
+mod kiosk;
+mod lock;
+mod logging;
+mod modules;
+mod nested;
+mod nightlight;
+mod notify;
+mod options;
+mod outputs;
+mod peers;
+mod power;
+mod presentation;
+mod privileged;
+mod profiles;
+mod reload;
+mod remote;
+mod restore;
+mod sandbox;
+mod schedule;
+mod screensaver;
+mod scripts;
+mod secrets;
+mod settings;
+mod sinks;
+mod solar;
+mod suspend;
+mod system;
+mod systemd;
+mod telemetry;
+mod template;
+mod thermal;
+mod timer;
 mod types;
 mod utils;
 mod wljoywake;
+mod x11;
 
 use types::Request;
 //use wljoywake::JoystickHandler;
@@ -59,37 +139,167 @@ pub enum FileRequest {
 struct Args {
     #[arg(short, long, default_value = config::CONFIG_FILE_NAME)]
     config: String,
+    /// Write the session events as JSON lines to this named pipe, created if missing
+    #[arg(long)]
+    events_fifo: Option<PathBuf>,
+    /// Log more, `-vv` includes trace messages
+    #[arg(short, long, action = clap::ArgAction::Count)]
+    verbose: u8,
+    /// Log less, `-qq` only errors
+    #[arg(short, long, action = clap::ArgAction::Count)]
+    quiet: u8,
+    /// Append the log to this file instead of stderr
+    #[arg(long)]
+    log_file: Option<PathBuf>,
+    /// Take over from the running instance, with its inhibitors, overrides and lock
+    #[arg(long)]
+    replace: bool,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Send a command to the running daemon
+    Ctl {
+        /// Control socket of another user's daemon
+        #[arg(long)]
+        socket: Option<PathBuf>,
+        /// File with the token for commands that change something on another user's daemon
+        #[arg(long)]
+        token_file: Option<PathBuf>,
+        #[command(subcommand)]
+        cmd: ipc::CtlCommand,
+    },
+    /// Load the config and the scripts without running them, and report errors, callbacks
+    /// that aren't defined and invalid timeouts
+    Check {
+        /// Config file to check instead of the configured one
+        path: Option<PathBuf>,
+    },
+    /// Write a systemd user unit that starts the daemon with the graphical session
+    InstallService {
+        /// Print the unit instead of writing it
+        #[arg(long)]
+        stdout: bool,
+        /// Replace an existing unit
+        #[arg(long)]
+        force: bool,
+    },
 }
 
 #[derive(Debug)]
 struct State {
-    wl_seat: Option<wl_seat::WlSeat>,
+    seats: backend::SeatsHandle,
+    /// Keyboard of the default seat, only used by the built-in locker
+    keyboard: Option<wl_keyboard::WlKeyboard>,
     qh: QueueHandle<State>,
     idle_notifier: Option<ext_idle_notifier_v1::ExtIdleNotifierV1>,
-    notification_list: NotificationListHandle,
-    dbus_handlers: CallbackListHandle,
+    /// Idle protocol of compositors without ext-idle-notify-v1
+    kde_idle: Option<org_kde_kwin_idle::OrgKdeKwinIdle>,
+    /// Picked once the globals are known
+    backend: Option<backend::BackendHandle>,
     tx: mpsc::Sender<Request>,
-    lua: LuaHandle,
     outputs: HashMap<u32, Output>,
+    toplevels: HashMap<ObjectId, Toplevel>,
+    /// Every global announced by the compositor with its version
+    globals: BTreeMap<String, u32>,
+    virtual_pointer_manager: Option<zwlr_virtual_pointer_manager_v1::ZwlrVirtualPointerManagerV1>,
+    gamma_manager: Option<zwlr_gamma_control_manager_v1::ZwlrGammaControlManagerV1>,
+    xdg_output_manager: Option<zxdg_output_manager_v1::ZxdgOutputManagerV1>,
+    /// Set once the config is loaded, outputs that come and go after are announced to it
+    hotplug: bool,
+    shared: Shared,
 }
 
+/// Handles to the state shared by the Wayland thread, the Lua config and the command loop.
 #[derive(Clone, Debug)]
-struct NotificationContext {
-    uuid: Uuid,
+struct Shared {
+    lua: LuaHandle,
+    /// Runs the Lua callbacks, off the request loop
+    callbacks: callbacks::Callbacks,
+    notification_list: NotificationListHandle,
+    dbus_handlers: CallbackListHandle,
+    scheduler: schedule::SchedulerHandle,
+    dnd: dnd::DndHandle,
+    notifier: notify::NotifierHandle,
+    apps: apps::AppRulesHandle,
+    settings: Arc<settings::Settings>,
+    failures: failures::FailureTrackerHandle,
+    streams: exec::StreamsHandle,
+    status: daemon::StatusHandle,
+    hooks: hooks::HooksHandle,
+    screensaver: screensaver::ScreensaverHandle,
+    kiosk: kiosk::KioskHandle,
+    battery: battery::BatteryHandle,
+    thermal: thermal::ThermalHandle,
+    jobs: jobs::JobsHandle,
+    caffeine: caffeinate::CaffeineHandle,
+    nightlight: nightlight::NightLightHandle,
+    outputs: outputs::OutputsHandle,
+    locker: lock::LockerHandle,
+    timers: timer::TimersHandle,
+    events: events::EventsHandle,
+    options: options::OptionsHandle,
+    peers: peers::PeersHandle,
+    accessibility: accessibility::AccessibilityHandle,
+    inhibitors: inhibitors::InhibitorsHandle,
+    escalation: escalation::EscalationHandle,
+    activity: activity::ActivityHandle,
+    audio: audio::AudioHandle,
+    backlight: backlight::BacklightHandle,
+    caps: caps::CapsHandle,
+    restore: restore::RestoreHandle,
+    profiles: profiles::ProfilesHandle,
+    solar: solar::SolarHandle,
+    clock: clock::Clock,
+    /// The idle backend, once one is up
+    idle_backend: Arc<Mutex<Option<backend::BackendHandle>>>,
+}
+
+#[derive(Debug)]
+struct IdleNotification {
+    fn_name: String,
+    /// Timeout in seconds as requested by the config, before any app rule is applied
+    timeout: i32,
+    /// `fn_name` is a maintenance job instead of a Lua function
+    job: bool,
+    /// Idled without a Resumed since
+    idled: bool,
+    /// Set up by the config before a reload, destroyed unless the new config asks for the
+    /// same notification
+    stale: bool,
+    /// Seat of the notification, the default seat if `None`
+    seat: Option<String>,
+    /// Time the callback may run before it's aborted, `callback_timeout_secs` if `None`
+    callback_timeout: Option<Duration>,
+    /// `idled` or `resumed`, and when, for `ctl status`
+    last_event: Option<(&'static str, chrono::DateTime<chrono::Local>)>,
+    notification: Box<dyn backend::IdleWatch>,
+}
+
+#[derive(Debug, Default)]
+struct Toplevel {
+    app_id: Option<String>,
+    activated: bool,
+    output: Option<ObjectId>,
 }
 
+#[derive(Clone)]
 struct MyLuaFunctions {
-    wl_seat: Option<wl_seat::WlSeat>,
-    qh: QueueHandle<State>,
-    idle_notifier: Option<ext_idle_notifier_v1::ExtIdleNotifierV1>,
+    backend: Option<backend::BackendHandle>,
     tx: mpsc::Sender<Request>,
     notification_list: NotificationListHandle,
+    apps: apps::AppRulesHandle,
+    accessibility: accessibility::AccessibilityHandle,
+    allow_exec: bool,
     //gamma_control: Option<zwlr_gamma_control_v1::ZwlrGammaControlV1>,
 }
 
 #[derive(Clone, Debug)]
 struct LuaHelpers {
     on_battery: bool,
+    allow_exec: bool,
 }
 
 #[derive(Clone, Debug)]
@@ -102,9 +312,11 @@ pub struct Output {
     reg_name: u32,
     wl_output: wl_output::WlOutput,
     name: Option<String>,
-    color: Color,
-    ramp_size: usize,
-    color_changed: bool,
+    description: Option<String>,
+    /// Names outputs of compositors before wl_output 4
+    xdg_output: Option<zxdg_output_v1::ZxdgOutputV1>,
+    /// The config was told about the output, by `output-added` or at the start
+    announced: bool,
 }
 
 impl UserData for DbusHandler {
@@ -118,20 +330,37 @@ impl UserData for DbusHandler {
         methods.add_method("LockHandler", |_lua, this, fn_name: String| {
             debug!("LcokHandler callback");
             let mut map = this.handlers.lock().unwrap();
-            map.insert("LockHandler".to_string(), fn_name);
+            map.insert("Lock".to_string(), fn_name);
             Ok(())
         });
         methods.add_method("UnlockHandler", |_lua, this, fn_name: String| {
             debug!("UnlcokHandler callback");
             let mut map = this.handlers.lock().unwrap();
-            map.insert("UnlockHandler".to_string(), fn_name);
+            map.insert("Unlock".to_string(), fn_name);
+            Ok(())
+        });
+        methods.add_method("on_sleep", |_lua, this, fn_name: String| {
+            debug!("on_sleep callback");
+            let mut map = this.handlers.lock().unwrap();
+            map.insert("PrepareSleep".to_string(), fn_name);
+            Ok(())
+        });
+        methods.add_method("on_resume", |_lua, this, fn_name: String| {
+            debug!("on_resume callback");
+            let mut map = this.handlers.lock().unwrap();
+            map.insert("Wakeup".to_string(), fn_name);
+            Ok(())
+        });
+        methods.add_method("on_shutdown", |_lua, this, fn_name: String| {
+            debug!("on_shutdown callback");
+            let mut map = this.handlers.lock().unwrap();
+            map.insert("PrepareShutdown".to_string(), fn_name);
             Ok(())
         });
     }
 }
 
-type NotificationListHandle =
-    Arc<Mutex<HashMap<Uuid, (String, ext_idle_notification_v1::ExtIdleNotificationV1)>>>;
+type NotificationListHandle = Arc<Mutex<HashMap<Uuid, IdleNotification>>>;
 
 type CallbackListHandle = Arc<Mutex<HashMap<String, String>>>;
 type LuaHandle = Arc<Mutex<Lua>>;
@@ -151,6 +380,166 @@ impl UserData for LuaHelpers {
             info!("{}", message);
             Ok(())
         });
+        methods.add_method(
+            "spawn",
+            |lua,
+             this,
+             (program, args, options): (String, Option<Vec<String>>, Option<mlua::Table>)| {
+                this.check_exec()?;
+                exec::spawn(lua, program, args.unwrap_or_default(), options)
+            },
+        );
+        methods.add_method(
+            "spawn_shell",
+            |lua, this, (cmd, options): (String, Option<mlua::Table>)| {
+                this.check_exec()?;
+                let Some(mut words) = shlex::split(&cmd) else {
+                    return Err(mlua::Error::RuntimeError(format!(
+                        "unbalanced quotes in {}",
+                        cmd
+                    )));
+                };
+                if words.is_empty() {
+                    return Err(mlua::Error::RuntimeError("empty command".to_string()));
+                }
+                let program = words.remove(0);
+                exec::spawn(lua, program, words, options)
+            },
+        );
+    }
+}
+
+impl LuaHelpers {
+    fn check_exec(&self) -> mlua::Result<()> {
+        if self.allow_exec {
+            Ok(())
+        } else {
+            Err(mlua::Error::RuntimeError(
+                "running commands is disabled by the sandbox policy".to_string(),
+            ))
+        }
+    }
+}
+
+impl MyLuaFunctions {
+    /// Creates an idle notification that calls the Lua function `fn_name`, or drives the
+    /// maintenance job `fn_name` when `job` is set. Returns the id of the notification.
+    fn watch_idle(
+        &self,
+        fn_name: String,
+        timeout: i32,
+        job: bool,
+        seat: Option<String>,
+        callback_timeout: Option<Duration>,
+    ) -> Option<Uuid> {
+        let Some(backend) = &self.backend else {
+            error!("Can't watch for {}: no idle backend is available", fn_name);
+            return None;
+        };
+        // A reload keeps the notifications that didn't change
+        if let Some((uuid, entry)) =
+            self.notification_list
+                .lock()
+                .unwrap()
+                .iter_mut()
+                .find(|(_, entry)| {
+                    entry.stale
+                        && entry.fn_name == fn_name
+                        && entry.timeout == timeout
+                        && entry.job == job
+                        && entry.seat == seat
+                })
+        {
+            debug!(
+                "Keeping notification fn: {} timeout: {} seconds",
+                fn_name, timeout
+            );
+            entry.stale = false;
+            entry.callback_timeout = callback_timeout;
+            return Some(*uuid);
+        }
+        let uuid = generate_uuid();
+
+        debug!(
+            "get_notification id: {} fn: {} timeout: {} seconds",
+            uuid, fn_name, timeout
+        );
+        let multiplier = timeout_multiplier(&self.apps, &self.accessibility);
+        let notification =
+            backend.watch(uuid, scaled_timeout(timeout, multiplier), seat.as_deref());
+
+        {
+            let mut map = self.notification_list.lock().unwrap();
+            map.insert(
+                uuid,
+                IdleNotification {
+                    fn_name,
+                    timeout,
+                    job,
+                    idled: false,
+                    stale: false,
+                    seat,
+                    callback_timeout,
+                    last_event: None,
+                    notification,
+                },
+            );
+        }
+
+        Some(uuid)
+    }
+
+    /// Stops the notification `uuid`. Returns false if it doesn't exist (anymore).
+    fn cancel_idle(&self, uuid: Uuid) -> bool {
+        let Some(entry) = self.notification_list.lock().unwrap().remove(&uuid) else {
+            return false;
+        };
+        debug!("Cancelling notification fn: {}", entry.fn_name);
+        entry.notification.destroy();
+        if let Some(backend) = &self.backend {
+            backend.flush();
+        }
+        true
+    }
+
+    /// Starts the idle period of the notification `uuid` over, with a new timeout if given.
+    fn rearm_idle(&self, uuid: Uuid, timeout: Option<i32>) -> bool {
+        let Some(backend) = &self.backend else {
+            return false;
+        };
+        let multiplier = timeout_multiplier(&self.apps, &self.accessibility);
+        {
+            let mut map = self.notification_list.lock().unwrap();
+            let Some(entry) = map.get_mut(&uuid) else {
+                return false;
+            };
+            if let Some(timeout) = timeout {
+                entry.timeout = timeout;
+            }
+            debug!(
+                "Re-arming notification fn: {} timeout: {} seconds",
+                entry.fn_name, entry.timeout
+            );
+            entry.notification.destroy();
+            entry.notification = backend.watch(
+                uuid,
+                scaled_timeout(entry.timeout, multiplier),
+                entry.seat.as_deref(),
+            );
+            entry.idled = false;
+        }
+        backend.flush();
+        true
+    }
+
+    fn check_exec(&self) -> mlua::Result<()> {
+        if self.allow_exec {
+            Ok(())
+        } else {
+            Err(mlua::Error::RuntimeError(
+                "running commands is disabled by the sandbox policy".to_string(),
+            ))
+        }
     }
 }
 
@@ -158,59 +547,371 @@ impl UserData for MyLuaFunctions {
     fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
         methods.add_method(
             "get_notification",
-            |_lua, this, (timeout, fn_name): (i32, String)| {
-                let ctx = NotificationContext {
-                    uuid: generate_uuid(),
-                };
-
-                debug!(
-                    "get_notification id: {} fn: {} timeout: {} seconds",
-                    ctx.uuid, fn_name, timeout
-                );
-                let notification = this.idle_notifier.as_ref().unwrap().get_idle_notification(
-                    (timeout * 1000).try_into().unwrap(),
-                    this.wl_seat.as_ref().unwrap(),
-                    &this.qh,
-                    ctx.clone(),
-                );
-
-                {
-                    let mut map = this.notification_list.lock().unwrap();
-                    map.insert(ctx.uuid, (fn_name, notification));
+            |_lua, this, (timeout, fn_name, options): (i32, String, Option<mlua::Table>)| {
+                // Zero and negative timeouts would fire right away
+                if timeout <= 0 {
+                    return Err(mlua::Error::RuntimeError(format!(
+                        "invalid timeout {}",
+                        timeout
+                    )));
                 }
-
-                Ok(())
+                let (seat, callback_timeout) = match options {
+                    Some(options) => (
+                        options.get::<_, Option<String>>("seat")?,
+                        options.get::<_, Option<f64>>("callback_timeout")?,
+                    ),
+                    None => (None, None),
+                };
+                let callback_timeout = match callback_timeout {
+                    Some(secs) if secs > 0.0 => Some(Duration::from_secs_f64(secs)),
+                    Some(secs) => {
+                        return Err(mlua::Error::RuntimeError(format!(
+                            "invalid callback_timeout {}",
+                            secs
+                        )))
+                    }
+                    None => None,
+                };
+                Ok(this
+                    .watch_idle(fn_name, timeout, false, seat, callback_timeout)
+                    .map(|uuid| NotificationHandle {
+                        uuid,
+                        functions: this.clone(),
+                    }))
             },
         );
         methods.add_method("run", |_lua, this, command: String| {
-            let tx = this.tx.clone();
-            std::thread::spawn(move || {
-                tx.blocking_send(Request::Run(command.to_string())).unwrap();
-            });
+            this.check_exec()?;
+            utils::send_request(&this.tx, Request::Run(command));
             Ok(())
         });
         methods.add_method("run_once", |_lua, this, command: String| {
-            let tx = this.tx.clone();
-            std::thread::spawn(move || {
-                tx.blocking_send(Request::RunOnce(command.to_string()))
-                    .unwrap();
-            });
+            this.check_exec()?;
+            utils::send_request(&this.tx, Request::RunOnce(command));
             Ok(())
         });
     }
 }
 
+/// Returned by `IdleNotifier:get_notification`, to change the notification at runtime.
+#[derive(Clone)]
+struct NotificationHandle {
+    uuid: Uuid,
+    functions: MyLuaFunctions,
+}
+
+impl UserData for NotificationHandle {
+    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_method("cancel", |_lua, this, (): ()| {
+            Ok(this.functions.cancel_idle(this.uuid))
+        });
+        methods.add_method("set_timeout", |_lua, this, timeout: i32| {
+            if timeout <= 0 {
+                return Err(mlua::Error::RuntimeError(format!(
+                    "invalid timeout {}",
+                    timeout
+                )));
+            }
+            Ok(this.functions.rearm_idle(this.uuid, Some(timeout)))
+        });
+        methods.add_method("rearm", |_lua, this, (): ()| {
+            Ok(this.functions.rearm_idle(this.uuid, None))
+        });
+    }
+}
+
 fn generate_uuid() -> uuid::Uuid {
     Uuid::new_v4()
 }
 
-pub async fn wayland_run(
-    lua: LuaHandle,
+/// What the config set up, to tell what a reload changed.
+fn reload_snapshot(shared: &Shared) -> reload::Snapshot {
+    let mut notifications: BTreeMap<String, Vec<i32>> = BTreeMap::new();
+    for entry in shared.notification_list.lock().unwrap().values() {
+        let name = match entry.job {
+            true => format!("job {}", entry.fn_name),
+            false => entry.fn_name.clone(),
+        };
+        notifications.entry(name).or_default().push(entry.timeout);
+    }
+    for timeouts in notifications.values_mut() {
+        timeouts.sort();
+    }
+    reload::Snapshot {
+        notifications,
+        schedules: shared
+            .scheduler
+            .lock()
+            .unwrap()
+            .summary()
+            .into_iter()
+            .collect(),
+        commands: shared.jobs.lock().unwrap().commands(),
+        multiplier: timeout_multiplier(&shared.apps, &shared.accessibility),
+    }
+}
+
+/// Converts a timeout in seconds to the milliseconds expected by the idle backends.
+fn scaled_timeout(timeout: i32, multiplier: f64) -> u32 {
+    (timeout as f64 * multiplier * 1000.0) as u32
+}
+
+/// Factor all idle timeouts are scaled with, from the app rules and the screen reader policy.
+fn timeout_multiplier(
+    apps: &apps::AppRulesHandle,
+    accessibility: &accessibility::AccessibilityHandle,
+) -> f64 {
+    apps.lock().unwrap().multiplier() * accessibility.lock().unwrap().multiplier()
+}
+
+/// Recreates all idle notifications, e.g. after the timeout multiplier changed. The
+/// notifications keep their id, so the Lua callbacks stay attached.
+fn rearm_notifications(state: &State) {
+    rearm_seat_notifications(state, |_| true);
+}
+
+/// Recreates the idle notifications `affected` picks, e.g. after their seat came or went.
+fn rearm_seat_notifications(state: &State, affected: impl Fn(&IdleNotification) -> bool) {
+    if let Some(backend) = &state.backend {
+        recreate_notifications(backend.as_ref(), &state.shared, affected);
+    }
+}
+
+fn recreate_notifications(
+    backend: &dyn backend::Backend,
+    shared: &Shared,
+    affected: impl Fn(&IdleNotification) -> bool,
+) {
+    let multiplier = timeout_multiplier(&shared.apps, &shared.accessibility);
+    let mut map = shared.notification_list.lock().unwrap();
+    for (uuid, entry) in map.iter_mut().filter(|(_, entry)| affected(entry)) {
+        entry.notification.destroy();
+        entry.notification = backend.watch(
+            *uuid,
+            scaled_timeout(entry.timeout, multiplier),
+            entry.seat.as_deref(),
+        );
+    }
+}
+
+/// Recreates all idle notifications, after the timeout multiplier changed or a wake. The
+/// backend is flushed, since this runs outside the Wayland thread, which isn't woken up to
+/// send the new notifications.
+fn rearm_all(shared: &Shared) {
+    let backend = shared.idle_backend.lock().unwrap().clone();
+    if let Some(backend) = backend {
+        recreate_notifications(backend.as_ref(), shared, |_| true);
+        backend.flush();
+    }
+}
+
+/// Idle timers can be skewed by the time spent suspended and fire right after the user
+/// unlocked, so the notifications are recreated after a wake. Stages that were idle get their
+/// resume callback, since the notifications that would report the user's return are gone.
+fn rearm_after_wake(shared: &Shared, tx: &mpsc::Sender<Request>) {
+    if shared.idle_backend.lock().unwrap().is_none() {
+        return;
+    }
+    let idled = take_idled(shared);
+    let away = shared.status.lock().unwrap().resumed();
+    rearm_all(shared);
+    info!("Idle notifications re-armed after waking up");
+    if let Some(away) = away {
+        user_returned(shared, away);
+    }
+    call_resumed(shared, tx, idled);
+}
+
+/// The stages that idled without a resume since, as function name and whether it's a job.
+/// They count as resumed from here on.
+fn take_idled(shared: &Shared) -> Vec<(String, bool)> {
+    shared
+        .notification_list
+        .lock()
+        .unwrap()
+        .values_mut()
+        .filter(|entry| entry.idled)
+        .map(|entry| {
+            entry.idled = false;
+            (entry.fn_name.clone(), entry.job)
+        })
+        .collect()
+}
+
+/// Calls the resume callbacks of stages whose notifications can't report the resume anymore.
+fn call_resumed(shared: &Shared, tx: &mpsc::Sender<Request>, idled: Vec<(String, bool)>) {
+    for (fn_name, job) in idled {
+        shared.restore.lock().unwrap().restore(Some(&fn_name), tx);
+        if job {
+            utils::send_request(tx, Request::JobResumed(fn_name));
+            continue;
+        }
+        let hooks = shared.hooks.clone();
+        shared.callbacks.run(move |lua| {
+            callbacks::call::<_, ()>(lua, &hooks, &fn_name, "resumed");
+        });
+    }
+}
+
+/// Lock bookkeeping for logind's `Lock` signal and locker processes: tells the peers, starts
+/// the escalation stages and publishes the `lock` event, once per lock.
+fn session_locked(shared: &Shared, tx: &mpsc::Sender<Request>) {
+    {
+        let mut escalation = shared.escalation.lock().unwrap();
+        if escalation.is_locked() {
+            return;
+        }
+        shared.status.lock().unwrap().locked();
+        shared
+            .peers
+            .lock()
+            .unwrap()
+            .broadcast(peers::PeerEvent::Lock);
+        if let Some(session) = escalation.locked() {
+            tokio::spawn(escalation::run(
+                session,
+                Duration::ZERO,
+                shared.escalation.clone(),
+                shared.status.clone(),
+                tx.clone(),
+            ));
+        }
+    }
+    events::publish(
+        &shared.callbacks,
+        &shared.hooks,
+        &shared.events,
+        events::Event::Lock,
+        (),
+        serde_json::Value::Null,
+    );
+}
+
+/// Locks with `lock_cmd` of the options, for configs without a lock handler. Input within the
+/// grace period cancels the lock, `lock_warning` is shown meanwhile.
+fn run_lock_cmd(shared: &Shared, tx: &mpsc::Sender<Request>) {
+    let (lock_cmd, grace, warning) = {
+        let options = shared.options.lock().unwrap();
+        (options.lock_cmd(), options.grace(), options.lock_warning())
+    };
+    let Some(lock_cmd) = lock_cmd else {
+        debug!("No lock handler or lock_cmd for Lock");
+        return;
+    };
+    if grace.is_zero() {
+        utils::send_request(tx, Request::RunOnce(lock_cmd));
+        return;
+    }
+    let requested = chrono::Local::now();
+    let (status, dnd, tx) = (shared.status.clone(), shared.dnd.clone(), tx.clone());
+    tokio::spawn(async move {
+        let warning = match warning {
+            Some(summary) => {
+                let notification = notify::Notification {
+                    summary,
+                    timeout: Some(grace),
+                    ..Default::default()
+                };
+                notify::notify(&dnd, &notification)
+                    .await
+                    .inspect_err(|e| error!("Failed to send the lock warning: {}", e))
+                    .ok()
+                    .flatten()
+            }
+            None => None,
+        };
+        tokio::time::sleep(grace).await;
+        if let Some(id) = warning {
+            notify::close_later(id);
+        }
+        let active = status
+            .lock()
+            .unwrap()
+            .last_activity_by_seat()
+            .values()
+            .any(|last| *last > requested);
+        if active {
+            info!("Input within the grace period, not locking");
+            return;
+        }
+        let _ = tx.send(Request::RunOnce(lock_cmd)).await;
+    });
+}
+
+fn session_unlocked(shared: &Shared) {
+    {
+        let mut escalation = shared.escalation.lock().unwrap();
+        if !escalation.is_locked() {
+            return;
+        }
+        escalation.unlocked();
+    }
+    shared
+        .peers
+        .lock()
+        .unwrap()
+        .broadcast(peers::PeerEvent::Unlock);
+    events::publish(
+        &shared.callbacks,
+        &shared.hooks,
+        &shared.events,
+        events::Event::Unlock,
+        (),
+        serde_json::Value::Null,
+    );
+    let away = shared.status.lock().unwrap().unlocked();
+    if let Some(away) = away {
+        user_returned(shared, away);
+    }
+}
+
+/// Runs the `on_return` hooks, and tells how long the user was away if the settings ask for it.
+fn user_returned(shared: &Shared, away: daemon::Away) {
+    info!("User back after {}s", away.away.as_secs());
+    notify::user_active(&shared.notifier);
+    let hooks = shared.hooks.clone();
+    let returned = away.clone();
+    shared
+        .callbacks
+        .run(move |lua| hooks::run_return_hooks(lua, &hooks, &returned));
+    let Some(min_secs) = shared.settings.hooks.notify_return_after_secs else {
+        return;
+    };
+    if away.away.as_secs() < min_secs {
+        return;
+    }
+    let summary = format!("Away {}", format_minutes(away.away));
+    let mut body = format!("Since {}", away.since.format("%H:%M"));
+    if !away.locked.is_zero() {
+        body = format!("{}, locked for {}", body, format_minutes(away.locked));
+    }
+    let dnd = shared.dnd.clone();
+    tokio::spawn(async move {
+        if let Err(e) = notify::send(&dnd, &summary, &body).await {
+            error!("Failed to send notification: {}", e);
+        }
+    });
+}
+
+/// E.g. "47 minutes" or "2 hours 5 minutes".
+fn format_minutes(duration: Duration) -> String {
+    let minutes = duration.as_secs() / 60;
+    let plural = |n: u64, unit: &str| format!("{} {}{}", n, unit, if n == 1 { "" } else { "s" });
+    match (minutes / 60, minutes % 60) {
+        (0, minutes) => plural(minutes, "minute"),
+        (hours, 0) => plural(hours, "hour"),
+        (hours, minutes) => format!("{} {}", plural(hours, "hour"), plural(minutes, "minute")),
+    }
+}
+
+/// Connects to the compositor, loads the config once the globals are known and returns what
+/// was detected for the `on_start` hooks, what reloads need to set up a fresh Lua state, and
+/// whether the config loaded.
+async fn wayland_run(
     tx: mpsc::Sender<Request>,
-    notification_list: NotificationListHandle,
-    dbus_handlers: CallbackListHandle,
-) -> anyhow::Result<(), anyhow::Error> {
-    let conn = Connection::connect_to_env().unwrap();
+    shared: Shared,
+) -> anyhow::Result<(hooks::StartContext, LuaEnv, bool), anyhow::Error> {
+    let conn = Connection::connect_to_env()?;
     let mut event_queue: EventQueue<State> = conn.new_event_queue();
     let qhandle = event_queue.handle();
 
@@ -218,20 +919,153 @@ pub async fn wayland_run(
     display.get_registry(&qhandle, ());
 
     let mut state = State {
-        wl_seat: None,
+        seats: backend::Seats::new(),
+        keyboard: None,
         idle_notifier: None,
+        kde_idle: None,
+        backend: None,
         qh: qhandle.clone(),
-        notification_list: notification_list.clone(),
-        dbus_handlers: dbus_handlers.clone(),
         tx: tx.clone(),
-        lua,
         outputs: HashMap::new(),
+        toplevels: HashMap::new(),
+        globals: BTreeMap::new(),
+        virtual_pointer_manager: None,
+        gamma_manager: None,
+        xdg_output_manager: None,
+        hotplug: false,
+        shared,
+    };
+
+    // The first roundtrip announces the globals, the second the seat and output names
+    event_queue.roundtrip(&mut state)?;
+    event_queue.roundtrip(&mut state)?;
+    let mut wayland: Vec<backend::BackendHandle> = Vec::new();
+    match &state.idle_notifier {
+        Some(idle_notifier) => wayland.push(Arc::new(backend::WaylandIdle::new(
+            idle_notifier.clone(),
+            state.seats.clone(),
+            state.qh.clone(),
+            conn.clone(),
+        ))),
+        None => info!("The compositor does not support ext-idle-notify-v1"),
+    }
+    if let Some(kde_idle) = &state.kde_idle {
+        wayland.push(Arc::new(backend::KdeIdle::new(
+            kde_idle.clone(),
+            state.seats.clone(),
+            state.qh.clone(),
+            conn.clone(),
+        )));
+    }
+    state.backend = backend::select(&state.shared.settings.backend.order, wayland, &tx);
+    use_backend(&state.shared, state.backend.as_ref());
+    state.shared.outputs.lock().unwrap().set_conn(conn.clone());
+    state.shared.locker.lock().unwrap().set_conn(conn.clone());
+    let default_seat = state.seats.lock().unwrap().default_seat().cloned();
+    if let (Some(manager), Some(wl_seat)) = (&state.virtual_pointer_manager, &default_seat) {
+        let pointer = manager.create_virtual_pointer(Some(wl_seat), &state.qh, ());
+        state
+            .shared
+            .caffeine
+            .lock()
+            .unwrap()
+            .set_pointer(pointer, conn.clone());
+    }
+    state
+        .shared
+        .caps
+        .lock()
+        .unwrap()
+        .set_protocols(state.globals.clone());
+    let lua_env = LuaEnv {
+        backend: state.backend.clone(),
+        qh: Some(state.qh.clone()),
+        tx: state.tx.clone(),
+        shared: state.shared.clone(),
+    };
+    let loaded = match lua_init(&lua_env) {
+        Ok(()) => true,
+        Err(e) => {
+            error!("Failed to load the config: {}", e);
+            false
+        }
+    };
+    // After the config, so that outputs it excludes are never touched
+    if state.gamma_manager.is_some() {
+        state.shared.nightlight.lock().unwrap().start(conn.clone());
+    } else {
+        info!("The compositor does not support wlr-gamma-control, the night light won't change the screens");
+    }
+
+    let mut outputs: Vec<String> = state
+        .outputs
+        .values()
+        .filter_map(|output| output.name.clone())
+        .collect();
+    outputs.sort();
+    // The config finds the outputs of the start in `Outputs:list()`
+    for output in state.outputs.values_mut() {
+        output.announced = true;
+    }
+    state.hotplug = true;
+    let seats = state.seats.lock().unwrap().names();
+    let ctx = hooks::StartContext {
+        protocols: state.globals.clone(),
+        seats,
+        outputs,
+        services: BTreeMap::new(),
     };
 
     let _ = tokio::task::spawn_blocking(move || loop {
         event_queue.blocking_dispatch(&mut state).unwrap();
     });
-    Ok(())
+    Ok((ctx, lua_env, loaded))
+}
+
+/// Without a Wayland compositor, e.g. in an X11 session, the config runs with the idle
+/// backends that don't need one. The built-in locker and the Wayland protocols are missing.
+fn headless_run(tx: mpsc::Sender<Request>, shared: Shared) -> (hooks::StartContext, LuaEnv, bool) {
+    let backend = backend::select(&shared.settings.backend.order, Vec::new(), &tx);
+    use_backend(&shared, backend.as_ref());
+    let lua_env = LuaEnv {
+        backend,
+        qh: None,
+        tx,
+        shared,
+    };
+    let loaded = match lua_init(&lua_env) {
+        Ok(()) => true,
+        Err(e) => {
+            error!("Failed to load the config: {}", e);
+            false
+        }
+    };
+    (hooks::StartContext::default(), lua_env, loaded)
+}
+
+/// Tells systemd that the daemon is ready once the config loaded and an idle backend is bound,
+/// so a daemon that can't do its job fails the unit instead of running as started.
+fn notify_ready(shared: &Shared) {
+    match shared.status.lock().unwrap().backend() {
+        Some(backend) => systemd::ready(&format!("Watching idle through {}", backend)),
+        None => systemd::status("No idle backend is available"),
+    }
+}
+
+/// Shows the selected idle backend in the status and lets the screen reader support re-arm
+/// its notifications.
+fn use_backend(shared: &Shared, backend: Option<&backend::BackendHandle>) {
+    let Some(backend) = backend.cloned() else {
+        error!(
+            "No idle backend is available, idle timeouts won't work. Tried {}; the compositor \
+             needs ext-idle-notify-v1 or org_kde_kwin_idle, x11 an X server with \
+             MIT-SCREEN-SAVER and evdev read access to /dev/input",
+            shared.settings.backend.order.join(", ")
+        );
+        return;
+    };
+    shared.status.lock().unwrap().set_backend(backend.name());
+    *shared.idle_backend.lock().unwrap() = Some(backend);
 }
 
 async fn wait_for_wayland_event(
@@ -246,15 +1080,26 @@ async fn wait_for_wayland_event(
     event_queue.dispatch_pending(state).unwrap();
 }
 
-pub async fn filewatcher_run(config_path: &Path, tx: mpsc::Sender<Request>) -> anyhow::Result<()> {
+/// Reloads the config when a file in the config directory changes. A change of one of the
+/// `scripts` only reloads that script, the directories of scripts are watched for those alone.
+pub async fn filewatcher_run(
+    config_path: &Path,
+    scripts: Vec<PathBuf>,
+    tx: mpsc::Sender<Request>,
+) -> anyhow::Result<()> {
     let mut inotify = Inotify::init().expect("Error while initializing inotify instance");
 
-    debug!("Watching {:?}", config_path);
-    // Watch for modify and close events.
-    inotify
-        .watches()
-        .add(config_path, WatchMask::MODIFY)
-        .expect("Failed to add file watch");
+    let mut dirs = Vec::new();
+    for dir in scripts::watched_dirs(config_path, &scripts) {
+        debug!("Watching {:?}", dir);
+        // Watch for modify and close events.
+        let watch = inotify
+            .watches()
+            .add(&dir, WatchMask::MODIFY)
+            .expect("Failed to add file watch");
+        dirs.push((watch, dir));
+    }
+    let config_path = config_path.to_path_buf();
 
     let mut buffer = [0; 1024];
 
@@ -269,7 +1114,22 @@ pub async fn filewatcher_run(config_path: &Path, tx: mpsc::Sender<Request>) -> a
             if event.mask.contains(EventMask::MODIFY) {
                 if !event.mask.contains(EventMask::ISDIR) {
                     debug!("File modified: {:?}", event.name);
-                    tx.blocking_send(Request::Reset).unwrap();
+                    let Some(dir) = dirs
+                        .iter()
+                        .find(|(watch, _)| *watch == event.wd)
+                        .map(|(_, dir)| dir)
+                    else {
+                        continue;
+                    };
+                    let script = event
+                        .name
+                        .and_then(|name| scripts::find(&config_path, &scripts, &dir.join(name)));
+                    let request = match script {
+                        Some(script) => Request::ScriptChanged(script.clone()),
+                        None if *dir == config_path => Request::Reset,
+                        None => continue,
+                    };
+                    tx.blocking_send(request).unwrap();
                 }
             }
         }
@@ -277,283 +1137,2554 @@ pub async fn filewatcher_run(config_path: &Path, tx: mpsc::Sender<Request>) -> a
     Ok(())
 }
 
+/// During shutdown, requests are handled until none arrive for this long
+const EXIT_IDLE_GAP: Duration = Duration::from_millis(500);
+/// The sleep delay lock is released once no requests arrived for this long, so commands the
+/// `PrepareSleep` handler started, like a locker, have run
+const SLEEP_IDLE_GAP: Duration = Duration::from_millis(300);
+
 async fn process_command(
-    lua: LuaHandle,
     tx: mpsc::Sender<Request>,
     rx: &mut mpsc::Receiver<Request>,
-    shared_map: NotificationListHandle,
-    dbus_handlers: CallbackListHandle,
+    shared: Shared,
+    lua_env: Option<LuaEnv>,
 ) -> anyhow::Result<()> {
-    while let Some(event) = rx.recv().await {
+    let Shared {
+        callbacks,
+        notification_list,
+        dbus_handlers,
+        scheduler,
+        dnd,
+        apps,
+        failures,
+        streams,
+        status,
+        hooks,
+        screensaver,
+        kiosk,
+        battery,
+        thermal,
+        jobs,
+        peers,
+        nightlight,
+        inhibitors,
+        escalation,
+        activity,
+        audio,
+        backlight,
+        timers,
+        events,
+        options,
+        restore,
+        profiles,
+        settings,
+        ..
+    } = shared.clone();
+    // Set once shutdown started, requests sent by the exit hooks are still handled until then
+    let mut shutdown_deadline: Option<Instant> = None;
+    // Time spent suspended as of the last PrepareSleep, to tell the wake hooks how long it slept
+    let mut suspended_before: Option<Duration> = None;
+    // Delay lock of an imminent suspend with its deadline
+    let mut sleep_delay: Option<(zbus::zvariant::OwnedFd, Instant)> = None;
+    let mut presentation = presentation::Presentation::default();
+    // Lines of `Exec:run_stream` commands, kept out of the request channel
+    let (lines_tx, mut lines_rx) = mpsc::channel(32);
+    loop {
+        let now = Instant::now();
+        if sleep_delay
+            .as_ref()
+            .is_some_and(|(_, deadline)| *deadline <= now)
+        {
+            warn!("Sleep handlers still busy, letting the suspend proceed");
+            sleep_delay = None;
+        }
+        let wait = [
+            shutdown_deadline
+                .map(|deadline| deadline.saturating_duration_since(now).min(EXIT_IDLE_GAP)),
+            sleep_delay
+                .as_ref()
+                .map(|(_, deadline)| deadline.saturating_duration_since(now).min(SLEEP_IDLE_GAP)),
+        ]
+        .into_iter()
+        .flatten()
+        .min();
+        let next = async {
+            tokio::select! {
+                biased;
+                event = rx.recv() => event,
+                Some(line) = lines_rx.recv() => Some(line),
+            }
+        };
+        let event = match wait {
+            None => next.await,
+            Some(wait) => match tokio::time::timeout(wait, next).await {
+                Ok(event) => event,
+                Err(_) if sleep_delay.take().is_some() => {
+                    debug!("Sleep handlers done, releasing the delay lock");
+                    continue;
+                }
+                Err(_) => None,
+            },
+        };
+        let Some(event) = event else {
+            break;
+        };
         match event {
             Request::Reset => {
                 debug!("Reloading config");
+                let before = reload_snapshot(&shared);
                 {
-                    let map = shared_map.lock().unwrap();
-                    for (_, (_, notification)) in map.iter() {
-                        notification.destroy();
-                    }
+                    // Idled and held back notifications are recreated, so the new config
+                    // sees the idle period from its start
+                    let activity = activity.lock().unwrap();
+                    let mut map = notification_list.lock().unwrap();
+                    map.retain(|uuid, entry| {
+                        if entry.idled || activity.is_held(uuid) {
+                            entry.notification.destroy();
+                            return false;
+                        }
+                        entry.stale = true;
+                        true
+                    });
                 }
-                tx.send(Request::LuaReload).await.unwrap();
-            }
-            Request::LuaReload => {
-                debug!("Reloading lua config");
-                let lua = lua.lock().unwrap();
-                let _ = lua_load_config(&lua).unwrap();
+                scheduler.lock().unwrap().clear();
+                apps.lock().unwrap().clear();
+                dnd.lock().unwrap().clear();
+                streams.lock().unwrap().clear();
+                screensaver.lock().unwrap().clear();
+                kiosk.lock().unwrap().clear();
+                battery.lock().unwrap().clear();
+                thermal.lock().unwrap().clear();
+                jobs.lock().unwrap().clear();
+                peers.lock().unwrap().clear();
+                nightlight.lock().unwrap().clear();
+                inhibitors.lock().unwrap().clear();
+                escalation.lock().unwrap().clear();
+                activity.lock().unwrap().clear();
+                audio.lock().unwrap().clear();
+                backlight.lock().unwrap().cancel_fade();
+                hooks.lock().unwrap().clear();
+                timers.lock().unwrap().clear();
+                options.lock().unwrap().clear();
+                events.lock().unwrap().clear();
+                profiles.lock().unwrap().clear();
+                shared.solar.lock().unwrap().clear();
+                restore.lock().unwrap().restore(None, &tx);
+                // Inline rather than through the channel, which may be full
+                lua_reload(&shared, lua_env.as_ref(), before);
             }
+            Request::ScriptChanged(script) => script_reload(&shared, lua_env.as_ref(), &script),
             Request::LuaMethod(method_name) => {
-                let lua = lua.lock().unwrap();
-                let globals = lua.globals();
-                let map = dbus_handlers.lock().unwrap();
-                match map.get(&method_name) {
+                let kind = match method_name.as_str() {
+                    "PrepareSleep" => "prepare_sleep".to_string(),
+                    "PrepareShutdown" => "prepare_shutdown".to_string(),
+                    name => name.to_lowercase(),
+                };
+                journal::event(&kind, &format!("{} signal received", method_name), &[]);
+                // Not held while the handler runs, it may register D-Bus handlers
+                let handler = dbus_handlers.lock().unwrap().get(&method_name).cloned();
+                let stage = handler.as_deref().unwrap_or("");
+                status.lock().unwrap().set_event(&kind, stage);
+                match handler {
                     Some(fn_name) => {
-                        let fn_name = fn_name.clone();
-                        let result: Result<Function, _> = globals.get(fn_name.clone());
-                        if let Ok(lua_func) = result {
-                            let _ = lua_func.call(())?;
-                        } else {
-                            debug!("Lua function not found: {}", fn_name);
-                        }
+                        let hooks = hooks.clone();
+                        callbacks.run(move |lua| {
+                            callbacks::call::<_, ()>(lua, &hooks, &fn_name, ());
+                        });
                     }
+                    None if method_name == "Lock" => run_lock_cmd(&shared, &tx),
                     None => {
                         debug!("No dbus handler found for {}", method_name);
                     }
                 }
+                match method_name.as_str() {
+                    "Lock" => session_locked(&shared, &tx),
+                    "Unlock" => session_unlocked(&shared),
+                    "PrepareSleep" => {
+                        suspended_before = Some(suspend::suspended_time());
+                        events::publish(
+                            &callbacks,
+                            &hooks,
+                            &events,
+                            events::Event::BeforeSleep,
+                            (),
+                            serde_json::Value::Null,
+                        );
+                    }
+                    "PrepareShutdown" => events::publish(
+                        &callbacks,
+                        &hooks,
+                        &events,
+                        events::Event::BeforeShutdown,
+                        (),
+                        serde_json::Value::Null,
+                    ),
+                    "Wakeup" => {
+                        let slept = suspended_before
+                            .take()
+                            .map(|before| suspend::suspended_time().saturating_sub(before))
+                            .unwrap_or_default();
+                        rearm_after_wake(&shared, &tx);
+                        let wake_hooks = hooks.clone();
+                        callbacks.run(move |lua| {
+                            hooks::run_wake_hooks(lua, &wake_hooks, slept, "logind")
+                        });
+                        let slept = slept.as_secs();
+                        events::publish(
+                            &callbacks,
+                            &hooks,
+                            &events,
+                            events::Event::AfterResume,
+                            slept,
+                            serde_json::json!({ "slept_secs": slept }),
+                        );
+                    }
+                    _ => {}
+                }
             }
-            Request::Run(cmd) => {
-                debug!("Running command: {}", cmd);
-                let _ = utils::run(cmd).await;
+            Request::LuaCallback(fn_name) => {
+                let hooks = hooks.clone();
+                callbacks.run(move |lua| {
+                    callbacks::call::<_, ()>(lua, &hooks, &fn_name, ());
+                });
             }
-            Request::RunOnce(cmd) => {
-                debug!("Running command once: {}", cmd);
-                let _ = utils::run_once(cmd).await;
+            Request::Screensaver {
+                commands,
+                cycle,
+                stop,
+            } => {
+                let commands = {
+                    let vars = status.lock().unwrap().template_vars();
+                    commands
+                        .iter()
+                        .map(|cmd| template::expand(cmd, &vars))
+                        .collect()
+                };
+                tokio::spawn(screensaver::run(commands, cycle, stop));
             }
-            Request::OnBattery(state) => {
-                let lua = lua.lock().unwrap();
-                let globals = lua.globals();
-                let res: mlua::Result<mlua::AnyUserData> = globals.get("Helpers");
-
-                match res {
-                    Ok(helpers) => {
-                        let _ = helpers.call_method::<_, bool>("set_on_battery", state);
+            Request::Kiosk {
+                cmd,
+                reset,
+                control,
+            } => {
+                let (cmd, reset) = {
+                    let vars = status.lock().unwrap().template_vars();
+                    (
+                        template::expand(&cmd, &vars),
+                        reset.map(|reset| template::expand(&reset, &vars)),
+                    )
+                };
+                tokio::spawn(kiosk::run(cmd, reset, control));
+            }
+            Request::RunStream {
+                id,
+                cmd,
+                fn_name,
+                cancel,
+            } => {
+                let cmd = template::expand(&cmd, &status.lock().unwrap().template_vars());
+                tokio::spawn(exec::stream(
+                    id,
+                    cmd,
+                    fn_name,
+                    cancel,
+                    streams.clone(),
+                    lines_tx.clone(),
+                ));
+            }
+            Request::StreamLine(fn_name, id, line) => {
+                let hooks = hooks.clone();
+                callbacks.run(move |lua| {
+                    callbacks::call::<_, ()>(lua, &hooks, &fn_name, (line, id));
+                });
+            }
+            Request::ScreenReader(active) => {
+                let changed = shared
+                    .accessibility
+                    .lock()
+                    .unwrap()
+                    .set_screen_reader(active);
+                if changed {
+                    rearm_all(&shared);
+                }
+            }
+            Request::Lid { closed, docked } => {
+                let (event, action) = match closed {
+                    true => (events::Event::LidClosed, "closed"),
+                    false => (events::Event::LidOpened, "opened"),
+                };
+                journal::event(
+                    "lid",
+                    &format!("Lid {}, docked: {}", action, docked),
+                    &[("DOCKED", &docked.to_string())],
+                );
+                events::publish(
+                    &callbacks,
+                    &hooks,
+                    &events,
+                    event,
+                    docked,
+                    serde_json::json!({ "docked": docked }),
+                );
+            }
+            Request::OutputAdded { name, description } => {
+                journal::event(
+                    "output",
+                    &format!("Output {} added", name),
+                    &[("OUTPUT", &name)],
+                );
+                events::publish(
+                    &callbacks,
+                    &hooks,
+                    &events,
+                    events::Event::OutputAdded,
+                    (name.clone(), description.clone()),
+                    serde_json::json!({ "output": name, "description": description }),
+                );
+            }
+            Request::OutputRemoved(name) => {
+                journal::event(
+                    "output",
+                    &format!("Output {} removed", name),
+                    &[("OUTPUT", &name)],
+                );
+                events::publish(
+                    &callbacks,
+                    &hooks,
+                    &events,
+                    events::Event::OutputRemoved,
+                    name.clone(),
+                    serde_json::json!({ "output": name }),
+                );
+            }
+            Request::PowerButton => {
+                journal::event("power-button", "Power button pressed", &[]);
+                events::publish(
+                    &callbacks,
+                    &hooks,
+                    &events,
+                    events::Event::PowerButton,
+                    (),
+                    serde_json::Value::Null,
+                );
+            }
+            Request::Peer(event, host) => {
+                journal::event(
+                    "peer",
+                    &format!("Peer {} sent {}", host, event.as_str()),
+                    &[("PEER", &host), ("PEER_EVENT", event.as_str())],
+                );
+                let mirror = match event {
+                    peers::PeerEvent::Lock => settings.peers.mirror_lock,
+                    peers::PeerEvent::Unlock => settings.peers.mirror_unlock,
+                };
+                // peers_run only forwards messages with a valid MAC that weren't seen before, so
+                // this can't be triggered by a forged or replayed unlock
+                if mirror {
+                    peers.lock().unwrap().mirror(event);
+                    tokio::spawn(async move {
+                        let result = match event {
+                            peers::PeerEvent::Lock => dbus::lock_session().await,
+                            peers::PeerEvent::Unlock => dbus::unlock_session().await,
+                        };
+                        if let Err(e) = result {
+                            error!("Failed to mirror {} of a peer: {}", event.as_str(), e);
+                        }
+                    });
+                }
+                let (peers, hooks) = (peers.clone(), hooks.clone());
+                callbacks.run(move |lua| peers::run_callbacks(lua, &peers, &hooks, event, &host));
+            }
+            Request::Escalation(fn_name, locked_secs) => {
+                journal::event(
+                    "escalation",
+                    &format!("Locked for {}s, running {}", locked_secs, fn_name),
+                    &[("STAGE", &fn_name), ("LOCKED", &locked_secs.to_string())],
+                );
+                let hooks = hooks.clone();
+                callbacks.run(move |lua| {
+                    callbacks::call::<_, ()>(lua, &hooks, &fn_name, locked_secs);
+                });
+            }
+            Request::Activity(name) => {
+                if let Err(e) = activity.lock().unwrap().report(&name) {
+                    error!("{}", e);
+                    continue;
+                }
+                // Callbacks that already idled see the user coming back, and idle again once
+                // the sources are quiet for their timeout
+                let idled: Vec<(Uuid, i32)> = notification_list
+                    .lock()
+                    .unwrap()
+                    .iter()
+                    .filter(|(_, entry)| entry.idled)
+                    .map(|(uuid, entry)| (*uuid, entry.timeout))
+                    .collect();
+                for (uuid, timeout) in idled {
+                    if activity.lock().unwrap().is_held(&uuid) {
+                        continue;
                     }
-                    Err(_e) => {}
+                    idle_event(&shared, &tx, uuid, backend::IdleEvent::Resumed);
+                    activity
+                        .lock()
+                        .unwrap()
+                        .hold(uuid, Duration::from_secs(timeout.max(0) as u64));
                 }
             }
-        }
-    }
-    Ok(())
-}
-
-#[tokio::main]
-async fn main() -> anyhow::Result<()> {
-    Builder::from_env(Env::default().default_filter_or("info")).init();
-    let _ = ensure_config_file_exists(config::CONFIG_FILE_NAME);
-    // Run the event loop in a separate async task
-    let (tx, mut rx) = mpsc::channel(32);
-
-    let map: HashMap<Uuid, (String, ext_idle_notification_v1::ExtIdleNotificationV1)> =
-        HashMap::new();
-    let shared_map = Arc::new(Mutex::new(map));
-    let lua = Arc::new(Mutex::new(Lua::new()));
-    let dbus_handlers = Arc::new(Mutex::new(HashMap::new()));
-    //let joystick_handler = Arc::new(TokioMutex::new(JoystickHandler::new()));
-    //let _ = tokio::spawn(JoystickHandler::run(joystick_handler.clone())).await;
-    //let _ = tokio::spawn(JoystickHandler::udev_handler_run(joystick_handler.clone())).await;
+            Request::ActivityIdle(uuid) => {
+                let idled = notification_list
+                    .lock()
+                    .unwrap()
+                    .get(&uuid)
+                    .is_some_and(|entry| entry.idled);
+                if idled {
+                    idle_event(&shared, &tx, uuid, backend::IdleEvent::Idled);
+                }
+            }
+            Request::Idle(uuid, event) => idle_notified(&shared, &tx, uuid, event),
+            Request::OutputSequence(sequence) => {
+                tokio::spawn(outputs::power_off_sequence(
+                    sequence,
+                    shared.outputs.clone(),
+                    tx.clone(),
+                ));
+            }
+            Request::Locker(Some(name)) => {
+                info!("Locker {} is running, the session counts as locked", name);
+                session_locked(&shared, &tx);
+            }
+            Request::Locker(None) => {
+                info!("Locker exited, the session counts as unlocked");
+                session_unlocked(&shared);
+            }
+            Request::SleepDelay(delay) => {
+                sleep_delay = Some((delay, Instant::now() + dbus::BEFORE_SLEEP_TIMEOUT));
+            }
+            Request::Returned(away) => {
+                user_returned(&shared, away);
+            }
+            Request::InhibitorPoll(name) => {
+                let Some(fn_name) = inhibitors.lock().unwrap().provider(&name) else {
+                    continue;
+                };
+                let (hooks, inhibitors) = (hooks.clone(), inhibitors.clone());
+                callbacks.run(move |lua| {
+                    let active = match callbacks::call::<_, mlua::Value>(lua, &hooks, &fn_name, ())
+                    {
+                        None | Some(mlua::Value::Nil | mlua::Value::Boolean(false)) => None,
+                        Some(mlua::Value::String(why)) => {
+                            Some(why.to_str().unwrap_or_default().to_string())
+                        }
+                        Some(_) => Some(String::new()),
+                    };
+                    inhibitors.lock().unwrap().set_provided(&name, active);
+                });
+            }
+            Request::Thermal(fn_name, zone, temperature) => {
+                let hooks = hooks.clone();
+                callbacks.run(move |lua| {
+                    callbacks::call::<_, ()>(lua, &hooks, &fn_name, (zone, temperature));
+                });
+            }
+            Request::PresentationExpired(session) => {
+                if session == presentation.session() {
+                    if let Some(action) = presentation.stop(&status, &dnd) {
+                        spawn_privileged(action, tx.clone());
+                    }
+                    nightlight.lock().unwrap().refresh();
+                }
+            }
+            Request::Ctl(cmd, reply) => {
+                let _ = reply.send(handle_ctl(cmd, &shared, &mut presentation, &tx));
+            }
+            Request::Handoff(state) => {
+                restore_handoff(*state, &shared, &mut presentation, &tx);
+            }
+            Request::Subscribe(reply) => {
+                let _ = reply.send(events.lock().unwrap().subscribe());
+            }
+            Request::Shutdown(reason) => {
+                if shutdown_deadline.is_some() {
+                    continue;
+                }
+                info!("Shutting down: {}", reason);
+                systemd::stopping();
+                screensaver.lock().unwrap().clear();
+                kiosk.lock().unwrap().stop();
+                let deadline =
+                    Instant::now() + Duration::from_secs(settings.hooks.exit_timeout_secs);
+                // Stages that dimmed the screen or paused something get to undo it
+                call_resumed(&shared, &tx, take_idled(&shared));
+                restore.lock().unwrap().restore(None, &tx);
+                let hooks = hooks.clone();
+                callbacks
+                    .run_and_wait(move |lua| hooks::run_exit_hooks(lua, &hooks, &reason, deadline));
+                shutdown_deadline = Some(deadline);
+            }
+            Request::Profile(profile) => {
+                let previous = status.lock().unwrap().profile();
+                if previous == profile {
+                    continue;
+                }
+                status.lock().unwrap().set_profile(profile.clone());
+                events.lock().unwrap().emit(
+                    "profile",
+                    serde_json::json!({ "profile": profile, "previous": previous }),
+                );
+                let (hooks, profiles) = (hooks.clone(), profiles.clone());
+                callbacks.run(move |lua| {
+                    profiles::run_transition(lua, &hooks, &profiles, &previous, &profile)
+                });
+            }
+            Request::TimezoneChanged => {
+                info!("Timezone changed, rescheduling");
+                scheduler.lock().unwrap().reschedule();
+            }
+            Request::Heartbeat => {
+                status.lock().unwrap().beat();
+                if let Some(path) = &settings.heartbeat.file {
+                    if let Err(e) = heartbeat::write(path) {
+                        error!("Failed to write heartbeat file {:?}: {}", path, e);
+                    }
+                }
+            }
+            Request::Watchdog => systemd::watchdog(),
+            Request::Woke(slept) => {
+                let secs = slept.as_secs().to_string();
+                journal::event(
+                    "wakeup",
+                    &format!("Woke up after {}s suspended", secs),
+                    &[("SLEPT", &secs)],
+                );
+                status.lock().unwrap().set_event("wakeup", "");
+                rearm_after_wake(&shared, &tx);
+                let hooks = hooks.clone();
+                callbacks.run(move |lua| hooks::run_wake_hooks(lua, &hooks, slept, "clock"));
+            }
+            Request::Started(ctx) => {
+                info!("Started: {:?}", ctx);
+                for missing in shared.caps.lock().unwrap().missing() {
+                    warn!("No {}: {}", missing.feature, missing.consequence);
+                }
+                let hooks = hooks.clone();
+                callbacks.run(move |lua| hooks::run_start_hooks(lua, &hooks, &ctx));
+            }
+            Request::Error(err, context) => {
+                let hooks = hooks.clone();
+                callbacks.run(move |lua| {
+                    let context: Vec<(&str, &str)> = context
+                        .iter()
+                        .map(|(key, value)| (key.as_str(), value.as_str()))
+                        .collect();
+                    hooks::report_error(lua, &hooks, &err, &context);
+                });
+            }
+            Request::Run(cmd) => spawn_command(cmd, false, &status, &failures, &dnd, &tx),
+            Request::RunOnce(cmd) => spawn_command(cmd, true, &status, &failures, &dnd, &tx),
+            Request::BatteryLevel(level) => {
+                debug!("Battery at {}%", level);
+                let due = battery.lock().unwrap().set_level(level);
+                let hooks = hooks.clone();
+                callbacks.run(move |lua| battery::run_actions(lua, &hooks, due, level));
+            }
+            Request::JobIdled(name) => {
+                let cmd = jobs.lock().unwrap().idled(&name);
+                if let Some(cmd) = cmd {
+                    let cmd = template::expand(&cmd, &status.lock().unwrap().template_vars());
+                    tokio::spawn(jobs::run(name, cmd, jobs.clone(), tx.clone()));
+                }
+            }
+            Request::JobResumed(name) => jobs.lock().unwrap().interrupt(&name),
+            Request::JobDone(name, success) => jobs.lock().unwrap().finished(&name, success),
+            Request::OnBattery(state) => {
+                jobs.lock().unwrap().set_on_battery(state);
+                let (due, level, power_changed) = {
+                    let mut battery = battery.lock().unwrap();
+                    let due = battery.set_on_battery(state);
+                    (due, battery.level(), battery.power_changed_callbacks())
+                };
+                let hooks = hooks.clone();
+                callbacks.run(move |lua| {
+                    if let Some(level) = level {
+                        battery::run_actions(lua, &hooks, due, level);
+                    }
+                    battery::run_power_changed(lua, &hooks, power_changed, state, level);
+                    let globals = lua.globals();
+                    let res: mlua::Result<mlua::AnyUserData> = globals.get("Helpers");
+
+                    match res {
+                        Ok(helpers) => {
+                            let _ = helpers.call_method::<_, bool>("set_on_battery", state);
+                        }
+                        Err(_e) => {}
+                    }
+                });
+            }
+            Request::Privileged(action) => spawn_privileged(action, tx.clone()),
+            Request::RestoreCommand(cmd) => {
+                tokio::spawn(restore::run_set(cmd, tx.clone()));
+            }
+            Request::Logout => {
+                let tx = tx.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = power::logout().await {
+                        error!("Logout failed: {}", e);
+                        let context = vec![("source".to_string(), "logout".to_string())];
+                        let _ = tx.send(Request::Error(e.to_string(), context)).await;
+                    }
+                });
+            }
+            Request::Hibernate(defer, confirm) => {
+                tokio::spawn(power::hibernate(defer, confirm, status.clone(), tx.clone()));
+            }
+            Request::IdleSuspend(guards, confirm) => {
+                let dnd = dnd.clone();
+                let status = status.clone();
+                let tx = tx.clone();
+                let logind_idle = settings.sleep.logind_idle_action;
+                tokio::spawn(async move {
+                    let suspend = power::idle_suspend(guards, confirm, dnd, status, logind_idle);
+                    if let Err(e) = suspend.await {
+                        error!("Idle suspend failed: {}", e);
+                        let context = vec![("source".to_string(), "suspend".to_string())];
+                        let _ = tx.send(Request::Error(e.to_string(), context)).await;
+                    }
+                });
+            }
+        }
+    }
+    release_wayland(&shared);
+    Ok(())
+}
+
+/// Loads the config into a fresh Lua state after `Request::Reset` cleared what the previous
+/// config set up, and logs what changed against `before`.
+fn lua_reload(shared: &Shared, lua_env: Option<&LuaEnv>, before: reload::Snapshot) {
+    let Shared {
+        lua,
+        notification_list,
+        kiosk,
+        hooks,
+        settings,
+        ..
+    } = shared;
+    debug!("Reloading lua config");
+    let mut lua = lua.lock().unwrap();
+    // A fresh state, so nothing of the previous config lingers
+    if let Some(lua_env) = lua_env {
+        let fresh = Lua::new();
+        match lua_globals(&fresh, lua_env) {
+            Ok(()) => *lua = fresh,
+            Err(e) => error!("Failed to set up a fresh Lua state: {}", e),
+        }
+    }
+    match lua_load_config(&lua, settings, hooks) {
+        // A config that failed at startup may be fixed by now
+        Ok(Ok(())) => notify_ready(shared),
+        Ok(Err(_)) => {}
+        Err(e) => error!("Failed to read the config: {}", e),
+    }
+    kiosk.lock().unwrap().sweep();
+    notification_list.lock().unwrap().retain(|_, entry| {
+        if entry.stale {
+            entry.notification.destroy();
+        }
+        !entry.stale
+    });
+    let backend = match lua_env {
+        Some(LuaEnv {
+            backend: Some(backend),
+            ..
+        }) => Some(backend.as_ref()),
+        _ => None,
+    };
+    // Notifications the config added and the destroyed ones only reach the compositor with a
+    // flush
+    if let Some(backend) = backend {
+        backend.flush();
+    }
+    let after = reload_snapshot(shared);
+    if after.multiplier != before.multiplier {
+        // Kept notifications still have the timeouts of the previous rules
+        if let Some(backend) = backend {
+            recreate_notifications(backend, shared, |_| true);
+            backend.flush();
+        }
+    }
+    let changes = before.diff(&after);
+    if changes.is_empty() {
+        info!("Config reloaded without changes");
+    }
+    for change in &changes {
+        info!("Config reloaded: {}", change);
+    }
+    journal::event(
+        "reload",
+        &format!("Config reloaded with {} changes", changes.len()),
+        &[("CHANGES", &changes.join("\n"))],
+    );
+}
+
+/// Runs a command without holding up the request loop, lockers that don't fork and slow
+/// commands would stall every callback and IPC reply otherwise. Failures come back as
+/// `Request::Error`.
+fn spawn_command(
+    cmd: String,
+    once: bool,
+    status: &daemon::StatusHandle,
+    failures: &failures::FailureTrackerHandle,
+    dnd: &dnd::DndHandle,
+    tx: &mpsc::Sender<Request>,
+) {
+    let (cmd, env) = {
+        let status = status.lock().unwrap();
+        (
+            template::expand(&cmd, &status.template_vars()),
+            status.env_vars(),
+        )
+    };
+    match once {
+        true => debug!("Running command once: {}", cmd),
+        false => debug!("Running command: {}", cmd),
+    }
+    let failures = failures.clone();
+    let dnd = dnd.clone();
+    let tx = tx.clone();
+    tokio::spawn(async move {
+        if let Some(e) = failures::run_tracked(cmd.clone(), env, once, &failures, &dnd).await {
+            let context = vec![
+                ("source".to_string(), "command".to_string()),
+                ("command".to_string(), cmd),
+            ];
+            let _ = tx.send(Request::Error(e, context)).await;
+        }
+    });
+}
+
+/// Leaves the compositor as it was before the daemon started: the idle notifications are
+/// destroyed, the gamma ramps restored and outputs the daemon turned off are turned back on.
+/// A lock of the built-in locker is kept, the session stays locked.
+fn release_wayland(shared: &Shared) {
+    for (_, entry) in shared.notification_list.lock().unwrap().drain() {
+        entry.notification.destroy();
+    }
+    shared.nightlight.lock().unwrap().release();
+    // Flushes the connection, with the requests above
+    shared.outputs.lock().unwrap().release();
+    info!("Released the Wayland objects");
+}
+
+fn spawn_privileged(action: privileged::Action, tx: mpsc::Sender<Request>) {
+    tokio::spawn(async move {
+        debug!("Running privileged action {:?}", action);
+        if let Err(e) = privileged::run(action.clone()).await {
+            error!("{:?} failed: {}", action, e);
+            let context = vec![
+                ("source".to_string(), "privileged".to_string()),
+                ("action".to_string(), format!("{:?}", action)),
+            ];
+            let _ = tx.send(Request::Error(e.to_string(), context)).await;
+        }
+    });
+}
+
+fn handle_ctl(
+    cmd: ipc::CtlCommand,
+    shared: &Shared,
+    presentation: &mut presentation::Presentation,
+    tx: &mpsc::Sender<Request>,
+) -> serde_json::Value {
+    let Shared {
+        scheduler,
+        dnd,
+        status,
+        settings,
+        caffeine,
+        nightlight,
+        accessibility,
+        activity,
+        audio,
+        clock,
+        ..
+    } = shared;
+    match cmd {
+        ipc::CtlCommand::Clock { action } => {
+            match action {
+                ipc::ClockAction::Show => {}
+                ipc::ClockAction::Set { time } => match clock::parse_time(&time) {
+                    Ok(time) => {
+                        clock.set(time);
+                        scheduler.lock().unwrap().reschedule();
+                        shared.solar.lock().unwrap().reschedule();
+                    }
+                    Err(e) => return serde_json::json!({ "ok": false, "error": e }),
+                },
+                ipc::ClockAction::Advance { duration } => {
+                    if let Err(e) = clock.advance(duration) {
+                        return serde_json::json!({ "ok": false, "error": e });
+                    }
+                    scheduler.lock().unwrap().refresh();
+                    shared.solar.lock().unwrap().refresh();
+                }
+                ipc::ClockAction::Reset => {
+                    clock.reset();
+                    scheduler.lock().unwrap().reschedule();
+                    shared.solar.lock().unwrap().reschedule();
+                }
+            }
+            nightlight.lock().unwrap().refresh();
+            serde_json::json!({
+                "ok": true,
+                "now": clock.now().to_rfc3339(),
+                "simulated": clock.is_simulated(),
+            })
+        }
+        ipc::CtlCommand::Snooze { duration } => {
+            let until = scheduler.lock().unwrap().snooze(duration);
+            info!("Scheduled actions snoozed until {:?}", until);
+            serde_json::json!({
+                "ok": true,
+                "snoozed_until": until.map(|until| until.to_rfc3339()),
+            })
+        }
+        ipc::CtlCommand::Dnd { mode } => {
+            let mut dnd = dnd.lock().unwrap();
+            dnd.set_mode(mode);
+            info!("Do-not-disturb mode set to {:?}", mode);
+            serde_json::json!({ "ok": true, "dnd": dnd.mode(), "active": dnd.is_active() })
+        }
+        ipc::CtlCommand::Presentation { mode, duration } => {
+            let action = match mode {
+                presentation::PresentationMode::On => {
+                    presentation.start(duration, &settings.presentation, status, dnd)
+                }
+                presentation::PresentationMode::Off => presentation.stop(status, dnd),
+            };
+            if let Some(action) = action {
+                spawn_privileged(action, tx.clone());
+            }
+            nightlight.lock().unwrap().refresh();
+            if let (presentation::PresentationMode::On, Some(duration)) = (mode, duration) {
+                expire_presentation(presentation, duration, tx);
+            }
+            serde_json::json!({
+                "ok": true,
+                "presenting": status.lock().unwrap().presenting(),
+                "until": presentation.until().map(|until| until.to_rfc3339()),
+            })
+        }
+        ipc::CtlCommand::Nightlight {
+            mode,
+            temperature,
+            duration,
+        } => {
+            let until = nightlight
+                .lock()
+                .unwrap()
+                .set_mode(mode, temperature, duration);
+            serde_json::json!({
+                "ok": true,
+                "mode": mode,
+                "until": until.map(|until| until.to_rfc3339()),
+            })
+        }
+        ipc::CtlCommand::ExcludeOutput { pattern } => {
+            let mut nightlight = nightlight.lock().unwrap();
+            match nightlight.exclude(&pattern, true) {
+                Ok(()) => {
+                    serde_json::json!({ "ok": true, "excluded": nightlight.excluded_outputs() })
+                }
+                Err(e) => serde_json::json!({ "ok": false, "error": e }),
+            }
+        }
+        ipc::CtlCommand::IncludeOutput { pattern } => {
+            let mut nightlight = nightlight.lock().unwrap();
+            nightlight.include(&pattern);
+            serde_json::json!({ "ok": true, "excluded": nightlight.excluded_outputs() })
+        }
+        ipc::CtlCommand::Caffeinate { duration } => {
+            let until = caffeine.lock().unwrap().set(duration);
+            serde_json::json!({
+                "ok": true,
+                "caffeinated_until": until.map(|until| until.to_rfc3339()),
+            })
+        }
+        ipc::CtlCommand::Status => {
+            let (
+                profile,
+                paused,
+                paused_until,
+                presenting,
+                idle_elapsed,
+                last_activity,
+                started,
+                backend,
+                parent_session,
+            ) = {
+                let status = status.lock().unwrap();
+                let last_activity: BTreeMap<String, String> = status
+                    .last_activity_by_seat()
+                    .into_iter()
+                    .map(|(seat, time)| (seat, time.to_rfc3339()))
+                    .collect();
+                (
+                    status.profile(),
+                    status.paused(),
+                    status.paused_until(),
+                    status.presenting(),
+                    status.idle_elapsed(),
+                    last_activity,
+                    status.started(),
+                    status.backend(),
+                    status.parent_session(),
+                )
+            };
+            let daemon::Overview {
+                inhibited,
+                next_stage,
+            } = overview(shared);
+            let dnd = dnd.lock().unwrap();
+            serde_json::json!({
+                "ok": true,
+                "inhibited": inhibited,
+                "inhibitors": all_inhibitors(shared),
+                "next_stage": next_stage.map(|(callback, secs)| serde_json::json!({
+                    "callback": callback,
+                    "in_secs": secs,
+                })),
+                "notifications": notifications_status(shared),
+                "backlight": backlight::levels(),
+                "profile": profile,
+                "paused": paused,
+                "paused_until": paused_until.map(|until| until.to_rfc3339()),
+                "presenting": presenting,
+                "idle_elapsed": idle_elapsed,
+                "last_activity": last_activity,
+                "temperature": status.lock().unwrap().temperature(),
+                "nightlight_override_until": nightlight.lock().unwrap().override_until().map(|until| until.to_rfc3339()),
+                "dnd": dnd.mode(),
+                "dnd_active": dnd.is_active(),
+                "caffeinated_until": caffeine.lock().unwrap().until().map(|until| until.to_rfc3339()),
+                "screen_reader": accessibility.lock().unwrap().screen_reader(),
+                "audio_playing": audio.lock().unwrap().is_playing(),
+                "daemon": {
+                    "version": config::VERSION,
+                    "commit": config::GIT_COMMIT,
+                    "features": config::features(),
+                    "backend": backend,
+                    "parent_session": parent_session,
+                    "started": started.to_rfc3339(),
+                    "uptime_secs": (chrono::Local::now() - started).num_seconds(),
+                },
+            })
+        }
+        ipc::CtlCommand::Activity { source } => {
+            if !activity.lock().unwrap().has_source(&source) {
+                return serde_json::json!({
+                    "ok": false,
+                    "error": format!("Unknown activity source {}", source),
+                });
+            }
+            utils::send_request(tx, Request::Activity(source));
+            serde_json::json!({ "ok": true })
+        }
+        ipc::CtlCommand::Caps => {
+            let mut reply = shared.caps.lock().unwrap().json();
+            reply["ok"] = serde_json::json!(true);
+            reply
+        }
+        ipc::CtlCommand::Inhibitors => {
+            serde_json::json!({ "ok": true, "inhibitors": all_inhibitors(shared) })
+        }
+        ipc::CtlCommand::Lock => {
+            tokio::spawn(async {
+                if let Err(e) = dbus::lock_session().await {
+                    error!("Failed to lock the session: {}", e);
+                }
+            });
+            serde_json::json!({ "ok": true })
+        }
+        ipc::CtlCommand::Reload => {
+            utils::send_request(tx, Request::Reset);
+            serde_json::json!({ "ok": true })
+        }
+        ipc::CtlCommand::Inhibit { duration } => {
+            let until = status.lock().unwrap().pause_for(duration);
+            info!("Idle callbacks inhibited until {:?}", until);
+            if let (Some(duration), Some(until)) = (duration, until) {
+                let status = status.clone();
+                tokio::spawn(async move {
+                    tokio::time::sleep(duration).await;
+                    status.lock().unwrap().pause_expired(until);
+                });
+            }
+            serde_json::json!({
+                "ok": true,
+                "paused_until": until.map(|until| until.to_rfc3339()),
+            })
+        }
+        ipc::CtlCommand::Uninhibit => {
+            status.lock().unwrap().set_paused(false);
+            info!("Idle callbacks no longer inhibited");
+            serde_json::json!({ "ok": true })
+        }
+        ipc::CtlCommand::Trigger { name } => {
+            let tx = tx.clone();
+            tokio::spawn(async move {
+                let _ = tx.send(Request::LuaCallback(name)).await;
+            });
+            serde_json::json!({ "ok": true })
+        }
+        ipc::CtlCommand::Ping => {
+            let heartbeat = status.lock().unwrap().heartbeat();
+            serde_json::json!({
+                "ok": true,
+                "pid": std::process::id(),
+                "last_heartbeat": heartbeat.map(|heartbeat| heartbeat.to_rfc3339()),
+            })
+        }
+        ipc::CtlCommand::Simulate { event } => simulate(event, shared, tx),
+        ipc::CtlCommand::Handoff => {
+            if shared.locker.lock().unwrap().is_locked() {
+                return serde_json::json!({
+                    "ok": false,
+                    "error": "the built-in locker can't be handed over, unlock first",
+                });
+            }
+            let state = handoff_state(shared, presentation);
+            info!("Handing over to a new instance");
+            utils::send_request(tx, Request::Shutdown(handoff::REASON.to_string()));
+            serde_json::json!({ "ok": true, "state": state })
+        }
+        // Answered by the connection itself, it stays open for the stream
+        ipc::CtlCommand::Subscribe => {
+            serde_json::json!({ "ok": false, "error": "subscribe needs a control connection" })
+        }
+    }
+}
+
+/// Feeds a made-up event into the same paths the compositor and logind events take.
+fn simulate(
+    event: ipc::SimulatedEvent,
+    shared: &Shared,
+    tx: &mpsc::Sender<Request>,
+) -> serde_json::Value {
+    use ipc::SimulatedEvent;
+
+    info!("Simulating {:?}", event);
+    let signal = match event {
+        SimulatedEvent::Idle { secs } => {
+            let mut due: Vec<(i32, Uuid, String)> = shared
+                .notification_list
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|(_, entry)| {
+                    !entry.idled && !entry.stale && i64::from(entry.timeout) <= i64::from(secs)
+                })
+                .map(|(uuid, entry)| (entry.timeout, *uuid, entry.fn_name.clone()))
+                .collect();
+            due.sort();
+            for (_, uuid, _) in &due {
+                idle_notified(shared, tx, *uuid, backend::IdleEvent::Idled);
+            }
+            let idled: Vec<String> = due.into_iter().map(|(_, _, fn_name)| fn_name).collect();
+            return serde_json::json!({ "ok": true, "idled": idled });
+        }
+        SimulatedEvent::Resume => {
+            let idled: Vec<(Uuid, String)> = shared
+                .notification_list
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|(_, entry)| entry.idled)
+                .map(|(uuid, entry)| (*uuid, entry.fn_name.clone()))
+                .collect();
+            for (uuid, _) in &idled {
+                idle_notified(shared, tx, *uuid, backend::IdleEvent::Resumed);
+            }
+            let resumed: Vec<String> = idled.into_iter().map(|(_, fn_name)| fn_name).collect();
+            return serde_json::json!({ "ok": true, "resumed": resumed });
+        }
+        SimulatedEvent::Lock => "Lock",
+        SimulatedEvent::Unlock => "Unlock",
+        SimulatedEvent::Sleep => "PrepareSleep",
+        SimulatedEvent::Wake => "Wakeup",
+        SimulatedEvent::Shutdown => "PrepareShutdown",
+        SimulatedEvent::LidClose | SimulatedEvent::LidOpen => {
+            let closed = matches!(event, SimulatedEvent::LidClose);
+            utils::send_request(
+                tx,
+                Request::Lid {
+                    closed,
+                    docked: false,
+                },
+            );
+            return serde_json::json!({ "ok": true });
+        }
+        SimulatedEvent::PowerButton => {
+            utils::send_request(tx, Request::PowerButton);
+            return serde_json::json!({ "ok": true });
+        }
+        SimulatedEvent::OutputAdd { name } => {
+            let description = None;
+            utils::send_request(tx, Request::OutputAdded { name, description });
+            return serde_json::json!({ "ok": true });
+        }
+        SimulatedEvent::OutputRemove { name } => {
+            utils::send_request(tx, Request::OutputRemoved(name));
+            return serde_json::json!({ "ok": true });
+        }
+    };
+    utils::send_request(tx, Request::LuaMethod(signal.to_string()));
+    serde_json::json!({ "ok": true })
+}
+
+/// Ends the current presentation mode session after `duration`.
+fn expire_presentation(
+    presentation: &presentation::Presentation,
+    duration: Duration,
+    tx: &mpsc::Sender<Request>,
+) {
+    let session = presentation.session();
+    let tx = tx.clone();
+    tokio::spawn(async move {
+        tokio::time::sleep(duration).await;
+        let _ = tx.send(Request::PresentationExpired(session)).await;
+    });
+}
+
+/// What a new instance started with `--replace` takes over. The manual overrides become
+/// control commands for the time they have left.
+fn handoff_state(shared: &Shared, presentation: &presentation::Presentation) -> handoff::State {
+    use ipc::CtlCommand;
+
+    let now = chrono::Local::now();
+    let mut commands = Vec::new();
+    let (profile, lock_times) = {
+        let status = shared.status.lock().unwrap();
+        if status.paused() {
+            match status.paused_until() {
+                None => commands.push(CtlCommand::Inhibit { duration: None }),
+                Some(until) => commands.extend(handoff::remaining(until, now).map(|duration| {
+                    CtlCommand::Inhibit {
+                        duration: Some(duration),
+                    }
+                })),
+            }
+        }
+        (status.profile(), status.lock_times())
+    };
+    let caffeinated = shared.caffeine.lock().unwrap().until();
+    if let Some(duration) = caffeinated.and_then(|until| handoff::remaining(until, now)) {
+        commands.push(CtlCommand::Caffeinate { duration });
+    }
+    let dnd = shared.dnd.lock().unwrap().mode();
+    if dnd != dnd::DndMode::Auto {
+        commands.push(CtlCommand::Dnd { mode: dnd });
+    }
+    if shared.clock.is_simulated() {
+        let time = shared.clock.now().format("%Y-%m-%d %H:%M:%S").to_string();
+        commands.push(CtlCommand::Clock {
+            action: ipc::ClockAction::Set { time },
+        });
+    }
+    let snoozed = shared.scheduler.lock().unwrap().snoozed_until();
+    if let Some(duration) = snoozed.and_then(|until| handoff::remaining(until, shared.clock.now()))
+    {
+        commands.push(CtlCommand::Snooze { duration });
+    }
+    let locked = shared.escalation.lock().unwrap().is_locked();
+    handoff::State {
+        pid: std::process::id(),
+        profile,
+        commands,
+        cookies: shared.inhibitors.lock().unwrap().cookies(),
+        presentation: presentation.handoff(),
+        locked: lock_times
+            .filter(|_| locked)
+            .map(|(locked_at, away_since)| (locked_at.timestamp(), away_since.timestamp())),
+    }
+}
+
+/// Restores the state of the replaced instance. A lock continues where it was, without
+/// another `lock` event, and only the escalation stages that weren't due yet still run.
+fn restore_handoff(
+    state: handoff::State,
+    shared: &Shared,
+    presentation: &mut presentation::Presentation,
+    tx: &mpsc::Sender<Request>,
+) {
+    let locked = state.lock_times();
+    for cmd in state.commands {
+        let reply = handle_ctl(cmd.clone(), shared, presentation, tx);
+        if reply["ok"] != true {
+            warn!("Failed to restore {:?}: {}", cmd, reply["error"]);
+        }
+    }
+    if let Some(saved) = state.presentation {
+        presentation.adopt(saved, &shared.status, &shared.dnd);
+        if let Some(until) = presentation.until() {
+            let duration = handoff::remaining(until, chrono::Local::now()).unwrap_or_default();
+            expire_presentation(presentation, duration, tx);
+        }
+        shared.nightlight.lock().unwrap().refresh();
+    }
+    if let Some((locked_at, away_since)) = locked {
+        shared
+            .status
+            .lock()
+            .unwrap()
+            .restore_lock(locked_at, away_since);
+        let session = shared.escalation.lock().unwrap().locked();
+        if let Some(session) = session {
+            let locked_for = (chrono::Local::now() - locked_at)
+                .to_std()
+                .unwrap_or_default();
+            tokio::spawn(escalation::run(
+                session,
+                locked_for,
+                shared.escalation.clone(),
+                shared.status.clone(),
+                tx.clone(),
+            ));
+        }
+    }
+    utils::send_request(tx, Request::Profile(state.profile));
+}
+
+/// The inhibitors of the daemon itself, of the Lua config and, as of the last refresh, of
+/// logind.
+fn all_inhibitors(shared: &Shared) -> Vec<inhibitors::Inhibitor> {
+    use inhibitors::Inhibitor;
+
+    let mut list = Vec::new();
+    let who = config::APP_NAME;
+    {
+        let status = shared.status.lock().unwrap();
+        if status.paused() {
+            list.push(Inhibitor::new("daemon", who, "idle", "paused"));
+        }
+        if status.presenting() {
+            list.push(Inhibitor::new("daemon", who, "idle", "presentation mode"));
+        }
+    }
+    if let Some(until) = shared.caffeine.lock().unwrap().until() {
+        let why = format!("caffeinated until {}", until.to_rfc3339());
+        list.push(Inhibitor::new("daemon", who, "idle", &why));
+    }
+    if shared.accessibility.lock().unwrap().inhibits_idle() {
+        list.push(Inhibitor::new(
+            "daemon",
+            who,
+            "idle",
+            "screen reader running",
+        ));
+    }
+    {
+        let audio = shared.audio.lock().unwrap();
+        if audio.inhibits_idle() {
+            for stream in audio.playing() {
+                let why = match &stream.media {
+                    Some(media) => format!("audio playing: {}", media),
+                    None => "audio playing".to_string(),
+                };
+                list.push(Inhibitor::new("daemon", &stream.application, "idle", &why));
+            }
+        }
+    }
+    {
+        let apps = shared.apps.lock().unwrap();
+        if let (Some(app_id), Some(inhibited)) = (apps.focused(), apps.inhibited()) {
+            list.push(Inhibitor::new(
+                "daemon",
+                &app_id,
+                "idle",
+                &format!("app rule: {}", inhibited),
+            ));
+        }
+    }
+    if shared.dnd.lock().unwrap().blocks_suspend() {
+        list.push(Inhibitor::new("daemon", who, "sleep", "do-not-disturb"));
+    }
+    let inhibitors = shared.inhibitors.lock().unwrap();
+    list.extend(inhibitors.lua());
+    list.extend(inhibitors.screensaver());
+    list.extend(inhibitors.logind());
+    list
+}
+
+/// Reloads the config on SIGHUP, like a change of the config files does.
+async fn reload_signal(tx: mpsc::Sender<Request>) -> anyhow::Result<()> {
+    let mut hangup = signal(SignalKind::hangup())?;
+    while hangup.recv().await.is_some() {
+        info!("SIGHUP received, reloading the config");
+        tx.send(Request::Reset).await?;
+    }
+    Ok(())
+}
+
+/// Waits for SIGTERM or SIGINT and starts a graceful shutdown.
+async fn shutdown_signal(tx: mpsc::Sender<Request>) -> anyhow::Result<()> {
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    let reason = tokio::select! {
+        _ = terminate.recv() => "sigterm",
+        _ = interrupt.recv() => "sigint",
+    };
+    tx.send(Request::Shutdown(reason.to_string())).await?;
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let args = Args::parse();
+    logging::init(args.verbose, args.quiet, args.log_file.as_deref())?;
+    match args.command {
+        Some(Command::Ctl {
+            cmd,
+            socket,
+            token_file,
+        }) => return ipc::ctl(cmd, socket, token_file).await,
+        Some(Command::Check { path }) => {
+            let path = match path {
+                Some(path) => path,
+                None => utils::xdg_config_path(Some(args.config))?,
+            };
+            let config_dir = utils::xdg_config_path(None)?;
+            if !check::run(&path, &settings::load()?, &config_dir)? {
+                std::process::exit(1);
+            }
+            return Ok(());
+        }
+        Some(Command::InstallService { stdout, force }) => {
+            let mut daemon_args = Vec::new();
+            if args.config != config::CONFIG_FILE_NAME {
+                daemon_args.extend(["--config".to_string(), args.config]);
+            }
+            return systemd::install(&daemon_args, stdout, force);
+        }
+        None => {}
+    }
+
+    cmdlog::daemon_started();
+    let _ = ensure_config_file_exists(config::CONFIG_FILE_NAME);
+    // Run the event loop in a separate async task
+    let (tx, mut rx) = mpsc::channel(32);
+
+    let settings = Arc::new(settings::load()?);
+    if let Some(endpoint) = &settings.telemetry.otlp_endpoint {
+        match telemetry::init(endpoint) {
+            Ok(()) => info!("Exporting traces to {}", endpoint),
+            Err(e) => error!("Failed to set up trace export: {}", e),
+        }
+    }
+    // Before anything claims the socket or the bus names of the running instance
+    let handoff = match args.replace {
+        true => handoff::take_over(&settings)
+            .await
+            .map_err(|e| anyhow::anyhow!("Can't replace the running instance: {}", e))?,
+        false => None,
+    };
+    let clock = clock::Clock::default();
+    let solar = solar::Solar::new(clock.clone());
+    let lua = Arc::new(Mutex::new(Lua::new()));
+    let shared = Shared {
+        lua: lua.clone(),
+        callbacks: callbacks::Callbacks::start(lua),
+        notification_list: Arc::new(Mutex::new(HashMap::new())),
+        dbus_handlers: Arc::new(Mutex::new(HashMap::new())),
+        scheduler: schedule::Scheduler::new(clock.clone()),
+        dnd: dnd::Dnd::new(),
+        notifier: notify::Notifier::new(),
+        apps: apps::AppRules::new(),
+        failures: failures::FailureTracker::new(settings.failures.clone()),
+        streams: exec::Streams::new(),
+        screensaver: screensaver::Screensaver::new(),
+        kiosk: kiosk::Kiosk::new(),
+        battery: battery::Battery::new(),
+        thermal: thermal::Thermal::new(),
+        jobs: jobs::Jobs::new(),
+        caffeine: caffeinate::Caffeine::new(),
+        nightlight: nightlight::NightLight::new(clock.clone(), solar.clone()),
+        outputs: outputs::Outputs::new(),
+        locker: lock::Locker::new(tx.clone()),
+        timers: timer::Timers::new(),
+        events: events::Events::new(),
+        options: options::Options::new(),
+        peers: peers::Peers::new(),
+        accessibility: accessibility::Accessibility::new(settings.accessibility.clone()),
+        inhibitors: inhibitors::Inhibitors::new(),
+        escalation: escalation::Escalation::new(),
+        activity: activity::Activity::new(),
+        audio: audio::Audio::new(settings.audio.clone()),
+        backlight: backlight::Backlight::new(),
+        caps: caps::Caps::new(),
+        restore: restore::Restore::new(),
+        profiles: profiles::Profiles::new(),
+        solar,
+        clock,
+        idle_backend: Arc::new(Mutex::new(None)),
+        status: daemon::Status::new(),
+        hooks: hooks::Hooks::new(),
+        settings,
+    };
+    shared
+        .status
+        .lock()
+        .unwrap()
+        .set_state_file(shared.settings.state.file.clone());
+    if let Some(state) = &handoff {
+        // Applications may release their cookies as soon as the ScreenSaver name is back
+        shared
+            .inhibitors
+            .lock()
+            .unwrap()
+            .adopt_cookies(state.cookies.clone());
+    }
+    //let joystick_handler = Arc::new(TokioMutex::new(JoystickHandler::new()));
+    //let _ = tokio::spawn(JoystickHandler::run(joystick_handler.clone())).await;
+    //let _ = tokio::spawn(JoystickHandler::udev_handler_run(joystick_handler.clone())).await;
 
     let config_path = utils::xdg_config_path(None)?;
-    let _task = filewatcher_run(&config_path, tx.clone())
+    let scripts = shared.settings.scripts.files.clone();
+    let _task = filewatcher_run(&config_path, scripts, tx.clone())
         .await
         .expect("Failed to spawn task");
-    let _ = wayland_run(
-        lua.clone(),
+    if let Err(e) = fleet::fetch(&shared.settings.fleet).await {
+        error!("Failed to fetch the fleet config: {}", e);
+    }
+    let (mut start, lua_env, loaded) = match wayland_run(tx.clone(), shared.clone()).await {
+        Ok((start, lua_env, loaded)) => (start, Some(lua_env), loaded),
+        Err(e) => {
+            warn!("Running without Wayland: {}", e);
+            let (start, lua_env, loaded) = headless_run(tx.clone(), shared.clone());
+            (start, Some(lua_env), loaded)
+        }
+    };
+    if let Some(interval) = systemd::watchdog_interval() {
+        tokio::spawn(systemd::watchdog_run(interval, tx.clone()));
+    }
+    tokio::spawn(heartbeat::heartbeat_run(
+        Duration::from_secs(shared.settings.heartbeat.interval_secs.max(1)),
         tx.clone(),
-        shared_map.clone(),
-        dbus_handlers.clone(),
-    )
-    .await;
-    tokio::try_join!(
-        dbus::upower_watcher(tx.clone()),
-        dbus::logind_watcher(tx.clone()),
-        process_command(
-            lua.clone(),
-            tx,
-            &mut rx,
-            shared_map.clone(),
-            dbus_handlers.clone()
+    ));
+    tokio::spawn(caffeinate::caffeinate_run(shared.caffeine.clone()));
+    tokio::spawn(nightlight::nightlight_run(
+        shared.nightlight.clone(),
+        shared.status.clone(),
+    ));
+    tokio::spawn(thermal::thermal_run(shared.thermal.clone(), tx.clone()));
+    tokio::spawn(timer::timer_run(shared.timers.clone(), tx.clone()));
+    tokio::spawn(inhibitors::inhibitors_run(shared.inhibitors.clone()));
+    tokio::spawn(inhibitors::providers_run(
+        shared.inhibitors.clone(),
+        tx.clone(),
+    ));
+    tokio::spawn(inhibitors::stream_run(
+        shared.inhibitors.clone(),
+        shared.events.clone(),
+    ));
+    sinks::start(&shared.settings.sinks, &shared.events);
+    if let Some(path) = args.events_fifo {
+        if let Err(e) = events::fifo_run(path, shared.events.clone()).await {
+            error!("Failed to set up the event FIFO: {}", e);
+        }
+    }
+    tokio::spawn(schedule::scheduler_run(
+        shared.scheduler.clone(),
+        tx.clone(),
+    ));
+    tokio::spawn(activity::activity_run(shared.activity.clone(), tx.clone()));
+    tokio::spawn(solar::solar_run(shared.solar.clone(), tx.clone()));
+    tokio::spawn(audio::audio_run(shared.audio.clone()));
+    tokio::spawn(buttons::buttons_run(
+        shared.settings.buttons.clone(),
+        tx.clone(),
+    ));
+    if let Some(parent) = nested::parent_display(&shared.settings.nested) {
+        shared
+            .status
+            .lock()
+            .unwrap()
+            .set_parent_session(parent.clone());
+        if shared.settings.nested.bridge {
+            nested::bridge_run(
+                parent,
+                Duration::from_secs(shared.settings.nested.idle_secs.max(1)),
+                shared.activity.clone(),
+                tx.clone(),
+            );
+        } else {
+            info!(
+                "Nested in the session {}, set bridge in [nested] to count its activity",
+                parent
+            );
+        }
+    }
+    tokio::spawn(profiles::profiles_run(
+        shared.profiles.clone(),
+        shared.battery.clone(),
+        shared.clock.clone(),
+        tx.clone(),
+    ));
+    tokio::spawn(escalation::locker_run(
+        shared.escalation.clone(),
+        tx.clone(),
+    ));
+    if let Err(e) = ipc::ipc_run(tx.clone(), shared.settings.ipc.clone()).await {
+        error!("Failed to start control socket: {}", e);
+    }
+    if let Err(e) = remote::remote_run(&shared.settings.remote, tx.clone()).await {
+        error!("Failed to start remote control: {}", e);
+    }
+    if let Err(e) = peers::peers_run(&shared.settings.peers, shared.peers.clone(), tx.clone()).await
+    {
+        error!("Failed to start peer mode: {}", e);
+    }
+    let signal_tx = tx.clone();
+    tokio::spawn(async move {
+        // The daemon can't be stopped gracefully without the handler, but keeps working
+        if let Err(e) = shutdown_signal(signal_tx).await {
+            error!("Failed to install signal handlers: {}", e);
+        }
+    });
+    let reload_tx = tx.clone();
+    tokio::spawn(async move {
+        if let Err(e) = reload_signal(reload_tx).await {
+            error!("Failed to install the SIGHUP handler: {}", e);
+        }
+    });
+    let services = [
+        (
+            "session_bus",
+            daemon::daemon_run(
+                shared.status.clone(),
+                {
+                    let shared = shared.clone();
+                    Arc::new(move || overview(&shared))
+                },
+                tx.clone(),
+            )
+            .await,
         ),
-    )?;
+        ("upower", dbus::upower_watcher(tx.clone()).await),
+        (
+            "logind",
+            dbus::logind_watcher(tx.clone(), shared.settings.sleep.clone()).await,
+        ),
+        ("timedated", dbus::timedate_watcher(tx.clone()).await),
+        ("at-spi", dbus::screen_reader_watcher(tx.clone()).await),
+        (
+            "screensaver",
+            inhibitors::screensaver_run(shared.inhibitors.clone()).await,
+        ),
+    ];
+    for (service, result) in services {
+        if let Err(e) = &result {
+            error!("Failed to connect to {}: {}", service, e);
+        }
+        start.services.insert(service.to_string(), result.is_ok());
+    }
+    shared
+        .caps
+        .lock()
+        .unwrap()
+        .set_services(start.services.clone());
+    if start.services.get("logind") != Some(&true) {
+        tokio::spawn(suspend::suspend_watcher(tx.clone()));
+    }
+    match loaded {
+        true => notify_ready(&shared),
+        false => systemd::status("Waiting for a config that loads"),
+    }
+    tx.send(Request::Started(start)).await?;
+    if let Some(state) = handoff {
+        tx.send(Request::Handoff(Box::new(state))).await?;
+    }
+
+    let result = process_command(tx, &mut rx, shared, lua_env).await;
+    telemetry::shutdown();
+    result?;
     // .await
     // .unwrap();
 
+    // The blocking Wayland and inotify loops never return, so don't wait for the runtime
+    std::process::exit(0);
+}
+
+fn lua_load_config(
+    lua: &Lua,
+    settings: &settings::Settings,
+    hooks: &hooks::HooksHandle,
+) -> anyhow::Result<Result<(), mlua::Error>> {
+    let args = Args::parse();
+
+    let config = match fleet::cached_config(&settings.fleet) {
+        Some(config) => config,
+        None => fs::read_to_string(utils::xdg_config_path(Some(args.config))?)?,
+    };
+    let result = lua.load(&config).exec();
+    match result {
+        Ok(_) => {}
+        Err(ref e) => {
+            error!("Error loading config: {}", e);
+        }
+    }
+
+    for script in &settings.scripts.files {
+        lua_load_script(lua, hooks, script)?;
+    }
+
+    Ok(result)
+}
+
+/// Runs the script `script` of the settings. Failures are passed to the `on_error` hook, the
+/// config and the other scripts keep working.
+fn lua_load_script(lua: &Lua, hooks: &hooks::HooksHandle, script: &Path) -> anyhow::Result<()> {
+    let path = utils::xdg_config_path(None)?.join(script);
+    if let Err(e) = scripts::load(lua, &scripts::name(script), &path) {
+        error!("Error loading script {:?}: {}", path, e);
+        let path = path.to_string_lossy();
+        hooks::report_error(
+            lua,
+            hooks,
+            &e.to_string(),
+            &[("source", "script"), ("script", &path)],
+        );
+    }
     Ok(())
 }
 
-fn lua_load_config(lua: &Lua) -> anyhow::Result<Result<(), mlua::Error>> {
-    let args = Args::parse();
-
-    let config_path = utils::xdg_config_path(Some(args.config))?;
-    let config = fs::read_to_string(config_path)?;
-    let result = lua.load(&config).exec();
-    match result {
-        Ok(_) => {}
-        Err(ref e) => {
-            error!("Error loading config: {}", e);
+/// Loads the script `script` again after it changed, leaving the config and the other
+/// scripts alone. What the script registered with callbacks of its own is dropped first.
+fn script_reload(shared: &Shared, lua_env: Option<&LuaEnv>, script: &Path) {
+    let name = scripts::name(script);
+    info!("Reloading script {}", name);
+    {
+        // Like on a full reload, kept notifications are those the script asks for again
+        let activity = shared.activity.lock().unwrap();
+        let mut map = shared.notification_list.lock().unwrap();
+        map.retain(|uuid, entry| {
+            if !scripts::owns(&name, &entry.fn_name) {
+                return true;
+            }
+            if entry.idled || activity.is_held(uuid) {
+                entry.notification.destroy();
+                return false;
+            }
+            entry.stale = true;
+            true
+        });
+    }
+    shared
+        .dbus_handlers
+        .lock()
+        .unwrap()
+        .retain(|_, fn_name| !scripts::owns(&name, fn_name));
+    shared.streams.lock().unwrap().forget_script(&name);
+    shared.battery.lock().unwrap().forget_script(&name);
+    shared.peers.lock().unwrap().forget_script(&name);
+    shared.inhibitors.lock().unwrap().forget_script(&name);
+    shared.hooks.lock().unwrap().forget_script(&name);
+    shared.timers.lock().unwrap().forget_script(&name);
+    shared.scheduler.lock().unwrap().forget_script(&name);
+    shared.events.lock().unwrap().forget_script(&name);
+    shared.profiles.lock().unwrap().forget_script(&name);
+    shared.solar.lock().unwrap().forget_script(&name);
+
+    let lua = shared.lua.lock().unwrap();
+    if let Err(e) = lua_load_script(&lua, &shared.hooks, script) {
+        error!("Failed to reload script {}: {}", name, e);
+    }
+    shared.notification_list.lock().unwrap().retain(|_, entry| {
+        if entry.stale {
+            entry.notification.destroy();
+        }
+        !entry.stale
+    });
+    if let Some(LuaEnv {
+        backend: Some(backend),
+        ..
+    }) = lua_env
+    {
+        backend.flush();
+    }
+    journal::event(
+        "reload",
+        &format!("Script {} reloaded", name),
+        &[("SCRIPT", &name)],
+    );
+}
+
+/// What the globals of a Lua state are made of, kept so that reloads can start over with a
+/// fresh state.
+#[derive(Clone)]
+struct LuaEnv {
+    backend: Option<backend::BackendHandle>,
+    /// `None` without a Wayland compositor
+    qh: Option<QueueHandle<State>>,
+    tx: mpsc::Sender<Request>,
+    shared: Shared,
+}
+
+fn lua_init(env: &LuaEnv) -> anyhow::Result<()> {
+    let lua = env.shared.lua.lock().unwrap();
+    lua_globals(&lua, env)?;
+    lua_load_config(&lua, &env.shared.settings, &env.shared.hooks)??;
+
+    Ok(())
+}
+
+/// Applies the sandbox and sets up the helpers of the config.
+fn lua_globals(lua: &Lua, env: &LuaEnv) -> anyhow::Result<()> {
+    let policy = &env.shared.settings.sandbox;
+    sandbox::apply(lua, policy)?;
+    callbacks::set_default_timeout(
+        lua,
+        Duration::from_secs(env.shared.settings.hooks.callback_timeout_secs),
+    );
+    let my_lua_functions = MyLuaFunctions {
+        backend: env.backend.clone(),
+        notification_list: env.shared.notification_list.clone(),
+        apps: env.shared.apps.clone(),
+        accessibility: env.shared.accessibility.clone(),
+        allow_exec: policy.os_execute,
+        tx: env.tx.clone(),
+    };
+
+    let globals = lua.globals();
+    let watch_idle = my_lua_functions.clone();
+    globals.set(
+        "Jobs",
+        jobs::JobHelpers {
+            jobs: env.shared.jobs.clone(),
+            allow_exec: policy.os_execute,
+            watch_idle: Arc::new(move |name, timeout| {
+                watch_idle
+                    .watch_idle(name, timeout, true, None, None)
+                    .is_some()
+            }),
+        },
+    )?;
+    globals.set("IdleNotifier", my_lua_functions)?;
+    globals.set("log", logging::lua_table(lua)?)?;
+    globals.set(
+        "Helpers",
+        LuaHelpers {
+            on_battery: true,
+            allow_exec: policy.os_execute,
+        },
+    )?;
+    globals.set(
+        "Power",
+        power::PowerHelpers::new(
+            env.tx.clone(),
+            env.shared.battery.clone(),
+            env.shared.restore.clone(),
+            policy.os_execute,
+        ),
+    )?;
+    globals.set(
+        "NightLight",
+        nightlight::NightLightHelpers {
+            nightlight: env.shared.nightlight.clone(),
+            status: env.shared.status.clone(),
+        },
+    )?;
+    globals.set(
+        "Outputs",
+        outputs::OutputsHelpers {
+            outputs: env.shared.outputs.clone(),
+            tx: env.tx.clone(),
+        },
+    )?;
+    globals.set(
+        "Lock",
+        lock::LockHelpers {
+            locker: env.shared.locker.clone(),
+            qh: env.qh.clone(),
+        },
+    )?;
+    globals.set(
+        "Events",
+        events::EventsHelpers {
+            events: env.shared.events.clone(),
+        },
+    )?;
+    globals.set(
+        "Profiles",
+        profiles::ProfilesHelpers {
+            profiles: env.shared.profiles.clone(),
+        },
+    )?;
+    globals.set(
+        "Restore",
+        restore::RestoreHelpers {
+            restore: env.shared.restore.clone(),
+            allow_exec: policy.os_execute,
+        },
+    )?;
+    globals.set(
+        "Options",
+        options::OptionsHelpers {
+            options: env.shared.options.clone(),
+            allow_exec: policy.os_execute,
+        },
+    )?;
+    globals.set(
+        "Timer",
+        timer::TimerHelpers {
+            timers: env.shared.timers.clone(),
+        },
+    )?;
+    globals.set(
+        "Schedule",
+        schedule::ScheduleHelpers {
+            scheduler: env.shared.scheduler.clone(),
+        },
+    )?;
+    globals.set(
+        "Solar",
+        solar::SolarHelpers {
+            solar: env.shared.solar.clone(),
+        },
+    )?;
+    globals.set(
+        "Dnd",
+        dnd::DndHelpers {
+            dnd: env.shared.dnd.clone(),
+        },
+    )?;
+    globals.set("Secrets", secrets::SecretHelpers::default())?;
+    globals.set("System", system::SystemHelpers)?;
+    globals.set(
+        "Notify",
+        notify::NotifyHelpers {
+            dnd: env.shared.dnd.clone(),
+            notifier: env.shared.notifier.clone(),
+        },
+    )?;
+    globals.set(
+        "Caps",
+        caps::CapsHelpers {
+            caps: env.shared.caps.clone(),
+        },
+    )?;
+    globals.set(
+        "Hooks",
+        hooks::HookHelpers {
+            hooks: env.shared.hooks.clone(),
+        },
+    )?;
+    globals.set(
+        "Status",
+        daemon::StatusHelpers {
+            status: env.shared.status.clone(),
+        },
+    )?;
+    globals.set(
+        "Exec",
+        exec::ExecHelpers {
+            streams: env.shared.streams.clone(),
+            allow_exec: policy.os_execute,
+            tx: env.tx.clone(),
+        },
+    )?;
+    globals.set(
+        "Thermal",
+        thermal::ThermalHelpers {
+            thermal: env.shared.thermal.clone(),
+        },
+    )?;
+    globals.set(
+        "Peers",
+        peers::PeerHelpers {
+            peers: env.shared.peers.clone(),
+            allow_network: policy.network,
+        },
+    )?;
+    let shared = env.shared.clone();
+    globals.set(
+        "Inhibitors",
+        inhibitors::InhibitorHelpers {
+            inhibitors: env.shared.inhibitors.clone(),
+            list: Arc::new(move || all_inhibitors(&shared)),
+            tx: env.tx.clone(),
+        },
+    )?;
+    globals.set(
+        "Activity",
+        activity::ActivityHelpers {
+            activity: env.shared.activity.clone(),
+            tx: env.tx.clone(),
+        },
+    )?;
+    globals.set(
+        "Audio",
+        audio::AudioHelpers {
+            audio: env.shared.audio.clone(),
+        },
+    )?;
+    globals.set(
+        "Backlight",
+        backlight::BacklightHelpers {
+            backlight: env.shared.backlight.clone(),
+            restore: env.shared.restore.clone(),
+            tx: env.tx.clone(),
+        },
+    )?;
+    globals.set(
+        "Escalation",
+        escalation::EscalationHelpers {
+            escalation: env.shared.escalation.clone(),
+        },
+    )?;
+    globals.set(
+        "Battery",
+        battery::BatteryHelpers {
+            battery: env.shared.battery.clone(),
+            tx: env.tx.clone(),
+        },
+    )?;
+    globals.set(
+        "Kiosk",
+        kiosk::KioskHelpers {
+            kiosk: env.shared.kiosk.clone(),
+            allow_exec: policy.os_execute,
+            tx: env.tx.clone(),
+        },
+    )?;
+    globals.set(
+        "Screensaver",
+        screensaver::ScreensaverHelpers {
+            screensaver: env.shared.screensaver.clone(),
+            allow_exec: policy.os_execute,
+            tx: env.tx.clone(),
+        },
+    )?;
+    globals.set(
+        "Apps",
+        apps::AppHelpers {
+            rules: env.shared.apps.clone(),
+        },
+    )?;
+    let _ = globals.set(
+        "DbusHandler",
+        DbusHandler {
+            handlers: env.shared.dbus_handlers.clone(),
+        },
+    );
+    Ok(())
+}
+
+impl Dispatch<wl_output::WlOutput, ()> for State {
+    fn event(
+        state: &mut Self,
+        wl_output: &wl_output::WlOutput,
+        event: wl_output::Event,
+        _: &(),
+        _: &Connection,
+        _qh: &QueueHandle<Self>,
+    ) {
+        match event {
+            wl_output::Event::Geometry {
+                x,
+                y,
+                physical_width,
+                physical_height,
+                subpixel,
+                make,
+                model,
+                transform,
+            } => {
+                info!(
+                    "Output geometry: x: {}, y: {}, physical_width: {}, physical_height: {}, subpixel: {:?}, make: {}, model: {}, transform: {:?}",
+                    x, y, physical_width, physical_height, subpixel, make, model, transform
+                );
+            }
+            wl_output::Event::Name { name } => {
+                debug!("Output name: {}", name);
+                if let Some(reg_name) = output_reg_name(state, wl_output) {
+                    output_named(state, reg_name, name);
+                }
+            }
+            wl_output::Event::Description { description } => {
+                debug!("Output description: {}", description);
+                if let Some(reg_name) = output_reg_name(state, wl_output) {
+                    output_described(state, reg_name, description);
+                }
+            }
+            wl_output::Event::Done => {
+                if let Some(reg_name) = output_reg_name(state, wl_output) {
+                    state
+                        .shared
+                        .nightlight
+                        .lock()
+                        .unwrap()
+                        .output_done(reg_name);
+                    announce_output(state, reg_name);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+fn output_reg_name(state: &State, wl_output: &wl_output::WlOutput) -> Option<u32> {
+    state
+        .outputs
+        .values()
+        .find(|output| &output.wl_output == wl_output)
+        .map(|output| output.reg_name)
+}
+
+/// Records the name of an output, from wl_output 4 or xdg-output.
+fn output_named(state: &mut State, reg_name: u32, name: String) {
+    if let Some(output) = state.outputs.get_mut(&reg_name) {
+        output.name = Some(name.clone());
+    }
+    let mut outputs = state.shared.outputs.lock().unwrap();
+    outputs.set_output_name(reg_name, name.clone());
+    let mut nightlight = state.shared.nightlight.lock().unwrap();
+    nightlight.set_output_name(reg_name, name);
+}
+
+fn output_described(state: &mut State, reg_name: u32, description: String) {
+    if let Some(output) = state.outputs.get_mut(&reg_name) {
+        output.description = Some(description.clone());
+    }
+    let mut outputs = state.shared.outputs.lock().unwrap();
+    outputs.set_output_description(reg_name, description.clone());
+    let mut nightlight = state.shared.nightlight.lock().unwrap();
+    nightlight.set_output_description(reg_name, description);
+}
+
+/// Tells the config about an output plugged in after the start, once its name is known.
+fn announce_output(state: &mut State, reg_name: u32) {
+    if !state.hotplug {
+        return;
+    }
+    let Some(output) = state.outputs.get_mut(&reg_name) else {
+        return;
+    };
+    let Some(name) = output.name.clone() else {
+        return;
+    };
+    if output.announced {
+        return;
+    }
+    output.announced = true;
+    info!("Output {} added", name);
+    let request = Request::OutputAdded {
+        name,
+        description: output.description.clone(),
+    };
+    let _ = state.tx.blocking_send(request);
+}
+
+/// Asks for the name of an output through xdg-output, if wl_output is too old to tell.
+fn request_output_name(state: &mut State, reg_name: u32, qh: &QueueHandle<State>) {
+    let Some(manager) = &state.xdg_output_manager else {
+        return;
+    };
+    let Some(output) = state.outputs.get_mut(&reg_name) else {
+        return;
+    };
+    if output.wl_output.version() < 4 && output.xdg_output.is_none() {
+        output.xdg_output = Some(manager.get_xdg_output(&output.wl_output, qh, reg_name));
+    }
+}
+
+impl Dispatch<zxdg_output_manager_v1::ZxdgOutputManagerV1, ()> for State {
+    fn event(
+        _: &mut Self,
+        _: &zxdg_output_manager_v1::ZxdgOutputManagerV1,
+        _: zxdg_output_manager_v1::Event,
+        _: &(),
+        _: &Connection,
+        _qh: &QueueHandle<Self>,
+    ) {
+    }
+}
+
+impl Dispatch<zxdg_output_v1::ZxdgOutputV1, u32> for State {
+    fn event(
+        state: &mut Self,
+        _: &zxdg_output_v1::ZxdgOutputV1,
+        event: zxdg_output_v1::Event,
+        reg_name: &u32,
+        _: &Connection,
+        _qh: &QueueHandle<Self>,
+    ) {
+        match event {
+            zxdg_output_v1::Event::Name { name } => {
+                debug!("Output name from xdg-output: {}", name);
+                output_named(state, *reg_name, name);
+            }
+            zxdg_output_v1::Event::Description { description } => {
+                output_described(state, *reg_name, description);
+            }
+            // Only sent before version 3, later the wl_output done covers xdg-output
+            zxdg_output_v1::Event::Done => announce_output(state, *reg_name),
+            _ => {}
+        }
+    }
+}
+
+/// Binds a global at the highest version both the compositor and sleepwatcher-rs support, and
+/// records that version for `Caps`. Features that need a later version check it there.
+fn bind_global<I>(
+    state: &mut State,
+    registry: &wl_registry::WlRegistry,
+    name: u32,
+    offered: u32,
+    qh: &QueueHandle<State>,
+) -> I
+where
+    I: Proxy + 'static,
+    State: Dispatch<I, ()>,
+{
+    let interface = I::interface();
+    let version = offered.min(interface.version);
+    debug!(
+        "{} bound at version {} (offered {}, supported {})",
+        interface.name, version, offered, interface.version
+    );
+    state.globals.insert(interface.name.to_string(), version);
+    registry.bind::<I, _, _>(name, version, qh, ())
+}
+
+impl Dispatch<wl_registry::WlRegistry, ()> for State {
+    fn event(
+        state: &mut Self,
+        registry: &wl_registry::WlRegistry,
+        event: wl_registry::Event,
+        _: &(),
+        _: &Connection,
+        qh: &QueueHandle<Self>,
+    ) {
+        if let wl_registry::Event::Global {
+            name,
+            interface,
+            version,
+        } = event
+        {
+            state.globals.insert(interface.clone(), version);
+            match &interface[..] {
+                "wl_seat" => {
+                    let wl_seat: wl_seat::WlSeat = bind_global(state, registry, name, version, qh);
+                    debug!("wl_seat: {:?}", name);
+                    let is_default = {
+                        let mut seats = state.seats.lock().unwrap();
+                        seats.add(name, wl_seat.clone());
+                        seats.is_default(&wl_seat)
+                    };
+                    // The first seat after all were gone, named seats wait for their name
+                    if is_default {
+                        rearm_seat_notifications(state, |entry| entry.seat.is_none());
+                    }
+                }
+                "ext_idle_notifier_v1" => {
+                    let idle_notifier: ext_idle_notifier_v1::ExtIdleNotifierV1 =
+                        bind_global(state, registry, name, version, qh);
+
+                    debug!("ext_idle_notifier_v1: {:?}", name);
+                    state.idle_notifier = Some(idle_notifier);
+                }
+                "org_kde_kwin_idle" => {
+                    let kde_idle: org_kde_kwin_idle::OrgKdeKwinIdle =
+                        bind_global(state, registry, name, version, qh);
+                    debug!("org_kde_kwin_idle: {:?}", name);
+                    state.kde_idle = Some(kde_idle);
+                }
+                "xdg_activation_v1" => {
+                    let _activation: xdg_activation_v1::XdgActivationV1 =
+                        bind_global(state, registry, name, version, qh);
+                    info!("xdg_activation_v1: {:?}", name);
+                }
+                "xdg_activation_token_v1" => {
+                    let _activation: xdg_activation_token_v1::XdgActivationTokenV1 =
+                        bind_global(state, registry, name, version, qh);
+                    info!("xdg_activation_token_v1: {:?}", name);
+                }
+                // Idle inhibitor is used to handle sleep events for joystick input
+                "zwp_idle_inhibitor_v1" => {
+                    let _inhibitor: zwp_idle_inhibitor_v1::ZwpIdleInhibitorV1 =
+                        bind_global(state, registry, name, version, qh);
+                    info!("zwp_idle_inhibitor_v1: {:?}", name);
+                }
+                "zwlr_gamma_control_manager_v1" => {
+                    let manager: zwlr_gamma_control_manager_v1::ZwlrGammaControlManagerV1 =
+                        bind_global(state, registry, name, version, qh);
+                    info!("zwlr_gamma_control_manager_v1: {:?}", name);
+                    let qh = qh.clone();
+                    let create_manager = manager.clone();
+                    state
+                        .shared
+                        .nightlight
+                        .lock()
+                        .unwrap()
+                        .set_create_control(Arc::new(move |wl_output, reg_name| {
+                            create_manager.get_gamma_control(wl_output, &qh, reg_name)
+                        }));
+                    state.gamma_manager = Some(manager);
+                }
+                "zwlr_output_power_manager_v1" => {
+                    let manager: zwlr_output_power_manager_v1::ZwlrOutputPowerManagerV1 =
+                        bind_global(state, registry, name, version, qh);
+                    debug!("zwlr_output_power_manager_v1: {:?}", name);
+                    let qh = qh.clone();
+                    state
+                        .shared
+                        .outputs
+                        .lock()
+                        .unwrap()
+                        .set_create_power(Arc::new(move |wl_output, reg_name| {
+                            manager.get_output_power(wl_output, &qh, reg_name)
+                        }));
+                }
+                "ext_session_lock_manager_v1" => {
+                    let manager: ext_session_lock_manager_v1::ExtSessionLockManagerV1 =
+                        bind_global(state, registry, name, version, qh);
+                    debug!("ext_session_lock_manager_v1: {:?}", name);
+                    state.shared.locker.lock().unwrap().set_manager(manager);
+                }
+                "wl_compositor" => {
+                    let compositor: wl_compositor::WlCompositor =
+                        bind_global(state, registry, name, version, qh);
+                    state
+                        .shared
+                        .locker
+                        .lock()
+                        .unwrap()
+                        .set_compositor(compositor);
+                }
+                "wl_shm" => {
+                    let shm: wl_shm::WlShm = bind_global(state, registry, name, version, qh);
+                    state.shared.locker.lock().unwrap().set_shm(shm);
+                }
+                "zwlr_foreign_toplevel_manager_v1" => {
+                    let _manager: zwlr_foreign_toplevel_manager_v1::ZwlrForeignToplevelManagerV1 =
+                        bind_global(state, registry, name, version, qh);
+                    info!("zwlr_foreign_toplevel_manager_v1: {:?}", name);
+                }
+                "zwlr_virtual_pointer_manager_v1" => {
+                    let manager: zwlr_virtual_pointer_manager_v1::ZwlrVirtualPointerManagerV1 =
+                        bind_global(state, registry, name, version, qh);
+                    state.virtual_pointer_manager = Some(manager);
+                    debug!("zwlr_virtual_pointer_manager_v1: {:?}", name);
+                }
+                "zxdg_output_manager_v1" => {
+                    let manager: zxdg_output_manager_v1::ZxdgOutputManagerV1 =
+                        bind_global(state, registry, name, version, qh);
+                    debug!("zxdg_output_manager_v1: {:?}", name);
+                    state.xdg_output_manager = Some(manager);
+                    let reg_names: Vec<u32> = state.outputs.keys().copied().collect();
+                    for reg_name in reg_names {
+                        request_output_name(state, reg_name, qh);
+                    }
+                }
+                "wl_output" => {
+                    let wl_output: wl_output::WlOutput =
+                        bind_global(state, registry, name, version, qh);
+                    let output = Output {
+                        reg_name: name,
+                        wl_output,
+                        name: None,
+                        description: None,
+                        xdg_output: None,
+                        announced: false,
+                    };
+                    state
+                        .shared
+                        .nightlight
+                        .lock()
+                        .unwrap()
+                        .add_output(name, output.wl_output.clone());
+                    state
+                        .shared
+                        .outputs
+                        .lock()
+                        .unwrap()
+                        .add_output(name, output.wl_output.clone());
+                    state.shared.locker.lock().unwrap().add_output(
+                        qh,
+                        name,
+                        output.wl_output.clone(),
+                    );
+                    state.outputs.insert(name, output);
+                    request_output_name(state, name, qh);
+                    info!("wl_output: {:?}", name);
+                }
+                _ => {}
+            }
+        } else if let wl_registry::Event::GlobalRemove { name } = event {
+            seat_removed(state, name);
+            // An unplugged monitor, it gets a new global when it comes back
+            if let Some(output) = state.outputs.remove(&name) {
+                info!("Output {:?} removed", output.name);
+                state.shared.nightlight.lock().unwrap().remove_output(name);
+                state.shared.outputs.lock().unwrap().remove_output(name);
+                state.shared.locker.lock().unwrap().remove_output(name);
+                if let Some(xdg_output) = output.xdg_output {
+                    xdg_output.destroy();
+                }
+                if output.wl_output.version() >= 3 {
+                    output.wl_output.release();
+                }
+                if let (true, Some(name)) = (output.announced && state.hotplug, output.name) {
+                    let _ = state.tx.blocking_send(Request::OutputRemoved(name));
+                }
+            }
+        }
+    }
+}
+
+impl Dispatch<wl_seat::WlSeat, ()> for State {
+    fn event(
+        state: &mut Self,
+        wl_seat: &wl_seat::WlSeat,
+        event: wl_seat::Event,
+        _: &(),
+        _: &Connection,
+        qh: &QueueHandle<Self>,
+    ) {
+        match event {
+            wl_seat::Event::Name { name } => {
+                debug!("Seat name: {}", name);
+                let is_default = {
+                    let mut seats = state.seats.lock().unwrap();
+                    seats.set_name(wl_seat, name.clone());
+                    seats.is_default(wl_seat)
+                };
+                if is_default {
+                    state.shared.status.lock().unwrap().set_seat(name.clone());
+                }
+                rearm_seat_notifications(state, |entry| entry.seat.as_ref() == Some(&name));
+            }
+            // The built-in locker only reads the keyboard of the default seat
+            wl_seat::Event::Capabilities {
+                capabilities: WEnum::Value(capabilities),
+            } if state.seats.lock().unwrap().is_default(wl_seat) => {
+                let has_keyboard = capabilities.contains(wl_seat::Capability::Keyboard);
+                if has_keyboard && state.keyboard.is_none() {
+                    state.keyboard = Some(wl_seat.get_keyboard(qh, ()));
+                } else if !has_keyboard {
+                    if let Some(keyboard) = state.keyboard.take() {
+                        if keyboard.version() >= 3 {
+                            keyboard.release();
+                        }
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Forgets a seat that went away. Its notifications wait for it to come back, and those of the
+/// default seat move to the next one.
+fn seat_removed(state: &mut State, global: u32) {
+    let (removed, was_default, default_name) = {
+        let mut seats = state.seats.lock().unwrap();
+        let was_default = seats.is_default_global(global);
+        let removed = seats.remove(global);
+        (removed, was_default, seats.default_name())
+    };
+    let Some((wl_seat, name)) = removed else {
+        return;
+    };
+    info!("Seat {:?} removed", name);
+    if was_default {
+        if let Some(keyboard) = state.keyboard.take() {
+            if keyboard.version() >= 3 {
+                keyboard.release();
+            }
+        }
+        if let Some(default_name) = default_name {
+            state.shared.status.lock().unwrap().set_seat(default_name);
+        }
+    }
+    rearm_seat_notifications(state, |entry| match &entry.seat {
+        Some(seat) => name.as_ref() == Some(seat),
+        None => was_default,
+    });
+    if wl_seat.version() >= 5 {
+        wl_seat.release();
+    }
+}
+
+/// Keys only reach the daemon while a lock surface of the built-in locker has the focus.
+impl Dispatch<wl_keyboard::WlKeyboard, ()> for State {
+    fn event(
+        state: &mut Self,
+        _: &wl_keyboard::WlKeyboard,
+        event: wl_keyboard::Event,
+        _: &(),
+        _: &Connection,
+        qh: &QueueHandle<Self>,
+    ) {
+        match event {
+            wl_keyboard::Event::Keymap {
+                format: WEnum::Value(wl_keyboard::KeymapFormat::XkbV1),
+                fd,
+                size,
+            } => state.shared.locker.lock().unwrap().set_keymap(fd, size),
+            wl_keyboard::Event::Modifiers {
+                mods_depressed,
+                mods_latched,
+                mods_locked,
+                group,
+                ..
+            } => state.shared.locker.lock().unwrap().set_modifiers(
+                mods_depressed,
+                mods_latched,
+                mods_locked,
+                group,
+            ),
+            wl_keyboard::Event::Key {
+                key,
+                state: WEnum::Value(wl_keyboard::KeyState::Pressed),
+                ..
+            } => lock::Locker::key_pressed(&state.shared.locker, qh, key),
+            _ => {}
+        }
+    }
+}
+
+impl Dispatch<ext_session_lock_v1::ExtSessionLockV1, ()> for State {
+    fn event(
+        state: &mut Self,
+        _: &ext_session_lock_v1::ExtSessionLockV1,
+        event: ext_session_lock_v1::Event,
+        _: &(),
+        _: &Connection,
+        _qh: &QueueHandle<Self>,
+    ) {
+        let mut locker = state.shared.locker.lock().unwrap();
+        match event {
+            ext_session_lock_v1::Event::Locked => locker.locked(),
+            ext_session_lock_v1::Event::Finished => locker.finished(),
+            _ => {}
+        }
+    }
+}
+
+impl Dispatch<ext_session_lock_surface_v1::ExtSessionLockSurfaceV1, u32> for State {
+    fn event(
+        state: &mut Self,
+        lock_surface: &ext_session_lock_surface_v1::ExtSessionLockSurfaceV1,
+        event: ext_session_lock_surface_v1::Event,
+        _: &u32,
+        _: &Connection,
+        qh: &QueueHandle<Self>,
+    ) {
+        if let ext_session_lock_surface_v1::Event::Configure {
+            serial,
+            width,
+            height,
+        } = event
+        {
+            let mut locker = state.shared.locker.lock().unwrap();
+            locker.configure(qh, lock_surface, serial, width, height);
+        }
+    }
+}
+
+impl Dispatch<wl_buffer::WlBuffer, ()> for State {
+    fn event(
+        _: &mut Self,
+        buffer: &wl_buffer::WlBuffer,
+        event: wl_buffer::Event,
+        _: &(),
+        _: &Connection,
+        _qh: &QueueHandle<Self>,
+    ) {
+        // Lock surfaces get a new buffer for every change
+        if let wl_buffer::Event::Release = event {
+            buffer.destroy();
         }
     }
-
-    Ok(result)
 }
 
-fn lua_init(state: &mut State) -> anyhow::Result<()> {
-    let lua = state.lua.lock().unwrap();
-    lua.sandbox(true)?;
-    let my_lua_functions = MyLuaFunctions {
-        wl_seat: state.wl_seat.clone(),
-        idle_notifier: state.idle_notifier.clone(),
-        qh: state.qh.clone(),
-        notification_list: state.notification_list.clone(),
-        tx: state.tx.clone(),
-    };
+impl Dispatch<ext_session_lock_manager_v1::ExtSessionLockManagerV1, ()> for State {
+    fn event(
+        _: &mut Self,
+        _: &ext_session_lock_manager_v1::ExtSessionLockManagerV1,
+        _: ext_session_lock_manager_v1::Event,
+        _: &(),
+        _: &Connection,
+        _qh: &QueueHandle<Self>,
+    ) {
+    }
+}
 
-    let globals = lua.globals();
-    globals.set("IdleNotifier", my_lua_functions)?;
-    globals.set("Helpers", LuaHelpers { on_battery: true })?;
-    let _ = globals.set(
-        "DbusHandler",
-        DbusHandler {
-            handlers: state.dbus_handlers.clone(),
-        },
-    );
-    let _ = lua_load_config(&lua)?;
+impl Dispatch<wl_compositor::WlCompositor, ()> for State {
+    fn event(
+        _: &mut Self,
+        _: &wl_compositor::WlCompositor,
+        _: wl_compositor::Event,
+        _: &(),
+        _: &Connection,
+        _qh: &QueueHandle<Self>,
+    ) {
+    }
+}
 
-    Ok(())
+impl Dispatch<wl_surface::WlSurface, ()> for State {
+    fn event(
+        _: &mut Self,
+        _: &wl_surface::WlSurface,
+        _: wl_surface::Event,
+        _: &(),
+        _: &Connection,
+        _qh: &QueueHandle<Self>,
+    ) {
+    }
 }
 
-impl Dispatch<wl_output::WlOutput, ()> for State {
+impl Dispatch<wl_shm::WlShm, ()> for State {
     fn event(
-        _state: &mut Self,
-        _output: &wl_output::WlOutput,
-        event: wl_output::Event,
+        _: &mut Self,
+        _: &wl_shm::WlShm,
+        _: wl_shm::Event,
         _: &(),
         _: &Connection,
         _qh: &QueueHandle<Self>,
     ) {
-        match event {
-            wl_output::Event::Geometry {
-                x,
-                y,
-                physical_width,
-                physical_height,
-                subpixel,
-                make,
-                model,
-                transform,
-            } => {
-                info!(
-                    "Output geometry: x: {}, y: {}, physical_width: {}, physical_height: {}, subpixel: {:?}, make: {}, model: {}, transform: {:?}",
-                    x, y, physical_width, physical_height, subpixel, make, model, transform
-                );
-            }
-            _ => {}
-        }
     }
 }
 
-impl Dispatch<wl_registry::WlRegistry, ()> for State {
+impl Dispatch<wl_shm_pool::WlShmPool, ()> for State {
     fn event(
-        state: &mut Self,
-        registry: &wl_registry::WlRegistry,
-        event: wl_registry::Event,
+        _: &mut Self,
+        _: &wl_shm_pool::WlShmPool,
+        _: wl_shm_pool::Event,
         _: &(),
         _: &Connection,
-        qh: &QueueHandle<Self>,
+        _qh: &QueueHandle<Self>,
     ) {
-        if let wl_registry::Event::Global {
-            name, interface, ..
-        } = event
-        {
-            match &interface[..] {
-                "wl_seat" => {
-                    let wl_seat = registry.bind::<wl_seat::WlSeat, _, _>(name, 1, qh, ());
-                    state.wl_seat = Some(wl_seat.clone());
-                    debug!("wl_seat: {:?}", name);
-                    if state.wl_seat.is_some() && state.idle_notifier.is_some() {
-                        let _ = lua_init(state);
-                    }
-                }
-                "ext_idle_notifier_v1" => {
-                    let idle_notifier = registry
-                        .bind::<ext_idle_notifier_v1::ExtIdleNotifierV1, _, _>(name, 1, qh, ());
+    }
+}
 
-                    debug!("ext_idle_notifier_v1: {:?}", name);
-                    state.idle_notifier = Some(idle_notifier);
-                    if state.wl_seat.is_some() && state.idle_notifier.is_some() {
-                        let _ = lua_init(state);
-                    }
-                }
-                "xdg_activation_v1" => {
-                    let _activation =
-                        registry.bind::<xdg_activation_v1::XdgActivationV1, _, _>(name, 1, qh, ());
-                    info!("xdg_activation_v1: {:?}", name);
-                }
-                "xdg_activation_token_v1" => {
-                    let _activation = registry
-                        .bind::<xdg_activation_token_v1::XdgActivationTokenV1, _, _>(
-                            name,
-                            1,
-                            qh,
-                            (),
-                        );
-                    info!("xdg_activation_token_v1: {:?}", name);
-                }
-                // Idle inhibitor is used to handle sleep events for joystick input
-                "zwp_idle_inhibitor_v1" => {
-                    let _inhibitor = registry
-                        .bind::<zwp_idle_inhibitor_v1::ZwpIdleInhibitorV1, _, _>(name, 1, qh, ());
-                    info!("zwp_idle_inhibitor_v1: {:?}", name);
-                }
-                "zwlr_gamma_control_v1" => {
-                    let gamma_control = registry
-                        .bind::<zwlr_gamma_control_v1::ZwlrGammaControlV1, _, _>(name, 1, qh, ());
-                    info!("zwlr_gamma_control_v1: {:?}", name);
-                    //state.gamma_control = Some(_gamma_control);
-                }
-                "zwlr_gamma_control_manager_v1" => {
-                    let _gamma_control_manager =
-                        registry
-                            .bind::<zwlr_gamma_control_manager_v1::ZwlrGammaControlManagerV1, _, _>(
-                                name,
-                                1,
-                                qh,
-                                (),
-                            );
-                    info!("zwlr_gamma_control_manager_v1: {:?}", name);
-                }
-                "wl_output" => {
-                    let wl_output = registry.bind::<wl_output::WlOutput, _, _>(name, 1, qh, ());
-                    let output = Output {
-                        reg_name: name,
-                        wl_output,
-                        name: None,
-                        color: Color::default(),
-                        ramp_size: 0,
-                        color_changed: false,
-                    };
-                    state.outputs.insert(name, output);
-                    info!("wl_output: {:?}", name);
-                }
-                _ => {}
-            }
-        }
+impl Dispatch<zwlr_virtual_pointer_manager_v1::ZwlrVirtualPointerManagerV1, ()> for State {
+    fn event(
+        _: &mut Self,
+        _: &zwlr_virtual_pointer_manager_v1::ZwlrVirtualPointerManagerV1,
+        _event: zwlr_virtual_pointer_manager_v1::Event,
+        _: &(),
+        _: &Connection,
+        _qh: &QueueHandle<Self>,
+    ) {
     }
 }
 
-impl Dispatch<wl_seat::WlSeat, ()> for State {
+impl Dispatch<zwlr_virtual_pointer_v1::ZwlrVirtualPointerV1, ()> for State {
     fn event(
         _: &mut Self,
-        _: &wl_seat::WlSeat,
-        _: wl_seat::Event,
+        _: &zwlr_virtual_pointer_v1::ZwlrVirtualPointerV1,
+        _event: zwlr_virtual_pointer_v1::Event,
         _: &(),
         _: &Connection,
         _qh: &QueueHandle<Self>,
@@ -574,16 +3705,30 @@ impl Dispatch<zwp_idle_inhibitor_v1::ZwpIdleInhibitorV1, ()> for State {
     }
 }
 
-impl Dispatch<zwlr_gamma_control_v1::ZwlrGammaControlV1, ()> for State {
+impl Dispatch<zwlr_gamma_control_v1::ZwlrGammaControlV1, u32> for State {
     fn event(
-        _: &mut Self,
+        state: &mut Self,
         _: &zwlr_gamma_control_v1::ZwlrGammaControlV1,
-        _event: zwlr_gamma_control_v1::Event,
-        _: &(),
+        event: zwlr_gamma_control_v1::Event,
+        reg_name: &u32,
         _: &Connection,
         _qh: &QueueHandle<Self>,
     ) {
-        info!("Gamma Control: {:?}", _event);
+        let mut nightlight = state.shared.nightlight.lock().unwrap();
+        match event {
+            zwlr_gamma_control_v1::Event::GammaSize { size } => {
+                debug!("Output {} has a gamma size of {}", reg_name, size);
+                nightlight.set_ramp_size(*reg_name, size as usize);
+            }
+            zwlr_gamma_control_v1::Event::Failed => {
+                warn!(
+                    "Gamma control of output {} failed, another client may be using it",
+                    reg_name
+                );
+                nightlight.control_failed(*reg_name);
+            }
+            _ => {}
+        }
     }
 }
 
@@ -600,6 +3745,36 @@ impl Dispatch<zwlr_gamma_control_manager_v1::ZwlrGammaControlManagerV1, ()> for
     }
 }
 
+impl Dispatch<zwlr_output_power_v1::ZwlrOutputPowerV1, u32> for State {
+    fn event(
+        state: &mut Self,
+        _: &zwlr_output_power_v1::ZwlrOutputPowerV1,
+        event: zwlr_output_power_v1::Event,
+        reg_name: &u32,
+        _: &Connection,
+        _qh: &QueueHandle<Self>,
+    ) {
+        let mut outputs = state.shared.outputs.lock().unwrap();
+        match event {
+            zwlr_output_power_v1::Event::Mode { mode } => outputs.set_mode(*reg_name, mode),
+            zwlr_output_power_v1::Event::Failed => outputs.control_failed(*reg_name),
+            _ => {}
+        }
+    }
+}
+
+impl Dispatch<zwlr_output_power_manager_v1::ZwlrOutputPowerManagerV1, ()> for State {
+    fn event(
+        _: &mut Self,
+        _: &zwlr_output_power_manager_v1::ZwlrOutputPowerManagerV1,
+        _event: zwlr_output_power_manager_v1::Event,
+        _: &(),
+        _: &Connection,
+        _qh: &QueueHandle<Self>,
+    ) {
+    }
+}
+
 impl Dispatch<xdg_activation_v1::XdgActivationV1, ()> for State {
     fn event(
         _: &mut Self,
@@ -637,25 +3812,372 @@ impl Dispatch<ext_idle_notifier_v1::ExtIdleNotifierV1, ()> for State {
     }
 }
 
-impl Dispatch<ext_idle_notification_v1::ExtIdleNotificationV1, NotificationContext> for State {
+impl Dispatch<ext_idle_notification_v1::ExtIdleNotificationV1, backend::NotificationContext>
+    for State
+{
     fn event(
         state: &mut Self,
         _idle_notification: &ext_idle_notification_v1::ExtIdleNotificationV1,
         event: ext_idle_notification_v1::Event,
-        ctx: &NotificationContext,
+        ctx: &backend::NotificationContext,
         _: &Connection,
         _qh: &QueueHandle<Self>,
     ) {
-        debug!("Idle Notification: {:?} {:?}", event, ctx.uuid);
-        let map = state.notification_list.lock().unwrap();
-        let binding = state.lua.lock().unwrap();
-        let globals = binding.globals();
-        let fn_name = map.get(&ctx.uuid).unwrap().0.clone();
-        let handler: Function = globals.get(fn_name).unwrap();
-        let _ = handler.call::<_, ()>(match event {
-            ext_idle_notification_v1::Event::Idled => "idled",
-            ext_idle_notification_v1::Event::Resumed => "resumed",
-            _ => "unknown",
-        });
+        let event = match event {
+            ext_idle_notification_v1::Event::Idled => backend::IdleEvent::Idled,
+            ext_idle_notification_v1::Event::Resumed => backend::IdleEvent::Resumed,
+            _ => return,
+        };
+        // The callback runs in the request loop, a slow one would stall the Wayland events
+        let _ = state.tx.blocking_send(Request::Idle(ctx.uuid, event));
+    }
+}
+
+impl Dispatch<org_kde_kwin_idle::OrgKdeKwinIdle, ()> for State {
+    fn event(
+        _state: &mut Self,
+        _kde_idle: &org_kde_kwin_idle::OrgKdeKwinIdle,
+        _event: org_kde_kwin_idle::Event,
+        _: &(),
+        _: &Connection,
+        _qh: &QueueHandle<Self>,
+    ) {
+    }
+}
+
+impl Dispatch<org_kde_kwin_idle_timeout::OrgKdeKwinIdleTimeout, backend::NotificationContext>
+    for State
+{
+    fn event(
+        state: &mut Self,
+        _idle_timeout: &org_kde_kwin_idle_timeout::OrgKdeKwinIdleTimeout,
+        event: org_kde_kwin_idle_timeout::Event,
+        ctx: &backend::NotificationContext,
+        _: &Connection,
+        _qh: &QueueHandle<Self>,
+    ) {
+        let event = match event {
+            org_kde_kwin_idle_timeout::Event::Idle => backend::IdleEvent::Idled,
+            org_kde_kwin_idle_timeout::Event::Resumed => backend::IdleEvent::Resumed,
+            _ => return,
+        };
+        // The callback runs in the request loop, a slow one would stall the Wayland events
+        let _ = state.tx.blocking_send(Request::Idle(ctx.uuid, event));
+    }
+}
+
+/// Records an event of an idle watch and passes it on to the callback.
+fn idle_notified(
+    shared: &Shared,
+    tx: &mpsc::Sender<Request>,
+    uuid: Uuid,
+    event: backend::IdleEvent,
+) {
+    debug!("Idle Notification: {:?} {:?}", event, uuid);
+    match shared.notification_list.lock().unwrap().get_mut(&uuid) {
+        Some(entry) => {
+            entry.idled = event == backend::IdleEvent::Idled;
+            let name = match event {
+                backend::IdleEvent::Idled => "idled",
+                backend::IdleEvent::Resumed => "resumed",
+            };
+            entry.last_event = Some((name, chrono::Local::now()));
+        }
+        None => return,
+    }
+    idle_event(shared, tx, uuid, event);
+}
+
+/// Seconds until the idle stage of `entry` fires on the default seat, `None` if it already
+/// idled. While the user is active that's its timeout, input restarts the count.
+fn fires_in(
+    entry: &IdleNotification,
+    seat: &str,
+    idle_elapsed: u64,
+    multiplier: f64,
+) -> Option<u64> {
+    if entry.idled || entry.seat.as_deref().is_some_and(|other| other != seat) {
+        return None;
+    }
+    let timeout = u64::from(scaled_timeout(entry.timeout, multiplier) / 1000);
+    Some(timeout.saturating_sub(idle_elapsed))
+}
+
+/// The idle notifications with their timeouts and last events, for `ctl status`.
+fn notifications_status(shared: &Shared) -> Vec<serde_json::Value> {
+    let multiplier = timeout_multiplier(&shared.apps, &shared.accessibility);
+    let (seat, idle_elapsed) = {
+        let status = shared.status.lock().unwrap();
+        (status.seat(), status.idle_elapsed())
+    };
+    let mut list: Vec<serde_json::Value> = shared
+        .notification_list
+        .lock()
+        .unwrap()
+        .values()
+        .map(|entry| {
+            serde_json::json!({
+                "callback": entry.fn_name,
+                "timeout": entry.timeout,
+                "job": entry.job,
+                "seat": entry.seat,
+                "idled": entry.idled,
+                "fires_in": fires_in(entry, &seat, idle_elapsed, multiplier),
+                "last_event": entry.last_event.map(|(event, at)| serde_json::json!({
+                    "event": event,
+                    "at": at.to_rfc3339(),
+                })),
+            })
+        })
+        .collect();
+    list.sort_by_key(|entry| entry["timeout"].as_i64());
+    list
+}
+
+/// Whether idle callbacks are held back and the next stage, for the D-Bus properties and
+/// `ctl status`. Jobs aren't stages the user sees.
+fn overview(shared: &Shared) -> daemon::Overview {
+    let inhibited = shared.status.lock().unwrap().paused() || idle_held_back(shared).is_some();
+    let multiplier = timeout_multiplier(&shared.apps, &shared.accessibility);
+    let (seat, idle_elapsed) = {
+        let status = shared.status.lock().unwrap();
+        (status.seat(), status.idle_elapsed())
+    };
+    let next_stage = shared
+        .notification_list
+        .lock()
+        .unwrap()
+        .values()
+        .filter(|entry| !entry.job)
+        .filter_map(|entry| {
+            let secs = fires_in(entry, &seat, idle_elapsed, multiplier)?;
+            Some((entry.fn_name.clone(), secs))
+        })
+        .min_by_key(|(_, secs)| *secs);
+    daemon::Overview {
+        inhibited,
+        next_stage,
+    }
+}
+
+/// Why idle callbacks are held back, besides a pause, which also holds back the resumes.
+fn idle_held_back(shared: &Shared) -> Option<&'static str> {
+    if shared.status.lock().unwrap().presenting() {
+        return Some("Presenting");
+    }
+    if shared.inhibitors.lock().unwrap().inhibits_idle() {
+        return Some("Held back by an inhibitor");
+    }
+    if shared.accessibility.lock().unwrap().inhibits_idle() {
+        return Some("Screen reader running");
+    }
+    if shared.audio.lock().unwrap().inhibits_idle() {
+        return Some("Audio playing");
+    }
+    if shared.caffeine.lock().unwrap().is_active() {
+        return Some("Caffeinated");
+    }
+    None
+}
+
+/// Calls the callback of an idle notification, unless something holds idle actions back.
+/// Also used for the idle and resume events of the activity sources.
+fn idle_event(shared: &Shared, tx: &mpsc::Sender<Request>, uuid: Uuid, event: backend::IdleEvent) {
+    let Some((fn_name, timeout, job, seat, callback_timeout)) = shared
+        .notification_list
+        .lock()
+        .unwrap()
+        .get(&uuid)
+        .map(|entry| {
+            (
+                entry.fn_name.clone(),
+                entry.timeout,
+                entry.job,
+                entry.seat.clone(),
+                entry.callback_timeout,
+            )
+        })
+    else {
+        return;
+    };
+    {
+        let mut activity = shared.activity.lock().unwrap();
+        let held_for = Duration::from_secs(timeout.max(0) as u64);
+        match event {
+            backend::IdleEvent::Idled if activity.active_within(held_for) => {
+                debug!("Reported activity holds back {}", fn_name);
+                activity.hold(uuid, held_for);
+                return;
+            }
+            backend::IdleEvent::Resumed if activity.release(&uuid) => {
+                // The callback never idled, or already saw the resume
+                debug!("{} was held back by reported activity", fn_name);
+                if let Some(away) = shared.status.lock().unwrap().resumed() {
+                    utils::send_request(tx, Request::Returned(away));
+                }
+                return;
+            }
+            _ => {}
+        }
+    }
+    if event == backend::IdleEvent::Resumed {
+        // A fade of the stage would go on after its brightness was put back
+        shared.backlight.lock().unwrap().cancel_fade();
+        shared.restore.lock().unwrap().restore(Some(&fn_name), tx);
+    }
+    let (kind, arg) = match event {
+        backend::IdleEvent::Idled => ("idle", "idled"),
+        backend::IdleEvent::Resumed => ("resume", "resumed"),
+    };
+    {
+        let mut status = shared.status.lock().unwrap();
+        // Other seats only keep their last activity, the session follows the default seat
+        let other_seat = seat.filter(|seat| *seat != status.seat());
+        let idle_for = Duration::from_secs(timeout.max(0) as u64);
+        match (event, other_seat) {
+            (backend::IdleEvent::Idled, Some(seat)) => status.seat_idled(&seat, idle_for),
+            (backend::IdleEvent::Resumed, Some(seat)) => status.seat_resumed(&seat),
+            (backend::IdleEvent::Idled, None) => status.idled(idle_for),
+            (backend::IdleEvent::Resumed, None) => {
+                if let Some(away) = status.resumed() {
+                    utils::send_request(tx, Request::Returned(away));
+                }
+            }
+        }
+        status.set_event(arg, &fn_name);
+        if status.paused() {
+            debug!("Paused, skipping {}", fn_name);
+            return;
+        }
+    }
+    if event == backend::IdleEvent::Idled {
+        if let Some(reason) = idle_held_back(shared) {
+            debug!("{}, skipping {}", reason, fn_name);
+            return;
+        }
+    }
+    if job {
+        let request = match event {
+            backend::IdleEvent::Idled => Request::JobIdled(fn_name),
+            backend::IdleEvent::Resumed => Request::JobResumed(fn_name),
+        };
+        utils::send_request(tx, request);
+        return;
+    }
+    if matches!(event, backend::IdleEvent::Idled) && shared.apps.lock().unwrap().inhibits(&fn_name)
+    {
+        info!("{} inhibited by the focused application", fn_name);
+        return;
+    }
+    let span = telemetry::idle_event("idle_event");
+    span.set_attribute("event", arg);
+    span.set_attribute("callback", &fn_name);
+    span.set_attribute("timeout", timeout);
+    journal::event(
+        kind,
+        &format!("{} after {}s: {}", arg, timeout, fn_name),
+        &[("STAGE", &fn_name), ("TIMEOUT", &timeout.to_string())],
+    );
+    let limit = callback_timeout.unwrap_or(Duration::from_secs(
+        shared.settings.hooks.callback_timeout_secs,
+    ));
+    let (hooks, restore) = (shared.hooks.clone(), shared.restore.clone());
+    let stage = fn_name.clone();
+    shared.callbacks.run(move |lua| {
+        let _span = span;
+        // What the stage saves is put back when the user returns to it
+        if event == backend::IdleEvent::Idled {
+            restore.lock().unwrap().enter(&stage);
+        }
+        callbacks::call_with_limit::<_, ()>(lua, &hooks, &stage, arg, limit);
+        restore.lock().unwrap().leave();
+    });
+    let event = match event {
+        backend::IdleEvent::Idled => events::Event::Idle,
+        backend::IdleEvent::Resumed => events::Event::Resume,
+    };
+    events::publish(
+        &shared.callbacks,
+        &shared.hooks,
+        &shared.events,
+        event,
+        (fn_name.clone(), timeout),
+        serde_json::json!({ "stage": fn_name, "timeout": timeout }),
+    );
+}
+
+impl Dispatch<zwlr_foreign_toplevel_manager_v1::ZwlrForeignToplevelManagerV1, ()> for State {
+    fn event(
+        _: &mut Self,
+        _: &zwlr_foreign_toplevel_manager_v1::ZwlrForeignToplevelManagerV1,
+        _event: zwlr_foreign_toplevel_manager_v1::Event,
+        _: &(),
+        _: &Connection,
+        _qh: &QueueHandle<Self>,
+    ) {
+    }
+
+    event_created_child!(State, zwlr_foreign_toplevel_manager_v1::ZwlrForeignToplevelManagerV1, [
+        zwlr_foreign_toplevel_manager_v1::EVT_TOPLEVEL_OPCODE => (zwlr_foreign_toplevel_handle_v1::ZwlrForeignToplevelHandleV1, ()),
+    ]);
+}
+
+impl Dispatch<zwlr_foreign_toplevel_handle_v1::ZwlrForeignToplevelHandleV1, ()> for State {
+    fn event(
+        state: &mut Self,
+        handle: &zwlr_foreign_toplevel_handle_v1::ZwlrForeignToplevelHandleV1,
+        event: zwlr_foreign_toplevel_handle_v1::Event,
+        _: &(),
+        _: &Connection,
+        _qh: &QueueHandle<Self>,
+    ) {
+        let done = matches!(
+            event,
+            zwlr_foreign_toplevel_handle_v1::Event::Done
+                | zwlr_foreign_toplevel_handle_v1::Event::Closed
+        );
+        match event {
+            zwlr_foreign_toplevel_handle_v1::Event::AppId { app_id } => {
+                state.toplevels.entry(handle.id()).or_default().app_id = Some(app_id);
+            }
+            zwlr_foreign_toplevel_handle_v1::Event::State { state: states } => {
+                let activated = states
+                    .chunks_exact(4)
+                    .map(|chunk| u32::from_ne_bytes(chunk.try_into().unwrap()))
+                    .any(|s| s == zwlr_foreign_toplevel_handle_v1::State::Activated as u32);
+                state.toplevels.entry(handle.id()).or_default().activated = activated;
+            }
+            zwlr_foreign_toplevel_handle_v1::Event::OutputEnter { output } => {
+                state.toplevels.entry(handle.id()).or_default().output = Some(output.id());
+            }
+            zwlr_foreign_toplevel_handle_v1::Event::OutputLeave { output } => {
+                let toplevel = state.toplevels.entry(handle.id()).or_default();
+                if toplevel.output == Some(output.id()) {
+                    toplevel.output = None;
+                }
+            }
+            zwlr_foreign_toplevel_handle_v1::Event::Closed => {
+                state.toplevels.remove(&handle.id());
+                handle.destroy();
+            }
+            _ => {}
+        }
+
+        if done {
+            let toplevel = state.toplevels.values().find(|toplevel| toplevel.activated);
+            let focused = toplevel.and_then(|toplevel| toplevel.app_id.clone());
+            let output_id = toplevel.and_then(|toplevel| toplevel.output.clone());
+            let output = state
+                .outputs
+                .values()
+                .find(|output| Some(output.wl_output.id()) == output_id)
+                .or_else(|| state.outputs.values().find(|output| output.name.is_some()))
+                .and_then(|output| output.name.clone());
+            state.shared.status.lock().unwrap().set_output(output);
+            let changed = state.shared.apps.lock().unwrap().set_focused(focused);
+            if changed {
+                rearm_notifications(state);
+            }
+        }
     }
 }
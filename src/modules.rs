@@ -0,0 +1,176 @@
+use log::{debug, info};
+use mlua::{Lua, Table, Value};
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+use super::config;
+use super::utils;
+
+const LOADED_MODULES: &str = "sleepwatcher.loaded";
+
+/// Reads the directories listed in the trust file, one per line. Empty lines and lines
+/// starting with `#` are ignored, `~/` expands to the home directory.
+fn trusted_paths(trust_file: &Path) -> std::io::Result<Vec<PathBuf>> {
+    let home = std::env::var_os("HOME").map(PathBuf::from);
+    let paths = fs::read_to_string(trust_file)?
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| match (line.strip_prefix("~/"), &home) {
+            (Some(rest), Some(home)) => Some(home.join(rest)),
+            (Some(_), None) => None,
+            (None, _) => Some(PathBuf::from(line)),
+        })
+        .filter_map(|path| path.canonicalize().ok())
+        .collect();
+    Ok(paths)
+}
+
+/// Maps a module name like `pl.stringx` to a path below one of the trusted directories.
+fn resolve_module(name: &str, trusted: &[PathBuf]) -> Option<PathBuf> {
+    let relative = PathBuf::from(name.replace('.', "/"));
+    if relative
+        .components()
+        .any(|component| !matches!(component, Component::Normal(_)))
+    {
+        return None;
+    }
+
+    trusted.iter().find_map(|dir| {
+        [
+            dir.join(&relative).with_extension("lua"),
+            dir.join(&relative).join("init.lua"),
+        ]
+        .into_iter()
+        .filter_map(|candidate| candidate.canonicalize().ok())
+        .find(|candidate| candidate.starts_with(dir))
+    })
+}
+
+/// Installs a `require` function restricted to the directories listed in the trust file.
+/// Without a trust file the sandbox stays closed and `require` is not available.
+pub fn install_require(lua: &Lua) -> anyhow::Result<()> {
+    let trust_file = utils::xdg_config_path(Some(config::TRUST_FILE_NAME.to_string()))?;
+    if !trust_file.exists() {
+        debug!(
+            "No trust file at {:?}, external modules disabled",
+            trust_file
+        );
+        return Ok(());
+    }
+    let trusted = trusted_paths(&trust_file)?;
+    info!("Loading external Lua modules from {:?}", trusted);
+
+    lua.set_named_registry_value(LOADED_MODULES, lua.create_table()?)?;
+    let require = lua.create_function(move |lua, name: String| {
+        let loaded: Table = lua.named_registry_value(LOADED_MODULES)?;
+        if let Some(module) = loaded.get::<_, Option<Value>>(name.as_str())? {
+            return Ok(module);
+        }
+
+        let path = resolve_module(&name, &trusted).ok_or_else(|| {
+            mlua::Error::RuntimeError(format!(
+                "module '{}' not found in trusted paths {:?}",
+                name, trusted
+            ))
+        })?;
+        debug!("Loading module {} from {:?}", name, path);
+        let source = fs::read_to_string(&path).map_err(mlua::Error::external)?;
+        let module: Value = lua
+            .load(&source)
+            .set_name(path.to_string_lossy())
+            .call(name.as_str())?;
+        // Like Lua's require, modules that return nothing are recorded as loaded
+        let module = match module {
+            Value::Nil => Value::Boolean(true),
+            module => module,
+        };
+        loaded.set(name.as_str(), module.clone())?;
+        Ok(module)
+    })?;
+    lua.globals().set("require", require)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::symlink;
+
+    /// A trusted directory with `pl/stringx.lua` and `pkg/init.lua`, next to an untrusted one.
+    fn setup() -> (tempfile::TempDir, PathBuf, PathBuf) {
+        let root = tempfile::tempdir().unwrap();
+        let trusted = root.path().join("trusted");
+        let outside = root.path().join("outside");
+        fs::create_dir_all(trusted.join("pl")).unwrap();
+        fs::create_dir_all(trusted.join("pkg")).unwrap();
+        fs::create_dir_all(&outside).unwrap();
+        fs::write(trusted.join("pl/stringx.lua"), "return {}").unwrap();
+        fs::write(trusted.join("pkg/init.lua"), "return {}").unwrap();
+        fs::write(outside.join("secret.lua"), "return {}").unwrap();
+        let trusted = trusted.canonicalize().unwrap();
+        let outside = outside.canonicalize().unwrap();
+        (root, trusted, outside)
+    }
+
+    #[test]
+    fn resolves_dotted_names() {
+        let (_root, trusted, _) = setup();
+        assert_eq!(
+            resolve_module("pl.stringx", std::slice::from_ref(&trusted)),
+            Some(trusted.join("pl/stringx.lua"))
+        );
+    }
+
+    #[test]
+    fn resolves_init_lua() {
+        let (_root, trusted, _) = setup();
+        assert_eq!(
+            resolve_module("pkg", std::slice::from_ref(&trusted)),
+            Some(trusted.join("pkg/init.lua"))
+        );
+    }
+
+    #[test]
+    fn rejects_parent_components() {
+        let (_root, trusted, _) = setup();
+        assert_eq!(
+            resolve_module("..outside.secret", std::slice::from_ref(&trusted)),
+            None
+        );
+        assert_eq!(resolve_module("../outside/secret", &[trusted]), None);
+    }
+
+    #[test]
+    fn rejects_absolute_names() {
+        let (_root, trusted, outside) = setup();
+        let name = outside.join("secret").to_string_lossy().into_owned();
+        assert_eq!(resolve_module(&name, &[trusted]), None);
+    }
+
+    #[test]
+    fn rejects_symlinks_out_of_trusted_dirs() {
+        let (_root, trusted, outside) = setup();
+        symlink(outside.join("secret.lua"), trusted.join("evil.lua")).unwrap();
+        symlink(&outside, trusted.join("linked")).unwrap();
+        assert_eq!(resolve_module("evil", std::slice::from_ref(&trusted)), None);
+        assert_eq!(resolve_module("linked.secret", &[trusted]), None);
+    }
+
+    #[test]
+    fn reads_trust_file() {
+        let (root, trusted, _) = setup();
+        let trust_file = root.path().join("trust");
+        let missing = root.path().join("missing");
+        fs::write(
+            &trust_file,
+            format!(
+                "# modules\n\n  {}  \n{}\n",
+                trusted.display(),
+                missing.display()
+            ),
+        )
+        .unwrap();
+        assert_eq!(trusted_paths(&trust_file).unwrap(), vec![trusted]);
+    }
+}
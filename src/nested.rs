@@ -0,0 +1,151 @@
+//! Sessions nested in another Wayland session, like sway running in a window of the outer
+//! session. The nested compositor only sees input while its window has the focus, so it would
+//! lock while the user works in other windows of the outer session. With `bridge` in the
+//! `[nested]` section, activity of the outer session is reported by the built-in
+//! `parent-session` activity source, which holds back the idle callbacks.
+
+use anyhow::Context;
+use log::{debug, error, info};
+use nix::sys::socket::{getsockopt, sockopt};
+use std::fs;
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use wayland_client::globals::{registry_queue_init, GlobalListContents};
+use wayland_client::protocol::{wl_registry, wl_seat};
+use wayland_client::{delegate_noop, Connection, Dispatch, QueueHandle};
+use wayland_protocols::ext::idle_notify::v1::client::{
+    ext_idle_notification_v1, ext_idle_notifier_v1,
+};
+
+use super::activity::ActivityHandle;
+use super::settings::NestedSettings;
+use super::types::Request;
+
+/// Activity source that reports the outer session
+pub const SOURCE: &str = "parent-session";
+/// Well below any sensible idle timeout, the callbacks idle this late after the outer session
+const REPORT_INTERVAL: Duration = Duration::from_secs(5);
+
+fn socket_path(display: &str) -> Option<PathBuf> {
+    if Path::new(display).is_absolute() {
+        return Some(PathBuf::from(display));
+    }
+    Some(PathBuf::from(std::env::var_os("XDG_RUNTIME_DIR")?).join(display))
+}
+
+/// `WAYLAND_DISPLAY` of the environment the compositor was started with. A compositor started
+/// from a tty has none, a nested one was started as a client of the outer session.
+fn detect() -> anyhow::Result<Option<String>> {
+    let Some(display) = std::env::var_os("WAYLAND_DISPLAY") else {
+        return Ok(None);
+    };
+    let display = display.to_string_lossy().into_owned();
+    let path = socket_path(&display).context("XDG_RUNTIME_DIR is not set")?;
+    let stream = UnixStream::connect(&path)?;
+    let compositor = getsockopt(&stream, sockopt::PeerCredentials)?.pid();
+    let environ = fs::read(format!("/proc/{}/environ", compositor))?;
+    let parent = environ
+        .split(|byte| *byte == 0)
+        .find_map(|var| var.strip_prefix(b"WAYLAND_DISPLAY="))
+        .map(|parent| String::from_utf8_lossy(parent).into_owned());
+    Ok(parent.filter(|parent| {
+        *parent != display && socket_path(parent).is_some_and(|path| path.exists())
+    }))
+}
+
+/// The outer session from the settings, or detected.
+pub fn parent_display(settings: &NestedSettings) -> Option<String> {
+    if let Some(parent) = &settings.parent {
+        return Some(parent.clone());
+    }
+    detect()
+        .inspect_err(|e| debug!("Can't tell whether the session is nested: {}", e))
+        .ok()
+        .flatten()
+}
+
+struct Bridge {
+    active: Arc<AtomicBool>,
+}
+
+impl Dispatch<wl_registry::WlRegistry, GlobalListContents> for Bridge {
+    fn event(
+        _: &mut Self,
+        _: &wl_registry::WlRegistry,
+        _: wl_registry::Event,
+        _: &GlobalListContents,
+        _: &Connection,
+        _: &QueueHandle<Self>,
+    ) {
+    }
+}
+
+delegate_noop!(Bridge: ignore wl_seat::WlSeat);
+delegate_noop!(Bridge: ext_idle_notifier_v1::ExtIdleNotifierV1);
+
+impl Dispatch<ext_idle_notification_v1::ExtIdleNotificationV1, ()> for Bridge {
+    fn event(
+        state: &mut Self,
+        _: &ext_idle_notification_v1::ExtIdleNotificationV1,
+        event: ext_idle_notification_v1::Event,
+        _: &(),
+        _: &Connection,
+        _: &QueueHandle<Self>,
+    ) {
+        let active = match event {
+            ext_idle_notification_v1::Event::Idled => false,
+            ext_idle_notification_v1::Event::Resumed => true,
+            _ => return,
+        };
+        debug!("Outer session active: {}", active);
+        state.active.store(active, Ordering::Relaxed);
+    }
+}
+
+/// Follows the idle state of the outer session until the connection is lost.
+fn watch(display: &str, idle: Duration, active: Arc<AtomicBool>) -> anyhow::Result<()> {
+    let path = socket_path(display).context("XDG_RUNTIME_DIR is not set")?;
+    let conn = Connection::from_socket(UnixStream::connect(path)?)?;
+    let (globals, mut queue) = registry_queue_init::<Bridge>(&conn)?;
+    let qh = queue.handle();
+    let seat: wl_seat::WlSeat = globals.bind(&qh, 1..=1, ())?;
+    let notifier: ext_idle_notifier_v1::ExtIdleNotifierV1 = globals.bind(&qh, 1..=1, ())?;
+    notifier.get_idle_notification(idle.as_millis() as u32, &seat, &qh, ());
+    info!("Bridging activity of the outer session {}", display);
+    let mut bridge = Bridge { active };
+    loop {
+        queue.blocking_dispatch(&mut bridge)?;
+    }
+}
+
+/// Reports activity while the outer session was active within `idle`.
+pub fn bridge_run(
+    display: String,
+    idle: Duration,
+    activity: ActivityHandle,
+    tx: mpsc::Sender<Request>,
+) {
+    activity.lock().unwrap().declare_builtin(SOURCE);
+    // A new idle notification starts out resumed
+    let active = Arc::new(AtomicBool::new(true));
+    let watched = active.clone();
+    std::thread::spawn(move || {
+        if let Err(e) = watch(&display, idle, watched.clone()) {
+            error!("Stopped bridging the outer session {}: {}", display, e);
+        }
+        watched.store(false, Ordering::Relaxed);
+    });
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(REPORT_INTERVAL);
+        loop {
+            ticker.tick().await;
+            if active.load(Ordering::Relaxed) {
+                let _ = tx.send(Request::Activity(SOURCE.to_string())).await;
+            }
+        }
+    });
+}
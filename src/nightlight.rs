@@ -0,0 +1,667 @@
+//! Night light. The color temperature follows a daily schedule from the config and is applied
+//! to every output with wlr-gamma-control. `ctl nightlight` overrides the schedule for a while.
+//! The applied state and the override are saved, so a restarted daemon or a re-plugged monitor
+//! gets them back instead of 6500K. Outputs can be excluded by name or description, e.g. a
+//! color-calibrated monitor.
+
+use chrono::{DateTime, Duration as ChronoDuration, Local, NaiveTime, TimeZone, Utc};
+use clap::ValueEnum;
+use glob::Pattern;
+use log::{debug, error, info};
+use mlua::{UserData, UserDataMethods};
+use nix::sys::memfd::{memfd_create, MemFdCreateFlag};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{Seek, SeekFrom, Write};
+use std::os::fd::AsFd;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::Notify;
+use wayland_client::protocol::wl_output;
+use wayland_client::{Connection, Proxy};
+use wayland_protocols_wlr::gamma_control::v1::client::zwlr_gamma_control_v1;
+use xdg::BaseDirectories;
+
+use super::clock::Clock;
+use super::color::{colorramp_fill, Color};
+use super::config;
+use super::daemon::{StatusHandle, NEUTRAL_TEMPERATURE};
+use super::schedule;
+use super::solar::{self, SolarHandle};
+
+/// Used by `ctl nightlight on` when the schedule has no lower temperature
+const DEFAULT_NIGHT_TEMPERATURE: u32 = 4000;
+/// Range of the black body table in `color`
+const MIN_TEMPERATURE: u32 = 1000;
+const MAX_TEMPERATURE: u32 = 10000;
+/// Keeps dimmed outputs readable
+const MIN_BRIGHTNESS: f64 = 0.1;
+/// Overrides and schedule points are checked at least this often
+const CHECK_INTERVAL: Duration = Duration::from_secs(60);
+/// Elevations of the sun between which `follow_sun` goes from the day to the night
+/// temperature, like gammastep's defaults
+const DAY_ELEVATION: f64 = 3.0;
+const NIGHT_ELEVATION: f64 = -6.0;
+
+/// Manual override of the night light schedule.
+#[derive(ValueEnum, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum NightLightMode {
+    /// Follow the schedule again
+    Auto,
+    /// Turn the night light on
+    On,
+    /// Turn the night light off
+    Off,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Override {
+    temperature: u32,
+    /// When the schedule takes over again, never for an override without schedule
+    until: Option<DateTime<Local>>,
+}
+
+/// What the gamma ramps of an output are set to.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+struct Gamma {
+    temperature: u32,
+    brightness: f64,
+}
+
+/// Creates the gamma control of an output, see `State::gamma_manager`.
+pub type CreateControl = Arc<
+    dyn Fn(&wl_output::WlOutput, u32) -> zwlr_gamma_control_v1::ZwlrGammaControlV1 + Send + Sync,
+>;
+
+#[derive(Debug)]
+struct GammaOutput {
+    wl_output: wl_output::WlOutput,
+    name: Option<String>,
+    /// Usually make, model and serial number
+    description: Option<String>,
+    /// The compositor sent all details of the output
+    ready: bool,
+    /// Held only while the output is adjusted, destroying it restores the original ramps
+    control: Option<zwlr_gamma_control_v1::ZwlrGammaControlV1>,
+    /// The compositor refused the gamma control, e.g. because another client has it
+    failed: bool,
+    /// Known once the compositor sent the gamma size
+    ramp_size: usize,
+    applied: Option<Gamma>,
+}
+
+impl GammaOutput {
+    fn matches(&self, pattern: &Pattern) -> bool {
+        [&self.name, &self.description]
+            .into_iter()
+            .flatten()
+            .any(|value| pattern.matches(value))
+    }
+
+    fn send(&mut self, reg_name: u32, gamma: Gamma) -> bool {
+        let Some(control) = &self.control else {
+            return false;
+        };
+        match set_gamma(control, self.ramp_size, gamma) {
+            Ok(()) => {
+                debug!("Output {} set to {:?}", reg_name, gamma);
+                self.applied = Some(gamma);
+                true
+            }
+            Err(e) => {
+                error!("Failed to set the gamma of output {}: {}", reg_name, e);
+                false
+            }
+        }
+    }
+}
+
+/// Contents of the state file.
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct Saved {
+    applied: Option<Gamma>,
+    brightness: Option<f64>,
+    override_temperature: Option<u32>,
+    /// Unix timestamp
+    override_until: Option<i64>,
+    /// Output patterns excluded with `ctl exclude-output`
+    #[serde(default)]
+    excluded: BTreeSet<String>,
+}
+
+pub struct NightLight {
+    /// Temperatures by the time of day they start at
+    schedule: BTreeMap<NaiveTime, u32>,
+    /// Day and night temperature of `follow_sun`, used instead of the schedule
+    sun: Option<(u32, u32)>,
+    solar: SolarHandle,
+    /// Kept across config reloads
+    manual: Option<Override>,
+    /// Outputs by their registry name
+    outputs: HashMap<u32, GammaOutput>,
+    /// Output patterns from the config
+    excluded: BTreeSet<String>,
+    /// Output patterns from `ctl exclude-output`, kept across config reloads
+    excluded_ctl: BTreeSet<String>,
+    create_control: Option<CreateControl>,
+    /// Software dimming factor, 1.0 is full brightness
+    brightness: f64,
+    /// Last state sent to the outputs
+    applied: Option<Gamma>,
+    conn: Option<Connection>,
+    clock: Clock,
+    changed: Arc<Notify>,
+}
+
+pub type NightLightHandle = Arc<Mutex<NightLight>>;
+
+impl fmt::Debug for NightLight {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("NightLight")
+            .field("schedule", &self.schedule)
+            .field("sun", &self.sun)
+            .field("manual", &self.manual)
+            .field("outputs", &self.outputs)
+            .field("excluded", &self.excluded)
+            .field("excluded_ctl", &self.excluded_ctl)
+            .field("applied", &self.applied)
+            .finish_non_exhaustive()
+    }
+}
+
+pub fn parse_pattern(pattern: &str) -> Result<Pattern, String> {
+    Pattern::new(pattern).map_err(|e| format!("invalid output pattern {}: {}", pattern, e))
+}
+
+fn clamp(temperature: u32) -> u32 {
+    temperature.clamp(MIN_TEMPERATURE, MAX_TEMPERATURE)
+}
+
+fn state_path() -> std::io::Result<PathBuf> {
+    BaseDirectories::with_prefix(config::APP_NAME)?
+        .place_state_file(config::NIGHTLIGHT_STATE_FILE_NAME)
+}
+
+fn load() -> Saved {
+    let Ok(path) = state_path() else {
+        return Saved::default();
+    };
+    match fs::read_to_string(&path) {
+        Ok(content) => serde_json::from_str(&content).unwrap_or_else(|e| {
+            error!("Ignoring the night light state {:?}: {}", path, e);
+            Saved::default()
+        }),
+        Err(_) => Saved::default(),
+    }
+}
+
+/// Writes the gamma ramps into a memfd and hands it to the compositor.
+fn set_gamma(
+    control: &zwlr_gamma_control_v1::ZwlrGammaControlV1,
+    ramp_size: usize,
+    gamma: Gamma,
+) -> anyhow::Result<()> {
+    let mut ramps = vec![0u16; ramp_size * 3];
+    let (r, rest) = ramps.split_at_mut(ramp_size);
+    let (g, b) = rest.split_at_mut(ramp_size);
+    let color = Color {
+        temp: gamma.temperature as u16,
+        brightness: gamma.brightness,
+        ..Color::default()
+    };
+    colorramp_fill(r, g, b, ramp_size, color);
+
+    let mut file = File::from(memfd_create(
+        c"sleepwatcher-gamma",
+        MemFdCreateFlag::MFD_CLOEXEC,
+    )?);
+    file.write_all(bytemuck::cast_slice(&ramps))?;
+    file.seek(SeekFrom::Start(0))?;
+    control.set_gamma(file.as_fd());
+    Ok(())
+}
+
+impl NightLight {
+    pub fn new(clock: Clock, solar: SolarHandle) -> NightLightHandle {
+        let saved = load();
+        let manual = saved.override_temperature.map(|temperature| Override {
+            temperature,
+            until: saved
+                .override_until
+                .and_then(|until| Local.timestamp_opt(until, 0).single()),
+        });
+        Arc::new(Mutex::new(Self {
+            schedule: BTreeMap::new(),
+            sun: None,
+            solar,
+            manual,
+            outputs: HashMap::new(),
+            excluded: BTreeSet::new(),
+            excluded_ctl: saved.excluded,
+            create_control: None,
+            brightness: saved.brightness.unwrap_or(1.0),
+            applied: saved.applied,
+            conn: None,
+            clock,
+            changed: Arc::new(Notify::new()),
+        }))
+    }
+
+    fn save(&self) {
+        let saved = Saved {
+            applied: self.applied,
+            brightness: Some(self.brightness),
+            override_temperature: self.manual.map(|manual| manual.temperature),
+            override_until: self.override_until().map(|until| until.timestamp()),
+            excluded: self.excluded_ctl.clone(),
+        };
+        let result = state_path().and_then(|path| {
+            let tmp = path.with_extension("tmp");
+            fs::write(&tmp, serde_json::to_string(&saved)?)?;
+            fs::rename(&tmp, path)
+        });
+        if let Err(e) = result {
+            error!("Failed to save the night light state: {}", e);
+        }
+    }
+
+    /// Removes the schedule and the excluded outputs of the config. A manual override and
+    /// the exclusions of `ctl exclude-output` survive config reloads.
+    pub fn clear(&mut self) {
+        self.schedule.clear();
+        self.sun = None;
+        self.excluded.clear();
+        self.update_controls();
+        self.changed.notify_one();
+    }
+
+    /// Starts adjusting outputs, once the config is loaded.
+    pub fn start(&mut self, conn: Connection) {
+        self.conn = Some(conn);
+        self.update_controls();
+    }
+
+    /// Called once the compositor supports wlr-gamma-control.
+    pub fn set_create_control(&mut self, create_control: CreateControl) {
+        self.create_control = Some(create_control);
+        self.update_controls();
+    }
+
+    pub fn add_output(&mut self, reg_name: u32, wl_output: wl_output::WlOutput) {
+        // Done events need version 2
+        let ready = wl_output.version() < 2;
+        self.outputs.insert(
+            reg_name,
+            GammaOutput {
+                wl_output,
+                name: None,
+                description: None,
+                ready,
+                control: None,
+                failed: false,
+                ramp_size: 0,
+                applied: None,
+            },
+        );
+    }
+
+    pub fn set_output_name(&mut self, reg_name: u32, name: String) {
+        if let Some(output) = self.outputs.get_mut(&reg_name) {
+            output.name = Some(name);
+        }
+    }
+
+    pub fn set_output_description(&mut self, reg_name: u32, description: String) {
+        if let Some(output) = self.outputs.get_mut(&reg_name) {
+            output.description = Some(description);
+        }
+    }
+
+    /// The details of the output are complete. Its gamma control is created only now, so that
+    /// an excluded output is never touched.
+    pub fn output_done(&mut self, reg_name: u32) {
+        if let Some(output) = self.outputs.get_mut(&reg_name) {
+            output.ready = true;
+        }
+        self.update_controls();
+    }
+
+    fn is_excluded(&self, output: &GammaOutput) -> bool {
+        self.excluded
+            .iter()
+            .chain(&self.excluded_ctl)
+            .filter_map(|pattern| Pattern::new(pattern).ok())
+            .any(|pattern| output.matches(&pattern))
+    }
+
+    /// Creates the gamma controls of outputs that are adjusted, and destroys those of
+    /// excluded outputs.
+    fn update_controls(&mut self) {
+        let Some(conn) = &self.conn else {
+            return;
+        };
+        let excluded: Vec<u32> = self
+            .outputs
+            .iter()
+            .filter(|(_, output)| self.is_excluded(output))
+            .map(|(reg_name, _)| *reg_name)
+            .collect();
+        let mut changed = false;
+        for (reg_name, output) in &mut self.outputs {
+            if excluded.contains(reg_name) {
+                if let Some(control) = output.control.take() {
+                    info!("Excluding output {:?} from the night light", output.name);
+                    control.destroy();
+                    output.ramp_size = 0;
+                    output.applied = None;
+                    changed = true;
+                }
+            } else if output.control.is_none() && output.ready && !output.failed {
+                if let Some(create_control) = &self.create_control {
+                    debug!("Adjusting output {:?}", output.name);
+                    output.control = Some(create_control(&output.wl_output, *reg_name));
+                    changed = true;
+                }
+            }
+        }
+        if changed {
+            if let Err(e) = conn.flush() {
+                error!("Failed to flush the gamma controls: {}", e);
+            }
+        }
+    }
+
+    /// Excludes outputs whose name or description matches `pattern`. `ctl` exclusions are
+    /// kept across config reloads.
+    pub fn exclude(&mut self, pattern: &str, ctl: bool) -> Result<(), String> {
+        parse_pattern(pattern)?;
+        if ctl {
+            self.excluded_ctl.insert(pattern.to_string());
+            self.save();
+        } else {
+            self.excluded.insert(pattern.to_string());
+        }
+        self.update_controls();
+        Ok(())
+    }
+
+    /// Removes `pattern` from the exclusions. An exclusion of the config comes back with the
+    /// next config reload.
+    pub fn include(&mut self, pattern: &str) {
+        self.excluded.remove(pattern);
+        if self.excluded_ctl.remove(pattern) {
+            self.save();
+        }
+        self.update_controls();
+        self.changed.notify_one();
+    }
+
+    /// Names of the outputs that are left alone.
+    pub fn excluded_outputs(&self) -> Vec<String> {
+        self.outputs
+            .values()
+            .filter(|output| self.is_excluded(output))
+            .map(|output| {
+                output
+                    .name
+                    .clone()
+                    .or_else(|| output.description.clone())
+                    .unwrap_or_default()
+            })
+            .collect()
+    }
+
+    /// Sets up a new gamma control. The last applied state is restored right away, before
+    /// `nightlight_run` catches up with the schedule.
+    pub fn set_ramp_size(&mut self, reg_name: u32, ramp_size: usize) {
+        let applied = self.applied;
+        if let Some(output) = self.outputs.get_mut(&reg_name) {
+            output.ramp_size = ramp_size;
+            if let Some(gamma) = applied {
+                output.send(reg_name, gamma);
+            }
+            self.changed.notify_one();
+        }
+    }
+
+    /// Forgets an output that was unplugged. When it comes back, its new gamma control gets
+    /// the current state again.
+    pub fn remove_output(&mut self, reg_name: u32) {
+        if let Some(control) = self
+            .outputs
+            .remove(&reg_name)
+            .and_then(|output| output.control)
+        {
+            control.destroy();
+        }
+    }
+
+    /// Destroys the gamma controls on exit, the compositor restores the original ramps.
+    pub fn release(&mut self) {
+        for output in self.outputs.values_mut() {
+            if let Some(control) = output.control.take() {
+                control.destroy();
+            }
+        }
+        if let Some(conn) = &self.conn {
+            if let Err(e) = conn.flush() {
+                error!("Failed to flush the gamma controls: {}", e);
+            }
+        }
+    }
+
+    /// Gives up on an output whose gamma control failed, e.g. because another client has it.
+    pub fn control_failed(&mut self, reg_name: u32) {
+        if let Some(output) = self.outputs.get_mut(&reg_name) {
+            if let Some(control) = output.control.take() {
+                control.destroy();
+            }
+            output.failed = true;
+        }
+    }
+
+    /// Wakes `nightlight_run`, e.g. after presentation mode changed the temperature.
+    pub fn refresh(&self) {
+        self.changed.notify_one();
+    }
+
+    /// The temperature of the most recent schedule point, the last one of the day before
+    /// counts until the first one of today. With `follow_sun` and a known location, it goes
+    /// from the day to the night temperature while the sun sets.
+    fn scheduled(&self, now: DateTime<Local>) -> u32 {
+        if let (Some((day, night)), Some(location)) =
+            (self.sun, self.solar.lock().unwrap().location())
+        {
+            let elevation = solar::elevation(location, now.with_timezone(&Utc));
+            let progress =
+                ((DAY_ELEVATION - elevation) / (DAY_ELEVATION - NIGHT_ELEVATION)).clamp(0.0, 1.0);
+            return (f64::from(day) + (f64::from(night) - f64::from(day)) * progress).round()
+                as u32;
+        }
+        self.schedule
+            .range(..=now.time())
+            .next_back()
+            .or_else(|| self.schedule.iter().next_back())
+            .map_or(NEUTRAL_TEMPERATURE, |(_, temperature)| *temperature)
+    }
+
+    fn next_change(&self, now: DateTime<Local>) -> Option<DateTime<Local>> {
+        if self.sun.is_some() {
+            return self.solar.lock().unwrap().next_change(now);
+        }
+        self.schedule
+            .keys()
+            .map(|time| schedule::next_occurrence(*time, schedule::Days::ALL, now))
+            .min()
+    }
+
+    /// Overrides the schedule for `duration`, or until the next schedule point. Returns when
+    /// the override ends.
+    pub fn set_mode(
+        &mut self,
+        mode: NightLightMode,
+        temperature: Option<u32>,
+        duration: Option<Duration>,
+    ) -> Option<DateTime<Local>> {
+        let now = self.clock.now();
+        let temperature = match mode {
+            NightLightMode::Auto => {
+                info!("Night light follows the schedule");
+                self.manual = None;
+                self.changed.notify_one();
+                return None;
+            }
+            NightLightMode::Off => NEUTRAL_TEMPERATURE,
+            NightLightMode::On => temperature.map(clamp).unwrap_or_else(|| {
+                self.schedule
+                    .values()
+                    .copied()
+                    .chain(self.sun.map(|(_, night)| night))
+                    .filter(|temperature| *temperature < NEUTRAL_TEMPERATURE)
+                    .min()
+                    .unwrap_or(DEFAULT_NIGHT_TEMPERATURE)
+            }),
+        };
+        let until = match duration {
+            Some(duration) => ChronoDuration::from_std(duration)
+                .ok()
+                .and_then(|duration| now.checked_add_signed(duration)),
+            None => self.next_change(now),
+        };
+        info!("Night light set to {}K until {:?}", temperature, until);
+        self.manual = Some(Override { temperature, until });
+        self.save();
+        self.changed.notify_one();
+        until
+    }
+
+    pub fn brightness(&self) -> f64 {
+        self.brightness
+    }
+
+    pub fn set_brightness(&mut self, brightness: f64) {
+        self.brightness = brightness.clamp(MIN_BRIGHTNESS, 1.0);
+        self.changed.notify_one();
+    }
+
+    pub fn override_until(&self) -> Option<DateTime<Local>> {
+        self.manual.and_then(|manual| manual.until)
+    }
+
+    /// The temperature the outputs should have now. Drops an expired override.
+    fn target(&mut self) -> u32 {
+        let now = self.clock.now();
+        if let Some(manual) = self.manual {
+            if manual.until.is_none_or(|until| until > now) {
+                return manual.temperature;
+            }
+            info!("Night light override expired, following the schedule");
+            self.manual = None;
+            self.save();
+        }
+        self.scheduled(now)
+    }
+
+    fn apply(&mut self, temperature: u32) {
+        let gamma = Gamma {
+            temperature,
+            brightness: self.brightness,
+        };
+        if self.applied != Some(gamma) {
+            self.applied = Some(gamma);
+            self.save();
+        }
+        let mut sent = false;
+        for (reg_name, output) in &mut self.outputs {
+            if output.ramp_size > 0 && output.applied != Some(gamma) {
+                sent |= output.send(*reg_name, gamma);
+            }
+        }
+        if let (true, Some(conn)) = (sent, &self.conn) {
+            if let Err(e) = conn.flush() {
+                error!("Failed to flush the gamma ramps: {}", e);
+            }
+        }
+    }
+}
+
+/// Follows the schedule and overrides, and applies the temperature whenever it changes.
+pub async fn nightlight_run(nightlight: NightLightHandle, status: StatusHandle) {
+    let changed = nightlight.lock().unwrap().changed.clone();
+    loop {
+        {
+            let mut nightlight = nightlight.lock().unwrap();
+            let target = nightlight.target();
+            let temperature = {
+                let mut status = status.lock().unwrap();
+                status.set_temperature(target);
+                status.temperature()
+            };
+            nightlight.apply(temperature);
+        }
+        tokio::select! {
+            _ = tokio::time::sleep(CHECK_INTERVAL) => {},
+            _ = changed.notified() => {},
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct NightLightHelpers {
+    pub nightlight: NightLightHandle,
+    pub status: StatusHandle,
+}
+
+impl UserData for NightLightHelpers {
+    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_method("at", |_lua, this, (time, temperature): (String, u32)| {
+            let time = schedule::parse_time(&time)?;
+            let mut nightlight = this.nightlight.lock().unwrap();
+            nightlight.schedule.insert(time, clamp(temperature));
+            nightlight.changed.notify_one();
+            Ok(())
+        });
+        methods.add_method("follow_sun", |_lua, this, (day, night): (u32, u32)| {
+            let mut nightlight = this.nightlight.lock().unwrap();
+            nightlight.sun = Some((clamp(day), clamp(night)));
+            nightlight.changed.notify_one();
+            Ok(())
+        });
+        methods.add_method(
+            "set_temperature",
+            |_lua, this, (temperature, duration): (Option<u32>, Option<f64>)| {
+                let mut nightlight = this.nightlight.lock().unwrap();
+                match temperature {
+                    Some(temperature) => nightlight.set_mode(
+                        NightLightMode::On,
+                        Some(temperature),
+                        duration.map(Duration::from_secs_f64),
+                    ),
+                    None => nightlight.set_mode(NightLightMode::Auto, None, None),
+                };
+                Ok(())
+            },
+        );
+        methods.add_method("temperature", |_lua, this, (): ()| {
+            Ok(this.status.lock().unwrap().temperature())
+        });
+        methods.add_method("brightness", |_lua, this, (): ()| {
+            Ok(this.nightlight.lock().unwrap().brightness())
+        });
+        methods.add_method("set_brightness", |_lua, this, brightness: f64| {
+            this.nightlight.lock().unwrap().set_brightness(brightness);
+            Ok(())
+        });
+        methods.add_method("exclude", |_lua, this, pattern: String| {
+            this.nightlight
+                .lock()
+                .unwrap()
+                .exclude(&pattern, false)
+                .map_err(mlua::Error::RuntimeError)
+        });
+    }
+}
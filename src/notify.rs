@@ -0,0 +1,203 @@
+//! Desktop notifications over `org.freedesktop.Notifications`, for the daemon and as the
+//! `Notify` global of the config. Notifications sent with `until_activity` are closed once the
+//! user is back, so a "locking in 30 seconds" warning doesn't outlive the lock it announced.
+
+use anyhow::anyhow;
+use log::{debug, error, info};
+use mlua::{UserData, UserDataMethods};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use zbus::zvariant::Value;
+
+use super::config;
+use super::dbus::NotificationsProxy;
+use super::dnd::DndHandle;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Urgency {
+    Low,
+    #[default]
+    Normal,
+    Critical,
+}
+
+impl Urgency {
+    fn parse(urgency: &str) -> Result<Self, String> {
+        match urgency {
+            "low" => Ok(Self::Low),
+            "normal" => Ok(Self::Normal),
+            "critical" => Ok(Self::Critical),
+            _ => Err(format!(
+                "unknown urgency {}, expected low, normal or critical",
+                urgency
+            )),
+        }
+    }
+
+    /// Value of the `urgency` hint
+    fn level(self) -> u8 {
+        match self {
+            Self::Low => 0,
+            Self::Normal => 1,
+            Self::Critical => 2,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Notification {
+    pub summary: String,
+    pub body: String,
+    pub urgency: Urgency,
+    /// Expiry, the server's default if `None`
+    pub timeout: Option<Duration>,
+    /// Id of an earlier notification this one replaces
+    pub replaces: u32,
+}
+
+/// Notifications to close when the user is back.
+#[derive(Debug, Default)]
+pub struct Notifier {
+    until_activity: Vec<u32>,
+}
+
+pub type NotifierHandle = Arc<Mutex<Notifier>>;
+
+impl Notifier {
+    pub fn new() -> NotifierHandle {
+        Arc::new(Mutex::new(Self::default()))
+    }
+}
+
+/// Sends a desktop notification, unless do-not-disturb is active and it isn't critical.
+/// Returns the notification id, or `None` if the notification was held back.
+pub async fn notify(dnd: &DndHandle, notification: &Notification) -> anyhow::Result<Option<u32>> {
+    if notification.urgency != Urgency::Critical && dnd.lock().unwrap().is_active() {
+        info!(
+            "Do-not-disturb active, suppressed notification: {}",
+            notification.summary
+        );
+        return Ok(None);
+    }
+
+    let conn = zbus::Connection::session().await?;
+    let proxy = NotificationsProxy::new(&conn).await?;
+    let urgency = Value::U8(notification.urgency.level());
+    let timeout = notification
+        .timeout
+        .map(|timeout| i32::try_from(timeout.as_millis()).unwrap_or(i32::MAX))
+        .unwrap_or(-1);
+    let id = proxy
+        .notify(
+            config::APP_NAME,
+            notification.replaces,
+            "",
+            &notification.summary,
+            &notification.body,
+            &[],
+            HashMap::from([("urgency", &urgency)]),
+            timeout,
+        )
+        .await?;
+    debug!("Sent notification {}: {}", id, notification.summary);
+    Ok(Some(id))
+}
+
+/// Sends a notification of normal urgency, see `notify`.
+pub async fn send(dnd: &DndHandle, summary: &str, body: &str) -> anyhow::Result<Option<u32>> {
+    let notification = Notification {
+        summary: summary.to_string(),
+        body: body.to_string(),
+        ..Default::default()
+    };
+    notify(dnd, &notification).await
+}
+
+pub async fn close(id: u32) -> anyhow::Result<()> {
+    let conn = zbus::Connection::session().await?;
+    NotificationsProxy::new(&conn)
+        .await?
+        .close_notification(id)
+        .await?;
+    debug!("Closed notification {}", id);
+    Ok(())
+}
+
+/// Closes the notification in the background and logs a failure.
+pub fn close_later(id: u32) {
+    tokio::spawn(async move {
+        if let Err(e) = close(id).await {
+            error!("Failed to close notification {}: {}", id, e);
+        }
+    });
+}
+
+/// The user is back, closes the notifications sent with `until_activity`.
+pub fn user_active(notifier: &NotifierHandle) {
+    for id in notifier.lock().unwrap().until_activity.drain(..) {
+        close_later(id);
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct NotifyHelpers {
+    pub dnd: DndHandle,
+    pub notifier: NotifierHandle,
+}
+
+impl UserData for NotifyHelpers {
+    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_method(
+            "send",
+            |_lua,
+             this,
+             (summary, body, options): (String, Option<String>, Option<mlua::Table>)| {
+                let mut notification = Notification {
+                    summary,
+                    body: body.unwrap_or_default(),
+                    ..Default::default()
+                };
+                let mut until_activity = false;
+                if let Some(options) = options {
+                    if let Some(urgency) = options.get::<_, Option<String>>("urgency")? {
+                        notification.urgency =
+                            Urgency::parse(&urgency).map_err(mlua::Error::RuntimeError)?;
+                    }
+                    notification.timeout = options
+                        .get::<_, Option<f64>>("timeout")?
+                        .map(|secs| Duration::from_secs_f64(secs.max(0.0)));
+                    notification.replaces = options.get::<_, Option<u32>>("replaces")?.unwrap_or(0);
+                    until_activity = options
+                        .get::<_, Option<bool>>("until_activity")?
+                        .unwrap_or(false);
+                }
+                // Lua runs inside the tokio runtime, so the D-Bus call gets a runtime of its own
+                let dnd = this.dnd.clone();
+                let sent = std::thread::spawn(move || {
+                    tokio::runtime::Builder::new_current_thread()
+                        .enable_all()
+                        .build()?
+                        .block_on(notify(&dnd, &notification))
+                })
+                .join()
+                .map_err(|_| anyhow!("sending the notification panicked"))
+                .and_then(|sent| sent)
+                .map_err(|e| mlua::Error::RuntimeError(e.to_string()))?;
+                if let (Some(id), true) = (sent, until_activity) {
+                    this.notifier.lock().unwrap().until_activity.push(id);
+                }
+                Ok(sent)
+            },
+        );
+        methods.add_method("close", |_lua, this, id: u32| {
+            this.notifier
+                .lock()
+                .unwrap()
+                .until_activity
+                .retain(|pending| *pending != id);
+            close_later(id);
+            Ok(())
+        });
+    }
+}
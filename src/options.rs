@@ -0,0 +1,275 @@
+//! Typed options of the Lua config, set with `Options:set{...}`. Every key is checked against
+//! the schema, so a typo, a wrong type or a value out of range fails loudly instead of being
+//! ignored. Options go back to their defaults when the config is reloaded.
+
+use log::debug;
+use mlua::{IntoLua, Lua, Table, UserData, UserDataMethods, Value};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+#[derive(Debug)]
+enum Kind {
+    String,
+    Number { min: f64, max: f64 },
+}
+
+#[derive(Debug)]
+enum DefaultValue {
+    Unset,
+    Number(f64),
+}
+
+#[derive(Debug)]
+struct Spec {
+    name: &'static str,
+    kind: Kind,
+    default: DefaultValue,
+    /// The value is a command the daemon runs, refused when the sandbox policy forbids that
+    command: bool,
+}
+
+const SCHEMA: &[Spec] = &[
+    // Run on logind's `Lock` when the config has no `DbusHandler:LockHandler`
+    Spec {
+        name: "lock_cmd",
+        kind: Kind::String,
+        default: DefaultValue::Unset,
+        command: true,
+    },
+    // Seconds before `lock_cmd` runs, input meanwhile cancels the lock
+    Spec {
+        name: "grace",
+        kind: Kind::Number {
+            min: 0.0,
+            max: 60.0,
+        },
+        default: DefaultValue::Number(0.0),
+        command: false,
+    },
+    // Desktop notification shown during `grace`, e.g. "Locking in 5 seconds"
+    Spec {
+        name: "lock_warning",
+        kind: Kind::String,
+        default: DefaultValue::Unset,
+        command: false,
+    },
+];
+
+#[derive(Clone, Debug, PartialEq)]
+enum OptionValue {
+    String(String),
+    Number(f64),
+}
+
+impl Spec {
+    fn default(&self) -> Option<OptionValue> {
+        match self.default {
+            DefaultValue::Unset => None,
+            DefaultValue::Number(number) => Some(OptionValue::Number(number)),
+        }
+    }
+
+    fn check(&self, value: Value) -> Result<OptionValue, String> {
+        let value = match (&self.kind, value) {
+            (Kind::String, Value::String(value)) => {
+                OptionValue::String(value.to_str().map_err(|e| e.to_string())?.to_string())
+            }
+            (Kind::Number { .. }, Value::Integer(value)) => OptionValue::Number(value as f64),
+            (Kind::Number { .. }, Value::Number(value)) => OptionValue::Number(value),
+            (kind, value) => {
+                let expected = match kind {
+                    Kind::String => "a string",
+                    Kind::Number { .. } => "a number",
+                };
+                return Err(format!(
+                    "option {} expects {}, got {}",
+                    self.name,
+                    expected,
+                    value.type_name()
+                ));
+            }
+        };
+        if let (Kind::Number { min, max }, OptionValue::Number(number)) = (&self.kind, &value) {
+            if !(min..=max).contains(&number) {
+                return Err(format!(
+                    "option {} must be between {} and {}, got {}",
+                    self.name, min, max, number
+                ));
+            }
+        }
+        Ok(value)
+    }
+}
+
+/// Checks a key of `Options:set{...}` and its value against the schema.
+fn check(
+    key: Value,
+    value: Value,
+    allow_exec: bool,
+) -> Result<(&'static str, OptionValue), String> {
+    let Value::String(key) = key else {
+        return Err(format!("option names are strings, got {}", key.type_name()));
+    };
+    let spec = spec(key.to_str().map_err(|e| e.to_string())?)?;
+    if spec.command && !allow_exec {
+        return Err(format!(
+            "option {} runs a command, which is disabled by the sandbox policy",
+            spec.name
+        ));
+    }
+    Ok((spec.name, spec.check(value)?))
+}
+
+fn spec(name: &str) -> Result<&'static Spec, String> {
+    SCHEMA.iter().find(|spec| spec.name == name).ok_or_else(|| {
+        let known: Vec<&str> = SCHEMA.iter().map(|spec| spec.name).collect();
+        format!(
+            "unknown option {}, expected one of {}",
+            name,
+            known.join(", ")
+        )
+    })
+}
+
+#[derive(Debug, Default)]
+pub struct Options {
+    values: HashMap<&'static str, OptionValue>,
+}
+
+pub type OptionsHandle = Arc<Mutex<Options>>;
+
+impl Options {
+    pub fn new() -> OptionsHandle {
+        Arc::new(Mutex::new(Self::default()))
+    }
+
+    pub fn clear(&mut self) {
+        self.values.clear();
+    }
+
+    fn get(&self, name: &str) -> Result<Option<OptionValue>, String> {
+        let spec = spec(name)?;
+        Ok(self
+            .values
+            .get(spec.name)
+            .cloned()
+            .or_else(|| spec.default()))
+    }
+
+    pub fn lock_cmd(&self) -> Option<String> {
+        match self.get("lock_cmd") {
+            Ok(Some(OptionValue::String(cmd))) => Some(cmd),
+            _ => None,
+        }
+    }
+
+    pub fn lock_warning(&self) -> Option<String> {
+        match self.get("lock_warning") {
+            Ok(Some(OptionValue::String(warning))) => Some(warning),
+            _ => None,
+        }
+    }
+
+    pub fn grace(&self) -> Duration {
+        match self.get("grace") {
+            Ok(Some(OptionValue::Number(secs))) => Duration::from_secs_f64(secs),
+            _ => Duration::ZERO,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct OptionsHelpers {
+    pub options: OptionsHandle,
+    pub allow_exec: bool,
+}
+
+impl UserData for OptionsHelpers {
+    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_method("set", |_lua, this, table: Table| {
+            // Checked as a whole, so a bad key leaves all options as they were
+            let mut values = Vec::new();
+            for pair in table.pairs::<Value, Value>() {
+                let (key, value) = pair?;
+                values.push(check(key, value, this.allow_exec).map_err(mlua::Error::RuntimeError)?);
+            }
+            let mut options = this.options.lock().unwrap();
+            for (name, value) in values {
+                debug!("Option {} = {:?}", name, value);
+                options.values.insert(name, value);
+            }
+            Ok(())
+        });
+        methods.add_method("get", |lua, this, name: String| {
+            let value = this
+                .options
+                .lock()
+                .unwrap()
+                .get(&name)
+                .map_err(mlua::Error::RuntimeError)?;
+            into_lua(lua, value)
+        });
+    }
+}
+
+fn into_lua(lua: &Lua, value: Option<OptionValue>) -> mlua::Result<Value<'_>> {
+    match value {
+        Some(OptionValue::String(value)) => value.into_lua(lua),
+        Some(OptionValue::Number(value)) => value.into_lua(lua),
+        None => Ok(Value::Nil),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check_str<'lua>(
+        lua: &'lua Lua,
+        key: &str,
+        value: Value<'lua>,
+        allow_exec: bool,
+    ) -> Result<(&'static str, OptionValue), String> {
+        check(
+            Value::String(lua.create_string(key).unwrap()),
+            value,
+            allow_exec,
+        )
+    }
+
+    #[test]
+    fn accepts_valid_options() {
+        let lua = Lua::new();
+        let cmd = Value::String(lua.create_string("swaylock -f").unwrap());
+        assert_eq!(
+            check_str(&lua, "lock_cmd", cmd, true),
+            Ok(("lock_cmd", OptionValue::String("swaylock -f".to_string())))
+        );
+        assert_eq!(
+            check_str(&lua, "grace", Value::Integer(5), false),
+            Ok(("grace", OptionValue::Number(5.0)))
+        );
+    }
+
+    #[test]
+    fn rejects_commands_when_exec_is_off() {
+        let lua = Lua::new();
+        let cmd = Value::String(lua.create_string("swaylock -f").unwrap());
+        let error = check_str(&lua, "lock_cmd", cmd, false).unwrap_err();
+        assert!(error.contains("sandbox"), "{}", error);
+    }
+
+    #[test]
+    fn rejects_invalid_options() {
+        let lua = Lua::new();
+        let error = check_str(&lua, "lock_comand", Value::Nil, true).unwrap_err();
+        assert!(error.starts_with("unknown option lock_comand"), "{}", error);
+        let error = check_str(&lua, "grace", Value::Boolean(true), true).unwrap_err();
+        assert_eq!(error, "option grace expects a number, got boolean");
+        let error = check_str(&lua, "grace", Value::Number(61.0), true).unwrap_err();
+        assert_eq!(error, "option grace must be between 0 and 60, got 61");
+        let error = check(Value::Integer(1), Value::Integer(1), true).unwrap_err();
+        assert_eq!(error, "option names are strings, got integer");
+    }
+}
@@ -0,0 +1,408 @@
+//! Output power management. Configs turn the screens off and on with
+//! wlr-output-power-management, like `swaymsg "output * dpms off"` in a swayidle setup, but on
+//! every compositor that has the protocol. Outputs that are slow to wake, like TVs or screens
+//! behind an MST hub, can be turned off in a sequence, which checks that each one powered down.
+
+use glob::Pattern;
+use log::{debug, error, info, warn};
+use mlua::{Table, UserData, UserDataMethods};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{mpsc, Notify};
+use tokio::time::Instant;
+use wayland_client::protocol::wl_output;
+use wayland_client::{Connection, WEnum};
+use wayland_protocols_wlr::output_power_management::v1::client::zwlr_output_power_v1::{
+    self, Mode,
+};
+
+use super::nightlight::parse_pattern;
+use super::types::Request;
+use super::utils;
+
+/// How long an output of a sequence gets to report that it is off, unless configured
+const DEFAULT_VERIFY: Duration = Duration::from_secs(3);
+
+/// Creates the power control of an output, see the `zwlr_output_power_manager_v1` global.
+pub type CreatePower =
+    Arc<dyn Fn(&wl_output::WlOutput, u32) -> zwlr_output_power_v1::ZwlrOutputPowerV1 + Send + Sync>;
+
+#[derive(Debug)]
+struct PowerOutput {
+    wl_output: wl_output::WlOutput,
+    name: Option<String>,
+    description: Option<String>,
+    /// Dropped when the compositor sends `failed`, e.g. for a disabled output, and created
+    /// again on the next change
+    power: Option<zwlr_output_power_v1::ZwlrOutputPowerV1>,
+    /// As last reported by the compositor
+    on: Option<bool>,
+    /// Turned off by the daemon, turned back on when it exits
+    switched_off: bool,
+}
+
+impl PowerOutput {
+    fn matches(&self, pattern: &Pattern) -> bool {
+        [&self.name, &self.description]
+            .into_iter()
+            .flatten()
+            .any(|value| pattern.matches(value))
+    }
+}
+
+/// A step of `Outputs:power_off_sequence`: the outputs matching the pattern, after a delay.
+#[derive(Clone, Debug)]
+struct Step {
+    pattern: Pattern,
+    delay: Duration,
+}
+
+#[derive(Clone, Debug)]
+pub struct Sequence {
+    steps: Vec<Step>,
+    /// How long each output gets to report that it is off
+    verify: Duration,
+}
+
+#[derive(Default)]
+pub struct Outputs {
+    /// Outputs by their registry name
+    outputs: HashMap<u32, PowerOutput>,
+    create_power: Option<CreatePower>,
+    conn: Option<Connection>,
+    /// Notified when an output reports its mode
+    changed: Arc<Notify>,
+}
+
+pub type OutputsHandle = Arc<Mutex<Outputs>>;
+
+impl fmt::Debug for Outputs {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Outputs")
+            .field("outputs", &self.outputs)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Outputs {
+    pub fn new() -> OutputsHandle {
+        Arc::new(Mutex::new(Self::default()))
+    }
+
+    /// Requests are sent from the Lua thread, which has to flush them.
+    pub fn set_conn(&mut self, conn: Connection) {
+        self.conn = Some(conn);
+    }
+
+    /// Called once the compositor supports wlr-output-power-management.
+    pub fn set_create_power(&mut self, create_power: CreatePower) {
+        for (reg_name, output) in &mut self.outputs {
+            // Known outputs report their current mode right away
+            output
+                .power
+                .get_or_insert_with(|| create_power(&output.wl_output, *reg_name));
+        }
+        self.create_power = Some(create_power);
+    }
+
+    pub fn add_output(&mut self, reg_name: u32, wl_output: wl_output::WlOutput) {
+        let power = self
+            .create_power
+            .as_ref()
+            .map(|create_power| create_power(&wl_output, reg_name));
+        self.outputs.insert(
+            reg_name,
+            PowerOutput {
+                wl_output,
+                name: None,
+                description: None,
+                power,
+                on: None,
+                switched_off: false,
+            },
+        );
+    }
+
+    pub fn set_output_name(&mut self, reg_name: u32, name: String) {
+        if let Some(output) = self.outputs.get_mut(&reg_name) {
+            output.name = Some(name);
+        }
+    }
+
+    pub fn set_output_description(&mut self, reg_name: u32, description: String) {
+        if let Some(output) = self.outputs.get_mut(&reg_name) {
+            output.description = Some(description);
+        }
+    }
+
+    pub fn remove_output(&mut self, reg_name: u32) {
+        if let Some(power) = self
+            .outputs
+            .remove(&reg_name)
+            .and_then(|output| output.power)
+        {
+            power.destroy();
+        }
+    }
+
+    pub fn set_mode(&mut self, reg_name: u32, mode: WEnum<Mode>) {
+        if let Some(output) = self.outputs.get_mut(&reg_name) {
+            let on = matches!(mode, WEnum::Value(Mode::On));
+            debug!(
+                "Output {:?} is {}",
+                output.name,
+                if on { "on" } else { "off" }
+            );
+            output.on = Some(on);
+        }
+        self.changed.notify_waiters();
+    }
+
+    pub fn control_failed(&mut self, reg_name: u32) {
+        if let Some(output) = self.outputs.get_mut(&reg_name) {
+            warn!("Power management of output {:?} failed", output.name);
+            if let Some(power) = output.power.take() {
+                power.destroy();
+            }
+            output.on = None;
+        }
+        self.changed.notify_waiters();
+    }
+
+    /// Turns the outputs matching `pattern`, or all of them, on or off. Returns how many
+    /// outputs were switched.
+    pub fn set_power(&mut self, on: bool, pattern: Option<&Pattern>) -> Result<usize, String> {
+        self.switch(on, pattern).map(|switched| switched.len())
+    }
+
+    /// Like `set_power`, returning the registry names of the switched outputs.
+    fn switch(&mut self, on: bool, pattern: Option<&Pattern>) -> Result<Vec<u32>, String> {
+        let Some(create_power) = &self.create_power else {
+            return Err("the compositor doesn't support wlr-output-power-management".to_string());
+        };
+        let mode = if on { Mode::On } else { Mode::Off };
+        let mut switched = Vec::new();
+        for (reg_name, output) in &mut self.outputs {
+            if pattern.is_some_and(|pattern| !output.matches(pattern)) {
+                continue;
+            }
+            info!("Turning output {:?} {:?}", output.name, mode);
+            output
+                .power
+                .get_or_insert_with(|| create_power(&output.wl_output, *reg_name))
+                .set_mode(mode);
+            output.switched_off = !on;
+            switched.push(*reg_name);
+        }
+        if let Some(conn) = &self.conn {
+            if let Err(e) = conn.flush() {
+                error!("Failed to flush the output power modes: {}", e);
+            }
+        }
+        Ok(switched)
+    }
+
+    /// Turns the outputs the daemon switched off back on and releases the power controls, on
+    /// exit. Also sends everything else that is queued on the Wayland connection.
+    pub fn release(&mut self) {
+        for output in self.outputs.values_mut() {
+            let Some(power) = output.power.take() else {
+                continue;
+            };
+            if output.switched_off {
+                info!("Turning output {:?} back on", output.name);
+                power.set_mode(Mode::On);
+            }
+            power.destroy();
+        }
+        if let Some(conn) = &self.conn {
+            if let Err(e) = conn.flush() {
+                error!("Failed to flush the output power modes: {}", e);
+            }
+        }
+    }
+
+    fn is_off(&self, reg_name: u32) -> bool {
+        self.outputs
+            .get(&reg_name)
+            .is_some_and(|output| output.on == Some(false))
+    }
+
+    fn name(&self, reg_name: u32) -> String {
+        self.outputs
+            .get(&reg_name)
+            .and_then(|output| output.name.clone())
+            .unwrap_or_else(|| format!("#{}", reg_name))
+    }
+
+    /// The named outputs sorted by name, with their description and whether they are on.
+    pub fn list(&self) -> Vec<(String, Option<String>, Option<bool>)> {
+        let mut list: Vec<_> = self
+            .outputs
+            .values()
+            .filter_map(|output| {
+                let name = output.name.clone()?;
+                Some((name, output.description.clone(), output.on))
+            })
+            .collect();
+        list.sort();
+        list
+    }
+
+    /// Whether an output whose name or description matches `pattern` is plugged in.
+    pub fn connected(&self, pattern: &Pattern) -> bool {
+        self.outputs.values().any(|output| output.matches(pattern))
+    }
+
+    /// Whether the output with the name is on, `None` for an unknown output or mode.
+    pub fn power(&self, name: &str) -> Option<bool> {
+        self.outputs
+            .values()
+            .find(|output| output.name.as_deref() == Some(name))
+            .and_then(|output| output.on)
+    }
+}
+
+async fn report(tx: &mpsc::Sender<Request>, output: &str, err: String) {
+    warn!("Output {}: {}", output, err);
+    let context = vec![
+        ("source".to_string(), "output".to_string()),
+        ("output".to_string(), output.to_string()),
+    ];
+    let _ = tx.send(Request::Error(err, context)).await;
+}
+
+/// Turns the outputs of the steps off one after another. Outputs that don't report being off
+/// within the verify time are passed to the `on_error` hooks.
+pub async fn power_off_sequence(
+    sequence: Sequence,
+    outputs: OutputsHandle,
+    tx: mpsc::Sender<Request>,
+) {
+    let changed = outputs.lock().unwrap().changed.clone();
+    for step in sequence.steps {
+        tokio::time::sleep(step.delay).await;
+        let switched = outputs.lock().unwrap().switch(false, Some(&step.pattern));
+        let mut pending = match switched {
+            Ok(switched) if switched.is_empty() => {
+                let err = "no output matches".to_string();
+                report(&tx, step.pattern.as_str(), err).await;
+                continue;
+            }
+            Ok(switched) => switched,
+            Err(e) => {
+                report(&tx, step.pattern.as_str(), e).await;
+                return;
+            }
+        };
+        let deadline = Instant::now() + sequence.verify;
+        loop {
+            // Created before the check, so a mode reported in between isn't missed
+            let notified = changed.notified();
+            pending.retain(|reg_name| !outputs.lock().unwrap().is_off(*reg_name));
+            if pending.is_empty() || tokio::time::timeout_at(deadline, notified).await.is_err() {
+                break;
+            }
+        }
+        for reg_name in pending {
+            let name = outputs.lock().unwrap().name(reg_name);
+            let err = format!("didn't power down within {:?}", sequence.verify);
+            report(&tx, &name, err).await;
+        }
+    }
+}
+
+fn sequence(steps: Table, options: Option<Table>) -> mlua::Result<Sequence> {
+    let steps = steps
+        .sequence_values::<Table>()
+        .map(|step| {
+            let step = step?;
+            let pattern = parse_pattern(&step.get::<_, String>("output")?)
+                .map_err(mlua::Error::RuntimeError)?;
+            let delay = step.get::<_, Option<f64>>("delay")?.unwrap_or(0.0);
+            let delay = Duration::try_from_secs_f64(delay)
+                .map_err(|_| mlua::Error::RuntimeError(format!("invalid delay {}", delay)))?;
+            Ok(Step { pattern, delay })
+        })
+        .collect::<mlua::Result<Vec<_>>>()?;
+    let verify = match options {
+        Some(options) => options.get::<_, Option<f64>>("verify")?,
+        None => None,
+    };
+    let verify = match verify {
+        Some(verify) => Duration::try_from_secs_f64(verify)
+            .map_err(|_| mlua::Error::RuntimeError(format!("invalid verify time {}", verify)))?,
+        None => DEFAULT_VERIFY,
+    };
+    Ok(Sequence { steps, verify })
+}
+
+#[derive(Clone, Debug)]
+pub struct OutputsHelpers {
+    pub outputs: OutputsHandle,
+    pub tx: mpsc::Sender<Request>,
+}
+
+impl UserData for OutputsHelpers {
+    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_method(
+            "set_power",
+            |_lua, this, (mode, pattern): (String, Option<String>)| {
+                let on = match mode.as_str() {
+                    "on" => true,
+                    "off" => false,
+                    _ => {
+                        return Err(mlua::Error::RuntimeError(format!(
+                            "invalid power mode {}, expected on or off",
+                            mode
+                        )))
+                    }
+                };
+                let pattern = pattern
+                    .as_deref()
+                    .map(parse_pattern)
+                    .transpose()
+                    .map_err(mlua::Error::RuntimeError)?;
+                this.outputs
+                    .lock()
+                    .unwrap()
+                    .set_power(on, pattern.as_ref())
+                    .map_err(mlua::Error::RuntimeError)
+            },
+        );
+        methods.add_method(
+            "power_off_sequence",
+            |_lua, this, (steps, options): (Table, Option<Table>)| {
+                let sequence = sequence(steps, options)?;
+                debug!("Output power off sequence: {:?}", sequence);
+                utils::send_request(&this.tx, Request::OutputSequence(sequence));
+                Ok(())
+            },
+        );
+        methods.add_method("list", |lua, this, (): ()| {
+            let list = lua.create_table()?;
+            for (name, description, on) in this.outputs.lock().unwrap().list() {
+                let entry = lua.create_table()?;
+                entry.set("name", name)?;
+                entry.set("description", description)?;
+                entry.set("power", on.map(|on| if on { "on" } else { "off" }))?;
+                list.push(entry)?;
+            }
+            Ok(list)
+        });
+        methods.add_method("connected", |_lua, this, pattern: String| {
+            let pattern = parse_pattern(&pattern).map_err(mlua::Error::RuntimeError)?;
+            Ok(this.outputs.lock().unwrap().connected(&pattern))
+        });
+        methods.add_method("power", |_lua, this, name: String| {
+            Ok(this
+                .outputs
+                .lock()
+                .unwrap()
+                .power(&name)
+                .map(|on| if on { "on" } else { "off" }))
+        });
+    }
+}
@@ -0,0 +1,377 @@
+//! Lock coordination between machines. Lock and unlock events of the session are sent to the
+//! configured peers over UDP, and events received from peers can be mirrored or handled by the
+//! Lua config.
+//!
+//! The shared token never goes on the wire. It keys an HMAC-SHA256 over each message, which
+//! carries a timestamp and a sequence number per instance, so captured messages can't be
+//! forged, and replays are rejected once stale or already seen.
+
+use chrono::Utc;
+use log::{debug, error, info};
+use mlua::{Lua, UserData, UserDataMethods};
+use ring::hmac;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+use uuid::Uuid;
+
+use super::callbacks;
+use super::hooks::HooksHandle;
+use super::ipc;
+use super::scripts;
+use super::settings::PeersSettings;
+use super::types::Request;
+
+/// A mirrored event comes back from logind well within this time
+const MIRROR_WINDOW: Duration = Duration::from_secs(5);
+const MAX_MESSAGE_SIZE: usize = 1024;
+/// Messages with a timestamp further off than this are rejected, which also bounds how long
+/// the last sequence number of an instance is remembered
+const MAX_AGE_SECS: i64 = 30;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum PeerEvent {
+    Lock,
+    Unlock,
+}
+
+impl PeerEvent {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PeerEvent::Lock => "lock",
+            PeerEvent::Unlock => "unlock",
+        }
+    }
+}
+
+/// What the HMAC covers.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct Payload {
+    /// Tells our own broadcasts apart from those of peers, new on every start
+    instance: String,
+    host: String,
+    event: PeerEvent,
+    /// Unix time of sending
+    timestamp: i64,
+    /// Counts up with every message of the instance
+    sequence: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Message {
+    payload: Payload,
+    /// Hex HMAC-SHA256 of the JSON of `payload`, keyed with the shared token
+    mac: String,
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn unhex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+impl Message {
+    fn sign(key: &hmac::Key, payload: Payload) -> serde_json::Result<Self> {
+        let tag = hmac::sign(key, &serde_json::to_vec(&payload)?);
+        Ok(Self {
+            payload,
+            mac: hex(tag.as_ref()),
+        })
+    }
+
+    /// The payload, if the MAC was made with `key`.
+    fn verify(self, key: &hmac::Key) -> Option<Payload> {
+        let mac = unhex(&self.mac)?;
+        let data = serde_json::to_vec(&self.payload).ok()?;
+        hmac::verify(key, &data, &mac).ok()?;
+        Some(self.payload)
+    }
+}
+
+/// Rejects messages that are stale or were seen before.
+#[derive(Debug, Default)]
+struct ReplayGuard {
+    /// Last sequence number and timestamp per instance
+    seen: HashMap<String, (u64, i64)>,
+}
+
+impl ReplayGuard {
+    fn accept(&mut self, payload: &Payload, now: i64) -> Result<(), &'static str> {
+        if (now - payload.timestamp).abs() > MAX_AGE_SECS {
+            return Err("stale");
+        }
+        // Messages of instances that were quiet for longer can't pass the age check anyway
+        self.seen
+            .retain(|_, (_, timestamp)| now - *timestamp <= MAX_AGE_SECS);
+        if let Some((sequence, _)) = self.seen.get(&payload.instance) {
+            if payload.sequence <= *sequence {
+                return Err("replayed");
+            }
+        }
+        self.seen.insert(
+            payload.instance.clone(),
+            (payload.sequence, payload.timestamp),
+        );
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Peers {
+    socket: Option<Arc<UdpSocket>>,
+    targets: Vec<SocketAddr>,
+    key: Option<hmac::Key>,
+    instance: String,
+    host: String,
+    sequence: u64,
+    /// An event that was mirrored from a peer, so it isn't sent back to the peers
+    mirrored: Option<(PeerEvent, Instant)>,
+    on_event: Vec<String>,
+}
+
+pub type PeersHandle = Arc<Mutex<Peers>>;
+
+impl Peers {
+    pub fn new() -> PeersHandle {
+        Arc::new(Mutex::new(Self::default()))
+    }
+
+    pub fn clear(&mut self) {
+        self.on_event.clear();
+    }
+
+    /// Drops the callbacks of the script `script`, before it's loaded again.
+    pub fn forget_script(&mut self, script: &str) {
+        self.on_event
+            .retain(|fn_name| !scripts::owns(script, fn_name));
+    }
+
+    /// Remembers that `event` is about to happen because a peer asked for it.
+    pub fn mirror(&mut self, event: PeerEvent) {
+        self.mirrored = Some((event, Instant::now()));
+    }
+
+    /// Sends a lock or unlock of this session to the peers.
+    pub fn broadcast(&mut self, event: PeerEvent) {
+        let (Some(socket), Some(key)) = (self.socket.clone(), self.key.as_ref()) else {
+            return;
+        };
+        if let Some((mirrored, at)) = self.mirrored.take() {
+            if mirrored == event && at.elapsed() < MIRROR_WINDOW {
+                debug!(
+                    "Not sending the mirrored {} back to the peers",
+                    event.as_str()
+                );
+                return;
+            }
+        }
+        self.sequence += 1;
+        let payload = Payload {
+            instance: self.instance.clone(),
+            host: self.host.clone(),
+            event,
+            timestamp: Utc::now().timestamp(),
+            sequence: self.sequence,
+        };
+        let message = Message::sign(key, payload).and_then(|message| serde_json::to_vec(&message));
+        let message = match message {
+            Ok(message) => message,
+            Err(e) => {
+                error!("Failed to encode peer message: {}", e);
+                return;
+            }
+        };
+        let targets = self.targets.clone();
+        tokio::spawn(async move {
+            for target in targets {
+                if let Err(e) = socket.send_to(&message, target).await {
+                    error!(
+                        "Failed to send {} to peer {}: {}",
+                        event.as_str(),
+                        target,
+                        e
+                    );
+                }
+            }
+        });
+    }
+}
+
+/// Calls the `Peers:on_event` callbacks with the event and the host it came from.
+pub fn run_callbacks(
+    lua: &Lua,
+    peers: &PeersHandle,
+    hooks: &HooksHandle,
+    event: PeerEvent,
+    host: &str,
+) {
+    let callbacks = peers.lock().unwrap().on_event.clone();
+    for fn_name in callbacks {
+        callbacks::call::<_, ()>(lua, hooks, &fn_name, (event.as_str(), host));
+    }
+}
+
+pub fn hostname() -> String {
+    fs::read_to_string("/proc/sys/kernel/hostname")
+        .map(|host| host.trim().to_string())
+        .unwrap_or_default()
+}
+
+/// Binds the peer socket and forwards the events of peers with `Request::Peer`. Does nothing
+/// unless `listen` is set.
+pub async fn peers_run(
+    settings: &PeersSettings,
+    peers: PeersHandle,
+    tx: mpsc::Sender<Request>,
+) -> anyhow::Result<()> {
+    let Some(addr) = settings.listen else {
+        return Ok(());
+    };
+    let Some(token_file) = &settings.token_file else {
+        anyhow::bail!("peer mode needs a token_file");
+    };
+    let key = hmac::Key::new(hmac::HMAC_SHA256, ipc::read_token(token_file)?.as_bytes());
+    let socket = Arc::new(UdpSocket::bind(addr).await?);
+    socket.set_broadcast(true)?;
+    let instance = Uuid::new_v4().to_string();
+    {
+        let mut peers = peers.lock().unwrap();
+        peers.socket = Some(socket.clone());
+        peers.targets = settings.peers.clone();
+        peers.key = Some(key.clone());
+        peers.instance = instance.clone();
+        peers.host = hostname();
+    }
+    info!("Peer mode listening on {}", addr);
+
+    tokio::spawn(async move {
+        let mut buf = [0u8; MAX_MESSAGE_SIZE];
+        let mut replays = ReplayGuard::default();
+        loop {
+            let (len, from) = match socket.recv_from(&mut buf).await {
+                Ok(received) => received,
+                Err(e) => {
+                    error!("Error receiving peer message: {}", e);
+                    continue;
+                }
+            };
+            let message = match serde_json::from_slice::<Message>(&buf[..len]) {
+                Ok(message) => message,
+                Err(e) => {
+                    debug!("Invalid peer message from {}: {}", from, e);
+                    continue;
+                }
+            };
+            // Only authenticated messages go on, they can lock and unlock the session
+            let Some(message) = message.verify(&key) else {
+                info!("Ignoring peer message from {} with a wrong MAC", from);
+                continue;
+            };
+            if message.instance == instance {
+                continue;
+            }
+            if let Err(why) = replays.accept(&message, Utc::now().timestamp()) {
+                info!("Ignoring {} peer message from {}", why, from);
+                continue;
+            }
+            debug!(
+                "Peer {} ({}) sent {}",
+                message.host,
+                from,
+                message.event.as_str()
+            );
+            if tx
+                .send(Request::Peer(message.event, message.host))
+                .await
+                .is_err()
+            {
+                return;
+            }
+        }
+    });
+    Ok(())
+}
+
+#[derive(Clone, Debug)]
+pub struct PeerHelpers {
+    pub peers: PeersHandle,
+    pub allow_network: bool,
+}
+
+impl UserData for PeerHelpers {
+    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_method("on_event", |_lua, this, fn_name: String| {
+            if !this.allow_network {
+                return Err(mlua::Error::RuntimeError(
+                    "peer events are disabled by the sandbox policy".to_string(),
+                ));
+            }
+            this.peers.lock().unwrap().on_event.push(fn_name);
+            Ok(())
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn payload(instance: &str, timestamp: i64, sequence: u64) -> Payload {
+        Payload {
+            instance: instance.to_string(),
+            host: "desk".to_string(),
+            event: PeerEvent::Unlock,
+            timestamp,
+            sequence,
+        }
+    }
+
+    #[test]
+    fn verifies_the_mac() {
+        let key = hmac::Key::new(hmac::HMAC_SHA256, b"shared token");
+        let other = hmac::Key::new(hmac::HMAC_SHA256, b"guessed token");
+        let signed = || Message::sign(&key, payload("a", 1000, 1)).unwrap();
+        assert!(signed().verify(&key).is_some());
+        assert!(signed().verify(&other).is_none());
+        let mut tampered = signed();
+        tampered.payload.event = PeerEvent::Lock;
+        assert!(tampered.verify(&key).is_none());
+        let mut garbled = signed();
+        garbled.mac.pop();
+        assert!(garbled.verify(&key).is_none());
+    }
+
+    #[test]
+    fn rejects_replayed_and_stale_messages() {
+        let mut guard = ReplayGuard::default();
+        let now = 1000;
+        assert_eq!(guard.accept(&payload("a", now, 1), now), Ok(()));
+        assert_eq!(guard.accept(&payload("a", now, 1), now), Err("replayed"));
+        assert_eq!(guard.accept(&payload("a", now, 0), now), Err("replayed"));
+        assert_eq!(guard.accept(&payload("a", now + 1, 2), now + 1), Ok(()));
+        // Sequence numbers count per instance
+        assert_eq!(guard.accept(&payload("b", now, 1), now + 1), Ok(()));
+        let stale = now - MAX_AGE_SECS - 1;
+        assert_eq!(guard.accept(&payload("c", stale, 1), now), Err("stale"));
+        let ahead = now + MAX_AGE_SECS + 1;
+        assert_eq!(guard.accept(&payload("c", ahead, 1), now), Err("stale"));
+        // Once forgotten, an old message of the instance is too old to pass
+        let later = now + MAX_AGE_SECS + 10;
+        assert_eq!(guard.accept(&payload("d", later, 1), later), Ok(()));
+        assert_eq!(guard.accept(&payload("a", now, 1), later), Err("stale"));
+    }
+}
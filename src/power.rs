@@ -0,0 +1,548 @@
+use log::{debug, info, warn};
+use mlua::{UserData, UserDataMethods};
+use std::fs;
+use std::path::Path;
+use std::process::Stdio;
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::process::Command;
+use tokio::sync::mpsc;
+
+use super::battery::BatteryHandle;
+use super::daemon::StatusHandle;
+use super::dbus::{
+    logind_idle_action, logind_session, LogindManagerInterfaceProxy, PackageKitInterfaceProxy,
+};
+use super::dnd::DndHandle;
+use super::journal;
+use super::privileged::Action;
+use super::restore::RestoreHandle;
+use super::settings::LogindIdlePolicy;
+use super::telemetry;
+use super::types::Request;
+use super::utils;
+
+const SCREEN_SHARING_PROCESSES: &[&str] =
+    &["wf-recorder", "obs", "zoom", "teams", "gpu-screen-recorder"];
+const UPDATE_PROCESSES: &[&str] = &["pacman", "dnf", "yum", "apt", "apt-get", "dpkg", "zypper"];
+const PACKAGEKIT_SERVICE: &str = "org.freedesktop.PackageKit";
+/// How often a deferred suspend or hibernate checks whether the update finished
+const UPDATE_POLL_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Conditions that have to hold before `Power:idle_suspend()` suspends the machine.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Guard {
+    /// No logind inhibitor is blocking idle or sleep
+    Inhibitors,
+    /// The session has been locked
+    Locked,
+    /// No screen sharing or recording application is running
+    ScreenSharing,
+    /// No package manager transaction is in progress
+    Updating,
+    /// No do-not-disturb window forbids suspending
+    Dnd,
+}
+
+impl Guard {
+    pub const ALL: [Guard; 5] = [
+        Guard::Inhibitors,
+        Guard::Locked,
+        Guard::ScreenSharing,
+        Guard::Updating,
+        Guard::Dnd,
+    ];
+}
+
+impl FromStr for Guard {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "inhibitors" => Ok(Guard::Inhibitors),
+            "locked" => Ok(Guard::Locked),
+            "screen_sharing" => Ok(Guard::ScreenSharing),
+            "updating" => Ok(Guard::Updating),
+            "dnd" => Ok(Guard::Dnd),
+            _ => Err(format!("unknown suspend guard: {}", s)),
+        }
+    }
+}
+
+/// Dialog that is shown before suspending or hibernating. Exiting with 0 confirms, any other
+/// exit status cancels and no answer within the timeout confirms. A dialog that can't be shown
+/// cancels as well.
+#[derive(Clone, Debug)]
+pub struct Confirm {
+    command: String,
+    timeout: Duration,
+}
+
+#[derive(Clone, Debug)]
+pub struct PowerHelpers {
+    tx: mpsc::Sender<Request>,
+    battery: BatteryHandle,
+    /// The previous brightness is saved before `set_backlight` changes it
+    restore: RestoreHandle,
+    guards: Vec<Guard>,
+    confirm: Option<Confirm>,
+    allow_exec: bool,
+}
+
+impl PowerHelpers {
+    pub fn new(
+        tx: mpsc::Sender<Request>,
+        battery: BatteryHandle,
+        restore: RestoreHandle,
+        allow_exec: bool,
+    ) -> Self {
+        Self {
+            tx,
+            battery,
+            restore,
+            guards: Guard::ALL.to_vec(),
+            confirm: None,
+            allow_exec,
+        }
+    }
+}
+
+impl UserData for PowerHelpers {
+    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_method_mut("set_guards", |_lua, this, guards: Vec<String>| {
+            this.guards = guards
+                .iter()
+                .map(|guard| guard.parse())
+                .collect::<Result<_, _>>()
+                .map_err(mlua::Error::RuntimeError)?;
+            Ok(())
+        });
+        methods.add_method_mut(
+            "set_confirm",
+            |_lua, this, (command, timeout): (Option<String>, Option<u64>)| {
+                if command.is_some() && !this.allow_exec {
+                    return Err(mlua::Error::RuntimeError(
+                        "running commands is disabled by the sandbox policy".to_string(),
+                    ));
+                }
+                this.confirm = command.map(|command| Confirm {
+                    command,
+                    timeout: Duration::from_secs(timeout.unwrap_or(30)),
+                });
+                Ok(())
+            },
+        );
+        methods.add_method("hibernate", |_lua, this, (): ()| {
+            let defer = this.guards.contains(&Guard::Updating);
+            utils::send_request(&this.tx, Request::Hibernate(defer, this.confirm.clone()));
+            Ok(())
+        });
+        methods.add_method(
+            "can_hibernate",
+            |_lua, _this, (): ()| match hibernation_problem() {
+                Some(problem) => Ok((false, Some(problem))),
+                None => Ok((true, None)),
+            },
+        );
+        methods.add_method("logout", |_lua, this, (): ()| {
+            utils::send_request(&this.tx, Request::Logout);
+            Ok(())
+        });
+        methods.add_method("wake_in", |_lua, this, secs: u64| {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_err(mlua::Error::external)?;
+            utils::send_request(
+                &this.tx,
+                Request::Privileged(Action::RtcWake(now.as_secs() + secs)),
+            );
+            Ok(())
+        });
+        methods.add_method(
+            "set_backlight",
+            |_lua, this, (device, brightness): (String, u32)| {
+                if let Err(e) = this.restore.lock().unwrap().save_backlight(&device) {
+                    warn!("Can't save backlight {}: {}", device, e);
+                }
+                utils::send_request(
+                    &this.tx,
+                    Request::Privileged(Action::Backlight { device, brightness }),
+                );
+                Ok(())
+            },
+        );
+        methods.add_method("on_battery", |_lua, this, (): ()| {
+            Ok(this.battery.lock().unwrap().on_battery())
+        });
+        methods.add_method("percentage", |_lua, this, (): ()| {
+            Ok(this.battery.lock().unwrap().level())
+        });
+        methods.add_method("on_power_changed", |_lua, this, fn_name: String| {
+            debug!("Calling {} when the power source changes", fn_name);
+            this.battery.lock().unwrap().watch_power(fn_name);
+            Ok(())
+        });
+        methods.add_method("idle_suspend", |_lua, this, (): ()| {
+            utils::send_request(
+                &this.tx,
+                Request::IdleSuspend(this.guards.clone(), this.confirm.clone()),
+            );
+            Ok(())
+        });
+    }
+}
+
+/// Describes the package manager transaction in progress, if any.
+async fn running_update(conn: &zbus::Connection) -> anyhow::Result<Option<String>> {
+    if std::path::Path::new("/system-update").exists() {
+        return Ok(Some("an offline system update is pending".to_string()));
+    }
+    if let Some(name) = utils::running_process_async(UPDATE_PROCESSES).await {
+        return Ok(Some(format!("package manager {} is running", name)));
+    }
+    // Asking PackageKit directly would start it through D-Bus activation
+    let bus = zbus::fdo::DBusProxy::new(conn).await?;
+    if bus.name_has_owner(PACKAGEKIT_SERVICE.try_into()?).await? {
+        let packagekit = PackageKitInterfaceProxy::new(conn).await?;
+        if packagekit.locked().await? {
+            return Ok(Some("a PackageKit transaction is running".to_string()));
+        }
+    }
+    Ok(None)
+}
+
+/// Waits until no update is running anymore. Returns false if the wait was started while
+/// idle and the user came back in the meantime, so the deferred action is dropped.
+async fn wait_for_updates(conn: &zbus::Connection, status: &StatusHandle) -> anyhow::Result<bool> {
+    let idle = status.lock().unwrap().idle_elapsed() > 0;
+    while running_update(conn).await?.is_some() {
+        tokio::time::sleep(UPDATE_POLL_INTERVAL).await;
+        if idle && status.lock().unwrap().idle_elapsed() == 0 {
+            info!("User returned while waiting for the update");
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+/// Returns a description of why the guard blocks suspending, or None if it passes.
+async fn check_guard(
+    guard: Guard,
+    conn: &zbus::Connection,
+    dnd: &DndHandle,
+) -> anyhow::Result<Option<String>> {
+    match guard {
+        Guard::Inhibitors => {
+            let manager = LogindManagerInterfaceProxy::new(conn).await?;
+            let blocking =
+                manager
+                    .list_inhibitors()
+                    .await?
+                    .into_iter()
+                    .find(|(what, _, _, mode, _, _)| {
+                        mode == "block" && what.split(':').any(|w| w == "idle" || w == "sleep")
+                    });
+            Ok(blocking.map(|(what, who, why, _, _, _)| {
+                format!("inhibitor held by {} ({}): {}", who, what, why)
+            }))
+        }
+        Guard::Locked => {
+            let session = logind_session(conn).await?;
+            if session.locked_hint().await? {
+                Ok(None)
+            } else {
+                Ok(Some("session is not locked".to_string()))
+            }
+        }
+        Guard::ScreenSharing => Ok(utils::running_process_async(SCREEN_SHARING_PROCESSES)
+            .await
+            .map(|name| format!("screen sharing application {} is running", name))),
+        Guard::Updating => running_update(conn).await,
+        Guard::Dnd => {
+            if dnd.lock().unwrap().blocks_suspend() {
+                Ok(Some("do-not-disturb is active".to_string()))
+            } else {
+                Ok(None)
+            }
+        }
+    }
+}
+
+/// Shows the confirmation dialog and returns whether `action`, e.g. `suspend`, may go ahead.
+async fn confirmed(confirm: &Confirm, action: &str) -> bool {
+    info!(
+        "Asking for confirmation, {} in {}s without an answer",
+        action,
+        confirm.timeout.as_secs()
+    );
+    let child = Command::new("sh")
+        .arg("-c")
+        .arg(&confirm.command)
+        .stdin(Stdio::null())
+        .kill_on_drop(true)
+        .spawn();
+    let mut child = match child {
+        Ok(child) => child,
+        Err(e) => {
+            warn!("Failed to show confirmation dialog, no {}: {}", action, e);
+            return false;
+        }
+    };
+
+    match tokio::time::timeout(confirm.timeout, child.wait()).await {
+        Ok(Ok(status)) if status.success() => true,
+        Ok(Ok(status)) => {
+            info!(
+                "Cancelled {} from the confirmation dialog ({})",
+                action, status
+            );
+            false
+        }
+        Ok(Err(e)) => {
+            warn!("Confirmation dialog failed, no {}: {}", action, e);
+            false
+        }
+        Err(_) => {
+            info!(
+                "No answer from the confirmation dialog, going ahead with {}",
+                action
+            );
+            let _ = child.kill().await;
+            true
+        }
+    }
+}
+
+/// Terminates the session, which ends all of its processes. Only done while the session is
+/// locked, so a misplaced call can't throw out someone who is using the machine.
+pub async fn logout() -> anyhow::Result<()> {
+    let conn = zbus::Connection::system().await?;
+    let session = logind_session(&conn).await?;
+    if !session.locked_hint().await? {
+        info!("Not logging out, the session is not locked");
+        return Ok(());
+    }
+    journal::event("logout", "Terminating the session", &[]);
+    session.terminate_session().await?;
+    Ok(())
+}
+
+/// A `Key: value kB` field of `/proc/meminfo`.
+fn meminfo_kib(meminfo: &str, key: &str) -> Option<u64> {
+    meminfo
+        .lines()
+        .find_map(|line| line.strip_prefix(key)?.strip_prefix(':'))?
+        .split_whitespace()
+        .next()?
+        .parse()
+        .ok()
+}
+
+/// Free swap in KiB, from the size and use of every swap area.
+fn free_swap_kib() -> Option<u64> {
+    let swaps = fs::read_to_string("/proc/swaps").ok()?;
+    Some(
+        swaps
+            .lines()
+            .skip(1)
+            .filter_map(|line| {
+                let fields: Vec<&str> = line.split_whitespace().collect();
+                let size: u64 = fields.get(2)?.parse().ok()?;
+                let used: u64 = fields.get(3)?.parse().ok()?;
+                Some(size.saturating_sub(used))
+            })
+            .sum(),
+    )
+}
+
+/// Why hibernating would fail, or not come back to the session: no kernel support, no resume
+/// device, or less free swap than memory in use.
+pub fn hibernation_problem() -> Option<String> {
+    let states = fs::read_to_string("/sys/power/state").unwrap_or_default();
+    if !states.split_whitespace().any(|state| state == "disk") {
+        return Some("the kernel doesn't support hibernation".to_string());
+    }
+    let cmdline = fs::read_to_string("/proc/cmdline").unwrap_or_default();
+    let resume_arg = cmdline
+        .split_whitespace()
+        .any(|arg| arg.starts_with("resume="));
+    let resume_dev =
+        fs::read_to_string("/sys/power/resume").is_ok_and(|dev| !matches!(dev.trim(), "" | "0:0"));
+    // On UEFI systemd passes the swap device to the next boot in the HibernateLocation variable
+    let efi = Path::new("/sys/firmware/efi").exists();
+    if !resume_arg && !resume_dev && !efi {
+        return Some(
+            "no resume device is configured, add resume= to the kernel arguments".to_string(),
+        );
+    }
+    let meminfo = fs::read_to_string("/proc/meminfo").ok()?;
+    let used =
+        meminfo_kib(&meminfo, "MemTotal")?.saturating_sub(meminfo_kib(&meminfo, "MemAvailable")?);
+    let free_swap = free_swap_kib()?;
+    if free_swap < used {
+        return Some(format!(
+            "{} MiB of free swap for {} MiB of memory in use",
+            free_swap / 1024,
+            used / 1024
+        ));
+    }
+    None
+}
+
+/// Hibernates once running updates finished, when `defer` is set, and the dialog of `confirm`
+/// agreed. Suspends instead if hibernating would fail.
+pub async fn hibernate(
+    defer: bool,
+    confirm: Option<Confirm>,
+    status: StatusHandle,
+    tx: mpsc::Sender<Request>,
+) {
+    if defer {
+        let waited = async {
+            let conn = zbus::Connection::system().await?;
+            if let Some(reason) = running_update(&conn).await? {
+                journal::event(
+                    "suspend_deferred",
+                    &format!("Hibernate deferred: {}", reason),
+                    &[("REASON", &reason)],
+                );
+                return wait_for_updates(&conn, &status).await;
+            }
+            anyhow::Ok(true)
+        };
+        match waited.await {
+            Ok(true) => {}
+            Ok(false) => return,
+            Err(e) => warn!("Failed to check for running updates: {}", e),
+        }
+    }
+    if let Some(confirm) = confirm {
+        if !confirmed(&confirm, "hibernate").await {
+            return;
+        }
+    }
+    if let Some(problem) = hibernation_problem() {
+        journal::event(
+            "hibernate_unsafe",
+            &format!("Not hibernating, {}. Suspending instead", problem),
+            &[("REASON", &problem)],
+        );
+        let suspended = async {
+            let conn = zbus::Connection::system().await?;
+            let manager = LogindManagerInterfaceProxy::new(&conn).await?;
+            manager.suspend(false).await?;
+            anyhow::Ok(())
+        };
+        if let Err(e) = suspended.await {
+            warn!("Failed to suspend: {}", e);
+        }
+        return;
+    }
+    let _ = tx.send(Request::Privileged(Action::Hibernate)).await;
+}
+
+pub async fn idle_suspend(
+    guards: Vec<Guard>,
+    confirm: Option<Confirm>,
+    dnd: DndHandle,
+    status: StatusHandle,
+    logind_idle: LogindIdlePolicy,
+) -> anyhow::Result<()> {
+    let conn = zbus::Connection::system().await?;
+
+    if logind_idle == LogindIdlePolicy::Defer {
+        let manager = LogindManagerInterfaceProxy::new(&conn).await?;
+        if let Some((action, after)) = logind_idle_action(&manager).await? {
+            let reason = format!(
+                "left to logind's IdleAction={} after {}",
+                action,
+                humantime::format_duration(after)
+            );
+            journal::event(
+                "suspend_deferred",
+                &format!("Idle suspend {}", reason),
+                &[("REASON", &reason)],
+            );
+            return Ok(());
+        }
+    }
+
+    // Starts over after waiting for an update, the other guards may have changed meanwhile
+    'check: loop {
+        for &guard in &guards {
+            debug!("Checking suspend guard {:?}", guard);
+            let span = telemetry::span("guard_check");
+            span.set_attribute("guard", format!("{:?}", guard));
+            let Some(reason) = check_guard(guard, &conn, &dnd).await? else {
+                continue;
+            };
+            span.set_attribute("blocked", &reason);
+            if guard == Guard::Updating {
+                journal::event(
+                    "suspend_deferred",
+                    &format!("Idle suspend deferred: {}", reason),
+                    &[("REASON", &reason)],
+                );
+                if wait_for_updates(&conn, &status).await? {
+                    continue 'check;
+                }
+                return Ok(());
+            }
+            journal::event(
+                "suspend_blocked",
+                &format!("Idle suspend blocked by guard {:?}: {}", guard, reason),
+                &[("GUARD", &format!("{:?}", guard)), ("REASON", &reason)],
+            );
+            return Ok(());
+        }
+        break;
+    }
+
+    info!("All suspend guards passed");
+    if let Some(confirm) = confirm {
+        if !confirmed(&confirm, "suspend").await {
+            return Ok(());
+        }
+    }
+    journal::event("suspend", "Suspending", &[]);
+    let _span = telemetry::span("suspend");
+    let manager = LogindManagerInterfaceProxy::new(&conn).await?;
+    manager.suspend(false).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn confirm(command: &str, timeout: Duration) -> Confirm {
+        Confirm {
+            command: command.to_string(),
+            timeout,
+        }
+    }
+
+    #[tokio::test]
+    async fn dialog_answers() {
+        let timeout = Duration::from_secs(10);
+        assert!(confirmed(&confirm("exit 0", timeout), "suspend").await);
+        assert!(!confirmed(&confirm("exit 1", timeout), "suspend").await);
+        assert!(!confirmed(&confirm("kill -9 $$", timeout), "suspend").await);
+    }
+
+    #[tokio::test]
+    async fn no_answer_confirms() {
+        let confirm = confirm("sleep 10", Duration::from_millis(100));
+        assert!(confirmed(&confirm, "hibernate").await);
+    }
+
+    #[test]
+    fn parses_guard_names() {
+        let names = ["inhibitors", "locked", "screen_sharing", "updating", "dnd"];
+        let guards: Vec<Guard> = names.iter().map(|name| name.parse().unwrap()).collect();
+        assert_eq!(guards, Guard::ALL);
+        assert!("Locked".parse::<Guard>().is_err());
+        assert!("screen-sharing".parse::<Guard>().is_err());
+        assert!("".parse::<Guard>().is_err());
+    }
+}
@@ -0,0 +1,140 @@
+//! Presentation mode: inhibits idle actions, disables the night light, raises the brightness
+//! and holds back notifications until it is turned off or expires.
+
+use chrono::{DateTime, Duration as ChronoDuration, Local, TimeZone};
+use clap::ValueEnum;
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+use super::backlight;
+use super::daemon::StatusHandle;
+use super::dnd::{DndHandle, DndMode};
+use super::privileged::Action;
+use super::settings::PresentationSettings;
+
+#[derive(ValueEnum, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum PresentationMode {
+    On,
+    Off,
+}
+
+/// What presentation mode changed, to be restored when it ends.
+#[derive(Debug)]
+struct Saved {
+    dnd_mode: DndMode,
+    brightness: Option<(String, u32)>,
+    until: Option<DateTime<Local>>,
+}
+
+/// `Saved` as handed over with `--replace`.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Handoff {
+    dnd_mode: DndMode,
+    brightness: Option<(String, u32)>,
+    /// Unix timestamp
+    until: Option<i64>,
+}
+
+#[derive(Debug, Default)]
+pub struct Presentation {
+    saved: Option<Saved>,
+    /// Identifies the current session, so that the timer of an earlier one can't end it
+    session: u64,
+}
+
+impl Presentation {
+    pub fn session(&self) -> u64 {
+        self.session
+    }
+
+    pub fn until(&self) -> Option<DateTime<Local>> {
+        self.saved.as_ref().and_then(|saved| saved.until)
+    }
+
+    /// Starts presentation mode, or extends it when it is already on. Returns the backlight
+    /// change to run.
+    pub fn start(
+        &mut self,
+        duration: Option<Duration>,
+        settings: &PresentationSettings,
+        status: &StatusHandle,
+        dnd: &DndHandle,
+    ) -> Option<Action> {
+        let until = duration
+            .and_then(|duration| ChronoDuration::from_std(duration).ok())
+            .and_then(|duration| Local::now().checked_add_signed(duration));
+        self.session += 1;
+        if let Some(saved) = &mut self.saved {
+            saved.until = until;
+            info!("Presentation mode extended until {:?}", until);
+            return None;
+        }
+
+        let mut action = None;
+        let mut brightness = None;
+        if let Some(device) = &settings.backlight {
+            match (
+                backlight::read(device, "brightness"),
+                backlight::read(device, "max_brightness"),
+            ) {
+                (Ok(current), Ok(max)) => {
+                    brightness = Some((device.clone(), current));
+                    action = Some(Action::Backlight {
+                        device: device.clone(),
+                        brightness: max * settings.brightness_percent.min(100) / 100,
+                    });
+                }
+                (Err(e), _) | (_, Err(e)) => error!("Can't read backlight {}: {}", device, e),
+            }
+        }
+        let mut dnd = dnd.lock().unwrap();
+        self.saved = Some(Saved {
+            dnd_mode: dnd.mode(),
+            brightness,
+            until,
+        });
+        dnd.set_mode(DndMode::On);
+        status.lock().unwrap().set_presenting(true);
+        info!("Presentation mode on until {:?}", until);
+        action
+    }
+
+    /// What has to be restored when presentation mode ends, for `--replace`.
+    pub fn handoff(&self) -> Option<Handoff> {
+        self.saved.as_ref().map(|saved| Handoff {
+            dnd_mode: saved.dnd_mode,
+            brightness: saved.brightness.clone(),
+            until: saved.until.map(|until| until.timestamp()),
+        })
+    }
+
+    /// Continues the presentation mode of the replaced instance, which raised the brightness
+    /// already.
+    pub fn adopt(&mut self, handoff: Handoff, status: &StatusHandle, dnd: &DndHandle) {
+        let until = handoff
+            .until
+            .and_then(|until| Local.timestamp_opt(until, 0).single());
+        self.session += 1;
+        self.saved = Some(Saved {
+            dnd_mode: handoff.dnd_mode,
+            brightness: handoff.brightness,
+            until,
+        });
+        dnd.lock().unwrap().set_mode(DndMode::On);
+        status.lock().unwrap().set_presenting(true);
+        info!("Presentation mode on until {:?}", until);
+    }
+
+    /// Ends presentation mode and returns the backlight change that restores the brightness.
+    pub fn stop(&mut self, status: &StatusHandle, dnd: &DndHandle) -> Option<Action> {
+        let saved = self.saved.take()?;
+        dnd.lock().unwrap().set_mode(saved.dnd_mode);
+        status.lock().unwrap().set_presenting(false);
+        info!("Presentation mode off");
+        saved
+            .brightness
+            .map(|(device, brightness)| Action::Backlight { device, brightness })
+    }
+}
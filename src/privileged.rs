@@ -0,0 +1,78 @@
+use anyhow::{bail, Context};
+use log::{debug, info};
+use tokio::process::Command;
+
+use super::config;
+use super::dbus::{logind_session, LogindManagerInterfaceProxy};
+
+/// Actions that need root. logind is used where it offers the action, everything else goes
+/// through the helper, which polkit authorizes via pkexec.
+#[derive(Clone, Debug)]
+pub enum Action {
+    Hibernate,
+    /// Wake the machine at the given unix timestamp
+    RtcWake(u64),
+    Backlight {
+        device: String,
+        brightness: u32,
+    },
+    /// Charge thresholds of a battery in percent, the start one only on drivers that have it
+    ChargeThreshold {
+        battery: String,
+        end: u32,
+        start: Option<u32>,
+    },
+}
+
+async fn run_helper(args: &[String]) -> anyhow::Result<()> {
+    debug!("Running {} {}", config::HELPER_PATH, args.join(" "));
+    let status = Command::new("pkexec")
+        .arg("--disable-internal-agent")
+        .arg(config::HELPER_PATH)
+        .args(args)
+        .status()
+        .await
+        .context("Failed to run pkexec")?;
+    match status.code() {
+        Some(0) => Ok(()),
+        Some(126) | Some(127) => bail!("not authorized by polkit"),
+        _ => bail!("{} exited with {}", config::HELPER_PATH, status),
+    }
+}
+
+pub async fn run(action: Action) -> anyhow::Result<()> {
+    let conn = zbus::Connection::system().await?;
+    match action {
+        Action::Hibernate => {
+            let manager = LogindManagerInterfaceProxy::new(&conn).await?;
+            match manager.hibernate(false).await {
+                Ok(()) => return Ok(()),
+                Err(e) => info!("logind cannot hibernate, using the helper: {}", e),
+            }
+            run_helper(&["hibernate".to_string()]).await
+        }
+        Action::RtcWake(timestamp) => {
+            run_helper(&["rtcwake".to_string(), timestamp.to_string()]).await
+        }
+        Action::Backlight { device, brightness } => {
+            let session = logind_session(&conn).await?;
+            match session
+                .set_brightness("backlight", &device, brightness)
+                .await
+            {
+                Ok(()) => return Ok(()),
+                Err(e) => info!("logind cannot set the brightness, using the helper: {}", e),
+            }
+            run_helper(&["backlight".to_string(), device, brightness.to_string()]).await
+        }
+        Action::ChargeThreshold {
+            battery,
+            end,
+            start,
+        } => {
+            let mut args = vec!["charge-threshold".to_string(), battery, end.to_string()];
+            args.extend(start.map(|start| start.to_string()));
+            run_helper(&args).await
+        }
+    }
+}
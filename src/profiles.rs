@@ -0,0 +1,273 @@
+//! Rules that pick the profile from the situation: the Wi-Fi network, whether the machine is
+//! docked or on battery, and the time of day. The rules are evaluated continuously and the
+//! matching rule with the highest priority decides. Profile changes run the `on_enter` and
+//! `on_exit` functions of the profiles involved.
+
+use chrono::{DateTime, Datelike, Local, NaiveTime};
+use log::{debug, info, warn};
+use mlua::{Lua, Table, UserData, UserDataMethods};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{mpsc, Notify};
+
+use super::battery::BatteryHandle;
+use super::callbacks;
+use super::clock::Clock;
+use super::dbus;
+use super::hooks::HooksHandle;
+use super::schedule::{self, Days};
+use super::scripts;
+use super::types::Request;
+
+/// How often the rules are evaluated, network and dock changes are noticed this late
+const EVALUATE_INTERVAL: Duration = Duration::from_secs(15);
+
+#[derive(Debug)]
+struct Rule {
+    profile: String,
+    priority: i64,
+    ssid: Option<String>,
+    docked: Option<bool>,
+    on_battery: Option<bool>,
+    /// From and to, a window that ends before it starts spans midnight
+    window: Option<(NaiveTime, NaiveTime)>,
+    days: Days,
+}
+
+/// What the rules are checked against. Unknown facts match no condition on them.
+#[derive(Debug)]
+struct Facts {
+    ssids: Option<Vec<String>>,
+    docked: Option<bool>,
+    on_battery: bool,
+    now: DateTime<Local>,
+}
+
+impl Rule {
+    fn matches(&self, facts: &Facts) -> bool {
+        let ssid = self.ssid.as_ref().is_none_or(|ssid| {
+            facts
+                .ssids
+                .as_ref()
+                .is_some_and(|ssids| ssids.contains(ssid))
+        });
+        let docked = self
+            .docked
+            .is_none_or(|docked| facts.docked == Some(docked));
+        let on_battery = self
+            .on_battery
+            .is_none_or(|on_battery| facts.on_battery == on_battery);
+        let time = facts.now.time();
+        let window = self.window.is_none_or(|(from, to)| match from <= to {
+            true => from <= time && time < to,
+            false => from <= time || time < to,
+        });
+        ssid && docked && on_battery && window && self.days.contains(facts.now.weekday())
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Profiles {
+    rules: Vec<Rule>,
+    on_enter: HashMap<String, Vec<String>>,
+    on_exit: HashMap<String, Vec<String>>,
+    /// Last profile the rules picked. Another switch, e.g. by the schedule, lasts until the
+    /// rules pick a different one
+    selected: Option<String>,
+    changed: Arc<Notify>,
+}
+
+pub type ProfilesHandle = Arc<Mutex<Profiles>>;
+
+impl Profiles {
+    pub fn new() -> ProfilesHandle {
+        Arc::new(Mutex::new(Self::default()))
+    }
+
+    /// Removes the rules and hooks of the config. The new rules are applied right away.
+    pub fn clear(&mut self) {
+        self.rules.clear();
+        self.on_enter.clear();
+        self.on_exit.clear();
+        self.selected = None;
+        self.changed.notify_one();
+    }
+
+    /// Drops the callbacks of the script `script`, before it's loaded again.
+    pub fn forget_script(&mut self, script: &str) {
+        for callbacks in self.on_enter.values_mut().chain(self.on_exit.values_mut()) {
+            callbacks.retain(|fn_name| !scripts::owns(script, fn_name));
+        }
+    }
+
+    fn uses_network(&self) -> bool {
+        self.rules
+            .iter()
+            .any(|rule| rule.ssid.is_some() || rule.docked.is_some())
+    }
+
+    /// The profile of the matching rule with the highest priority, the first one on a tie.
+    fn select(&self, facts: &Facts) -> Option<String> {
+        let mut best: Option<&Rule> = None;
+        for rule in self.rules.iter().filter(|rule| rule.matches(facts)) {
+            if best.is_none_or(|best| rule.priority > best.priority) {
+                best = Some(rule);
+            }
+        }
+        best.map(|rule| rule.profile.clone())
+    }
+}
+
+pub async fn profiles_run(
+    profiles: ProfilesHandle,
+    battery: BatteryHandle,
+    clock: Clock,
+    tx: mpsc::Sender<Request>,
+) {
+    let changed = profiles.lock().unwrap().changed.clone();
+    let conn = match zbus::Connection::system().await {
+        Ok(conn) => Some(conn),
+        Err(e) => {
+            warn!("Profile rules can't check the network and dock: {}", e);
+            None
+        }
+    };
+    loop {
+        let uses_network = profiles.lock().unwrap().uses_network();
+        let (ssids, docked) = match (&conn, uses_network) {
+            (Some(conn), true) => (
+                dbus::wifi_ssids(conn)
+                    .await
+                    .inspect_err(|e| debug!("Can't get the Wi-Fi networks: {}", e))
+                    .ok(),
+                dbus::docked(conn)
+                    .await
+                    .inspect_err(|e| debug!("Can't get the dock state: {}", e))
+                    .ok(),
+            ),
+            _ => (None, None),
+        };
+        let facts = Facts {
+            ssids,
+            docked,
+            on_battery: battery.lock().unwrap().on_battery(),
+            now: clock.now(),
+        };
+        let switch = {
+            let mut profiles = profiles.lock().unwrap();
+            let selected = profiles.select(&facts);
+            if selected.is_some() && selected != profiles.selected {
+                debug!("Profile rules picked {:?} with {:?}", selected, facts);
+                profiles.selected = selected.clone();
+                selected
+            } else {
+                None
+            }
+        };
+        if let Some(profile) = switch {
+            info!("Switching to profile {} by rule", profile);
+            let _ = tx.send(Request::Profile(profile)).await;
+        }
+        tokio::select! {
+            _ = tokio::time::sleep(EVALUATE_INTERVAL) => {},
+            _ = changed.notified() => {},
+        }
+    }
+}
+
+/// Calls the `on_exit` functions of `previous` with the new profile, then the `on_enter`
+/// functions of `profile` with the previous one.
+pub fn run_transition(
+    lua: &Lua,
+    hooks: &HooksHandle,
+    profiles: &ProfilesHandle,
+    previous: &str,
+    profile: &str,
+) {
+    let (on_exit, on_enter) = {
+        let profiles = profiles.lock().unwrap();
+        (
+            profiles.on_exit.get(previous).cloned().unwrap_or_default(),
+            profiles.on_enter.get(profile).cloned().unwrap_or_default(),
+        )
+    };
+    let calls = on_exit
+        .into_iter()
+        .map(|fn_name| (fn_name, profile))
+        .chain(on_enter.into_iter().map(|fn_name| (fn_name, previous)));
+    for (fn_name, arg) in calls {
+        debug!("Profile {} -> {} calling {}", previous, profile, fn_name);
+        callbacks::call::<_, ()>(lua, hooks, &fn_name, arg);
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ProfilesHelpers {
+    pub profiles: ProfilesHandle,
+}
+
+impl UserData for ProfilesHelpers {
+    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_method(
+            "rule",
+            |_lua, this, (profile, conditions): (String, Option<Table>)| {
+                let (priority, ssid, docked, on_battery, from, to) = match &conditions {
+                    Some(conditions) => (
+                        conditions.get::<_, Option<i64>>("priority")?,
+                        conditions.get::<_, Option<String>>("ssid")?,
+                        conditions.get::<_, Option<bool>>("docked")?,
+                        conditions.get::<_, Option<bool>>("on_battery")?,
+                        conditions.get::<_, Option<String>>("from")?,
+                        conditions.get::<_, Option<String>>("to")?,
+                    ),
+                    None => (None, None, None, None, None, None),
+                };
+                let window = match (from, to) {
+                    (Some(from), Some(to)) => {
+                        Some((schedule::parse_time(&from)?, schedule::parse_time(&to)?))
+                    }
+                    (None, None) => None,
+                    _ => {
+                        return Err(mlua::Error::RuntimeError(
+                            "a time window needs both from and to".to_string(),
+                        ))
+                    }
+                };
+                let days = Days::parse(conditions.as_ref())?;
+                debug!("Profile rule for {}", profile);
+                let mut profiles = this.profiles.lock().unwrap();
+                profiles.rules.push(Rule {
+                    profile,
+                    priority: priority.unwrap_or(0),
+                    ssid,
+                    docked,
+                    on_battery,
+                    window,
+                    days,
+                });
+                profiles.changed.notify_one();
+                Ok(())
+            },
+        );
+        methods.add_method(
+            "on_enter",
+            |_lua, this, (profile, fn_name): (String, String)| {
+                let mut profiles = this.profiles.lock().unwrap();
+                profiles.on_enter.entry(profile).or_default().push(fn_name);
+                Ok(())
+            },
+        );
+        methods.add_method(
+            "on_exit",
+            |_lua, this, (profile, fn_name): (String, String)| {
+                let mut profiles = this.profiles.lock().unwrap();
+                profiles.on_exit.entry(profile).or_default().push(fn_name);
+                Ok(())
+            },
+        );
+        methods.add_method("selected", |_lua, this, (): ()| {
+            Ok(this.profiles.lock().unwrap().selected.clone())
+        });
+    }
+}
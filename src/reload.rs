@@ -0,0 +1,75 @@
+//! What a config reload changed. The setup of the config is captured before and after the
+//! reload, and the differences are logged, so it can be checked that an edit did what it was
+//! meant to.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+#[derive(Debug, Default)]
+pub struct Snapshot {
+    /// Timeouts of the idle notifications by callback, jobs prefixed with `job `
+    pub notifications: BTreeMap<String, Vec<i32>>,
+    pub schedules: BTreeSet<String>,
+    /// Commands of the maintenance jobs by name
+    pub commands: BTreeMap<String, String>,
+    /// Factor the idle timeouts were scaled with
+    pub multiplier: f64,
+}
+
+fn timeouts(timeouts: &[i32]) -> String {
+    timeouts
+        .iter()
+        .map(|timeout| format!("{}s", timeout))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+impl Snapshot {
+    /// One line per change from `self` to `after`.
+    pub fn diff(&self, after: &Snapshot) -> Vec<String> {
+        let mut changes = Vec::new();
+        for (name, before) in &self.notifications {
+            match after.notifications.get(name) {
+                None => changes.push(format!("removed idle {} ({})", name, timeouts(before))),
+                Some(now) if now != before => changes.push(format!(
+                    "retimed idle {} from {} to {}",
+                    name,
+                    timeouts(before),
+                    timeouts(now)
+                )),
+                Some(_) => {}
+            }
+        }
+        for (name, now) in &after.notifications {
+            if !self.notifications.contains_key(name) {
+                changes.push(format!("added idle {} ({})", name, timeouts(now)));
+            }
+        }
+        for schedule in self.schedules.difference(&after.schedules) {
+            changes.push(format!("removed schedule {}", schedule));
+        }
+        for schedule in after.schedules.difference(&self.schedules) {
+            changes.push(format!("added schedule {}", schedule));
+        }
+        for (name, before) in &self.commands {
+            match after.commands.get(name) {
+                None => changes.push(format!("removed job {}", name)),
+                Some(now) if now != before => {
+                    changes.push(format!("changed job {} from {} to {}", name, before, now))
+                }
+                Some(_) => {}
+            }
+        }
+        for (name, now) in &after.commands {
+            if !self.commands.contains_key(name) {
+                changes.push(format!("added job {}: {}", name, now));
+            }
+        }
+        if after.multiplier != self.multiplier {
+            changes.push(format!(
+                "timeouts scaled by {} instead of {}",
+                after.multiplier, self.multiplier
+            ));
+        }
+        changes
+    }
+}
@@ -0,0 +1,143 @@
+//! Optional control listener for other machines, enabled with the `remote` cargo feature.
+//!
+//! It speaks the JSON lines of the control socket over TLS. Every command has to carry the
+//! token, and only the commands of `CtlCommand::is_remote` are accepted. Connections are
+//! limited in number and closed after `CONNECTION_TIMEOUT`, so idle or slow clients can't
+//! pile up.
+
+#[cfg(feature = "remote")]
+mod imp {
+    use log::{error, info, warn};
+    use std::fs::File;
+    use std::io::BufReader;
+    use std::path::Path;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::net::TcpListener;
+    use tokio::sync::{mpsc, Semaphore};
+    use tokio_rustls::rustls::{Certificate, PrivateKey, ServerConfig};
+    use tokio_rustls::TlsAcceptor;
+
+    use crate::ipc::{self, CtlRequest};
+    use crate::settings::RemoteSettings;
+    use crate::types::Request;
+
+    /// Connections served at the same time, more are closed right away
+    const MAX_CONNECTIONS: usize = 8;
+    /// Time a connection may stay open, handshake included
+    const CONNECTION_TIMEOUT: Duration = Duration::from_secs(30);
+
+    fn load_certs(path: &Path) -> anyhow::Result<Vec<Certificate>> {
+        let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(path)?))?;
+        Ok(certs.into_iter().map(Certificate).collect())
+    }
+
+    fn load_key(path: &Path) -> anyhow::Result<PrivateKey> {
+        let mut reader = BufReader::new(File::open(path)?);
+        while let Some(item) = rustls_pemfile::read_one(&mut reader)? {
+            match item {
+                rustls_pemfile::Item::PKCS8Key(key)
+                | rustls_pemfile::Item::RSAKey(key)
+                | rustls_pemfile::Item::ECKey(key) => return Ok(PrivateKey(key)),
+                _ => {}
+            }
+        }
+        anyhow::bail!("no private key in {:?}", path)
+    }
+
+    pub async fn remote_run(
+        settings: &RemoteSettings,
+        tx: mpsc::Sender<Request>,
+    ) -> anyhow::Result<()> {
+        let Some(addr) = settings.listen else {
+            return Ok(());
+        };
+        let (Some(cert), Some(key), Some(token_file)) =
+            (&settings.cert, &settings.key, &settings.token_file)
+        else {
+            anyhow::bail!("the remote listener needs cert, key and token_file");
+        };
+        let token = Arc::new(ipc::read_token(token_file)?);
+        let actions = Arc::new(settings.actions.clone());
+        let slots = Arc::new(Semaphore::new(MAX_CONNECTIONS));
+        let config = ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(load_certs(cert)?, load_key(key)?)?;
+        let acceptor = TlsAcceptor::from(Arc::new(config));
+        let listener = TcpListener::bind(addr).await?;
+        info!("Remote control listening on {}", addr);
+
+        tokio::spawn(async move {
+            loop {
+                let (stream, peer) = match listener.accept().await {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        error!("Error accepting remote connection: {}", e);
+                        continue;
+                    }
+                };
+                let Ok(slot) = slots.clone().try_acquire_owned() else {
+                    warn!("Too many remote connections, refusing {}", peer);
+                    continue;
+                };
+                let acceptor = acceptor.clone();
+                let token = token.clone();
+                let actions = actions.clone();
+                let tx = tx.clone();
+                tokio::spawn(async move {
+                    let _slot = slot;
+                    let serve = async {
+                        let stream = match acceptor.accept(stream).await {
+                            Ok(stream) => stream,
+                            Err(e) => {
+                                info!("TLS handshake with {} failed: {}", peer, e);
+                                return;
+                            }
+                        };
+                        let authorize = |request: &CtlRequest| {
+                            request.cmd.is_remote(&actions)
+                                && request
+                                    .token
+                                    .as_deref()
+                                    .is_some_and(|given| ipc::token_matches(&token, given))
+                        };
+                        if let Err(e) = ipc::serve(stream, authorize, tx).await {
+                            error!("Remote client {} error: {}", peer, e);
+                        }
+                    };
+                    if tokio::time::timeout(CONNECTION_TIMEOUT, serve)
+                        .await
+                        .is_err()
+                    {
+                        info!(
+                            "Closed remote connection of {} after {:?}",
+                            peer, CONNECTION_TIMEOUT
+                        );
+                    }
+                });
+            }
+        });
+        Ok(())
+    }
+}
+
+#[cfg(not(feature = "remote"))]
+mod imp {
+    use tokio::sync::mpsc;
+
+    use crate::settings::RemoteSettings;
+    use crate::types::Request;
+
+    pub async fn remote_run(
+        settings: &RemoteSettings,
+        _tx: mpsc::Sender<Request>,
+    ) -> anyhow::Result<()> {
+        if settings.listen.is_some() {
+            anyhow::bail!("sleepwatcher-rs was built without the remote feature");
+        }
+        Ok(())
+    }
+}
+
+pub use imp::*;
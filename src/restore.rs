@@ -0,0 +1,227 @@
+//! Values of the system that idle stages change, like the backlight, the audio volume or the
+//! power profile. A stage saves the value before it changes it, and the value is put back when
+//! the user returns to that stage, when the config is reloaded and when the daemon exits. The
+//! stages don't need resume branches of their own for it.
+
+use log::{debug, error, info};
+use mlua::{UserData, UserDataMethods};
+use std::process::{Command, Stdio};
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc;
+
+use super::backlight;
+use super::privileged::Action;
+use super::template;
+use super::types::Request;
+use super::utils;
+
+#[derive(Clone, Debug, PartialEq)]
+enum Saved {
+    Backlight {
+        device: String,
+        brightness: u32,
+    },
+    /// Output of the `get` command, put back with the `set` command
+    Command {
+        set: String,
+        value: String,
+    },
+}
+
+impl Saved {
+    /// Tells apart what was saved, the same thing is only saved once per stage.
+    fn key(&self) -> String {
+        match self {
+            Saved::Backlight { device, .. } => format!("backlight {}", device),
+            Saved::Command { set, .. } => format!("command {}", set),
+        }
+    }
+
+    fn request(self) -> Request {
+        match self {
+            Saved::Backlight { device, brightness } => {
+                info!("Restoring backlight {} to {}", device, brightness);
+                Request::Privileged(Action::Backlight { device, brightness })
+            }
+            Saved::Command { set, value } => {
+                // Quoted for `sh -c`, which gets the command as it is, without expanding it again
+                let value = shlex::try_quote(&value)
+                    .map(|value| value.into_owned())
+                    .unwrap_or_default();
+                let cmd = template::expand(&set, &[("value", value)].into());
+                info!("Restoring with {}", cmd);
+                Request::RestoreCommand(cmd)
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Snapshot {
+    /// Stage that saved it, `None` outside of idle callbacks
+    stage: Option<String>,
+    saved: Saved,
+}
+
+#[derive(Debug, Default)]
+pub struct Restore {
+    /// The idle callback that is running
+    stage: Option<String>,
+    /// In the order they were taken
+    snapshots: Vec<Snapshot>,
+}
+
+pub type RestoreHandle = Arc<Mutex<Restore>>;
+
+impl Restore {
+    pub fn new() -> RestoreHandle {
+        Arc::new(Mutex::new(Self::default()))
+    }
+
+    /// Snapshots taken until `leave` belong to `stage`.
+    pub fn enter(&mut self, stage: &str) {
+        self.stage = Some(stage.to_string());
+    }
+
+    pub fn leave(&mut self) {
+        self.stage = None;
+    }
+
+    /// Keeps `saved` unless the current stage already saved the same thing, so a stage that
+    /// runs twice doesn't save its own change.
+    fn save(&mut self, saved: Saved) -> bool {
+        let key = saved.key();
+        if self
+            .snapshots
+            .iter()
+            .any(|snapshot| snapshot.stage == self.stage && snapshot.saved.key() == key)
+        {
+            return false;
+        }
+        debug!("Saved {} for {:?}", key, self.stage);
+        self.snapshots.push(Snapshot {
+            stage: self.stage.clone(),
+            saved,
+        });
+        true
+    }
+
+    /// Puts back what `stage` saved, or everything for `None`. When stages saved the same
+    /// thing, the value from before the first one is only put back once none of them is idle
+    /// anymore.
+    pub fn restore(&mut self, stage: Option<&str>, tx: &mpsc::Sender<Request>) {
+        let Some(stage) = stage else {
+            let mut restored = Vec::new();
+            for snapshot in self.snapshots.drain(..) {
+                let key = snapshot.saved.key();
+                if !restored.contains(&key) {
+                    utils::send_request(tx, snapshot.saved.request());
+                    restored.push(key);
+                }
+            }
+            return;
+        };
+        let mut i = 0;
+        while i < self.snapshots.len() {
+            if self.snapshots[i].stage.as_deref() != Some(stage) {
+                i += 1;
+                continue;
+            }
+            let snapshot = self.snapshots.remove(i);
+            let key = snapshot.saved.key();
+            let earlier = self.snapshots[..i]
+                .iter()
+                .any(|other| other.saved.key() == key);
+            if earlier {
+                // Back to the value of the earlier stage, which is still idle
+                utils::send_request(tx, snapshot.saved.request());
+                continue;
+            }
+            match self.snapshots[i..]
+                .iter_mut()
+                .find(|other| other.saved.key() == key)
+            {
+                // The later stage is still idle and puts back the older value when it resumes
+                Some(later) => later.saved = snapshot.saved,
+                None => utils::send_request(tx, snapshot.saved.request()),
+            }
+        }
+    }
+
+    /// Saves the brightness of a backlight before it's changed through `Power:set_backlight` or
+    /// the `Backlight` global.
+    pub fn save_backlight(&mut self, device: &str) -> Result<bool, String> {
+        let brightness = backlight::read(device, "brightness").map_err(|e| e.to_string())?;
+        Ok(self.save(Saved::Backlight {
+            device: device.to_string(),
+            brightness,
+        }))
+    }
+}
+
+/// Runs the `get` command of `Restore:command`. It runs before the stage goes on, so it should
+/// return quickly.
+fn read_command(get: &str) -> Result<String, String> {
+    let output = Command::new("sh")
+        .arg("-c")
+        .arg(get)
+        .output()
+        .map_err(|e| format!("failed to run {}: {}", get, e))?;
+    if !output.status.success() {
+        return Err(format!("{} failed with {}", get, output.status));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+/// Runs the `set` command of `Restore:command` with the value in place. Like `get`, it runs
+/// through `sh -c`.
+pub async fn run_set(cmd: String, tx: mpsc::Sender<Request>) {
+    let status = tokio::process::Command::new("sh")
+        .arg("-c")
+        .arg(&cmd)
+        .stdin(Stdio::null())
+        .status()
+        .await;
+    let e = match status {
+        Ok(status) if status.success() => return,
+        Ok(status) => format!("{} failed with {}", cmd, status),
+        Err(e) => format!("failed to run {}: {}", cmd, e),
+    };
+    error!("Failed to restore a value: {}", e);
+    let context = vec![
+        ("source".to_string(), "restore".to_string()),
+        ("command".to_string(), cmd),
+    ];
+    let _ = tx.send(Request::Error(e, context)).await;
+}
+
+#[derive(Clone, Debug)]
+pub struct RestoreHelpers {
+    pub restore: RestoreHandle,
+    pub allow_exec: bool,
+}
+
+impl UserData for RestoreHelpers {
+    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_method("backlight", |_lua, this, device: String| {
+            this.restore
+                .lock()
+                .unwrap()
+                .save_backlight(&device)
+                .map_err(mlua::Error::RuntimeError)
+        });
+        methods.add_method("command", |_lua, this, (get, set): (String, String)| {
+            if !this.allow_exec {
+                return Err(mlua::Error::RuntimeError(
+                    "running commands is disabled by the sandbox policy".to_string(),
+                ));
+            }
+            let value = read_command(&get).map_err(mlua::Error::RuntimeError)?;
+            Ok(this
+                .restore
+                .lock()
+                .unwrap()
+                .save(Saved::Command { set, value }))
+        });
+    }
+}
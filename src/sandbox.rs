@@ -0,0 +1,198 @@
+use log::{error, info};
+use mlua::{Lua, Table, UserData, UserDataMethods, Value, Variadic};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::process::Command;
+
+use super::modules;
+use super::settings::SandboxPolicy;
+
+/// A file opened through `io.open`.
+struct LuaFile {
+    file: Option<BufReader<File>>,
+}
+
+impl LuaFile {
+    fn file(&mut self) -> mlua::Result<&mut BufReader<File>> {
+        self.file
+            .as_mut()
+            .ok_or_else(|| mlua::Error::RuntimeError("attempt to use a closed file".to_string()))
+    }
+}
+
+fn read_line(file: &mut BufReader<File>, keep_newline: bool) -> mlua::Result<Option<String>> {
+    let mut line = String::new();
+    if file.read_line(&mut line).map_err(mlua::Error::external)? == 0 {
+        return Ok(None);
+    }
+    if !keep_newline && line.ends_with('\n') {
+        line.pop();
+    }
+    Ok(Some(line))
+}
+
+impl UserData for LuaFile {
+    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_method_mut("read", |_lua, this, format: Option<String>| {
+            let file = this.file()?;
+            match format.as_deref().unwrap_or("l").trim_start_matches('*') {
+                "a" => {
+                    let mut content = String::new();
+                    file.read_to_string(&mut content)
+                        .map_err(mlua::Error::external)?;
+                    Ok(Some(content))
+                }
+                "l" => read_line(file, false),
+                "L" => read_line(file, true),
+                format => Err(mlua::Error::RuntimeError(format!(
+                    "invalid read format: {}",
+                    format
+                ))),
+            }
+        });
+        methods.add_method_mut("lines", |lua, this, (): ()| {
+            let file = this.file()?;
+            let mut lines = Vec::new();
+            while let Some(line) = read_line(file, false)? {
+                lines.push(line);
+            }
+            let mut lines = lines.into_iter();
+            lua.create_function_mut(move |_lua, (): ()| Ok(lines.next()))
+        });
+        methods.add_method_mut("write", |_lua, this, values: Variadic<String>| {
+            let file = this.file()?.get_mut();
+            for value in values {
+                file.write_all(value.as_bytes())
+                    .map_err(mlua::Error::external)?;
+            }
+            Ok(())
+        });
+        methods.add_method_mut("close", |_lua, this, (): ()| {
+            this.file = None;
+            Ok(())
+        });
+    }
+}
+
+fn create_io(lua: &Lua) -> mlua::Result<Table<'_>> {
+    let io = lua.create_table()?;
+    io.set(
+        "open",
+        lua.create_function(|_lua, (path, mode): (String, Option<String>)| {
+            let mode = mode.unwrap_or_else(|| "r".to_string());
+            let mut options = OpenOptions::new();
+            match mode.trim_end_matches('b') {
+                "r" => options.read(true),
+                "w" => options.write(true).create(true).truncate(true),
+                "a" => options.append(true).create(true),
+                "r+" => options.read(true).write(true),
+                "w+" => options.read(true).write(true).create(true).truncate(true),
+                "a+" => options.read(true).append(true).create(true),
+                _ => {
+                    return Ok((None, Some(format!("invalid mode: {}", mode))));
+                }
+            };
+            // Like Lua, failures are returned as nil plus a message instead of raising
+            match options.open(&path) {
+                Ok(file) => Ok((
+                    Some(LuaFile {
+                        file: Some(BufReader::new(file)),
+                    }),
+                    None,
+                )),
+                Err(e) => Ok((None, Some(format!("{}: {}", path, e)))),
+            }
+        })?,
+    )?;
+    Ok(io)
+}
+
+/// Copies the builtin `os` table, which is read-only in the sandbox, and adds `os.execute`.
+fn create_os(lua: &Lua) -> mlua::Result<Table<'_>> {
+    let os = lua.create_table()?;
+    if let Value::Table(builtin) = lua.globals().get("os")? {
+        for pair in builtin.pairs::<Value, Value>() {
+            let (key, value) = pair?;
+            os.set(key, value)?;
+        }
+    }
+    os.set(
+        "execute",
+        lua.create_function(|_lua, command: String| {
+            let status = Command::new("sh")
+                .arg("-c")
+                .arg(&command)
+                .status()
+                .map_err(mlua::Error::external)?;
+            let code = status.code().unwrap_or(-1);
+            Ok((status.success().then_some(true), "exit", code))
+        })?,
+    )?;
+    Ok(os)
+}
+
+/// Sets up the Lua environment according to the sandbox policy.
+pub fn apply(lua: &Lua, policy: &SandboxPolicy) -> anyhow::Result<()> {
+    info!(
+        "Lua sandbox: enabled={} os_execute={} io={} package={} network={}",
+        policy.enabled, policy.os_execute, policy.io, policy.package, policy.network
+    );
+    lua.sandbox(policy.enabled)?;
+
+    let globals = lua.globals();
+    if policy.os_execute {
+        globals.set("os", create_os(lua)?)?;
+    }
+    if policy.io {
+        globals.set("io", create_io(lua)?)?;
+    }
+    if policy.package {
+        if let Err(e) = modules::install_require(lua) {
+            error!("Failed to enable external modules: {}", e);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn globals_after(policy: &SandboxPolicy, check: &str) -> bool {
+        let lua = Lua::new();
+        apply(&lua, policy).unwrap();
+        lua.load(check).eval().unwrap()
+    }
+
+    #[test]
+    fn restrictive_policy_removes_commands_and_files() {
+        let policy = SandboxPolicy {
+            enabled: true,
+            os_execute: false,
+            io: false,
+            package: false,
+            network: false,
+        };
+        assert!(globals_after(&policy, "os.execute == nil and io == nil"));
+        assert!(globals_after(&policy, "type(os.time) == 'function'"));
+        // Builtins are read-only, so the config can't swap them for scripts loaded later
+        assert!(globals_after(
+            &policy,
+            "not pcall(function() string.upper = nil end)"
+        ));
+    }
+
+    #[test]
+    fn permissive_policy_adds_commands_and_files() {
+        let policy = SandboxPolicy {
+            os_execute: true,
+            io: true,
+            ..SandboxPolicy::default()
+        };
+        assert!(globals_after(
+            &policy,
+            "type(os.execute) == 'function' and type(io.open) == 'function'"
+        ));
+        assert!(globals_after(&policy, "os.execute('exit 3') == nil"));
+    }
+}
@@ -0,0 +1,544 @@
+use chrono::{
+    DateTime, Datelike, Duration as ChronoDuration, FixedOffset, Local, LocalResult, NaiveDate,
+    NaiveDateTime, NaiveTime, Offset, TimeZone, Weekday,
+};
+use log::{debug, info};
+use mlua::{UserData, UserDataMethods};
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{mpsc, Notify};
+
+use super::clock::Clock;
+use super::scripts;
+use super::types::Request;
+
+/// Upper bound for a single sleep, so that suspends and clock changes are noticed quickly.
+const MAX_SLEEP: Duration = Duration::from_secs(30);
+/// DST gaps are at most two hours long
+const MAX_GAP_MINUTES: i64 = 120;
+/// Jitter has to end before the next day's run
+const MAX_JITTER: Duration = Duration::from_secs(23 * 60 * 60);
+
+/// Set of weekdays a schedule applies to.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Days(u8);
+
+impl Days {
+    pub const ALL: Days = Days(0b111_1111);
+    const WORKDAYS: Days = Days(0b001_1111);
+    const WEEKEND: Days = Days(0b110_0000);
+
+    fn with(self, day: Weekday) -> Days {
+        Days(self.0 | 1 << day.num_days_from_monday())
+    }
+
+    pub fn contains(self, day: Weekday) -> bool {
+        self.0 & 1 << day.num_days_from_monday() != 0
+    }
+
+    fn parse_one(days: &str) -> mlua::Result<Days> {
+        match days {
+            "daily" => Ok(Days::ALL),
+            "weekdays" | "workdays" => Ok(Days::WORKDAYS),
+            "weekend" => Ok(Days::WEEKEND),
+            day => day
+                .parse::<Weekday>()
+                .map(|day| Days(0).with(day))
+                .map_err(|_| mlua::Error::RuntimeError(format!("invalid day {}", day))),
+        }
+    }
+
+    /// Parses the `days` option: `"daily"`, `"weekdays"`, `"weekend"`, a day name like `"mon"`
+    /// or `"monday"`, or a list of those. Defaults to every day.
+    pub fn parse(options: Option<&mlua::Table>) -> mlua::Result<Days> {
+        let Some(options) = options else {
+            return Ok(Days::ALL);
+        };
+        let days = match options.get::<_, mlua::Value>("days")? {
+            mlua::Value::Nil => Days::ALL,
+            mlua::Value::Table(list) => {
+                let mut days = Days(0);
+                for day in list.sequence_values::<String>() {
+                    days = Days(days.0 | Days::parse_one(&day?)?.0);
+                }
+                days
+            }
+            mlua::Value::String(days) => Days::parse_one(days.to_str()?)?,
+            value => {
+                return Err(mlua::Error::RuntimeError(format!(
+                    "invalid days {:?}",
+                    value
+                )))
+            }
+        };
+        if days == Days(0) {
+            return Err(mlua::Error::RuntimeError("days is empty".to_string()));
+        }
+        Ok(days)
+    }
+}
+
+#[derive(Clone, Debug)]
+enum Action {
+    Callback(String),
+    Profile(String),
+}
+
+#[derive(Debug)]
+struct ScheduleEntry {
+    time: NaiveTime,
+    days: Days,
+    /// Upper bound of the random delay added to every run
+    jitter: Duration,
+    action: Action,
+    next: DateTime<Local>,
+}
+
+impl ScheduleEntry {
+    fn next_run(&self, now: DateTime<Local>) -> DateTime<Local> {
+        next_occurrence(self.time, self.days, now) + random_delay(self.jitter)
+    }
+}
+
+#[derive(Debug)]
+pub struct Scheduler {
+    entries: Vec<ScheduleEntry>,
+    snoozed_until: Option<DateTime<Local>>,
+    /// UTC offset the entries were scheduled with
+    offset: FixedOffset,
+    /// The profile that should be active right now has to be applied
+    profile_pending: bool,
+    clock: Clock,
+    changed: Arc<Notify>,
+}
+
+pub type SchedulerHandle = Arc<Mutex<Scheduler>>;
+
+/// Resolves a date and time of `zone`. Ambiguous times resolve to their first occurrence and
+/// times skipped by a DST change to the first minute after the gap.
+fn resolve<Tz: TimeZone>(zone: &Tz, local: NaiveDateTime) -> Option<DateTime<Tz>> {
+    (0..=MAX_GAP_MINUTES).find_map(|minutes| {
+        match zone.from_local_datetime(&(local + ChronoDuration::minutes(minutes))) {
+            LocalResult::Single(time) => Some(time),
+            // chrono orders the two by their offset, which puts the later one first east of UTC
+            LocalResult::Ambiguous(first, second) => Some(first.min(second)),
+            LocalResult::None => None,
+        }
+    })
+}
+
+/// Resolves `time` on `date` if the schedule applies to that day.
+fn occurrence<Tz: TimeZone>(
+    zone: &Tz,
+    date: NaiveDate,
+    time: NaiveTime,
+    days: Days,
+) -> Option<DateTime<Tz>> {
+    if !days.contains(date.weekday()) {
+        return None;
+    }
+    resolve(zone, date.and_time(time))
+}
+
+/// Returns the first point in time after `now` at which the clock of its zone shows `time`
+/// on one of `days`.
+pub fn next_occurrence<Tz: TimeZone>(
+    time: NaiveTime,
+    days: Days,
+    now: DateTime<Tz>,
+) -> DateTime<Tz> {
+    let zone = now.timezone();
+    let mut date = now.date_naive();
+    loop {
+        if let Some(next) = occurrence(&zone, date, time, days) {
+            if next > now {
+                return next;
+            }
+        }
+        date = date.succ_opt().unwrap();
+    }
+}
+
+/// Returns a random delay below `max`, drawn again for every run so that machines sharing a
+/// config spread out.
+fn random_delay(max: Duration) -> ChronoDuration {
+    let secs = max.as_secs();
+    if secs == 0 {
+        return ChronoDuration::zero();
+    }
+    let random = RandomState::new().build_hasher().finish();
+    ChronoDuration::seconds((random % secs) as i64)
+}
+
+/// Returns the most recent point in time up to `now` at which the local clock showed `time`
+/// on one of `days`.
+fn last_occurrence(time: NaiveTime, days: Days, now: DateTime<Local>) -> Option<DateTime<Local>> {
+    let today = now.date_naive();
+    (0..8)
+        .filter_map(|back| occurrence(&Local, today - ChronoDuration::days(back), time, days))
+        .find(|last| *last <= now)
+}
+
+impl Scheduler {
+    pub fn new(clock: Clock) -> SchedulerHandle {
+        Arc::new(Mutex::new(Self {
+            entries: Vec::new(),
+            snoozed_until: None,
+            offset: Local::now().offset().fix(),
+            profile_pending: false,
+            clock,
+            changed: Arc::new(Notify::new()),
+        }))
+    }
+
+    fn push(&mut self, time: NaiveTime, days: Days, jitter: Duration, action: Action) {
+        let mut entry = ScheduleEntry {
+            time,
+            days,
+            jitter,
+            action,
+            next: self.clock.now(),
+        };
+        entry.next = entry.next_run(entry.next);
+        debug!(
+            "Scheduling {:?} at {} (next: {})",
+            entry.action, time, entry.next
+        );
+        self.entries.push(entry);
+        self.changed.notify_one();
+    }
+
+    /// Calls `fn_name` at `time`, delayed by a random amount up to `jitter`.
+    pub fn add(&mut self, time: NaiveTime, days: Days, jitter: Duration, fn_name: String) {
+        self.push(time, days, jitter, Action::Callback(fn_name));
+    }
+
+    /// Switches the profile at `time`. The profile of the most recent switch is applied right
+    /// away, so the right one is active after startup, reloads and missed switches.
+    pub fn add_profile(&mut self, time: NaiveTime, days: Days, profile: String) {
+        self.push(time, days, Duration::ZERO, Action::Profile(profile));
+        self.profile_pending = true;
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.changed.notify_one();
+    }
+
+    /// Drops the callbacks of the script `script`, before it's loaded again.
+    pub fn forget_script(&mut self, script: &str) {
+        self.entries.retain(|entry| match &entry.action {
+            Action::Callback(fn_name) => !scripts::owns(script, fn_name),
+            Action::Profile(_) => true,
+        });
+        self.changed.notify_one();
+    }
+
+    /// Recomputes the trigger times for the current timezone. Called when the timezone changed,
+    /// since the pending trigger times still refer to the old one.
+    pub fn reschedule(&mut self) {
+        let now = self.clock.now();
+        self.offset = now.offset().fix();
+        for entry in self.entries.iter_mut() {
+            entry.next = entry.next_run(now);
+            debug!("Rescheduled {:?} to {}", entry.action, entry.next);
+        }
+        self.profile_pending = true;
+        self.changed.notify_one();
+    }
+
+    /// Defers all scheduled actions that become due within `duration`. They fire once the
+    /// snooze expires. A zero duration cancels an active snooze.
+    pub fn snooze(&mut self, duration: Duration) -> Option<DateTime<Local>> {
+        self.snoozed_until = ChronoDuration::from_std(duration)
+            .ok()
+            .filter(|duration| !duration.is_zero())
+            .and_then(|duration| self.clock.now().checked_add_signed(duration));
+        self.changed.notify_one();
+        self.snoozed_until
+    }
+
+    pub fn snoozed_until(&self) -> Option<DateTime<Local>> {
+        self.snoozed_until
+    }
+
+    /// Looks for due actions again, e.g. after the simulated clock moved forward.
+    pub fn refresh(&self) {
+        self.changed.notify_one();
+    }
+
+    /// The scheduled actions as text, to compare them across reloads.
+    pub fn summary(&self) -> Vec<String> {
+        self.entries
+            .iter()
+            .map(|entry| {
+                let action = match &entry.action {
+                    Action::Callback(fn_name) => fn_name.clone(),
+                    Action::Profile(profile) => format!("profile {}", profile),
+                };
+                let mut summary = format!("{} at {}", action, entry.time.format("%H:%M"));
+                if entry.days != Days::ALL {
+                    let days: Vec<String> = (0..7u8)
+                        .filter_map(|day| Weekday::try_from(day).ok())
+                        .filter(|day| entry.days.contains(*day))
+                        .map(|day| day.to_string())
+                        .collect();
+                    summary.push_str(&format!(" on {}", days.join(",")));
+                }
+                summary
+            })
+            .collect()
+    }
+
+    /// The profile of the most recent scheduled switch.
+    fn current_profile(&self, now: DateTime<Local>) -> Option<String> {
+        self.entries
+            .iter()
+            .filter_map(|entry| match &entry.action {
+                Action::Profile(profile) => {
+                    Some((last_occurrence(entry.time, entry.days, now)?, profile))
+                }
+                Action::Callback(_) => None,
+            })
+            .max_by_key(|(last, _)| *last)
+            .map(|(_, profile)| profile.clone())
+    }
+
+    /// Collects the actions that are due and returns them together with the next point in
+    /// time an action becomes due. Profile switches are not held back by a snooze.
+    fn poll(&mut self, now: DateTime<Local>) -> (Vec<Action>, Option<DateTime<Local>>) {
+        // Catches timezone changes without timedated and TZ changes picked up late
+        if now.offset().fix() != self.offset {
+            info!("UTC offset changed to {}, rescheduling", now.offset());
+            self.reschedule();
+        }
+
+        let mut due = Vec::new();
+        if std::mem::take(&mut self.profile_pending) {
+            due.extend(self.current_profile(now).map(Action::Profile));
+        }
+        let snoozed = match self.snoozed_until {
+            Some(until) if now < until => true,
+            Some(_) => {
+                info!("Schedule snooze expired");
+                self.snoozed_until = None;
+                false
+            }
+            None => false,
+        };
+        for entry in self.entries.iter_mut() {
+            let is_profile = matches!(entry.action, Action::Profile(_));
+            if entry.next <= now && (is_profile || !snoozed) {
+                due.push(entry.action.clone());
+                entry.next = entry.next_run(now);
+            }
+        }
+        let wakeup = self
+            .entries
+            .iter()
+            .filter(|entry| !snoozed || matches!(entry.action, Action::Profile(_)))
+            .map(|entry| entry.next)
+            .chain(self.snoozed_until)
+            .min();
+        (due, wakeup)
+    }
+}
+
+pub async fn scheduler_run(scheduler: SchedulerHandle, tx: mpsc::Sender<Request>) {
+    let (changed, clock) = {
+        let scheduler = scheduler.lock().unwrap();
+        (scheduler.changed.clone(), scheduler.clock.clone())
+    };
+
+    loop {
+        let now = clock.now();
+        let (due, wakeup) = scheduler.lock().unwrap().poll(now);
+
+        for action in due {
+            let request = match action {
+                Action::Callback(fn_name) => {
+                    info!("Running scheduled callback {}", fn_name);
+                    Request::LuaCallback(fn_name)
+                }
+                Action::Profile(profile) => {
+                    info!("Switching to scheduled profile {}", profile);
+                    Request::Profile(profile)
+                }
+            };
+            let _ = tx.send(request).await;
+        }
+
+        let sleep = wakeup
+            .and_then(|wakeup| (wakeup - now).to_std().ok())
+            .map_or(MAX_SLEEP, |remaining| remaining.min(MAX_SLEEP));
+        tokio::select! {
+            _ = tokio::time::sleep(sleep) => {},
+            _ = changed.notified() => {},
+        }
+    }
+}
+
+/// Parses a local time of day given as `HH:MM` or `HH:MM:SS`.
+pub fn parse_time(time: &str) -> mlua::Result<NaiveTime> {
+    NaiveTime::parse_from_str(time, "%H:%M")
+        .or_else(|_| NaiveTime::parse_from_str(time, "%H:%M:%S"))
+        .map_err(|e| mlua::Error::RuntimeError(format!("invalid time {}: {}", time, e)))
+}
+
+#[derive(Clone, Debug)]
+pub struct ScheduleHelpers {
+    pub scheduler: SchedulerHandle,
+}
+
+impl UserData for ScheduleHelpers {
+    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_method(
+            "at",
+            |_lua, this, (time, fn_name, options): (String, String, Option<mlua::Table>)| {
+                let time = parse_time(&time)?;
+                let days = Days::parse(options.as_ref())?;
+                let jitter = match &options {
+                    Some(options) => options.get::<_, Option<u64>>("jitter")?,
+                    None => None,
+                };
+                let jitter = Duration::from_secs(jitter.unwrap_or(0));
+                if jitter > MAX_JITTER {
+                    return Err(mlua::Error::RuntimeError(format!(
+                        "jitter of {}s is longer than {}s",
+                        jitter.as_secs(),
+                        MAX_JITTER.as_secs()
+                    )));
+                }
+                this.scheduler
+                    .lock()
+                    .unwrap()
+                    .add(time, days, jitter, fn_name);
+                Ok(())
+            },
+        );
+        methods.add_method(
+            "profile",
+            |_lua, this, (time, profile, options): (String, String, Option<mlua::Table>)| {
+                let time = parse_time(&time)?;
+                let days = Days::parse(options.as_ref())?;
+                this.scheduler
+                    .lock()
+                    .unwrap()
+                    .add_profile(time, days, profile);
+                Ok(())
+            },
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+
+    /// Berlin in 2024: CEST from 03-31 01:00 to 10-27 01:00 UTC, CET otherwise. Ambiguous
+    /// times list CET first, like chrono does east of UTC.
+    #[derive(Clone, Copy, Debug)]
+    struct Berlin;
+
+    impl Berlin {
+        const CET: i32 = 3600;
+        const CEST: i32 = 7200;
+
+        fn offset(secs: i32) -> FixedOffset {
+            FixedOffset::east_opt(secs).unwrap()
+        }
+
+        fn summer(utc: &NaiveDateTime) -> bool {
+            let start = NaiveDate::from_ymd_opt(2024, 3, 31)
+                .unwrap()
+                .and_hms_opt(1, 0, 0);
+            let end = NaiveDate::from_ymd_opt(2024, 10, 27)
+                .unwrap()
+                .and_hms_opt(1, 0, 0);
+            (start.unwrap()..end.unwrap()).contains(utc)
+        }
+    }
+
+    impl TimeZone for Berlin {
+        type Offset = FixedOffset;
+
+        fn from_offset(_: &FixedOffset) -> Self {
+            Berlin
+        }
+
+        fn offset_from_local_date(&self, local: &NaiveDate) -> LocalResult<FixedOffset> {
+            self.offset_from_local_datetime(&local.and_hms_opt(12, 0, 0).unwrap())
+        }
+
+        fn offset_from_local_datetime(&self, local: &NaiveDateTime) -> LocalResult<FixedOffset> {
+            let fits = |secs: i32| {
+                Self::summer(&(*local - ChronoDuration::seconds(secs.into())))
+                    == (secs == Self::CEST)
+            };
+            match (fits(Self::CET), fits(Self::CEST)) {
+                (true, true) => {
+                    LocalResult::Ambiguous(Self::offset(Self::CET), Self::offset(Self::CEST))
+                }
+                (true, false) => LocalResult::Single(Self::offset(Self::CET)),
+                (false, true) => LocalResult::Single(Self::offset(Self::CEST)),
+                (false, false) => LocalResult::None,
+            }
+        }
+
+        fn offset_from_utc_date(&self, utc: &NaiveDate) -> FixedOffset {
+            self.offset_from_utc_datetime(&utc.and_hms_opt(12, 0, 0).unwrap())
+        }
+
+        fn offset_from_utc_datetime(&self, utc: &NaiveDateTime) -> FixedOffset {
+            match Self::summer(utc) {
+                true => Self::offset(Self::CEST),
+                false => Self::offset(Self::CET),
+            }
+        }
+    }
+
+    fn berlin(y: i32, m: u32, d: u32, h: u32, min: u32) -> DateTime<Berlin> {
+        Berlin
+            .with_ymd_and_hms(y, m, d, h, min, 0)
+            .single()
+            .unwrap()
+    }
+
+    fn utc(y: i32, m: u32, d: u32, h: u32, min: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(y, m, d, h, min, 0).unwrap()
+    }
+
+    #[test]
+    fn skips_to_the_end_of_a_dst_gap() {
+        // 02:30 doesn't exist on 2024-03-31, the clocks go from 02:00 to 03:00 CEST
+        let now = berlin(2024, 3, 31, 0, 0);
+        let time = NaiveTime::from_hms_opt(2, 30, 0).unwrap();
+        let next = next_occurrence(time, Days::ALL, now);
+        assert_eq!(next, utc(2024, 3, 31, 1, 0));
+        let after = next_occurrence(time, Days::ALL, next);
+        assert_eq!(after, utc(2024, 4, 1, 0, 30));
+    }
+
+    #[test]
+    fn runs_once_in_a_dst_overlap() {
+        // 02:30 happens twice on 2024-10-27, first in CEST
+        let now = berlin(2024, 10, 27, 0, 0);
+        let time = NaiveTime::from_hms_opt(2, 30, 0).unwrap();
+        let next = next_occurrence(time, Days::ALL, now);
+        assert_eq!(next, utc(2024, 10, 27, 0, 30));
+        let after = next_occurrence(time, Days::ALL, next);
+        assert_eq!(after, utc(2024, 10, 28, 1, 30));
+    }
+
+    #[test]
+    fn skips_days_not_scheduled() {
+        // 2024-03-30 is a Saturday
+        let now = berlin(2024, 3, 30, 12, 0);
+        let time = NaiveTime::from_hms_opt(9, 0, 0).unwrap();
+        let next = next_occurrence(time, Days::WORKDAYS, now);
+        assert_eq!(next, utc(2024, 4, 1, 7, 0));
+    }
+}
@@ -0,0 +1,164 @@
+use log::{debug, error, info};
+use mlua::{UserData, UserDataMethods};
+use nix::sys::signal::{kill, Signal};
+use nix::unistd::Pid;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::process::{Child, Command};
+use tokio::sync::{mpsc, oneshot};
+
+use super::types::Request;
+use super::utils;
+
+/// Time a screensaver gets to exit after SIGTERM before it is killed
+const TERM_TIMEOUT: Duration = Duration::from_secs(3);
+/// Pause before the next command when one exits on its own, so a broken list doesn't spin
+const RESTART_DELAY: Duration = Duration::from_secs(5);
+
+/// The running screensaver. Dropping the sender stops it.
+#[derive(Debug, Default)]
+pub struct Screensaver {
+    running: Option<oneshot::Sender<()>>,
+}
+
+pub type ScreensaverHandle = Arc<Mutex<Screensaver>>;
+
+impl Screensaver {
+    pub fn new() -> ScreensaverHandle {
+        Arc::new(Mutex::new(Self::default()))
+    }
+
+    /// Stops the screensaver.
+    pub fn clear(&mut self) {
+        self.running = None;
+    }
+
+    fn is_running(&self) -> bool {
+        self.running
+            .as_ref()
+            .is_some_and(|running| !running.is_closed())
+    }
+}
+
+/// Asks the screensaver to exit and kills it if it doesn't within `TERM_TIMEOUT`.
+async fn terminate(mut child: Child, cmd: &str) {
+    if let Some(pid) = child.id() {
+        let _ = kill(Pid::from_raw(pid as i32), Signal::SIGTERM);
+        if tokio::time::timeout(TERM_TIMEOUT, child.wait())
+            .await
+            .is_ok()
+        {
+            debug!("Screensaver {} exited", cmd);
+            return;
+        }
+    }
+    info!("Killing screensaver {}", cmd);
+    let _ = child.kill().await;
+}
+
+/// Runs the commands one after another, switching every `cycle` or when one exits, until
+/// `stop` fires.
+pub async fn run(commands: Vec<String>, cycle: Option<Duration>, mut stop: oneshot::Receiver<()>) {
+    for cmd in commands.iter().cycle() {
+        info!("Starting screensaver {}", cmd);
+        let child = utils::get_args(cmd).and_then(|(program, args)| {
+            Ok(Command::new(&program)
+                .args(args)
+                .kill_on_drop(true)
+                .spawn()?)
+        });
+        let mut child = match child {
+            Ok(child) => child,
+            Err(e) => {
+                error!("Failed to start screensaver {}: {}", cmd, e);
+                tokio::select! {
+                    _ = &mut stop => return,
+                    _ = tokio::time::sleep(RESTART_DELAY) => continue,
+                }
+            }
+        };
+        let next = async {
+            match cycle {
+                Some(cycle) => tokio::time::sleep(cycle).await,
+                None => std::future::pending().await,
+            }
+        };
+        tokio::select! {
+            _ = &mut stop => {
+                terminate(child, cmd).await;
+                return;
+            }
+            _ = next => terminate(child, cmd).await,
+            status = child.wait() => {
+                debug!("Screensaver {} ended: {:?}", cmd, status);
+                tokio::select! {
+                    _ = &mut stop => return,
+                    _ = tokio::time::sleep(RESTART_DELAY) => {},
+                }
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ScreensaverHelpers {
+    pub screensaver: ScreensaverHandle,
+    pub allow_exec: bool,
+    pub tx: mpsc::Sender<Request>,
+}
+
+impl UserData for ScreensaverHelpers {
+    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_method(
+            "start",
+            |_lua, this, (commands, options): (mlua::Value, Option<mlua::Table>)| {
+                if !this.allow_exec {
+                    return Err(mlua::Error::RuntimeError(
+                        "running commands is disabled by the sandbox policy".to_string(),
+                    ));
+                }
+                let commands: Vec<String> = match commands {
+                    mlua::Value::String(cmd) => vec![cmd.to_str()?.to_string()],
+                    mlua::Value::Table(list) => list.sequence_values().collect::<Result<_, _>>()?,
+                    value => {
+                        return Err(mlua::Error::RuntimeError(format!(
+                            "invalid screensaver commands {:?}",
+                            value
+                        )))
+                    }
+                };
+                if commands.is_empty() || commands.iter().any(|cmd| cmd.trim().is_empty()) {
+                    return Err(mlua::Error::RuntimeError("empty command".to_string()));
+                }
+                let cycle = match options {
+                    Some(options) => options.get::<_, Option<u64>>("cycle")?,
+                    None => None,
+                };
+                let cycle = cycle.filter(|secs| *secs > 0).map(Duration::from_secs);
+                let (stop_tx, stop) = oneshot::channel();
+                // Replacing the sender stops a screensaver that is already running
+                this.screensaver.lock().unwrap().running = Some(stop_tx);
+                debug!(
+                    "Starting screensavers {:?}, cycling every {:?}",
+                    commands, cycle
+                );
+                utils::send_request(
+                    &this.tx,
+                    Request::Screensaver {
+                        commands,
+                        cycle,
+                        stop,
+                    },
+                );
+                Ok(())
+            },
+        );
+        methods.add_method("stop", |_lua, this, (): ()| {
+            this.screensaver.lock().unwrap().clear();
+            Ok(())
+        });
+        methods.add_method("running", |_lua, this, (): ()| {
+            Ok(this.screensaver.lock().unwrap().is_running())
+        });
+    }
+}
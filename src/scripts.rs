@@ -0,0 +1,116 @@
+//! Extra Lua scripts of the `[scripts]` settings. Each runs in an environment of its own, and
+//! its functions stay there: callbacks of a script are named `script:function`, where
+//! `script` is the path in the settings without `.lua`, so no script can replace a callback
+//! of the config or of another script.
+//!
+//! Reads of an environment fall through to the globals of the config, so a script only has
+//! its own variables: the tables it reaches there, like `string` or `Helpers`, are the ones
+//! of the config and every other script, and changing a field of one changes it for all.
+
+use log::debug;
+use mlua::{Function, Lua, Table};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const SCRIPT_ENVS: &str = "sleepwatcher.scripts";
+
+/// The name callbacks of the script at `file`, as listed in the settings, are prefixed with.
+pub fn name(file: &Path) -> String {
+    match file.extension() {
+        Some(extension) if extension == "lua" => file.with_extension(""),
+        _ => file.to_path_buf(),
+    }
+    .to_string_lossy()
+    .into_owned()
+}
+
+/// Whether the callback `fn_name` belongs to the script `script`.
+pub fn owns(script: &str, fn_name: &str) -> bool {
+    fn_name
+        .split_once(':')
+        .is_some_and(|(owner, _)| owner == script)
+}
+
+fn envs(lua: &Lua) -> mlua::Result<Table<'_>> {
+    match lua.named_registry_value::<Option<Table>>(SCRIPT_ENVS)? {
+        Some(envs) => Ok(envs),
+        None => {
+            let envs = lua.create_table()?;
+            lua.set_named_registry_value(SCRIPT_ENVS, envs.clone())?;
+            Ok(envs)
+        }
+    }
+}
+
+/// Runs the script at `path` in a fresh environment, which replaces the one of an earlier run.
+/// Globals of the config are visible to it, what it assigns to globals stays in its
+/// environment. Tables it reaches through the globals are shared, not copied.
+pub fn load(lua: &Lua, name: &str, path: &Path) -> anyhow::Result<()> {
+    let source = fs::read_to_string(path)?;
+    let env = lua.create_table()?;
+    let meta = lua.create_table()?;
+    meta.set("__index", lua.globals())?;
+    env.set_metatable(Some(meta));
+    // Before running it, so callbacks it names while loading can already be found
+    envs(lua)?.set(name, env.clone())?;
+    debug!("Loading script {} from {:?}", name, path);
+    lua.load(&source)
+        .set_name(path.to_string_lossy())
+        .set_environment(env)
+        .exec()?;
+    Ok(())
+}
+
+/// The Lua function a callback name stands for: `script:function` in the environment of a
+/// script, a global of the config otherwise.
+pub fn callback<'lua>(lua: &'lua Lua, fn_name: &str) -> mlua::Result<Function<'lua>> {
+    let Some((script, function)) = fn_name.split_once(':') else {
+        return lua.globals().get(fn_name);
+    };
+    let env: Option<Table> = envs(lua)?.get(script)?;
+    let env = env.ok_or_else(|| mlua::Error::RuntimeError(format!("no script {}", script)))?;
+    env.raw_get(function)
+}
+
+/// The directories to watch for changes of the config in `config_dir` and of `files`, which
+/// may be in directories of their own.
+pub fn watched_dirs(config_dir: &Path, files: &[PathBuf]) -> Vec<PathBuf> {
+    let mut dirs = vec![config_dir.to_path_buf()];
+    for file in files {
+        if let Some(dir) = config_dir.join(file).parent() {
+            if !dirs.iter().any(|known| known == dir) {
+                dirs.push(dir.to_path_buf());
+            }
+        }
+    }
+    dirs
+}
+
+/// The entry of `files` at `changed`, a full path.
+pub fn find<'a>(config_dir: &Path, files: &'a [PathBuf], changed: &Path) -> Option<&'a PathBuf> {
+    files.iter().find(|file| config_dir.join(file) == changed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_scripts_in_subdirectories() {
+        let config_dir = Path::new("/home/me/.config/sleepwatcher-rs");
+        let files = vec![
+            PathBuf::from("presence.lua"),
+            PathBuf::from("experiments/presence.lua"),
+            PathBuf::from("experiments/dim.lua"),
+        ];
+        assert_eq!(
+            watched_dirs(config_dir, &files),
+            [config_dir.to_path_buf(), config_dir.join("experiments")]
+        );
+        let found = |changed: &str| find(config_dir, &files, &config_dir.join(changed));
+        assert_eq!(found("presence.lua"), Some(&files[0]));
+        assert_eq!(found("experiments/presence.lua"), Some(&files[1]));
+        assert_eq!(found("dim.lua"), None);
+        assert_eq!(found("idle_config.lua"), None);
+    }
+}
@@ -0,0 +1,107 @@
+use anyhow::{anyhow, bail};
+use log::{debug, error};
+use mlua::{UserData, UserDataMethods};
+use nix::unistd::getuid;
+use std::collections::HashMap;
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::sync::{Arc, Mutex};
+use zbus::zvariant::Value;
+
+use super::config;
+use super::dbus::SecretServiceProxy;
+use super::utils;
+
+/// Reads `~/.config/sleepwatcher-rs/secrets/<name>`. The file has to belong to the user and
+/// must not be accessible by anyone else.
+fn secret_from_file(name: &str) -> anyhow::Result<Option<String>> {
+    if name.contains('/') || name.starts_with('.') {
+        bail!("invalid secret name {}", name);
+    }
+    let path = utils::xdg_config_path(None)?
+        .join(config::SECRETS_DIR_NAME)
+        .join(name);
+    let metadata = match fs::metadata(&path) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    if metadata.uid() != getuid().as_raw() || metadata.mode() & 0o077 != 0 {
+        bail!(
+            "refusing to read {:?}: it has to be owned by you with mode 0600",
+            path
+        );
+    }
+    let secret = fs::read_to_string(&path)?;
+    Ok(Some(secret.trim_end_matches('\n').to_string()))
+}
+
+/// Looks up the item with the attributes `application=sleepwatcher-rs name=<name>`.
+async fn secret_from_service(name: &str) -> anyhow::Result<Option<String>> {
+    let conn = zbus::Connection::session().await?;
+    let service = SecretServiceProxy::new(&conn).await?;
+
+    let attributes = HashMap::from([("application", config::APP_NAME), ("name", name)]);
+    let (unlocked, locked) = service.search_items(attributes).await?;
+    let Some(item) = unlocked.first() else {
+        if !locked.is_empty() {
+            bail!("secret {} is in a locked keyring", name);
+        }
+        return Ok(None);
+    };
+
+    let (_, session) = service.open_session("plain", &Value::from("")).await?;
+    let secrets = service.get_secrets(&[item.as_ref()], &session).await?;
+    let (_, _, value, _) = secrets
+        .into_values()
+        .next()
+        .ok_or_else(|| anyhow!("secret service returned no value for {}", name))?;
+    Ok(Some(String::from_utf8(value)?))
+}
+
+fn lookup(name: &str) -> anyhow::Result<Option<String>> {
+    if let Some(secret) = secret_from_file(name)? {
+        debug!("Secret {} read from file", name);
+        return Ok(Some(secret));
+    }
+
+    // Lua runs inside the tokio runtime, so the D-Bus calls get a runtime of their own
+    let name = name.to_string();
+    std::thread::spawn(move || {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?
+            .block_on(secret_from_service(&name))
+    })
+    .join()
+    .map_err(|_| anyhow!("secret lookup panicked"))?
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct SecretHelpers {
+    cache: Arc<Mutex<HashMap<String, String>>>,
+}
+
+impl UserData for SecretHelpers {
+    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_method("get", |_lua, this, name: String| {
+            if let Some(secret) = this.cache.lock().unwrap().get(&name) {
+                return Ok(Some(secret.clone()));
+            }
+            match lookup(&name) {
+                Ok(Some(secret)) => {
+                    this.cache.lock().unwrap().insert(name, secret.clone());
+                    Ok(Some(secret))
+                }
+                Ok(None) => {
+                    error!("Secret {} not found", name);
+                    Ok(None)
+                }
+                Err(e) => {
+                    error!("Error retrieving secret {}: {}", name, e);
+                    Ok(None)
+                }
+            }
+        });
+    }
+}
@@ -0,0 +1,470 @@
+use anyhow::Context;
+use log::debug;
+use serde::Deserialize;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+
+use super::backend;
+use super::config;
+use super::utils;
+
+/// Daemon settings that have to be known before the Lua config runs.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Settings {
+    pub sandbox: SandboxPolicy,
+    pub failures: FailurePolicy,
+    pub telemetry: TelemetrySettings,
+    pub hooks: HookSettings,
+    pub heartbeat: HeartbeatSettings,
+    pub presentation: PresentationSettings,
+    pub ipc: IpcSettings,
+    pub remote: RemoteSettings,
+    pub peers: PeersSettings,
+    pub accessibility: AccessibilitySettings,
+    pub state: StateSettings,
+    pub sleep: SleepSettings,
+    pub scripts: ScriptSettings,
+    pub fleet: FleetSettings,
+    pub backend: BackendSettings,
+    pub nested: NestedSettings,
+    pub audio: AudioSettings,
+    pub buttons: ButtonSettings,
+    pub sinks: Vec<SinkSettings>,
+}
+
+/// Which capabilities the Lua config gets. The defaults match the behavior before the policy
+/// was configurable.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct SandboxPolicy {
+    /// Run the config in the Luau sandbox with read-only builtins
+    pub enabled: bool,
+    /// Allow spawning commands (`IdleNotifier:run`, `os.execute`)
+    pub os_execute: bool,
+    /// Allow file access through `io.open`
+    pub io: bool,
+    /// Allow `require` of modules from the trusted paths
+    pub package: bool,
+    /// Allow Lua APIs that exchange data with other machines (`Peers:on_event`). Commands
+    /// reach the network regardless, unless `os_execute` is off as well.
+    pub network: bool,
+}
+
+impl Default for SandboxPolicy {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            os_execute: true,
+            io: false,
+            package: true,
+            network: true,
+        }
+    }
+}
+
+/// How repeated failures of the same command are handled.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct FailurePolicy {
+    /// Consecutive failures after which the command is suppressed
+    pub threshold: u32,
+    /// Suppression period after the first streak, doubled for every following streak
+    pub cooldown_secs: u64,
+    /// Upper bound for the suppression period
+    pub max_cooldown_secs: u64,
+    /// Send a desktop notification when a command gets suppressed
+    pub notify: bool,
+    /// Send a desktop notification with the end of stderr for every failed run
+    pub notify_each: bool,
+    /// A command requested again within this window after it was launched is skipped, 0
+    /// launches every request
+    pub coalesce_ms: u64,
+}
+
+impl Default for FailurePolicy {
+    fn default() -> Self {
+        Self {
+            threshold: 3,
+            cooldown_secs: 60,
+            max_cooldown_secs: 3600,
+            notify: true,
+            notify_each: false,
+            coalesce_ms: 2000,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct HookSettings {
+    /// Time the `on_exit` hooks and the commands they start get before the daemon exits
+    pub exit_timeout_secs: u64,
+    /// Time an idle callback may run before it's aborted and reported as an error
+    pub callback_timeout_secs: u64,
+    /// Show a desktop notification like "Away 47 minutes" after absences at least this long
+    pub notify_return_after_secs: Option<u64>,
+}
+
+impl Default for HookSettings {
+    fn default() -> Self {
+        Self {
+            exit_timeout_secs: 5,
+            callback_timeout_secs: 10,
+            notify_return_after_secs: None,
+        }
+    }
+}
+
+/// Commands around suspend, for configs that need no Lua. `before_sleep_cmd` holds a logind
+/// delay lock, so it finishes before the machine sleeps.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct SleepSettings {
+    pub before_sleep_cmd: Option<String>,
+    pub after_resume_cmd: Option<String>,
+    pub logind_idle_action: LogindIdlePolicy,
+}
+
+/// What happens when logind has an `IdleAction` of its own, which would suspend next to the
+/// idle actions of the config.
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogindIdlePolicy {
+    /// Log a warning at startup
+    #[default]
+    Warn,
+    /// Hold an idle inhibitor, so only the config suspends
+    Inhibit,
+    /// Skip `Power:idle_suspend()` and leave suspending to logind
+    Defer,
+    /// Don't check, for setups that use both on purpose
+    Ignore,
+}
+
+/// Config bundle fetched at startup instead of the local config, see `fleet.rs`.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct FleetSettings {
+    pub url: Option<String>,
+    /// Defaults to the URL with `.sig` appended
+    pub signature_url: Option<String>,
+    /// Base64 encoded Ed25519 public key
+    pub public_key: Option<String>,
+    pub timeout_secs: u64,
+}
+
+impl Default for FleetSettings {
+    fn default() -> Self {
+        Self {
+            url: None,
+            signature_url: None,
+            public_key: None,
+            timeout_secs: 10,
+        }
+    }
+}
+
+/// Which idle backends are tried, in order, see `backend.rs`.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct BackendSettings {
+    pub order: Vec<String>,
+}
+
+impl Default for BackendSettings {
+    fn default() -> Self {
+        Self {
+            order: vec![
+                backend::WAYLAND.to_string(),
+                backend::KDE_IDLE.to_string(),
+                backend::X11.to_string(),
+                backend::EVDEV.to_string(),
+            ],
+        }
+    }
+}
+
+/// A session nested in another Wayland session, see `nested.rs`.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct NestedSettings {
+    /// Count activity of the outer session as activity of this one
+    pub bridge: bool,
+    /// `WAYLAND_DISPLAY` of the outer session, detected if unset
+    pub parent: Option<String>,
+    /// The outer session counts as active until it was idle this long
+    pub idle_secs: u64,
+}
+
+impl Default for NestedSettings {
+    fn default() -> Self {
+        Self {
+            bridge: false,
+            parent: None,
+            idle_secs: 10,
+        }
+    }
+}
+
+/// Audio playback as a reason to stay awake, see `audio.rs`.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct AudioSettings {
+    /// Follow the playback streams with `pactl`
+    pub enabled: bool,
+    /// Applications whose streams don't count, by `application.name`, e.g. `speech-dispatcher`
+    pub ignore: Vec<String>,
+}
+
+/// The lid switch and the power button, see `buttons.rs`.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct ButtonSettings {
+    /// Keep logind from acting on the lid switch, the `lid-closed` handlers decide instead
+    pub handle_lid_switch: bool,
+    /// Keep logind from acting on the power key, the `power-button` handlers decide instead
+    pub handle_power_key: bool,
+    /// Socket of acpid, which reports the power button
+    pub acpid_socket: PathBuf,
+}
+
+impl Default for ButtonSettings {
+    fn default() -> Self {
+        Self {
+            handle_lid_switch: false,
+            handle_power_key: false,
+            acpid_socket: PathBuf::from("/run/acpid.socket"),
+        }
+    }
+}
+
+/// Where the session events go besides the event stream, see `sinks.rs`.
+#[derive(Deserialize, Debug, Clone)]
+pub struct SinkSettings {
+    #[serde(flatten)]
+    pub kind: SinkKind,
+    /// Names of the events to pass on, all if unset
+    pub events: Option<Vec<String>>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum SinkKind {
+    Exec {
+        command: String,
+    },
+    Dbus,
+    Webhook {
+        url: String,
+    },
+    Mqtt {
+        host: String,
+        #[serde(default = "default_mqtt_port")]
+        port: u16,
+        topic: String,
+    },
+    Log,
+}
+
+fn default_mqtt_port() -> u16 {
+    1883
+}
+
+/// Extra Lua scripts, run after the config. Each gets an environment of its own, so an error
+/// in one of them leaves the config and the other scripts working.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct ScriptSettings {
+    /// Relative to the config directory
+    pub files: Vec<PathBuf>,
+}
+
+/// File with the last event for shell scripts, in the format of the environment variables
+/// passed to commands.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct StateSettings {
+    pub file: Option<PathBuf>,
+}
+
+/// What happens to idle timeouts while a screen reader runs.
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ScreenReaderPolicy {
+    #[default]
+    Ignore,
+    /// Scale all idle timeouts with `extend_multiplier`
+    Extend,
+    /// Skip idle callbacks altogether
+    Disable,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct AccessibilitySettings {
+    pub screen_reader: ScreenReaderPolicy,
+    pub extend_multiplier: f64,
+}
+
+impl Default for AccessibilitySettings {
+    fn default() -> Self {
+        Self {
+            screen_reader: ScreenReaderPolicy::Ignore,
+            extend_multiplier: 3.0,
+        }
+    }
+}
+
+/// Who may use the control socket. The owner always has full access.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct IpcSettings {
+    /// Socket path instead of `$XDG_RUNTIME_DIR/sleepwatcher-rs/ctl.sock`, which other users
+    /// can't reach
+    pub socket: Option<PathBuf>,
+    /// Other users that may query the state, needs `socket`
+    pub readonly_uids: Vec<u32>,
+    /// Other users with full control, needs `socket`
+    pub allowed_uids: Vec<u32>,
+    /// File with a token that grants full control to read-only users
+    pub token_file: Option<PathBuf>,
+}
+
+/// Control over TLS from other machines, needs the `remote` cargo feature. Only `ping`,
+/// `status`, `lock` and `trigger` of the `actions` are accepted, and every command has to
+/// carry the token.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct RemoteSettings {
+    /// Address to listen on, e.g. `0.0.0.0:7070`. The listener is off when unset.
+    pub listen: Option<SocketAddr>,
+    /// PEM certificate chain
+    pub cert: Option<PathBuf>,
+    /// PEM private key
+    pub key: Option<PathBuf>,
+    /// File with the token that remote clients have to send
+    pub token_file: Option<PathBuf>,
+    /// Lua functions remote clients may call with `trigger`, none by default
+    pub actions: Vec<String>,
+}
+
+/// Lock coordination with other sleepwatcher-rs instances on the LAN.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct PeersSettings {
+    /// UDP address to listen on, e.g. `0.0.0.0:7071`. Peer mode is off when unset.
+    pub listen: Option<SocketAddr>,
+    /// Where lock and unlock events are sent, a broadcast address like `192.168.1.255:7071`
+    /// works as well
+    pub peers: Vec<SocketAddr>,
+    /// File with the token shared by all peers
+    pub token_file: Option<PathBuf>,
+    /// Lock the session when a peer locks
+    pub mirror_lock: bool,
+    /// Unlock the session when a peer unlocks
+    pub mirror_unlock: bool,
+}
+
+/// What `ctl presentation on` changes besides idle actions, night light and notifications.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct PresentationSettings {
+    /// Backlight device to raise, e.g. `intel_backlight`
+    pub backlight: Option<String>,
+    pub brightness_percent: u32,
+}
+
+impl Default for PresentationSettings {
+    fn default() -> Self {
+        Self {
+            backlight: None,
+            brightness_percent: 100,
+        }
+    }
+}
+
+/// Liveness signal for external watchdogs.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct HeartbeatSettings {
+    /// File that gets the current unix timestamp on every heartbeat
+    pub file: Option<PathBuf>,
+    pub interval_secs: u64,
+}
+
+impl Default for HeartbeatSettings {
+    fn default() -> Self {
+        Self {
+            file: None,
+            interval_secs: 60,
+        }
+    }
+}
+
+/// Trace export for centrally monitored machines, needs the `otlp` cargo feature.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct TelemetrySettings {
+    /// OTLP/gRPC collector, e.g. `http://localhost:4317`. Export is off when unset.
+    pub otlp_endpoint: Option<String>,
+}
+
+/// Parses a settings file. Sections and fields left out get their defaults, unknown ones are
+/// an error.
+pub fn parse(content: &str) -> anyhow::Result<Settings> {
+    Ok(toml::from_str(content)?)
+}
+
+/// Loads the settings file. A missing file gives the defaults. An invalid one is an error:
+/// falling back to the defaults would silently lift the restrictions of its `[sandbox]`.
+pub fn load() -> anyhow::Result<Settings> {
+    let path = utils::xdg_config_path(Some(config::SETTINGS_FILE_NAME.to_string()))
+        .context("Failed to locate the settings file")?;
+    if !path.exists() {
+        debug!("No settings file at {:?}, using defaults", path);
+        return Ok(Settings::default());
+    }
+    fs::read_to_string(&path)
+        .map_err(anyhow::Error::from)
+        .and_then(|content| parse(&content))
+        .with_context(|| format!("Invalid settings file {:?}", path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_sandbox_restrictions() {
+        let settings =
+            parse("[sandbox]\nos_execute = false\npackage = false\nnetwork = false\n").unwrap();
+        assert!(settings.sandbox.enabled);
+        assert!(!settings.sandbox.os_execute);
+        assert!(!settings.sandbox.io);
+        assert!(!settings.sandbox.package);
+        assert!(!settings.sandbox.network);
+    }
+
+    #[test]
+    fn rejects_invalid_sandbox() {
+        // Each would give the default policy, with os_execute and package allowed, if the
+        // file fell back to the defaults
+        for content in [
+            "[sandbox]\nos_exceute = false\n",
+            "[sandbox]\nos_execute = \"no\"\n",
+            "[sandbox]\nos_execute = false\n[sandbox]\n",
+            "[sandbox\nos_execute = false\n",
+        ] {
+            assert!(parse(content).is_err(), "{:?} was accepted", content);
+        }
+    }
+
+    #[test]
+    fn missing_sections_get_defaults() {
+        let settings = parse("").unwrap();
+        assert!(settings.sandbox.enabled);
+        assert!(settings.sandbox.os_execute);
+    }
+}
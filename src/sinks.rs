@@ -0,0 +1,226 @@
+//! Sinks get the lifecycle events of the session, the JSON lines of the event stream, and pass
+//! them on: to a command, a D-Bus signal, a webhook, an MQTT broker or the log. They are set up
+//! from `[[sinks]]` in the settings. The crate has no library target, so new kinds of sinks are
+//! added here: an implementation of `Sink`, a `SinkKind` and a call to `register`.
+
+use log::{debug, error, info, warn};
+use std::fmt;
+use std::process::Stdio;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+use tokio::sync::broadcast;
+
+use super::daemon;
+use super::events::EventsHandle;
+use super::settings::{SinkKind, SinkSettings};
+
+/// Gets the events one after the other, each as its name and the JSON line of the stream.
+pub trait Sink: Send + Sync + fmt::Debug {
+    fn name(&self) -> String;
+    /// Called from the async runtime, so it shouldn't block for long.
+    fn send(&self, event: &str, line: &str);
+}
+
+/// Runs `command` with `line` on stdin and logs a failure.
+fn pipe(mut command: Command, line: &str) {
+    command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped());
+    let program = command
+        .as_std()
+        .get_program()
+        .to_string_lossy()
+        .into_owned();
+    let line = format!("{}\n", line);
+    tokio::spawn(async move {
+        let mut child = match command.spawn() {
+            Ok(child) => child,
+            Err(e) => {
+                error!("Failed to run {}: {}", program, e);
+                return;
+            }
+        };
+        if let Some(mut stdin) = child.stdin.take() {
+            if let Err(e) = stdin.write_all(line.as_bytes()).await {
+                debug!("{} didn't read the event: {}", program, e);
+            }
+        }
+        match child.wait_with_output().await {
+            Ok(output) if output.status.success() => {}
+            Ok(output) => warn!(
+                "{} failed with {}: {}",
+                program,
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            ),
+            Err(e) => error!("Failed to wait for {}: {}", program, e),
+        }
+    });
+}
+
+/// Runs a shell command per event, with the event as `SLEEPWATCHER_EVENT` and the JSON line
+/// on stdin.
+#[derive(Debug)]
+struct ExecSink {
+    command: String,
+}
+
+impl Sink for ExecSink {
+    fn name(&self) -> String {
+        format!("exec {}", self.command)
+    }
+
+    fn send(&self, event: &str, line: &str) {
+        let mut command = Command::new("sh");
+        command
+            .arg("-c")
+            .arg(&self.command)
+            .env("SLEEPWATCHER_EVENT", event);
+        pipe(command, line);
+    }
+}
+
+/// Emits the `Event` signal of the daemon interface with the name and the JSON line.
+#[derive(Debug)]
+struct DbusSink;
+
+impl Sink for DbusSink {
+    fn name(&self) -> String {
+        "dbus".to_string()
+    }
+
+    fn send(&self, event: &str, line: &str) {
+        let Some(conn) = daemon::CONNECTION.get().cloned() else {
+            debug!("Not on the session bus, dropping {}", event);
+            return;
+        };
+        let body = (event.to_string(), line.to_string());
+        tokio::spawn(async move {
+            let emitted = conn
+                .emit_signal(
+                    None::<()>,
+                    daemon::OBJECT_PATH,
+                    daemon::BUS_NAME,
+                    "Event",
+                    &body,
+                )
+                .await;
+            if let Err(e) = emitted {
+                error!("Failed to emit the event signal: {}", e);
+            }
+        });
+    }
+}
+
+/// POSTs the JSON line with curl.
+#[derive(Debug)]
+struct WebhookSink {
+    url: String,
+}
+
+impl Sink for WebhookSink {
+    fn name(&self) -> String {
+        format!("webhook {}", self.url)
+    }
+
+    fn send(&self, _event: &str, line: &str) {
+        let mut command = Command::new("curl");
+        command
+            .args(["--fail", "--silent", "--show-error", "--max-time", "10"])
+            .args(["--header", "Content-Type: application/json"])
+            .args(["--data-binary", "@-"])
+            .arg(&self.url);
+        pipe(command, line);
+    }
+}
+
+/// Publishes the JSON line with `mosquitto_pub` to `topic`, with `/<event>` appended.
+#[derive(Debug)]
+struct MqttSink {
+    host: String,
+    port: u16,
+    topic: String,
+}
+
+impl Sink for MqttSink {
+    fn name(&self) -> String {
+        format!("mqtt {}:{}", self.host, self.port)
+    }
+
+    fn send(&self, event: &str, line: &str) {
+        let mut command = Command::new("mosquitto_pub");
+        command
+            .arg("-h")
+            .arg(&self.host)
+            .arg("-p")
+            .arg(self.port.to_string())
+            .arg("-t")
+            .arg(format!("{}/{}", self.topic.trim_end_matches('/'), event))
+            // The message from stdin
+            .arg("-s");
+        pipe(command, line);
+    }
+}
+
+#[derive(Debug)]
+struct LogSink;
+
+impl Sink for LogSink {
+    fn name(&self) -> String {
+        "log".to_string()
+    }
+
+    fn send(&self, _event: &str, line: &str) {
+        info!("{}", line);
+    }
+}
+
+/// Passes the events to `sink` from now on, only the ones named in `filter` if given.
+pub fn register(events: &EventsHandle, sink: Box<dyn Sink>, filter: Option<Vec<String>>) {
+    info!("Sending events to the {} sink", sink.name());
+    let mut lines = events.lock().unwrap().subscribe();
+    tokio::spawn(async move {
+        loop {
+            let line = match lines.recv().await {
+                Ok(line) => line,
+                Err(broadcast::error::RecvError::Lagged(missed)) => {
+                    warn!("The {} sink missed {} events", sink.name(), missed);
+                    continue;
+                }
+                Err(broadcast::error::RecvError::Closed) => return,
+            };
+            let event = serde_json::from_str::<serde_json::Value>(&line)
+                .ok()
+                .and_then(|value| value["event"].as_str().map(str::to_string))
+                .unwrap_or_default();
+            if filter
+                .as_ref()
+                .is_some_and(|filter| !filter.contains(&event))
+            {
+                continue;
+            }
+            sink.send(&event, &line);
+        }
+    });
+}
+
+/// Sets up the sinks of the settings.
+pub fn start(settings: &[SinkSettings], events: &EventsHandle) {
+    for settings in settings {
+        let sink: Box<dyn Sink> = match &settings.kind {
+            SinkKind::Exec { command } => Box::new(ExecSink {
+                command: command.clone(),
+            }),
+            SinkKind::Dbus => Box::new(DbusSink),
+            SinkKind::Webhook { url } => Box::new(WebhookSink { url: url.clone() }),
+            SinkKind::Mqtt { host, port, topic } => Box::new(MqttSink {
+                host: host.clone(),
+                port: *port,
+                topic: topic.clone(),
+            }),
+            SinkKind::Log => Box::new(LogSink),
+        };
+        register(events, sink, settings.events.clone());
+    }
+}
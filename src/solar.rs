@@ -0,0 +1,440 @@
+//! Sunrise and sunset at the location of the machine, for callbacks that follow the sun instead
+//! of the clock and for the night light. The position of the sun comes from the NOAA solar
+//! calculations, accurate to about a minute. The location is set by the config or asked from
+//! geoclue, and kept across config reloads.
+
+use chrono::{DateTime, Duration as ChronoDuration, Local, NaiveDate, TimeZone, Timelike, Utc};
+use futures::stream::StreamExt;
+use log::{debug, error, info};
+use mlua::{UserData, UserDataMethods};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{mpsc, Notify};
+
+use super::clock::Clock;
+use super::config;
+use super::dbus::{GeoclueClientProxy, GeoclueLocationProxy, GeoclueManagerProxy};
+use super::scripts;
+use super::types::Request;
+
+/// Upper bound for a single sleep, like the scheduler's
+const MAX_SLEEP: Duration = Duration::from_secs(30);
+/// Elevation of the sun's center at sunrise and sunset, below the horizon because of
+/// refraction and the radius of the sun
+const HORIZON: f64 = -0.833;
+/// Days searched for the next sunrise or sunset, a polar night is shorter
+const MAX_SEARCH_DAYS: i64 = 200;
+/// Geoclue's accuracy level `CITY`, plenty for the sun
+const GEOCLUE_ACCURACY_CITY: u32 = 4;
+/// Geoclue only reports moves further than this, in meters
+const GEOCLUE_THRESHOLD: u32 = 10_000;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Location {
+    pub latitude: f64,
+    pub longitude: f64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum SunEvent {
+    Sunrise,
+    Sunset,
+}
+
+#[derive(Debug)]
+struct SolarCallback {
+    event: SunEvent,
+    /// Moves the callback before (negative) or after the event
+    offset: ChronoDuration,
+    fn_name: String,
+    next: Option<DateTime<Local>>,
+}
+
+/// Declination of the sun and the equation of time in minutes at `time`.
+fn sun_position(time: DateTime<Utc>) -> (f64, f64) {
+    let julian_day = time.timestamp() as f64 / 86400.0 + 2440587.5;
+    let century = (julian_day - 2451545.0) / 36525.0;
+    let mean_longitude = (280.46646 + century * (36000.76983 + century * 0.0003032)) % 360.0;
+    let mean_anomaly = 357.52911 + century * (35999.05029 - 0.0001537 * century);
+    let eccentricity = 0.016708634 - century * (0.000042037 + 0.0000001267 * century);
+    let center = (mean_anomaly.to_radians()).sin()
+        * (1.914602 - century * (0.004817 + 0.000014 * century))
+        + (2.0 * mean_anomaly.to_radians()).sin() * (0.019993 - 0.000101 * century)
+        + (3.0 * mean_anomaly.to_radians()).sin() * 0.000289;
+    let omega = (125.04 - 1934.136 * century).to_radians();
+    let apparent_longitude = mean_longitude + center - 0.00569 - 0.00478 * omega.sin();
+    let mean_obliquity = 23.0
+        + (26.0 + (21.448 - century * (46.815 + century * (0.00059 - century * 0.001813))) / 60.0)
+            / 60.0;
+    let obliquity = (mean_obliquity + 0.00256 * omega.cos()).to_radians();
+    let declination = (obliquity.sin() * apparent_longitude.to_radians().sin()).asin();
+    let y = (obliquity / 2.0).tan().powi(2);
+    let l = mean_longitude.to_radians();
+    let m = mean_anomaly.to_radians();
+    let equation_of_time = 4.0
+        * (y * (2.0 * l).sin() - 2.0 * eccentricity * m.sin()
+            + 4.0 * eccentricity * y * m.sin() * (2.0 * l).cos()
+            - 0.5 * y * y * (4.0 * l).sin()
+            - 1.25 * eccentricity * eccentricity * (2.0 * m).sin())
+        .to_degrees();
+    (declination, equation_of_time)
+}
+
+/// Elevation of the sun above the horizon in degrees.
+pub fn elevation(location: Location, time: DateTime<Utc>) -> f64 {
+    let (declination, equation_of_time) = sun_position(time);
+    let minutes = f64::from(time.num_seconds_from_midnight()) / 60.0;
+    let solar_time = (minutes + equation_of_time + 4.0 * location.longitude).rem_euclid(1440.0);
+    let hour_angle = (solar_time / 4.0 - 180.0).to_radians();
+    let latitude = location.latitude.to_radians();
+    let zenith = (latitude.sin() * declination.sin()
+        + latitude.cos() * declination.cos() * hour_angle.cos())
+    .clamp(-1.0, 1.0)
+    .acos();
+    90.0 - zenith.to_degrees()
+}
+
+/// Sunrise or sunset on the solar day around noon of `date`, `None` during a polar day or
+/// night.
+fn sun_event(location: Location, date: NaiveDate, event: SunEvent) -> Option<DateTime<Utc>> {
+    let midnight = Utc.from_utc_datetime(&date.and_hms_opt(0, 0, 0)?);
+    let noon_minutes = 720.0 - 4.0 * location.longitude;
+    let (declination, equation_of_time) =
+        sun_position(midnight + ChronoDuration::seconds((noon_minutes * 60.0) as i64));
+    let latitude = location.latitude.to_radians();
+    let cos_hour_angle = HORIZON.to_radians().sin() / (latitude.cos() * declination.cos())
+        - latitude.tan() * declination.tan();
+    if !(-1.0..=1.0).contains(&cos_hour_angle) {
+        return None;
+    }
+    let hour_angle = cos_hour_angle.acos().to_degrees();
+    let minutes = match event {
+        SunEvent::Sunrise => noon_minutes - equation_of_time - 4.0 * hour_angle,
+        SunEvent::Sunset => noon_minutes - equation_of_time + 4.0 * hour_angle,
+    };
+    Some(midnight + ChronoDuration::seconds((minutes * 60.0).round() as i64))
+}
+
+/// The first `event` after `now`, moved by `offset`.
+fn next_event(
+    location: Location,
+    event: SunEvent,
+    offset: ChronoDuration,
+    now: DateTime<Local>,
+) -> Option<DateTime<Local>> {
+    let today = now.date_naive();
+    (-1..MAX_SEARCH_DAYS)
+        .filter_map(|days| sun_event(location, today + ChronoDuration::days(days), event))
+        .map(|time| time.with_timezone(&Local) + offset)
+        .find(|time| *time > now)
+}
+
+#[derive(Debug)]
+pub struct Solar {
+    location: Option<Location>,
+    callbacks: Vec<SolarCallback>,
+    /// Geoclue is followed already, across config reloads
+    locating: bool,
+    clock: Clock,
+    changed: Arc<Notify>,
+}
+
+pub type SolarHandle = Arc<Mutex<Solar>>;
+
+impl Solar {
+    pub fn new(clock: Clock) -> SolarHandle {
+        Arc::new(Mutex::new(Self {
+            location: None,
+            callbacks: Vec::new(),
+            locating: false,
+            clock,
+            changed: Arc::new(Notify::new()),
+        }))
+    }
+
+    pub fn clear(&mut self) {
+        self.callbacks.clear();
+        self.changed.notify_one();
+    }
+
+    /// Drops the callbacks of the script `script`, before it's loaded again.
+    pub fn forget_script(&mut self, script: &str) {
+        self.callbacks
+            .retain(|callback| !scripts::owns(script, &callback.fn_name));
+        self.changed.notify_one();
+    }
+
+    pub fn location(&self) -> Option<Location> {
+        self.location
+    }
+
+    fn set_location(&mut self, location: Location) {
+        if self.location == Some(location) {
+            return;
+        }
+        info!(
+            "Location set to {:.2}, {:.2}",
+            location.latitude, location.longitude
+        );
+        self.location = Some(location);
+        self.reschedule();
+    }
+
+    /// Computes the next run of the callbacks again, e.g. after the simulated clock moved.
+    pub fn reschedule(&mut self) {
+        let now = self.clock.now();
+        for callback in &mut self.callbacks {
+            callback.next = self
+                .location
+                .and_then(|location| next_event(location, callback.event, callback.offset, now));
+            debug!("Rescheduled {} to {:?}", callback.fn_name, callback.next);
+        }
+        self.changed.notify_one();
+    }
+
+    /// Looks for due callbacks again, e.g. after the simulated clock moved forward.
+    pub fn refresh(&self) {
+        self.changed.notify_one();
+    }
+
+    /// The next sunrise or sunset.
+    pub fn next_change(&self, now: DateTime<Local>) -> Option<DateTime<Local>> {
+        let location = self.location?;
+        [SunEvent::Sunrise, SunEvent::Sunset]
+            .into_iter()
+            .filter_map(|event| next_event(location, event, ChronoDuration::zero(), now))
+            .min()
+    }
+
+    /// Whether the sun is below the horizon, `None` without a location.
+    pub fn is_night(&self) -> Option<bool> {
+        let now = self.clock.now().with_timezone(&Utc);
+        Some(elevation(self.location?, now) < HORIZON)
+    }
+
+    /// Today's sunrise or sunset in local time.
+    fn today(&self, event: SunEvent) -> Option<DateTime<Local>> {
+        let now = self.clock.now();
+        let time = sun_event(self.location?, now.date_naive(), event)?;
+        Some(time.with_timezone(&Local))
+    }
+
+    fn add(&mut self, event: SunEvent, offset: ChronoDuration, fn_name: String) {
+        let next = self
+            .location
+            .and_then(|location| next_event(location, event, offset, self.clock.now()));
+        debug!("Calling {} at {:?} (next: {:?})", fn_name, event, next);
+        self.callbacks.push(SolarCallback {
+            event,
+            offset,
+            fn_name,
+            next,
+        });
+        self.changed.notify_one();
+    }
+
+    /// The callbacks that are due, and when the next one is.
+    fn poll(&mut self, now: DateTime<Local>) -> (Vec<String>, Option<DateTime<Local>>) {
+        let mut due = Vec::new();
+        let location = self.location;
+        for callback in &mut self.callbacks {
+            if callback.next.is_some_and(|next| next <= now) {
+                due.push(callback.fn_name.clone());
+                callback.next = location.and_then(|location| {
+                    next_event(location, callback.event, callback.offset, now)
+                });
+            }
+        }
+        let wakeup = self
+            .callbacks
+            .iter()
+            .filter_map(|callback| callback.next)
+            .min();
+        (due, wakeup)
+    }
+}
+
+pub async fn solar_run(solar: SolarHandle, tx: mpsc::Sender<Request>) {
+    let (changed, clock) = {
+        let solar = solar.lock().unwrap();
+        (solar.changed.clone(), solar.clock.clone())
+    };
+    loop {
+        let now = clock.now();
+        let (due, wakeup) = solar.lock().unwrap().poll(now);
+        for fn_name in due {
+            info!("Running solar callback {}", fn_name);
+            let _ = tx.send(Request::LuaCallback(fn_name)).await;
+        }
+        let sleep = wakeup
+            .and_then(|wakeup| (wakeup - now).to_std().ok())
+            .map_or(MAX_SLEEP, |remaining| remaining.min(MAX_SLEEP));
+        tokio::select! {
+            _ = tokio::time::sleep(sleep) => {},
+            _ = changed.notified() => {},
+        }
+    }
+}
+
+/// Follows the location reported by geoclue.
+async fn geoclue_run(solar: SolarHandle) -> anyhow::Result<()> {
+    let conn = zbus::Connection::system().await?;
+    let manager = GeoclueManagerProxy::new(&conn).await?;
+    let client = GeoclueClientProxy::builder(&conn)
+        .path(manager.get_client().await?)?
+        .build()
+        .await?;
+    client.set_desktop_id(config::APP_NAME).await?;
+    client
+        .set_requested_accuracy_level(GEOCLUE_ACCURACY_CITY)
+        .await?;
+    client.set_distance_threshold(GEOCLUE_THRESHOLD).await?;
+    let mut updates = client.receive_location_updated().await?;
+    client.start().await?;
+    info!("Asking geoclue for the location");
+    while let Some(update) = updates.next().await {
+        let path = update.args()?.new().to_owned();
+        let location = GeoclueLocationProxy::builder(&conn)
+            .path(path)?
+            .build()
+            .await?;
+        let location = Location {
+            latitude: location.latitude().await?,
+            longitude: location.longitude().await?,
+        };
+        solar.lock().unwrap().set_location(location);
+    }
+    anyhow::bail!("geoclue stopped sending updates")
+}
+
+#[derive(Clone, Debug)]
+pub struct SolarHelpers {
+    pub solar: SolarHandle,
+}
+
+impl SolarHelpers {
+    fn on(
+        &self,
+        event: SunEvent,
+        fn_name: String,
+        options: Option<mlua::Table>,
+    ) -> mlua::Result<()> {
+        let offset = match options {
+            Some(options) => options.get::<_, Option<f64>>("offset")?.unwrap_or(0.0),
+            None => 0.0,
+        };
+        let offset = ChronoDuration::seconds((offset * 60.0).round() as i64);
+        self.solar.lock().unwrap().add(event, offset, fn_name);
+        Ok(())
+    }
+}
+
+impl UserData for SolarHelpers {
+    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_method(
+            "set_location",
+            |_lua, this, (latitude, longitude): (f64, f64)| {
+                if !(-90.0..=90.0).contains(&latitude) || !(-180.0..=180.0).contains(&longitude) {
+                    return Err(mlua::Error::RuntimeError(format!(
+                        "invalid location {}, {}",
+                        latitude, longitude
+                    )));
+                }
+                this.solar.lock().unwrap().set_location(Location {
+                    latitude,
+                    longitude,
+                });
+                Ok(())
+            },
+        );
+        methods.add_method("locate", |_lua, this, (): ()| {
+            let mut solar = this.solar.lock().unwrap();
+            if std::mem::replace(&mut solar.locating, true) {
+                return Ok(());
+            }
+            let handle = this.solar.clone();
+            tokio::spawn(async move {
+                if let Err(e) = geoclue_run(handle.clone()).await {
+                    error!("Failed to get the location from geoclue: {}", e);
+                }
+                handle.lock().unwrap().locating = false;
+            });
+            Ok(())
+        });
+        methods.add_method(
+            "on_sunrise",
+            |_lua, this, (fn_name, options): (String, Option<mlua::Table>)| {
+                this.on(SunEvent::Sunrise, fn_name, options)
+            },
+        );
+        methods.add_method(
+            "on_sunset",
+            |_lua, this, (fn_name, options): (String, Option<mlua::Table>)| {
+                this.on(SunEvent::Sunset, fn_name, options)
+            },
+        );
+        methods.add_method("is_night", |_lua, this, (): ()| {
+            Ok(this.solar.lock().unwrap().is_night())
+        });
+        methods.add_method("sunrise", |_lua, this, (): ()| {
+            let sunrise = this.solar.lock().unwrap().today(SunEvent::Sunrise);
+            Ok(sunrise.map(|time| time.format("%H:%M").to_string()))
+        });
+        methods.add_method("sunset", |_lua, this, (): ()| {
+            let sunset = this.solar.lock().unwrap().today(SunEvent::Sunset);
+            Ok(sunset.map(|time| time.format("%H:%M").to_string()))
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LONDON: Location = Location {
+        latitude: 51.5074,
+        longitude: -0.1278,
+    };
+
+    fn utc(y: i32, m: u32, d: u32, h: u32, min: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(y, m, d, h, min, 0).unwrap()
+    }
+
+    /// Within two minutes of `expected`, the NOAA calculator rounds to the minute
+    fn assert_near(time: DateTime<Utc>, expected: DateTime<Utc>) {
+        let diff = (time - expected).num_seconds().abs();
+        assert!(diff <= 120, "{} is {}s off {}", time, diff, expected);
+    }
+
+    #[test]
+    fn matches_noaa_sunrise_and_sunset() {
+        // NOAA: 04:43 and 21:21 BST in London at the solstice
+        let date = NaiveDate::from_ymd_opt(2024, 6, 21).unwrap();
+        let sunrise = sun_event(LONDON, date, SunEvent::Sunrise).unwrap();
+        let sunset = sun_event(LONDON, date, SunEvent::Sunset).unwrap();
+        assert_near(sunrise, utc(2024, 6, 21, 3, 43));
+        assert_near(sunset, utc(2024, 6, 21, 20, 21));
+    }
+
+    #[test]
+    fn no_sunrise_in_polar_day() {
+        let tromso = Location {
+            latitude: 69.6492,
+            longitude: 18.9553,
+        };
+        let date = NaiveDate::from_ymd_opt(2024, 6, 21).unwrap();
+        assert_eq!(sun_event(tromso, date, SunEvent::Sunrise), None);
+        assert_eq!(sun_event(tromso, date, SunEvent::Sunset), None);
+    }
+
+    #[test]
+    fn elevation_at_sunrise_is_the_horizon() {
+        let date = NaiveDate::from_ymd_opt(2024, 6, 21).unwrap();
+        let sunrise = sun_event(LONDON, date, SunEvent::Sunrise).unwrap();
+        assert!((elevation(LONDON, sunrise) - HORIZON).abs() < 0.2);
+        // 90° - 51.5° + 23.4° at noon
+        let noon = elevation(LONDON, utc(2024, 6, 21, 12, 2));
+        assert!((noon - 61.9).abs() < 0.3, "{}", noon);
+        assert!(elevation(LONDON, utc(2024, 6, 21, 0, 0)) < 0.0);
+    }
+}
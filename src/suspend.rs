@@ -0,0 +1,40 @@
+//! Suspend detection without logind. `CLOCK_BOOTTIME` keeps counting while the system is
+//! suspended and `CLOCK_MONOTONIC` does not, so a growing gap between them means the system
+//! slept in between.
+
+use log::{debug, info};
+use nix::time::{clock_gettime, ClockId};
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+use super::types::Request;
+
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+/// Smaller jumps are scheduling noise
+const MIN_SUSPEND: Duration = Duration::from_secs(2);
+
+/// Total time spent suspended since boot.
+pub fn suspended_time() -> Duration {
+    let now = |clock| clock_gettime(clock).map(Duration::from).unwrap_or_default();
+    now(ClockId::CLOCK_BOOTTIME).saturating_sub(now(ClockId::CLOCK_MONOTONIC))
+}
+
+/// Polls the clocks and sends `Request::Woke` after every suspend.
+pub async fn suspend_watcher(tx: mpsc::Sender<Request>) {
+    info!("Detecting suspends from clock jumps");
+    let mut last = suspended_time();
+    let mut ticker = tokio::time::interval(POLL_INTERVAL);
+    loop {
+        ticker.tick().await;
+        let current = suspended_time();
+        let slept = current.saturating_sub(last);
+        last = current;
+        if slept < MIN_SUSPEND {
+            continue;
+        }
+        debug!("Clock jump of {:?} detected", slept);
+        if tx.send(Request::Woke(slept)).await.is_err() {
+            break;
+        }
+    }
+}
@@ -0,0 +1,37 @@
+//! Facts about the system for the config, so it can decide without shelling out, e.g. not to
+//! lock while a game of a blocklist runs.
+
+use mlua::{UserData, UserDataMethods};
+
+use super::peers;
+use super::utils;
+
+/// `wayland`, `x11` or `tty`. logind's `XDG_SESSION_TYPE` wins over the display variables.
+fn session_type() -> String {
+    match std::env::var("XDG_SESSION_TYPE").as_deref() {
+        Ok(kind @ ("wayland" | "x11" | "tty")) => kind.to_string(),
+        _ if std::env::var_os("WAYLAND_DISPLAY").is_some() => "wayland".to_string(),
+        _ if std::env::var_os("DISPLAY").is_some() => "x11".to_string(),
+        _ => "tty".to_string(),
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct SystemHelpers;
+
+impl UserData for SystemHelpers {
+    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_method(
+            "process_running",
+            |_lua, _this, names: mlua::Variadic<String>| {
+                let names: Vec<&str> = names.iter().map(String::as_str).collect();
+                Ok(utils::running_process(&names))
+            },
+        );
+        methods.add_method("getenv", |_lua, _this, name: String| {
+            Ok(std::env::var(name).ok())
+        });
+        methods.add_method("session_type", |_lua, _this, (): ()| Ok(session_type()));
+        methods.add_method("hostname", |_lua, _this, (): ()| Ok(peers::hostname()));
+    }
+}
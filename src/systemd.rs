@@ -0,0 +1,147 @@
+//! Running as a `Type=notify` systemd user service. Readiness and watchdog pings go to the
+//! socket in `NOTIFY_SOCKET`; outside of systemd, or with another service type, the variable
+//! isn't set and nothing is sent.
+
+use log::{debug, info, warn};
+use once_cell::sync::Lazy;
+use std::fs;
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use tokio::sync::mpsc;
+use xdg::BaseDirectories;
+
+use super::types::Request;
+
+/// Name of the unit written by `install-service`
+pub const UNIT_NAME: &str = "sleepwatcher-rs.service";
+
+static NOTIFY: Lazy<Option<(UnixDatagram, SocketAddr)>> = Lazy::new(|| {
+    let path = std::env::var_os("NOTIFY_SOCKET")?;
+    let path = path.to_string_lossy();
+    // A leading `@` stands for the abstract namespace
+    let addr = match path.strip_prefix('@') {
+        Some(name) => SocketAddr::from_abstract_name(name.as_bytes()),
+        None => SocketAddr::from_pathname(path.as_ref()),
+    };
+    let addr = match addr {
+        Ok(addr) => addr,
+        Err(e) => {
+            warn!("Invalid NOTIFY_SOCKET {}: {}", path, e);
+            return None;
+        }
+    };
+    Some((UnixDatagram::unbound().ok()?, addr))
+});
+
+static READY: AtomicBool = AtomicBool::new(false);
+
+/// Sends `state`, e.g. `WATCHDOG=1`, to systemd if it's listening.
+fn notify(state: &str) {
+    let Some((socket, addr)) = NOTIFY.as_ref() else {
+        return;
+    };
+    if let Err(e) = socket.send_to_addr(state.as_bytes(), addr) {
+        warn!("Failed to notify systemd: {}", e);
+    }
+}
+
+/// Tells systemd that the daemon is up, once.
+pub fn ready(status: &str) {
+    if READY.swap(true, Ordering::Relaxed) {
+        return;
+    }
+    if NOTIFY.is_some() {
+        info!("Notifying systemd that the daemon is ready");
+    }
+    notify(&format!("READY=1\nSTATUS={}", status));
+}
+
+/// Shows what the daemon is waiting for in `systemctl --user status`.
+pub fn status(status: &str) {
+    notify(&format!("STATUS={}", status));
+}
+
+pub fn stopping() {
+    notify("STOPPING=1");
+}
+
+pub fn watchdog() {
+    notify("WATCHDOG=1");
+}
+
+/// Half the watchdog timeout of the unit, if the watchdog is enabled for this process.
+pub fn watchdog_interval() -> Option<Duration> {
+    let usec: u64 = std::env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+    if let Ok(pid) = std::env::var("WATCHDOG_PID") {
+        if pid.parse() != Ok(std::process::id()) {
+            return None;
+        }
+    }
+    Some(Duration::from_micros(usec / 2))
+}
+
+/// Asks the request loop for a watchdog ping at `interval`, so systemd restarts the daemon when
+/// the loop is wedged, not only when the process died.
+pub async fn watchdog_run(interval: Duration, tx: mpsc::Sender<Request>) {
+    debug!("Pinging the systemd watchdog every {:?}", interval);
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        if tx.send(Request::Watchdog).await.is_err() {
+            break;
+        }
+    }
+}
+
+/// The user unit for the binary at `exe`, started with the graphical session so that
+/// `WAYLAND_DISPLAY` is set.
+fn unit(exe: &str, args: &[String]) -> String {
+    let mut exec_start = exe.to_string();
+    for arg in args {
+        exec_start.push(' ');
+        exec_start.push_str(arg);
+    }
+    format!(
+        "[Unit]
+Description=Idle, sleep and power manager for Wayland sessions
+Documentation=https://github.com/fishman/sleepwatcher-rs
+PartOf=graphical-session.target
+After=graphical-session.target
+Requisite=graphical-session.target
+
+[Service]
+Type=notify
+NotifyAccess=main
+ExecStart={exec_start}
+ExecReload=/bin/kill -HUP $MAINPID
+Restart=on-failure
+RestartSec=5
+TimeoutStartSec=30
+WatchdogSec=30
+
+[Install]
+WantedBy=graphical-session.target
+"
+    )
+}
+
+/// Writes the user unit to `~/.config/systemd/user`, or prints it with `stdout`.
+pub fn install(args: &[String], stdout: bool, force: bool) -> anyhow::Result<()> {
+    let exe = std::env::current_exe()?;
+    let unit = unit(&exe.to_string_lossy(), args);
+    if stdout {
+        print!("{}", unit);
+        return Ok(());
+    }
+    let path = BaseDirectories::new()?.place_config_file(format!("systemd/user/{}", UNIT_NAME))?;
+    if path.exists() && !force {
+        anyhow::bail!("{:?} exists, pass --force to replace it", path);
+    }
+    fs::write(&path, unit)?;
+    println!("Wrote {:?}, enable it with", path);
+    println!("  systemctl --user daemon-reload");
+    println!("  systemctl --user enable --now {}", UNIT_NAME);
+    Ok(())
+}
@@ -0,0 +1,102 @@
+//! Optional OpenTelemetry trace export, enabled with the `otlp` cargo feature.
+//!
+//! Every idle event starts a new trace. Spans for guard checks and commands that run
+//! afterwards become children of the most recent idle event.
+
+#[cfg(feature = "otlp")]
+mod imp {
+    use once_cell::sync::Lazy;
+    use opentelemetry::trace::{Status, TraceContextExt, Tracer};
+    use opentelemetry::{global, Context, KeyValue};
+    use opentelemetry_otlp::WithExportConfig;
+    use opentelemetry_sdk::{runtime, trace, Resource};
+    use std::sync::Mutex;
+
+    use crate::config;
+
+    static EVENT: Lazy<Mutex<Option<Context>>> = Lazy::new(|| Mutex::new(None));
+
+    pub fn init(endpoint: &str) -> anyhow::Result<()> {
+        opentelemetry_otlp::new_pipeline()
+            .tracing()
+            .with_exporter(
+                opentelemetry_otlp::new_exporter()
+                    .tonic()
+                    .with_endpoint(endpoint),
+            )
+            .with_trace_config(trace::config().with_resource(Resource::new([KeyValue::new(
+                "service.name",
+                config::APP_NAME,
+            )])))
+            .install_batch(runtime::Tokio)?;
+        Ok(())
+    }
+
+    /// Flushes pending spans.
+    pub fn shutdown() {
+        global::shutdown_tracer_provider();
+    }
+
+    /// A running span, ended when dropped.
+    pub struct Span(Context);
+
+    impl Span {
+        pub fn set_attribute(&self, key: &'static str, value: impl ToString) {
+            self.0
+                .span()
+                .set_attribute(KeyValue::new(key, value.to_string()));
+        }
+
+        pub fn error(&self, message: impl ToString) {
+            self.0.span().set_status(Status::error(message.to_string()));
+        }
+    }
+
+    impl Drop for Span {
+        fn drop(&mut self) {
+            self.0.span().end();
+        }
+    }
+
+    /// Starts the trace of an idle event.
+    pub fn idle_event(name: &'static str) -> Span {
+        let span = global::tracer(config::APP_NAME).start_with_context(name, &Context::new());
+        let cx = Context::new().with_span(span);
+        *EVENT.lock().unwrap() = Some(cx.clone());
+        Span(cx)
+    }
+
+    /// Starts a span below the most recent idle event.
+    pub fn span(name: &'static str) -> Span {
+        let parent = EVENT.lock().unwrap().clone().unwrap_or_default();
+        let span = global::tracer(config::APP_NAME).start_with_context(name, &parent);
+        Span(parent.with_span(span))
+    }
+}
+
+#[cfg(not(feature = "otlp"))]
+mod imp {
+    pub fn init(_endpoint: &str) -> anyhow::Result<()> {
+        anyhow::bail!("sleepwatcher-rs was built without the otlp feature")
+    }
+
+    pub fn shutdown() {}
+
+    pub struct Span;
+
+    impl Span {
+        pub fn set_attribute(&self, _key: &'static str, _value: impl ToString) {}
+
+        pub fn error(&self, _message: impl ToString) {}
+    }
+
+    pub fn idle_event(_name: &'static str) -> Span {
+        Span
+    }
+
+    pub fn span(_name: &'static str) -> Span {
+        Span
+    }
+}
+
+pub use imp::*;
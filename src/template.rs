@@ -0,0 +1,63 @@
+use log::warn;
+use std::collections::HashMap;
+
+/// Replaces `${name}` placeholders in a command with the values known to the executor.
+/// Unknown placeholders are left as they are.
+pub fn expand(cmd: &str, vars: &HashMap<&str, String>) -> String {
+    let mut result = String::with_capacity(cmd.len());
+    let mut rest = cmd;
+    while let Some(start) = rest.find("${") {
+        result.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        let Some(end) = after.find('}') else {
+            rest = &rest[start..];
+            break;
+        };
+        let name = &after[..end];
+        match vars.get(name) {
+            Some(value) => result.push_str(value),
+            None => {
+                warn!("Unknown placeholder ${{{}}} in {}", name, cmd);
+                result.push_str(&rest[start..start + end + 3]);
+            }
+        }
+        rest = &after[end + 1..];
+    }
+    result.push_str(rest);
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars() -> HashMap<&'static str, String> {
+        HashMap::from([
+            ("event", "sleep".to_string()),
+            ("timeout", "300".to_string()),
+        ])
+    }
+
+    #[test]
+    fn replaces_known_placeholders() {
+        assert_eq!(
+            expand("notify ${event} after ${timeout}s", &vars()),
+            "notify sleep after 300s"
+        );
+    }
+
+    #[test]
+    fn keeps_unknown_placeholders() {
+        assert_eq!(
+            expand("echo ${nope} ${event}", &vars()),
+            "echo ${nope} sleep"
+        );
+    }
+
+    #[test]
+    fn keeps_unterminated_placeholder() {
+        assert_eq!(expand("echo ${event", &vars()), "echo ${event");
+        assert_eq!(expand("${event} ${", &vars()), "sleep ${");
+        assert_eq!(expand("a $ {event}", &vars()), "a $ {event}");
+    }
+}
@@ -0,0 +1,156 @@
+use log::{debug, info};
+use mlua::{UserData, UserDataMethods};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+use super::types::Request;
+
+const THERMAL_DIR: &str = "/sys/class/thermal";
+const HWMON_DIR: &str = "/sys/class/hwmon";
+const POLL_INTERVAL: Duration = Duration::from_secs(10);
+/// A threshold is armed again once the temperature dropped this far below it
+const HYSTERESIS: f64 = 3.0;
+
+fn read_trimmed(path: &Path) -> Option<String> {
+    fs::read_to_string(path)
+        .ok()
+        .map(|content| content.trim().to_string())
+}
+
+/// Reads a sysfs temperature in millidegrees Celsius.
+fn read_millidegrees(path: &Path) -> Option<f64> {
+    read_trimmed(path)?
+        .parse::<f64>()
+        .ok()
+        .map(|millis| millis / 1000.0)
+}
+
+fn subdirs(dir: &str) -> Vec<PathBuf> {
+    let mut dirs: Vec<PathBuf> = fs::read_dir(dir)
+        .map(|entries| entries.flatten().map(|entry| entry.path()).collect())
+        .unwrap_or_default();
+    dirs.sort();
+    dirs
+}
+
+/// Reads the temperature of a zone in °C. `zone` is a thermal zone directory like
+/// `thermal_zone0`, a thermal zone type like `x86_pkg_temp`, or a hwmon name like `coretemp`,
+/// whose first sensor is used.
+pub fn read(zone: &str) -> Option<f64> {
+    let thermal_zones = subdirs(THERMAL_DIR);
+    let by_dir = thermal_zones
+        .iter()
+        .find(|dir| dir.file_name().is_some_and(|name| name == zone));
+    let by_type = || {
+        thermal_zones
+            .iter()
+            .find(|dir| read_trimmed(&dir.join("type")).as_deref() == Some(zone))
+    };
+    if let Some(dir) = by_dir.or_else(by_type) {
+        return read_millidegrees(&dir.join("temp"));
+    }
+    subdirs(HWMON_DIR)
+        .iter()
+        .find(|dir| read_trimmed(&dir.join("name")).as_deref() == Some(zone))
+        .and_then(|dir| read_millidegrees(&dir.join("temp1_input")))
+}
+
+#[derive(Debug)]
+struct Threshold {
+    zone: String,
+    celsius: f64,
+    fn_name: String,
+    /// Cleared when the callback ran, set again once the zone cooled down
+    armed: bool,
+}
+
+/// Callbacks for temperatures that rise above a threshold.
+#[derive(Debug, Default)]
+pub struct Thermal {
+    thresholds: Vec<Threshold>,
+}
+
+pub type ThermalHandle = Arc<Mutex<Thermal>>;
+
+impl Thermal {
+    pub fn new() -> ThermalHandle {
+        Arc::new(Mutex::new(Self::default()))
+    }
+
+    pub fn clear(&mut self) {
+        self.thresholds.clear();
+    }
+
+    /// Returns `(fn_name, zone, temperature)` for every threshold that was crossed.
+    fn poll(&mut self) -> Vec<(String, String, f64)> {
+        let mut due = Vec::new();
+        for threshold in self.thresholds.iter_mut() {
+            let Some(temperature) = read(&threshold.zone) else {
+                continue;
+            };
+            if threshold.armed && temperature >= threshold.celsius {
+                threshold.armed = false;
+                due.push((
+                    threshold.fn_name.clone(),
+                    threshold.zone.clone(),
+                    temperature,
+                ));
+            } else if temperature < threshold.celsius - HYSTERESIS {
+                threshold.armed = true;
+            }
+        }
+        due
+    }
+}
+
+/// Checks the registered thresholds periodically.
+pub async fn thermal_run(thermal: ThermalHandle, tx: mpsc::Sender<Request>) {
+    let mut ticker = tokio::time::interval(POLL_INTERVAL);
+    loop {
+        ticker.tick().await;
+        let due = thermal.lock().unwrap().poll();
+        for (fn_name, zone, temperature) in due {
+            info!("{} reached {}°C, running {}", zone, temperature, fn_name);
+            if tx
+                .send(Request::Thermal(fn_name, zone, temperature))
+                .await
+                .is_err()
+            {
+                return;
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ThermalHelpers {
+    pub thermal: ThermalHandle,
+}
+
+impl UserData for ThermalHelpers {
+    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_method("get", |_lua, _this, zone: String| Ok(read(&zone)));
+        methods.add_method(
+            "at",
+            |_lua, this, (zone, celsius, fn_name): (String, f64, String)| {
+                if read(&zone).is_none() {
+                    return Err(mlua::Error::RuntimeError(format!(
+                        "no temperature sensor {}",
+                        zone
+                    )));
+                }
+                debug!("Thermal callback {} at {}°C on {}", fn_name, celsius, zone);
+                this.thermal.lock().unwrap().thresholds.push(Threshold {
+                    zone,
+                    celsius,
+                    fn_name,
+                    armed: true,
+                });
+                Ok(())
+            },
+        );
+    }
+}
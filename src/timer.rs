@@ -0,0 +1,147 @@
+//! Timers of the Lua config: one-shot and recurring callbacks after a number of seconds,
+//! independent of idle events and the time of day. They run on the monotonic clock, so time
+//! spent suspended doesn't count, and are dropped when the config is reloaded.
+
+use log::{debug, info};
+use mlua::{UserData, UserDataMethods};
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{mpsc, Notify};
+use tokio::time::Instant;
+
+use super::scripts;
+use super::types::Request;
+
+/// Recurring timers can't be shorter, so a typo doesn't flood the event loop
+const MIN_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Debug)]
+struct Timer {
+    fn_name: String,
+    due: Instant,
+    /// `None` for one-shot timers
+    interval: Option<Duration>,
+}
+
+#[derive(Debug, Default)]
+pub struct Timers {
+    timers: BTreeMap<u32, Timer>,
+    next_id: u32,
+    changed: Arc<Notify>,
+}
+
+pub type TimersHandle = Arc<Mutex<Timers>>;
+
+impl Timers {
+    pub fn new() -> TimersHandle {
+        Arc::new(Mutex::new(Self::default()))
+    }
+
+    pub fn clear(&mut self) {
+        self.timers.clear();
+        self.changed.notify_one();
+    }
+
+    /// Drops the callbacks of the script `script`, before it's loaded again.
+    pub fn forget_script(&mut self, script: &str) {
+        self.timers
+            .retain(|_, timer| !scripts::owns(script, &timer.fn_name));
+        self.changed.notify_one();
+    }
+
+    fn add(&mut self, fn_name: String, delay: Duration, interval: Option<Duration>) -> u32 {
+        self.next_id += 1;
+        self.timers.insert(
+            self.next_id,
+            Timer {
+                fn_name,
+                due: Instant::now() + delay,
+                interval,
+            },
+        );
+        self.changed.notify_one();
+        self.next_id
+    }
+
+    /// The callbacks of the timers that are due, and when the next one is.
+    fn poll(&mut self, now: Instant) -> (Vec<String>, Option<Instant>) {
+        let mut due = Vec::new();
+        self.timers.retain(|_, timer| {
+            if timer.due > now {
+                return true;
+            }
+            due.push(timer.fn_name.clone());
+            match timer.interval {
+                Some(interval) => {
+                    // Runs missed while the event loop was busy are skipped
+                    timer.due = (timer.due + interval).max(now);
+                    true
+                }
+                None => false,
+            }
+        });
+        let next = self.timers.values().map(|timer| timer.due).min();
+        (due, next)
+    }
+}
+
+pub async fn timer_run(timers: TimersHandle, tx: mpsc::Sender<Request>) {
+    let changed = timers.lock().unwrap().changed.clone();
+
+    loop {
+        let (due, next) = timers.lock().unwrap().poll(Instant::now());
+        for fn_name in due {
+            debug!("Timer calling {}", fn_name);
+            let _ = tx.send(Request::LuaCallback(fn_name)).await;
+        }
+        match next {
+            Some(next) => tokio::select! {
+                _ = tokio::time::sleep_until(next) => {},
+                _ = changed.notified() => {},
+            },
+            None => changed.notified().await,
+        }
+    }
+}
+
+fn duration(seconds: f64) -> mlua::Result<Duration> {
+    Duration::try_from_secs_f64(seconds)
+        .map_err(|_| mlua::Error::RuntimeError(format!("invalid timer duration {}", seconds)))
+}
+
+#[derive(Clone, Debug)]
+pub struct TimerHelpers {
+    pub timers: TimersHandle,
+}
+
+impl UserData for TimerHelpers {
+    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_method("after", |_lua, this, (seconds, fn_name): (f64, String)| {
+            let delay = duration(seconds)?;
+            debug!("Timer calling {} after {:?}", fn_name, delay);
+            Ok(this.timers.lock().unwrap().add(fn_name, delay, None))
+        });
+        methods.add_method("every", |_lua, this, (seconds, fn_name): (f64, String)| {
+            let interval = duration(seconds)?;
+            if interval < MIN_INTERVAL {
+                return Err(mlua::Error::RuntimeError(format!(
+                    "timer interval {}s is below {:?}",
+                    seconds, MIN_INTERVAL
+                )));
+            }
+            info!("Timer calling {} every {:?}", fn_name, interval);
+            Ok(this
+                .timers
+                .lock()
+                .unwrap()
+                .add(fn_name, interval, Some(interval)))
+        });
+        methods.add_method("cancel", |_lua, this, id: u32| {
+            let mut timers = this.timers.lock().unwrap();
+            let cancelled = timers.timers.remove(&id).is_some();
+            timers.changed.notify_one();
+            Ok(cancelled)
+        });
+    }
+}
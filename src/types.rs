@@ -1,9 +1,122 @@
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::sync::{broadcast, mpsc, oneshot};
+use uuid::Uuid;
+
+use super::backend::IdleEvent;
+use super::daemon::Away;
+use super::handoff;
+use super::hooks::StartContext;
+use super::ipc::CtlCommand;
+use super::outputs::Sequence;
+use super::peers::PeerEvent;
+use super::power::{Confirm, Guard};
+use super::privileged;
+
 #[derive(Debug)]
 pub enum Request {
-    LuaReload,
     LuaMethod(String),
     Reset,
+    /// A script of the `[scripts]` settings changed, only it is loaded again
+    ScriptChanged(PathBuf),
     Run(String),
     RunOnce(String),
+    /// The `set` command of `Restore:command`, run through `sh -c` as it is
+    RestoreCommand(String),
     OnBattery(bool),
+    /// Charge of the battery in percent
+    BatteryLevel(f64),
+    IdleSuspend(Vec<Guard>, Option<Confirm>),
+    /// Hibernate, after running updates finished if set and once confirmed
+    Hibernate(bool, Option<Confirm>),
+    /// Terminate the locked session
+    Logout,
+    Privileged(privileged::Action),
+    LuaCallback(String),
+    RunStream {
+        id: u64,
+        cmd: String,
+        fn_name: String,
+        cancel: oneshot::Receiver<()>,
+    },
+    /// A line of a stream, these come through a channel of their own so that chatty commands
+    /// can't fill up the request channel
+    StreamLine(String, u64, String),
+    /// The idle timeout of a maintenance job passed
+    JobIdled(String),
+    /// The user came back while a maintenance job was waiting or running
+    JobResumed(String),
+    /// A maintenance job exited, successfully or not
+    JobDone(String, bool),
+    /// An escalation stage is due: callback and seconds since locking
+    Escalation(String, u64),
+    /// An activity source of the Lua config reported activity
+    Activity(String),
+    /// Event of an idle watch of a backend that isn't dispatched by the Wayland event loop
+    Idle(Uuid, IdleEvent),
+    /// A held idle notification idles, the activity sources were quiet for its timeout
+    ActivityIdle(Uuid),
+    /// An inhibitor provider of the Lua config has to be polled
+    InhibitorPoll(String),
+    /// A thermal threshold was crossed: callback, zone and temperature
+    Thermal(String, String, f64),
+    Screensaver {
+        commands: Vec<String>,
+        cycle: Option<Duration>,
+        stop: oneshot::Receiver<()>,
+    },
+    /// Supervise a kiosk application, resetting it on every message of `control`
+    Kiosk {
+        cmd: String,
+        reset: Option<String>,
+        control: mpsc::Receiver<()>,
+    },
+    Ctl(CtlCommand, oneshot::Sender<serde_json::Value>),
+    /// A control connection following the event stream
+    Subscribe(oneshot::Sender<broadcast::Receiver<String>>),
+    /// An internal failure for the `on_error` hook, with context as key/value pairs
+    Error(String, Vec<(String, String)>),
+    /// Graceful shutdown with the reason passed to the `on_exit` hooks
+    Shutdown(String),
+    /// All subsystems are up, runs the `on_start` hooks
+    Started(StartContext),
+    Heartbeat,
+    /// Time for a ping of the systemd watchdog
+    Watchdog,
+    TimezoneChanged,
+    /// The duration of a presentation mode session ran out
+    PresentationExpired(u64),
+    /// Switch to a profile from the schedule, the profile rules or D-Bus
+    Profile(String),
+    /// State of the instance replaced with `--replace`, restored once the config is loaded
+    Handoff(Box<handoff::State>),
+    /// A locker process started, with its name, or the last one exited
+    Locker(Option<String>),
+    /// The logind delay lock of a suspend, held until the `PrepareSleep` handler and the
+    /// commands it started are done
+    SleepDelay(zbus::zvariant::OwnedFd),
+    /// The user came back after the given absence
+    Returned(Away),
+    /// The system resumed after being suspended for the given time
+    Woke(Duration),
+    /// An AT-SPI screen reader was started or stopped
+    ScreenReader(bool),
+    /// A monitor was plugged in after the start, with its name and description
+    OutputAdded {
+        name: String,
+        description: Option<String>,
+    },
+    /// A monitor was unplugged
+    OutputRemoved(String),
+    /// The lid was closed or opened, and whether the machine is docked
+    Lid {
+        closed: bool,
+        docked: bool,
+    },
+    /// The power button was pressed, as reported by acpid
+    PowerButton,
+    /// A peer locked or unlocked its session, with the peer's host name
+    Peer(PeerEvent, String),
+    /// Turn outputs off one after another, see `Outputs:power_off_sequence`
+    OutputSequence(Sequence),
 }
@@ -1,16 +1,63 @@
-use log::info;
+use anyhow::{bail, Context};
+use log::{debug, info};
+use std::collections::VecDeque;
 use std::path::PathBuf;
+use std::process::{ExitStatus, Stdio};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use sysinfo::{ProcessExt, System, SystemExt};
+use tokio::io::{AsyncBufReadExt, BufReader};
 use tokio::process::Command;
+use tokio::sync::mpsc::{self, error::TrySendError};
 use xdg::BaseDirectories;
 
 use super::config;
+use super::types::Request;
 
-fn get_args(cmd: String) -> (String, Vec<String>) {
-    let mut args = cmd.split_whitespace();
-    let cmd = args.next().unwrap().to_string();
-    let args: Vec<String> = args.map(|s| s.to_string()).collect();
-    (cmd, args)
+/// Number of stderr lines kept from a command
+const STDERR_TAIL_LINES: usize = 10;
+
+/// Splits a command line into the program and its arguments, with shell quoting rules.
+pub fn get_args(cmd: &str) -> anyhow::Result<(String, Vec<String>)> {
+    let Some(mut args) = shlex::split(cmd) else {
+        bail!("unbalanced quotes in {}", cmd);
+    };
+    if args.is_empty() {
+        bail!("empty command");
+    }
+    let program = args.remove(0);
+    Ok((program, args))
+}
+
+/// Sends a request from a synchronous context such as a Lua method, where blocking on the
+/// runtime is not allowed. While the channel is full the request waits in a task, or in a
+/// thread outside the runtime, so it isn't lost.
+pub fn send_request(tx: &mpsc::Sender<Request>, request: Request) {
+    let request = match tx.try_send(request) {
+        Ok(()) => return,
+        Err(TrySendError::Full(request)) => request,
+        Err(TrySendError::Closed(request)) => {
+            debug!("Request loop stopped, dropping {:?}", request);
+            return;
+        }
+    };
+    let tx = tx.clone();
+    match tokio::runtime::Handle::try_current() {
+        Ok(runtime) => {
+            runtime.spawn(async move {
+                if let Err(e) = tx.send(request).await {
+                    debug!("Request loop stopped, dropping {:?}", e.0);
+                }
+            });
+        }
+        Err(_) => {
+            std::thread::spawn(move || {
+                if let Err(e) = tx.blocking_send(request) {
+                    debug!("Request loop stopped, dropping {:?}", e.0);
+                }
+            });
+        }
+    }
 }
 
 pub fn xdg_config_path(filename: Option<String>) -> std::io::Result<PathBuf> {
@@ -25,37 +72,114 @@ pub fn xdg_config_path(filename: Option<String>) -> std::io::Result<PathBuf> {
     }
 }
 
-pub async fn run(cmd: String) -> anyhow::Result<(), Box<dyn std::error::Error>> {
+/// Outcome of a finished command.
+#[derive(Debug)]
+pub struct Finished {
+    pub status: ExitStatus,
+    /// The last lines the command wrote to stderr
+    pub stderr_tail: Vec<String>,
+}
+
+pub async fn run(cmd: String, env: Vec<(&'static str, String)>) -> anyhow::Result<Finished> {
     info!("cmd: {}", cmd);
-    //TODO: get_args executed twice
-    let (cmd, args) = get_args(cmd);
+    let (cmd, args) = get_args(&cmd)?;
 
     let mut child = Command::new(&cmd)
         .args(args)
+        .envs(env)
+        .stderr(Stdio::piped())
         .spawn()
-        .expect(&format!("Failed to spawn {} process", cmd));
+        .with_context(|| format!("Failed to spawn {} process", cmd))?;
+
+    let tail = Arc::new(Mutex::new(VecDeque::new()));
+    let mut lines = BufReader::new(child.stderr.take().unwrap()).lines();
+    let reader = {
+        let tail = tail.clone();
+        let cmd = cmd.clone();
+        tokio::spawn(async move {
+            while let Ok(Some(line)) = lines.next_line().await {
+                debug!("{}: {}", cmd, line);
+                let mut tail = tail.lock().unwrap();
+                if tail.len() == STDERR_TAIL_LINES {
+                    tail.pop_front();
+                }
+                tail.push_back(line);
+            }
+        })
+    };
 
     // Wait for the process to complete to avoid a defunct process
-    let _ = child
+    let status = child
         .wait()
         .await
-        .expect(&format!("{} process failed to run", cmd));
+        .with_context(|| format!("{} process failed to run", cmd))?;
+    // Daemonizing commands such as `swaylock -f` leave stderr open in the background process,
+    // so only wait briefly for the remaining output
+    let _ = tokio::time::timeout(Duration::from_millis(200), reader).await;
+
+    let stderr_tail = tail.lock().unwrap().drain(..).collect();
+    Ok(Finished {
+        status,
+        stderr_tail,
+    })
+}
+
+/// The first of `names` that a running process has, matched exactly.
+pub fn running_process(names: &[&str]) -> Option<String> {
+    // Only the process list, a full refresh also reads disks, networks and CPU usage
+    let mut s = System::new();
+    s.refresh_processes();
+    let running = s
+        .processes()
+        .values()
+        .find(|p| names.contains(&p.name()))
+        .map(|p| p.name().to_string());
+    running
+}
+
+/// `running_process` on the blocking pool, for async code on the runtime's workers.
+pub async fn running_process_async(names: &'static [&'static str]) -> Option<String> {
+    tokio::task::spawn_blocking(move || running_process(names))
+        .await
+        .ok()
+        .flatten()
+}
 
-    Ok(())
+/// Runs the command unless a process of the same name is running already, in which case
+/// `None` is returned.
+pub async fn run_once(
+    cmd: String,
+    env: Vec<(&'static str, String)>,
+) -> anyhow::Result<Option<Finished>> {
+    let (cmd_name, _) = get_args(&cmd)?;
+    let running = tokio::task::spawn_blocking(move || running_process(&[&cmd_name])).await?;
+    if running.is_some() {
+        return Ok(None);
+    }
+    Ok(Some(run(cmd, env).await?))
 }
 
-pub async fn run_once(cmd: String) -> anyhow::Result<(), Box<dyn std::error::Error>> {
-    let s = System::new_all();
-    //TODO: get_args executed twice
-    let (cmd_name, _) = get_args(cmd.clone());
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    // Check if the process is already running
-    let is_running = s
-        .processes_by_exact_name(&cmd_name)
-        .any(|p| p.name() == cmd_name);
+    #[test]
+    fn splits_commands_like_a_shell() {
+        let (program, args) = get_args("notify-send 'Back soon' \"in 5 min\"").unwrap();
+        assert_eq!(program, "notify-send");
+        assert_eq!(args, ["Back soon", "in 5 min"]);
+        assert!(get_args("swaylock -c '000000").is_err());
+        assert!(get_args("  ").is_err());
+    }
 
-    if !is_running {
-        let _ = run(cmd.clone()).await;
+    #[tokio::test]
+    async fn requests_wait_while_the_channel_is_full() {
+        let (tx, mut rx) = mpsc::channel(1);
+        send_request(&tx, Request::Heartbeat);
+        send_request(&tx, Request::Watchdog);
+        assert!(matches!(rx.recv().await, Some(Request::Heartbeat)));
+        assert!(matches!(rx.recv().await, Some(Request::Watchdog)));
+        drop(rx);
+        send_request(&tx, Request::Heartbeat);
     }
-    Ok(())
 }
@@ -1,4 +1,4 @@
-use log::info;
+use log::{debug, error, info};
 use nix::fcntl;
 use nix::sys::stat::Mode;
 use std::{
@@ -63,14 +63,14 @@ impl JoystickHandler {
             if unsafe { FDS[f] } >= 0 {
                 continue;
             }
-            println!("Adding joystick {}", inputdev);
+            debug!("Adding joystick {}", inputdev);
             match fcntl::open(
                 inputdev.as_str(),
                 fcntl::OFlag::O_RDONLY | fcntl::OFlag::O_NONBLOCK,
                 Mode::empty(),
             ) {
                 Ok(fd) => {
-                    println!("{} => [{}]", inputdev, fd);
+                    debug!("{} => [{}]", inputdev, fd);
                     unsafe {
                         FDS[f] = fd;
 
@@ -80,7 +80,7 @@ impl JoystickHandler {
                     return;
                 }
                 Err(err) => {
-                    eprintln!("Error opening {}: {}", inputdev, err);
+                    error!("Error opening {}: {}", inputdev, err);
                     return;
                 }
             }
@@ -0,0 +1,55 @@
+//! Idle time of an X server for the `x11` idle backend, with the `x11` cargo feature.
+
+#[cfg(feature = "x11")]
+mod imp {
+    use std::time::Duration;
+    use x11rb::connection::{Connection, RequestConnection};
+    use x11rb::protocol::screensaver::{self, ConnectionExt};
+    use x11rb::protocol::xproto::Window;
+    use x11rb::rust_connection::RustConnection;
+
+    pub struct IdleQuery {
+        conn: RustConnection,
+        root: Window,
+    }
+
+    impl IdleQuery {
+        /// Connects to the server of `DISPLAY`, which needs the MIT-SCREEN-SAVER extension.
+        pub fn connect() -> anyhow::Result<Self> {
+            let (conn, screen) = x11rb::connect(None)?;
+            if conn
+                .extension_information(screensaver::X11_EXTENSION_NAME)?
+                .is_none()
+            {
+                anyhow::bail!("the X server has no MIT-SCREEN-SAVER extension");
+            }
+            let root = conn.setup().roots[screen].root;
+            Ok(Self { conn, root })
+        }
+
+        /// Time since the last input.
+        pub fn idle(&self) -> anyhow::Result<Duration> {
+            let info = self.conn.screensaver_query_info(self.root)?.reply()?;
+            Ok(Duration::from_millis(info.ms_since_user_input.into()))
+        }
+    }
+}
+
+#[cfg(not(feature = "x11"))]
+mod imp {
+    use std::time::Duration;
+
+    pub struct IdleQuery;
+
+    impl IdleQuery {
+        pub fn connect() -> anyhow::Result<Self> {
+            anyhow::bail!("sleepwatcher-rs was built without the x11 feature")
+        }
+
+        pub fn idle(&self) -> anyhow::Result<Duration> {
+            anyhow::bail!("sleepwatcher-rs was built without the x11 feature")
+        }
+    }
+}
+
+pub use imp::IdleQuery;